@@ -0,0 +1,143 @@
+//! Access control lists by client prefix. The rules of an operation are
+//! checked in order and the first one containing the client decides, the
+//! operations without a matching rule are allowed.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::config::{AclAction, AclRule, Operation};
+use crate::dns::DnsRequest;
+use crate::dns_header::OpCode;
+use crate::dns_type::QType;
+use crate::{Error, Result};
+
+/// Address prefix such as 192.0.2.0/24, a plain address is a single host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub length: u8,
+}
+
+impl Cidr {
+    pub fn new(address: IpAddr, length: u8) -> Option<Self> {
+        let max_length = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (length <= max_length).then_some(Self { address, length })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        let (address, prefix, bits) = match (address, &self.address) {
+            (IpAddr::V4(a), IpAddr::V4(p)) => (u32::from(*a) as u128, u32::from(*p) as u128, 32),
+            (IpAddr::V6(a), IpAddr::V6(p)) => (u128::from(*a), u128::from(*p), 128),
+            _ => return false,
+        };
+        let shift = bits - self.length as u32;
+        shift >= bits || address >> shift == prefix >> shift
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, length) = match s.split_once('/') {
+            Some((address, length)) => (address.parse::<IpAddr>()?, Some(length.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let length = length.unwrap_or(match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        });
+        Self::new(address, length).ok_or_else(|| anyhow::anyhow!("Invalid prefix length in {}", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+/// Operation the request asks for, recursion being checked on top of query
+/// for the questions we are not authoritative for
+pub fn operation(dns_request: &DnsRequest) -> Operation {
+    match dns_request.header.third_byte.operation_code {
+        OpCode::Notify => Operation::Notify,
+        OpCode::Update => Operation::Update,
+        _ if dns_request
+            .questions
+            .iter()
+            .any(|question| question.q_type == QType::Axfr) =>
+        {
+            Operation::Transfer
+        }
+        _ => Operation::Query,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn check(&self, operation: &Operation, client: &IpAddr) -> AclAction {
+        self.rules
+            .iter()
+            .find(|rule| &rule.operation == operation && rule.prefix.contains(client))
+            .map_or(AclAction::Allow, |rule| rule.action.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() -> Result<()> {
+        let cidr: Cidr = "192.0.2.0/24".parse()?;
+        assert!(cidr.contains(&"192.0.2.200".parse()?));
+        assert!(!cidr.contains(&"192.0.3.1".parse()?));
+        assert!(!cidr.contains(&"::1".parse()?));
+        let any: Cidr = "::/0".parse()?;
+        assert!(any.contains(&"2001:db8::1".parse()?));
+        let host: Cidr = "2001:db8::1".parse()?;
+        assert_eq!(host.length, 128);
+        assert_eq!(host.to_string(), "2001:db8::1/128");
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("example/8".parse::<Cidr>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl() -> Result<()> {
+        let rule = |operation, action, prefix: &str| -> Result<AclRule> {
+            Ok(AclRule {
+                operation,
+                action,
+                prefix: prefix.parse()?,
+            })
+        };
+        let acl = Acl::new(vec![
+            rule(Operation::Recursion, AclAction::Allow, "10.0.0.0/8")?,
+            rule(Operation::Recursion, AclAction::Refuse, "0.0.0.0/0")?,
+            rule(Operation::Transfer, AclAction::Drop, "0.0.0.0/0")?,
+        ]);
+        let inside: IpAddr = "10.1.2.3".parse()?;
+        let outside: IpAddr = "192.0.2.1".parse()?;
+        assert_eq!(acl.check(&Operation::Recursion, &inside), AclAction::Allow);
+        assert_eq!(
+            acl.check(&Operation::Recursion, &outside),
+            AclAction::Refuse
+        );
+        assert_eq!(acl.check(&Operation::Query, &outside), AclAction::Allow);
+        assert_eq!(acl.check(&Operation::Transfer, &inside), AclAction::Drop);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Policy blocking names listed in hosts files or adblock lists, with their
+//! subdomains. Allowed names and their subdomains are never blocked, whether
+//! they come from an allow list or from adblock exceptions (`@@||domain^`).
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use crate::cache::CachedAnswer;
+use crate::config::BlockResponse;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{labels_from_str, labels_to_key, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::Result;
+
+/// TTL of the sinkhole records
+const SINKHOLE_TTL: u32 = 60;
+
+/// Names hosts files map to the loopback address without blocking them
+const HOSTS_LOCAL_NAMES: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+/// Rule held by a line of a list, with whether it is an exception
+fn parse_line(line: &str) -> Vec<(String, bool)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return vec![];
+    }
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule)
+            .map(|domain| (domain, true))
+            .into_iter()
+            .collect();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule)
+            .map(|domain| (domain, false))
+            .into_iter()
+            .collect();
+    }
+
+    let line = line.split('#').next().unwrap_or_default();
+    let mut tokens = line.split_whitespace().peekable();
+    // hosts file: an address then names
+    if tokens
+        .peek()
+        .is_some_and(|token| token.parse::<IpAddr>().is_ok())
+    {
+        tokens.next();
+    }
+    tokens
+        .filter(|name| !HOSTS_LOCAL_NAMES.contains(name))
+        .filter_map(domain)
+        .map(|domain| (domain, false))
+        .collect()
+}
+
+/// Domain of an adblock rule, only rules on whole domains are kept
+fn adblock_domain(rule: &str) -> Option<String> {
+    let (name, options) = rule.split_once('^').unwrap_or((rule, ""));
+    if !(options.is_empty() || options.starts_with('$')) {
+        return None;
+    }
+    domain(name)
+}
+
+/// Lowercase key of a domain name, none when it is not one
+fn domain(name: &str) -> Option<String> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let labels = labels_from_str(name);
+    match valid && !labels.is_empty() {
+        true => Some(labels_to_key(&labels)),
+        false => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    blocked: HashSet<String>,
+    allowed: HashSet<String>,
+    response: BlockResponse,
+}
+
+impl Blocklist {
+    pub fn new(response: BlockResponse) -> Self {
+        Self {
+            response,
+            ..Self::default()
+        }
+    }
+
+    pub fn load(
+        blocklists: &[String],
+        allowlists: &[String],
+        response: BlockResponse,
+    ) -> Result<Self> {
+        let mut blocklist = Self::new(response);
+        for (paths, allow) in [(blocklists, false), (allowlists, true)] {
+            for path in paths {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Could not read list {}: {}", path, e))?;
+                blocklist.add_rules(&content, allow);
+            }
+        }
+        Ok(blocklist)
+    }
+
+    /// Adds the rules of a list in hosts, adblock or plain domain format.
+    /// All the names of an allow list are allowed.
+    pub fn add_rules(&mut self, content: &str, allow: bool) {
+        for (domain, exception) in content.lines().flat_map(parse_line) {
+            match allow || exception {
+                true => self.allowed.insert(domain),
+                false => self.blocked.insert(domain),
+            };
+        }
+    }
+
+    pub fn is_blocked(&self, name: &[DnsLabel]) -> bool {
+        let keys: Vec<String> = (0..name.len())
+            .map(|start| labels_to_key(&name[start..]))
+            .collect();
+        !keys.iter().any(|key| self.allowed.contains(key))
+            && keys.iter().any(|key| self.blocked.contains(key))
+    }
+
+    /// Answer to the question when its name is blocked
+    pub fn answer(&self, question: &DnsQuestion) -> Option<CachedAnswer> {
+        if !self.is_blocked(&question.q_name) {
+            return None;
+        }
+        let mut answer = CachedAnswer {
+            rcode: RCode::NoError,
+            answers: vec![],
+            authorities: vec![],
+            secure: false,
+            prefetch: false,
+        };
+        match &self.response {
+            BlockResponse::NxDomain => answer.rcode = RCode::NameError,
+            BlockResponse::NoData => {}
+            BlockResponse::Refused => answer.rcode = RCode::Refused,
+            BlockResponse::Sinkhole(address) => {
+                let (r_type, r_data) = match address {
+                    IpAddr::V4(address) => (QType::A, address.octets().to_vec()),
+                    IpAddr::V6(address) => (QType::Aaaa, address.octets().to_vec()),
+                };
+                if question.q_type == r_type && question.q_class == QClass::In {
+                    answer.answers.push(DnsAnswer {
+                        r_name: question.q_name.clone(),
+                        r_type,
+                        r_class: QClass::In,
+                        ttl: SINKHOLE_TTL,
+                        rd_length: r_data.len() as u16,
+                        r_data,
+                    });
+                }
+            }
+        }
+        Some(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTS: &str = "
+# ads
+127.0.0.1 localhost
+0.0.0.0 ads.example tracker.example # inline comment
+::1 ip6-localhost
+";
+
+    const ADBLOCK: &str = "
+[Adblock Plus 2.0]
+! comment
+||doubleclick.example^
+||metrics.example^$third-party
+||cdn.example/script.js
+@@||good.ads.example^
+plain.example
+";
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    #[test]
+    fn test_blocklist_formats() -> Result<()> {
+        let mut blocklist = Blocklist::new(BlockResponse::NxDomain);
+        blocklist.add_rules(HOSTS, false);
+        blocklist.add_rules(ADBLOCK, false);
+        blocklist.add_rules("tracker.example", true);
+
+        let blocked = |name: &str| blocklist.is_blocked(&labels_from_str(name));
+        assert!(blocked("ads.example"));
+        assert!(blocked("x.ADS.example."));
+        assert!(blocked("doubleclick.example"));
+        assert!(blocked("metrics.example"));
+        assert!(blocked("plain.example"));
+        assert!(!blocked("localhost"));
+        assert!(!blocked("example"));
+        assert!(!blocked("cdn.example"));
+        // exceptions and allow lists win over the blocklists
+        assert!(!blocked("good.ads.example"));
+        assert!(!blocked("www.good.ads.example"));
+        assert!(!blocked("tracker.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocklist_responses() -> Result<()> {
+        let answer = |response: BlockResponse, q_type: QType| {
+            let mut blocklist = Blocklist::new(response);
+            blocklist.add_rules("||ads.example^", false);
+            assert!(blocklist
+                .answer(&question("www.example", QType::A))
+                .is_none());
+            blocklist.answer(&question("ads.example", q_type)).unwrap()
+        };
+        assert_eq!(
+            answer(BlockResponse::NxDomain, QType::A).rcode,
+            RCode::NameError
+        );
+        assert_eq!(
+            answer(BlockResponse::Refused, QType::A).rcode,
+            RCode::Refused
+        );
+        let nodata = answer(BlockResponse::NoData, QType::A);
+        assert_eq!(nodata.rcode, RCode::NoError);
+        assert!(nodata.answers.is_empty());
+
+        let sinkhole = BlockResponse::Sinkhole("0.0.0.0".parse()?);
+        let a = answer(sinkhole.clone(), QType::A);
+        assert_eq!(a.answers[0].r_data, vec![0, 0, 0, 0]);
+        assert_eq!(a.answers[0].ttl, SINKHOLE_TTL);
+        assert!(answer(sinkhole, QType::Aaaa).answers.is_empty());
+        let aaaa = answer(BlockResponse::Sinkhole("::".parse()?), QType::Aaaa);
+        assert_eq!(aaaa.answers[0].r_data, vec![0; 16]);
+        Ok(())
+    }
+}
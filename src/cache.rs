@@ -0,0 +1,666 @@
+//! Cache of the forwarded answers, kept as RRsets keyed by name, type and
+//! class. TTLs count down from the time of insertion, and the least recently
+//! used entries are evicted once the cache is full.
+//! Negative answers are cached too: NODATA for a name and type, NXDOMAIN for
+//! a whole name.
+//! https://datatracker.ietf.org/doc/html/rfc1035#section-7.4
+//! Expired entries are kept for a while, to be served when the upstream is
+//! unavailable. Popular entries about to expire are flagged so that they can
+//! be refreshed ahead of time.
+//! https://datatracker.ietf.org/doc/html/rfc2308
+//! https://datatracker.ietf.org/doc/html/rfc8767
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::CacheConfig;
+use crate::dns::DnsReply;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{labels_eq, labels_to_key, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dns_zone::negative_ttl;
+use crate::dnssec::{read_name, Rrsig};
+use crate::dnssec_signer::group_rrsets;
+
+/// Bound on the CNAME chain assembled from cached RRsets
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// TTL of the expired records served when the upstream is unavailable
+/// https://datatracker.ietf.org/doc/html/rfc8767#section-4
+pub const STALE_TTL: u32 = 30;
+
+/// Lowercase owner name, type and class. NXDOMAIN entries have no type, they
+/// answer every type of the name.
+type CacheKey = (String, Option<QType>, QClass);
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    /// Stored with the clamped TTL, empty for negative entries
+    records: Vec<DnsAnswer>,
+    /// Signatures covering the RRset
+    rrsigs: Vec<DnsAnswer>,
+    /// SOA and denial proofs of negative entries
+    authorities: Vec<DnsAnswer>,
+    /// Validated, or marked authentic by the upstream
+    secure: bool,
+    stored_at: u32,
+    ttl: u32,
+    /// Position in the usage order
+    last_used: u64,
+    /// Times the entry was served
+    hits: u32,
+}
+
+impl CacheEntry {
+    /// Seconds left before expiration, none once expired
+    fn remaining(&self, now: u32) -> Option<u32> {
+        let age = now.saturating_sub(self.stored_at);
+        self.ttl.checked_sub(age).filter(|remaining| *remaining > 0)
+    }
+
+    /// Seconds since expiration
+    fn stale_for(&self, now: u32) -> u32 {
+        now.saturating_sub(self.stored_at).saturating_sub(self.ttl)
+    }
+
+    /// Popular entry in the last fraction of its TTL, worth refreshing
+    /// before it expires
+    fn needs_prefetch(&self, now: u32, config: &CacheConfig) -> bool {
+        let Some(remaining) = self.remaining(now) else {
+            return false;
+        };
+        config.prefetch > 0.0
+            && self.hits >= config.prefetch_hits
+            && remaining as f64 <= self.ttl as f64 * config.prefetch
+    }
+}
+
+/// Answer assembled from the cache
+#[derive(Debug, PartialEq)]
+pub struct CachedAnswer {
+    pub rcode: RCode,
+    /// CNAME chain and final RRset, with their remaining TTL
+    pub answers: Vec<DnsAnswer>,
+    /// SOA of negative answers, with the proofs when DNSSEC was asked for
+    pub authorities: Vec<DnsAnswer>,
+    /// Every RRset of the answer is secure
+    pub secure: bool,
+    /// Part of the answer is popular and about to expire
+    pub prefetch: bool,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys by last use, oldest first
+    usage: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+/// Records with their TTL replaced
+fn with_ttl(records: &[DnsAnswer], ttl: u32) -> Vec<DnsAnswer> {
+    records
+        .iter()
+        .map(|record| DnsAnswer {
+            ttl,
+            ..record.clone()
+        })
+        .collect()
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Moves an entry to the most recently used end
+    fn touch(&mut self, key: &CacheKey) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.usage.remove(&entry.last_used);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.usage.insert(self.tick, key.clone());
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.usage.remove(&entry.last_used);
+        }
+    }
+
+    /// Adds an entry, replacing the previous one and evicting the least
+    /// recently used ones when full
+    fn store(&mut self, key: CacheKey, entry: CacheEntry) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.config.capacity {
+            let Some((_, oldest)) = self.usage.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(key.clone(), entry);
+        self.touch(&key);
+    }
+
+    /// Stores an RRset with its signatures, replacing the previous one
+    pub fn insert_rrset(
+        &mut self,
+        rrset: &[DnsAnswer],
+        rrsigs: &[DnsAnswer],
+        secure: bool,
+        now: u32,
+    ) {
+        let Some(first) = rrset.first() else {
+            return;
+        };
+        let ttl = rrset
+            .iter()
+            .map(|record| record.ttl)
+            .min()
+            .unwrap_or_default()
+            .clamp(self.config.min_ttl, self.config.max_ttl);
+
+        let name = labels_to_key(&first.r_name);
+        // the name exists after all
+        self.remove(&(name.clone(), None, first.r_class.clone()));
+        let key = (name, Some(first.r_type.clone()), first.r_class.clone());
+        let entry = CacheEntry {
+            records: with_ttl(rrset, ttl),
+            rrsigs: with_ttl(rrsigs, ttl),
+            authorities: vec![],
+            secure,
+            stored_at: now,
+            ttl,
+            last_used: 0,
+            hits: 0,
+        };
+        self.store(key, entry);
+    }
+
+    /// Stores a negative answer for a name and type, or for the whole name
+    /// when `q_type` is none. The authority section must hold the SOA.
+    pub fn insert_negative(
+        &mut self,
+        name: &[DnsLabel],
+        q_type: Option<QType>,
+        q_class: &QClass,
+        authorities: &[DnsAnswer],
+        secure: bool,
+        now: u32,
+    ) {
+        let Some(soa) = authorities.iter().find(|r| r.r_type == QType::Soa) else {
+            return;
+        };
+        let ttl = negative_ttl(soa).clamp(
+            self.config.min_ttl.min(self.config.max_negative_ttl),
+            self.config.max_negative_ttl,
+        );
+        let key = (labels_to_key(name), q_type, q_class.clone());
+        let entry = CacheEntry {
+            records: vec![],
+            rrsigs: vec![],
+            authorities: with_ttl(authorities, ttl),
+            secure,
+            stored_at: now,
+            ttl,
+            last_used: 0,
+            hits: 0,
+        };
+        self.store(key, entry);
+    }
+
+    /// Stores the answer RRsets of the CNAME chain starting at the question
+    /// name, and the negative answer at its end for NXDOMAIN and NODATA
+    /// replies. The other records are not cached, as the upstream is not
+    /// trusted for the names it was not asked about.
+    pub fn insert_reply(
+        &mut self,
+        question: &DnsQuestion,
+        reply: &DnsReply,
+        secure: bool,
+        now: u32,
+    ) {
+        let rcode = &reply.header.fourth_byte.response_code;
+        let cacheable = matches!(rcode, RCode::NoError | RCode::NameError);
+        if !cacheable || reply.header.third_byte.truncation {
+            return;
+        }
+        let records: Vec<DnsAnswer> = reply
+            .answers
+            .iter()
+            .filter(|record| record.r_type != QType::Rrsig)
+            .cloned()
+            .collect();
+
+        let mut name = question.q_name.clone();
+        let mut chain = vec![name.clone()];
+        // a CNAME that can not be read leaves the end of the chain unknown
+        let mut broken = false;
+        if question.q_type != QType::Cname {
+            for _ in 0..MAX_CNAME_CHAIN {
+                let Some(cname) = records
+                    .iter()
+                    .find(|r| r.r_type == QType::Cname && labels_eq(&r.r_name, &name))
+                else {
+                    break;
+                };
+                let Ok((target, _)) = read_name(&cname.r_data, 0) else {
+                    broken = true;
+                    break;
+                };
+                name = target;
+                chain.push(name.clone());
+            }
+        }
+
+        for rrset in group_rrsets(&records) {
+            let owner = &rrset[0].r_name;
+            if !chain.iter().any(|name| labels_eq(name, owner)) {
+                continue;
+            }
+            let rrsigs: Vec<DnsAnswer> = reply
+                .answers
+                .iter()
+                .filter(|record| {
+                    record.r_type == QType::Rrsig
+                        && labels_eq(&record.r_name, owner)
+                        && Rrsig::try_from(&record.r_data[..])
+                            .is_ok_and(|rrsig| rrsig.type_covered == rrset[0].r_type)
+                })
+                .cloned()
+                .collect();
+            self.insert_rrset(&rrset, &rrsigs, secure, now);
+        }
+        if broken {
+            return;
+        }
+
+        // referrals carry NS records instead of the SOA
+        let authorities: Vec<DnsAnswer> = reply
+            .authorities
+            .iter()
+            .filter(|record| record.r_type != QType::Ns)
+            .cloned()
+            .collect();
+        let q_class = &question.q_class;
+        let answered = records
+            .iter()
+            .any(|r| r.r_type == question.q_type && labels_eq(&r.r_name, &name));
+        match rcode {
+            RCode::NameError => {
+                self.insert_negative(&name, None, q_class, &authorities, secure, now)
+            }
+            _ if !answered => {
+                let q_type = Some(question.q_type.clone());
+                self.insert_negative(&name, q_type, q_class, &authorities, secure, now)
+            }
+            _ => {}
+        }
+    }
+
+    /// Unexpired entry, with the records carrying the remaining TTL.
+    /// With `stale`, entries expired for less than the serve-stale window
+    /// are also returned, with the stale TTL.
+    fn get(
+        &mut self,
+        name: &[DnsLabel],
+        q_type: Option<&QType>,
+        q_class: &QClass,
+        now: u32,
+        stale: bool,
+    ) -> Option<CacheEntry> {
+        let key = (labels_to_key(name), q_type.cloned(), q_class.clone());
+        let entry = self.entries.get_mut(&key)?;
+        let within_window = entry.stale_for(now) <= self.config.serve_stale;
+        let remaining = match entry.remaining(now) {
+            Some(remaining) => remaining,
+            None if stale && within_window => STALE_TTL,
+            None => {
+                if !within_window {
+                    self.remove(&key);
+                }
+                return None;
+            }
+        };
+        entry.hits = entry.hits.saturating_add(1);
+        let found = CacheEntry {
+            records: with_ttl(&entry.records, remaining),
+            rrsigs: with_ttl(&entry.rrsigs, remaining),
+            authorities: with_ttl(&entry.authorities, remaining),
+            ..entry.clone()
+        };
+        self.touch(&key);
+        Some(found)
+    }
+
+    /// Answers a question from the cache, following cached CNAMEs.
+    /// Signatures and denial proofs are included when `dnssec_ok` is set.
+    pub fn lookup(
+        &mut self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        now: u32,
+    ) -> Option<CachedAnswer> {
+        self.answer(question, dnssec_ok, now, false)
+    }
+
+    /// Like [`Cache::lookup`], also using recently expired entries. Meant for
+    /// when the upstream cannot be reached.
+    pub fn lookup_stale(
+        &mut self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        now: u32,
+    ) -> Option<CachedAnswer> {
+        self.answer(question, dnssec_ok, now, true)
+    }
+
+    fn answer(
+        &mut self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        now: u32,
+        stale: bool,
+    ) -> Option<CachedAnswer> {
+        let q_class = &question.q_class;
+        let mut answer = CachedAnswer {
+            rcode: RCode::NoError,
+            answers: vec![],
+            authorities: vec![],
+            secure: true,
+            prefetch: false,
+        };
+        let config = self.config.clone();
+        let negative = |mut answer: CachedAnswer, entry: CacheEntry| {
+            answer.secure &= entry.secure;
+            answer.prefetch |= entry.needs_prefetch(now, &config);
+            answer.authorities = entry.authorities;
+            if !dnssec_ok {
+                answer.authorities.retain(|record| {
+                    !matches!(record.r_type, QType::Rrsig | QType::Nsec | QType::Nsec3)
+                });
+            }
+            Some(answer)
+        };
+
+        let mut name = question.q_name.clone();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            if let Some(entry) = self.get(&name, None, q_class, now, stale) {
+                answer.rcode = RCode::NameError;
+                return negative(answer, entry);
+            }
+            let found = self.get(&name, Some(&question.q_type), q_class, now, stale);
+            let is_final = found.is_some();
+            let entry = found.or_else(|| match question.q_type {
+                QType::Cname => None,
+                _ => self.get(&name, Some(&QType::Cname), q_class, now, stale),
+            })?;
+            if entry.records.is_empty() {
+                return negative(answer, entry);
+            }
+            answer.secure &= entry.secure;
+            answer.prefetch |= entry.needs_prefetch(now, &config);
+            let target = read_name(&entry.records[0].r_data, 0).ok();
+            answer.answers.extend(entry.records);
+            if dnssec_ok {
+                answer.answers.extend(entry.rrsigs);
+            }
+            if is_final {
+                return Some(answer);
+            }
+            name = target?.0;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_header::{DnsHeader, DnsHeaderFourthByte, DnsHeaderThirdByte};
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+    use crate::Result;
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    fn cache(capacity: usize) -> Cache {
+        Cache::new(CacheConfig {
+            capacity,
+            min_ttl: 60,
+            max_ttl: 3600,
+            max_negative_ttl: 600,
+            serve_stale: 3600,
+            prefetch: 0.1,
+            prefetch_hits: 3,
+        })
+    }
+
+    fn upstream_reply(
+        question: &DnsQuestion,
+        rcode: RCode,
+        answers: &str,
+        authorities: &str,
+    ) -> Result<DnsReply> {
+        let mut header = DnsHeader {
+            packet_id: 1,
+            third_byte: DnsHeaderThirdByte::from(0),
+            fourth_byte: DnsHeaderFourthByte::from(0),
+            question_count: 0,
+            answer_record_count: 0,
+            authority_record_count: 0,
+            additional_record_count: 0,
+        };
+        header.third_byte.query_response_ind = true;
+        header.fourth_byte.response_code = rcode;
+        let mut reply = DnsReply {
+            header,
+            questions: vec![question.clone()],
+            answers: master_file::parse(answers, &[])?,
+            authorities: master_file::parse(authorities, &[])?,
+            additionals: vec![],
+        };
+        reply.update_counts();
+        Ok(reply)
+    }
+
+    #[test]
+    fn test_cache_ttl_decay_and_clamps() -> Result<()> {
+        let mut cache = cache(10);
+        let records = master_file::parse(
+            "alias.example. 300 CNAME www.example.\n\
+             www.example. 10 A 192.0.2.1\n\
+             long.example. 86400 A 192.0.2.2",
+            &[],
+        )?;
+        for rrset in group_rrsets(&records) {
+            cache.insert_rrset(&rrset, &[], false, 1000);
+        }
+
+        // the CNAME is followed, the A record TTL was raised to the minimum
+        let answer = cache
+            .lookup(&question("ALIAS.example", QType::A), false, 1010)
+            .unwrap();
+        assert_eq!(answer.answers.len(), 2);
+        assert_eq!(answer.answers[0].ttl, 290);
+        assert_eq!(answer.answers[1].ttl, 50);
+        assert!(!answer.secure);
+
+        let answer = cache
+            .lookup(&question("long.example", QType::A), false, 1000)
+            .unwrap();
+        assert_eq!(answer.answers[0].ttl, 3600);
+
+        assert!(cache
+            .lookup(&question("www.example", QType::A), false, 1060)
+            .is_none());
+        assert!(cache
+            .lookup(&question("alias.example", QType::A), false, 1060)
+            .is_none());
+        assert!(cache
+            .lookup(&question("www.example", QType::Aaaa), false, 1000)
+            .is_none());
+        Ok(())
+    }
+
+    fn is_cached(cache: &mut Cache, name: &str) -> bool {
+        cache.lookup(&question(name, QType::A), false, 0).is_some()
+    }
+
+    #[test]
+    fn test_cache_lru_eviction() -> Result<()> {
+        let mut cache = cache(2);
+        let records = master_file::parse(
+            "a.example. 300 A 192.0.2.1\nb.example. 300 A 192.0.2.2\nc.example. 300 A 192.0.2.3",
+            &[],
+        )?;
+        cache.insert_rrset(&records[0..1], &[], true, 0);
+        cache.insert_rrset(&records[1..2], &[], true, 0);
+        // a becomes the most recently used, b is evicted by c
+        assert!(is_cached(&mut cache, "a.example"));
+        cache.insert_rrset(&records[2..3], &[], true, 0);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(!is_cached(&mut cache, "b.example"));
+        assert!(is_cached(&mut cache, "a.example"));
+        assert!(is_cached(&mut cache, "c.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_negative_answers() -> Result<()> {
+        let mut cache = cache(10);
+        let soa = "example. 3600 SOA ns1.example. hostmaster.example. 1 7200 3600 1209600 300";
+        let nsec = "example. 300 NSEC www.example. A NS SOA";
+
+        // NXDOMAIN at the end of a CNAME chain answers every type of the target
+        let nxdomain = question("alias.example", QType::A);
+        let answers = "alias.example. 300 CNAME gone.example.";
+        let authorities = format!("{}\n{}", soa, nsec);
+        let reply = upstream_reply(&nxdomain, RCode::NameError, answers, &authorities)?;
+        cache.insert_reply(&nxdomain, &reply, false, 1000);
+
+        let answer = cache.lookup(&nxdomain, false, 1100).unwrap();
+        assert_eq!(answer.rcode, RCode::NameError);
+        assert_eq!(answer.answers.len(), 1);
+        // the proofs are only given with DO, the TTL is the SOA minimum
+        assert_eq!(answer.authorities.len(), 1);
+        assert_eq!(answer.authorities[0].ttl, 200);
+        let answer = cache
+            .lookup(&question("gone.example", QType::Mx), true, 1100)
+            .unwrap();
+        assert_eq!(answer.rcode, RCode::NameError);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authorities.len(), 2);
+        assert!(cache
+            .lookup(&question("gone.example", QType::A), false, 1300)
+            .is_none());
+
+        // NODATA only answers the type asked
+        let nodata = question("www.example", QType::Aaaa);
+        let reply = upstream_reply(&nodata, RCode::NoError, "", soa)?;
+        cache.insert_reply(&nodata, &reply, true, 1000);
+        let answer = cache.lookup(&nodata, false, 1000).unwrap();
+        assert_eq!(answer.rcode, RCode::NoError);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authorities[0].r_type, QType::Soa);
+        assert!(answer.secure);
+        assert!(!is_cached(&mut cache, "www.example"));
+
+        // a referral without SOA is not cached
+        let referral = question("sub.example", QType::A);
+        let delegation = "sub.example. 300 NS ns.sub.example.";
+        let reply = upstream_reply(&referral, RCode::NoError, "", delegation)?;
+        cache.insert_reply(&referral, &reply, false, 1000);
+        assert!(!is_cached(&mut cache, "sub.example"));
+
+        // a name learnt to exist replaces the NXDOMAIN
+        let records = master_file::parse("gone.example. 300 A 192.0.2.3", &[])?;
+        cache.insert_rrset(&records, &[], false, 1000);
+        assert!(is_cached(&mut cache, "gone.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_off_chain_records() -> Result<()> {
+        let mut cache = cache(10);
+        let question = question("www.evil.example", QType::A);
+        let answers = "www.evil.example. 300 CNAME web.evil.example.\n\
+                       web.evil.example. 300 A 192.0.2.66\n\
+                       www.bank.example. 300 A 192.0.2.66";
+        let reply = upstream_reply(&question, RCode::NoError, answers, "")?;
+        cache.insert_reply(&question, &reply, false, 0);
+        assert!(is_cached(&mut cache, "www.evil.example"));
+        assert!(is_cached(&mut cache, "web.evil.example"));
+        assert!(!is_cached(&mut cache, "www.bank.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_serve_stale() -> Result<()> {
+        let mut cache = cache(10);
+        let records = master_file::parse("www.example. 300 A 192.0.2.1", &[])?;
+        cache.insert_rrset(&records, &[], false, 1000);
+
+        let www = question("www.example", QType::A);
+        assert!(cache.lookup(&www, false, 1300).is_none());
+        let answer = cache.lookup_stale(&www, false, 1300).unwrap();
+        assert_eq!(answer.answers[0].ttl, STALE_TTL);
+        // fresh entries keep their TTL
+        let answer = cache.lookup_stale(&www, false, 1100).unwrap();
+        assert_eq!(answer.answers[0].ttl, 200);
+
+        // dropped once expired for longer than the window
+        assert!(cache.lookup(&www, false, 1000 + 300 + 3601).is_none());
+        assert!(cache.entries.is_empty());
+        assert!(cache.lookup_stale(&www, false, 1300).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_prefetch() -> Result<()> {
+        let mut cache = cache(10);
+        let records = master_file::parse(
+            "alias.example. 1000 CNAME www.example.
+www.example. 100 A 192.0.2.1",
+            &[],
+        )?;
+        for rrset in group_rrsets(&records) {
+            cache.insert_rrset(&rrset, &[], false, 0);
+        }
+        let www = question("www.example", QType::A);
+        let prefetch = |cache: &mut Cache, question: &DnsQuestion, now: u32| {
+            cache.lookup(question, false, now).unwrap().prefetch
+        };
+        // popular but not close to expiration, then close but not popular enough
+        assert!(!prefetch(&mut cache, &www, 10));
+        assert!(!prefetch(&mut cache, &www, 10));
+        assert!(!prefetch(&mut cache, &www, 20));
+        assert!(prefetch(&mut cache, &www, 90));
+        // the last RRset of the chain expires soon
+        let alias = question("alias.example", QType::A);
+        assert!(prefetch(&mut cache, &alias, 95));
+
+        // a new entry starts with no hits
+        cache.insert_rrset(&records[1..], &[], false, 90);
+        assert!(!prefetch(&mut cache, &www, 180));
+        Ok(())
+    }
+}
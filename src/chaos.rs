@@ -0,0 +1,179 @@
+//! Server identity: the TXT questions of the CHAOS class for version.bind,
+//! version.server, hostname.bind and id.server, and the NSID option of
+//! EDNS, so that monitoring can tell which instance answered.
+//! https://datatracker.ietf.org/doc/html/rfc4892
+//! https://datatracker.ietf.org/doc/html/rfc5001
+use crate::config::IdentityConfig;
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_edns::{Edns, EdnsOption};
+use crate::dns_header::{OpCode, RCode};
+use crate::dns_label::labels_to_key;
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+
+/// EDNS option code of the name server identifier
+const NSID: u16 = 3;
+
+/// TTL of the identity records, which must not be cached
+const IDENTITY_TTL: u32 = 0;
+
+/// TXT data of a text, split in character strings of at most 255 bytes
+fn txt_data(text: &str) -> Vec<u8> {
+    let mut r_data = Vec::new();
+    for chunk in text.as_bytes().chunks(255) {
+        r_data.push(chunk.len() as u8);
+        r_data.extend(chunk);
+    }
+    if r_data.is_empty() {
+        r_data.push(0);
+    }
+    r_data
+}
+
+#[derive(Debug)]
+pub struct Identity {
+    config: IdentityConfig,
+}
+
+impl Identity {
+    pub fn new(config: IdentityConfig) -> Self {
+        Self { config }
+    }
+
+    /// Text of the name, none for the names we do not answer and the hidden
+    /// values
+    fn text(&self, question: &DnsQuestion) -> Option<&str> {
+        match labels_to_key(&question.q_name).as_str() {
+            "version.bind." | "version.server." => self.config.version.as_deref(),
+            "hostname.bind." | "id.server." => self.config.server_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Answers the questions of the CHAOS class, REFUSED for the unknown
+    /// names and the hidden values
+    pub fn answer(&self, dns_request: &DnsRequest) -> DnsReply {
+        let mut header = dns_request.header.clone();
+        header.third_byte.query_response_ind = true;
+        header.third_byte.authoritative_answer = true;
+        header.third_byte.truncation = false;
+        header.fourth_byte.recursion_available = false;
+        header.fourth_byte.reserved = 0;
+        header.fourth_byte.response_code = RCode::NoError;
+
+        let mut dns_reply = DnsReply {
+            header,
+            questions: dns_request.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        if dns_request.header.third_byte.operation_code != OpCode::Query {
+            dns_reply.header.fourth_byte.response_code = RCode::NotImplemented;
+        }
+
+        for question in dns_request.questions.iter() {
+            if dns_reply.header.fourth_byte.response_code != RCode::NoError {
+                break;
+            }
+            let Some(text) = self.text(question) else {
+                dns_reply.header.fourth_byte.response_code = RCode::Refused;
+                break;
+            };
+            if question.q_type == QType::Txt || question.q_type == QType::StarSign {
+                let r_data = txt_data(text);
+                dns_reply.answers.push(DnsAnswer {
+                    r_name: question.q_name.clone(),
+                    r_type: QType::Txt,
+                    r_class: QClass::Ch,
+                    ttl: IDENTITY_TTL,
+                    rd_length: r_data.len() as u16,
+                    r_data,
+                });
+            }
+        }
+
+        if dns_request.edns().is_some() {
+            dns_reply.additionals.push(Edns::new(false).into());
+        }
+        dns_reply.update_counts();
+        dns_reply
+    }
+
+    /// Adds the NSID option to the reply when the client asked for it and
+    /// one is configured
+    pub fn add_nsid(&self, client_edns: Option<&Edns>, dns_reply: &mut DnsReply) {
+        let Some(nsid) = self.config.nsid.as_ref() else {
+            return;
+        };
+        let requested =
+            client_edns.is_some_and(|edns| edns.options.iter().any(|option| option.code == NSID));
+        if !requested {
+            return;
+        }
+        let mut edns = Edns::find(&dns_reply.additionals).unwrap_or_else(|| Edns::new(false));
+        edns.options.retain(|option| option.code != NSID);
+        edns.options.push(EdnsOption {
+            code: NSID,
+            data: nsid.clone(),
+        });
+        dns_reply
+            .additionals
+            .retain(|record| record.r_type != QType::Opt);
+        dns_reply.additionals.push(edns.into());
+        dns_reply.update_counts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+    use crate::Result;
+
+    fn request(name: &str, q_type: QType) -> DnsRequest {
+        let question = DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::Ch,
+        };
+        DnsRequest::query(1, question, false)
+    }
+
+    #[test]
+    fn test_identity() -> Result<()> {
+        let identity = Identity::new(IdentityConfig {
+            version: None,
+            server_id: Some("ns1.example".to_string()),
+            nsid: Some(b"ns1".to_vec()),
+        });
+
+        let reply = identity.answer(&request("ID.server", QType::Txt));
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(reply.answers[0].r_class, QClass::Ch);
+        assert_eq!(reply.answers[0].r_data, txt_data("ns1.example"));
+        let reply = identity.answer(&request("hostname.bind", QType::A));
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert!(reply.answers.is_empty());
+        let reply = identity.answer(&request("version.bind", QType::Txt));
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::Refused);
+        let reply = identity.answer(&request("authors.bind", QType::Txt));
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::Refused);
+
+        let mut client_edns = Edns::new(false);
+        let mut reply = identity.answer(&request("id.server", QType::Txt));
+        identity.add_nsid(Some(&client_edns), &mut reply);
+        assert!(Edns::find(&reply.additionals).is_none());
+        client_edns.options.push(EdnsOption {
+            code: NSID,
+            data: vec![],
+        });
+        identity.add_nsid(Some(&client_edns), &mut reply);
+        let edns = Edns::find(&reply.additionals).unwrap();
+        assert_eq!(edns.options[0].data, b"ns1".to_vec());
+        assert_eq!(reply.header.additional_record_count, 1);
+        Ok(())
+    }
+}
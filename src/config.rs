@@ -0,0 +1,767 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::acl::Cidr;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_label::{labels_from_str, DnsLabel};
+use crate::encoding::hex_decode;
+use crate::master_file::{self, parse_ttl};
+use crate::Result;
+
+/// Default validity of online signatures: two weeks
+const DEFAULT_SIGNATURE_VALIDITY: u32 = 14 * 86400;
+
+/// Address the server listens on when none is given
+const DEFAULT_LISTEN: &str = "127.0.0.1:2053";
+
+/// Default TTL of the records of synthetic zones
+const DEFAULT_SYNTHETIC_TTL: u32 = 300;
+
+/// Version given to CHAOS version queries by default
+const DEFAULT_SERVER_VERSION: &str =
+    concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Default number of RRsets kept in the cache
+const DEFAULT_CACHE_SIZE: usize = 10000;
+/// Default upper bound on cached TTLs: one day
+const DEFAULT_CACHE_MAX_TTL: u32 = 86400;
+/// Default upper bound on negative TTLs: three hours
+/// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+const DEFAULT_CACHE_MAX_NEGATIVE_TTL: u32 = 10800;
+/// Default time expired entries can still be served: one day
+/// https://datatracker.ietf.org/doc/html/rfc8767#section-5
+const DEFAULT_CACHE_SERVE_STALE: u32 = 86400;
+/// Default time rate-limited clients stay in debt, in seconds
+const DEFAULT_RRL_WINDOW: u32 = 15;
+/// Default share of the limited responses sent truncated
+const DEFAULT_RRL_SLIP: u32 = 2;
+/// Default share of the TTL left when popular entries are refreshed
+const DEFAULT_CACHE_PREFETCH: f64 = 0.1;
+/// Default number of hits making an entry popular
+const DEFAULT_CACHE_PREFETCH_HITS: u32 = 3;
+
+/// Settings given on the command line
+#[derive(Debug, Default)]
+pub struct Config {
+    /// UDP addresses the server receives the requests on
+    pub listen: Vec<SocketAddr>,
+    /// Upstream servers the questions are forwarded to
+    pub resolvers: Vec<SocketAddr>,
+    /// Domains forwarded to their own upstream servers
+    pub forward_zones: Vec<ForwardZoneConfig>,
+    /// Root hints file, questions are resolved iteratively from the root
+    /// servers when set
+    pub root_hints: Option<String>,
+    /// Zones this server is authoritative for
+    pub zones: Vec<ZoneConfig>,
+    /// Zones answering the addresses embedded in the names
+    pub synthetic_zones: Vec<SyntheticZoneConfig>,
+    /// Files of DS or DNSKEY records, forwarded replies are validated when set
+    pub trust_anchors: Vec<String>,
+    pub cache: CacheConfig,
+    /// Files of blocked names in hosts or adblock format
+    pub blocklists: Vec<String>,
+    /// Files of names never blocked, in the same formats
+    pub allowlists: Vec<String>,
+    pub block_response: BlockResponse,
+    /// Response policy zones, in the order they are applied
+    pub policy_zones: Vec<PolicyZoneConfig>,
+    /// Files in /etc/hosts format answered before forwarding
+    pub hosts_files: Vec<String>,
+    /// Records answered before forwarding, over the hosts files
+    pub static_records: Vec<DnsAnswer>,
+    pub rate_limit: RateLimitConfig,
+    /// Access rules by client prefix, checked in order
+    pub acl: Vec<AclRule>,
+    pub dns64: Dns64Config,
+    pub identity: IdentityConfig,
+    /// Settings for groups of clients, the others get the settings above
+    pub views: Vec<ViewConfig>,
+}
+
+/// Settings of the clients of a view, which has its own zones, forwarding,
+/// policies and cache
+#[derive(Debug)]
+pub struct ViewConfig {
+    pub name: String,
+    /// Prefixes of the clients of the view
+    pub clients: Vec<Cidr>,
+    /// Addresses of the listeners the view is restricted to, all of them
+    /// when empty
+    pub listeners: Vec<SocketAddr>,
+    pub config: Config,
+}
+
+impl ViewConfig {
+    /// <name> <prefix,...|any> [--view-listener <addr>]... then the options
+    /// of the view
+    fn from_args(args: &[String]) -> Result<Self> {
+        let [name, clients, options @ ..] = args else {
+            anyhow::bail!("--view needs a name and client prefixes");
+        };
+        let clients = match clients.as_str() {
+            "any" => vec!["0.0.0.0/0".parse::<Cidr>()?, "::/0".parse::<Cidr>()?],
+            clients => clients
+                .split(',')
+                .map(|prefix| prefix.parse::<Cidr>())
+                .collect::<Result<Vec<_>>>()?,
+        };
+        let mut listeners = Vec::new();
+        let mut view_options = Vec::new();
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.as_str() {
+                "--view-listener" => {
+                    let listener = options
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("{} needs a value", option))?;
+                    listeners.push(listener.parse::<SocketAddr>()?);
+                }
+                _ => view_options.push(option.clone()),
+            }
+        }
+        let config = Config::parse_options(&view_options)
+            .map_err(|e| anyhow::anyhow!("view {}: {}", name, e))?;
+        if !config.listen.is_empty() {
+            anyhow::bail!("view {}: --listen applies to the whole server", name);
+        }
+        Ok(Self {
+            name: name.clone(),
+            clients,
+            listeners,
+            config,
+        })
+    }
+}
+
+/// What a client asks the server to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Query,
+    /// Questions answered from the upstreams or the root servers
+    Recursion,
+    Transfer,
+    Update,
+    Notify,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AclAction {
+    Allow,
+    /// Answered with REFUSED
+    Refuse,
+    /// No response is sent
+    Drop,
+}
+
+/// Applies the action to the operation for the clients of the prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclRule {
+    pub operation: Operation,
+    pub action: AclAction,
+    pub prefix: Cidr,
+}
+
+/// Domain whose names resolve to the address they embed, such as
+/// 10-1-2-3.preview.example.com
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticZoneConfig {
+    pub domain: Vec<DnsLabel>,
+    pub ttl: u32,
+}
+
+/// Where a response policy zone is loaded from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyZoneConfig {
+    File(String),
+    /// Transferred from its primary server at startup
+    Transfer {
+        zone: Vec<DnsLabel>,
+        primary: SocketAddr,
+    },
+}
+
+/// Answer to questions for blocked names
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum BlockResponse {
+    #[default]
+    NxDomain,
+    NoData,
+    Refused,
+    /// A or AAAA record of this address, NODATA for the other types
+    Sinkhole(IpAddr),
+}
+
+/// Questions for the domain and its subdomains go to these upstreams
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardZoneConfig {
+    pub domain: Vec<DnsLabel>,
+    pub upstreams: Vec<SocketAddr>,
+    /// When the upstreams fail, the question is not resolved from the root
+    /// servers instead
+    pub only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    /// Maximum number of cached RRsets, 0 disables the cache
+    pub capacity: usize,
+    /// TTLs of cached RRsets are clamped to this range
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Upper bound on the TTL of NXDOMAIN and NODATA answers
+    pub max_negative_ttl: u32,
+    /// Time expired entries are kept to be served while the upstream is
+    /// unavailable, 0 disables serve-stale
+    pub serve_stale: u32,
+    /// Entries served at least `prefetch_hits` times are refreshed once this
+    /// fraction of their TTL is left, 0 disables prefetching
+    pub prefetch: f64,
+    pub prefetch_hits: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CACHE_SIZE,
+            min_ttl: 0,
+            max_ttl: DEFAULT_CACHE_MAX_TTL,
+            max_negative_ttl: DEFAULT_CACHE_MAX_NEGATIVE_TTL,
+            serve_stale: DEFAULT_CACHE_SERVE_STALE,
+            prefetch: DEFAULT_CACHE_PREFETCH,
+            prefetch_hits: DEFAULT_CACHE_PREFETCH_HITS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    /// Responses per second to a client prefix for each response class, 0
+    /// disables rate limiting
+    pub responses_per_second: u32,
+    /// Seconds of responses a limited client can owe before it is sent
+    /// responses again
+    pub window: u32,
+    /// One limited response in `slip` is sent truncated instead of dropped,
+    /// 0 drops them all
+    pub slip: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 0,
+            window: DEFAULT_RRL_WINDOW,
+            slip: DEFAULT_RRL_SLIP,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dns64Config {
+    /// Network of the NAT64 gateway, the /96 prefix of the synthesized
+    /// addresses. None disables DNS64.
+    pub prefix: Option<Ipv6Addr>,
+    /// AAAA records in the IPv6 prefixes are ignored, and A records in the
+    /// IPv4 prefixes are not synthesized
+    pub exclude: Vec<Cidr>,
+}
+
+/// What the server tells about itself, so that the instance answering can
+/// be told apart
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentityConfig {
+    /// Answer to version.bind and version.server, hidden when none
+    pub version: Option<String>,
+    /// Answer to hostname.bind and id.server, hidden when none
+    pub server_id: Option<String>,
+    /// Name server identifier given to the clients asking for it in EDNS
+    pub nsid: Option<Vec<u8>>,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            version: Some(DEFAULT_SERVER_VERSION.to_string()),
+            server_id: None,
+            nsid: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ZoneConfig {
+    pub file: String,
+    /// Key file prefixes, the zone is signed online when there is at least one
+    pub keys: Vec<String>,
+    /// Directory holding the keys of the zone, as maintained by `keymgr`
+    pub key_dir: Option<String>,
+    /// NSEC3 salt and iterations, NSEC is used when not set
+    pub nsec3: Option<(Vec<u8>, u16)>,
+    /// Validity of the online signatures in seconds
+    pub signature_validity: u32,
+}
+
+impl Config {
+    /// --listen <addr>                     (repeatable, 127.0.0.1:2053 by default)
+    /// --resolver <addr>                   (repeatable)
+    /// --root-hints <file>
+    /// --forward-zone <domain> <addr>[,<addr>...] <only|first> (repeatable)
+    /// --zone <file>                       (repeatable)
+    /// --zone-key <key file prefix>        (repeatable, applies to the last zone)
+    /// --zone-key-dir <dir>                (applies to the last zone)
+    /// --nsec3 <salt hex or -> <iterations> (applies to the last zone)
+    /// --signature-validity <duration>     (applies to the last zone)
+    /// --synthetic-zone <domain>           (repeatable)
+    /// --synthetic-ttl <duration>          (applies to the last synthetic zone)
+    /// --trust-anchor <file>               (repeatable)
+    /// --cache-size <RRsets>
+    /// --cache-min-ttl <duration>
+    /// --cache-max-ttl <duration>
+    /// --cache-max-negative-ttl <duration>
+    /// --cache-serve-stale <duration>
+    /// --cache-prefetch <fraction of the TTL>
+    /// --cache-prefetch-hits <count>
+    /// --blocklist <file>                  (repeatable)
+    /// --allowlist <file>                  (repeatable)
+    /// --block-response <nxdomain|nodata|refused|address>
+    /// --rpz <file>                        (repeatable)
+    /// --rpz-transfer <zone> <primary addr> (repeatable)
+    /// --hosts <file>                      (repeatable)
+    /// --static-record "<name> [ttl] <type> <data>" (repeatable)
+    /// --rrl-responses-per-second <count>
+    /// --rrl-window <duration>
+    /// --rrl-slip <count>
+    /// --acl <query|recursion|transfer|update|notify> <allow|refuse|drop> <prefix> (repeatable)
+    /// --dns64-prefix <IPv6 prefix>/96
+    /// --dns64-exclude <prefix>            (repeatable)
+    /// --server-version <text|none>
+    /// --server-id <text|none>
+    /// --nsid <text>
+    /// --view <name> <prefix,...|any>      (repeatable, the options up to the next
+    ///                                      --view apply to the view)
+    /// --view-listener <addr>              (repeatable, applies to the last view)
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut segments = args.split(|arg| arg == "--view");
+        let mut config = Self::parse_options(segments.next().unwrap_or_default())?;
+        for segment in segments {
+            config.views.push(ViewConfig::from_args(segment)?);
+        }
+        if config.listen.is_empty() {
+            config.listen.push(DEFAULT_LISTEN.parse()?);
+        }
+        Ok(config)
+    }
+
+    /// Options of the server or of a view
+    fn parse_options(args: &[String]) -> Result<Self> {
+        let mut config = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--listen" => config.listen.push(value()?.parse::<SocketAddr>()?),
+                "--resolver" => config.resolvers.push(value()?.parse::<SocketAddr>()?),
+                "--root-hints" => config.root_hints = Some(value()?.to_string()),
+                "--forward-zone" => {
+                    let domain = labels_from_str(value()?);
+                    let upstreams = value()?
+                        .split(',')
+                        .map(|upstream| upstream.parse::<SocketAddr>())
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+                    let only = match value()?.as_str() {
+                        "only" => true,
+                        "first" => false,
+                        mode => anyhow::bail!("Unknown forwarding mode {}", mode),
+                    };
+                    config.forward_zones.push(ForwardZoneConfig {
+                        domain,
+                        upstreams,
+                        only,
+                    });
+                }
+                "--zone" => config.zones.push(ZoneConfig {
+                    file: value()?.to_string(),
+                    keys: vec![],
+                    key_dir: None,
+                    nsec3: None,
+                    signature_validity: DEFAULT_SIGNATURE_VALIDITY,
+                }),
+                "--zone-key" => {
+                    let key = value()?.to_string();
+                    config.last_zone(arg)?.keys.push(key);
+                }
+                "--zone-key-dir" => {
+                    let key_dir = value()?.to_string();
+                    config.last_zone(arg)?.key_dir = Some(key_dir);
+                }
+                "--nsec3" => {
+                    let salt = match value()?.as_str() {
+                        "-" => vec![],
+                        salt => hex_decode(salt)?,
+                    };
+                    let iterations = value()?.parse::<u16>()?;
+                    config.last_zone(arg)?.nsec3 = Some((salt, iterations));
+                }
+                "--signature-validity" => {
+                    let validity = parse_ttl(value()?)?;
+                    config.last_zone(arg)?.signature_validity = validity;
+                }
+                "--synthetic-zone" => config.synthetic_zones.push(SyntheticZoneConfig {
+                    domain: labels_from_str(value()?),
+                    ttl: DEFAULT_SYNTHETIC_TTL,
+                }),
+                "--synthetic-ttl" => {
+                    let ttl = parse_ttl(value()?)?;
+                    config
+                        .synthetic_zones
+                        .last_mut()
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("{} must follow a --synthetic-zone argument", arg)
+                        })?
+                        .ttl = ttl;
+                }
+                "--trust-anchor" => config.trust_anchors.push(value()?.to_string()),
+                "--cache-size" => config.cache.capacity = value()?.parse::<usize>()?,
+                "--cache-min-ttl" => config.cache.min_ttl = parse_ttl(value()?)?,
+                "--cache-max-ttl" => config.cache.max_ttl = parse_ttl(value()?)?,
+                "--cache-max-negative-ttl" => config.cache.max_negative_ttl = parse_ttl(value()?)?,
+                "--cache-serve-stale" => config.cache.serve_stale = parse_ttl(value()?)?,
+                "--cache-prefetch" => config.cache.prefetch = value()?.parse::<f64>()?,
+                "--cache-prefetch-hits" => config.cache.prefetch_hits = value()?.parse::<u32>()?,
+                "--blocklist" => config.blocklists.push(value()?.to_string()),
+                "--allowlist" => config.allowlists.push(value()?.to_string()),
+                "--block-response" => {
+                    config.block_response =
+                        match value()?.as_str() {
+                            "nxdomain" => BlockResponse::NxDomain,
+                            "nodata" => BlockResponse::NoData,
+                            "refused" => BlockResponse::Refused,
+                            address => BlockResponse::Sinkhole(address.parse::<IpAddr>().map_err(
+                                |_| anyhow::anyhow!("Unknown block response {}", address),
+                            )?),
+                        }
+                }
+                "--rpz" => config
+                    .policy_zones
+                    .push(PolicyZoneConfig::File(value()?.to_string())),
+                "--rpz-transfer" => {
+                    let zone = labels_from_str(value()?);
+                    let primary = value()?.parse::<SocketAddr>()?;
+                    config
+                        .policy_zones
+                        .push(PolicyZoneConfig::Transfer { zone, primary });
+                }
+                "--hosts" => config.hosts_files.push(value()?.to_string()),
+                "--static-record" => {
+                    let records = master_file::parse(value()?, &[])?;
+                    config.static_records.extend(records);
+                }
+                "--rrl-responses-per-second" => {
+                    config.rate_limit.responses_per_second = value()?.parse::<u32>()?
+                }
+                "--rrl-window" => config.rate_limit.window = parse_ttl(value()?)?,
+                "--rrl-slip" => config.rate_limit.slip = value()?.parse::<u32>()?,
+                "--acl" => {
+                    let operation = match value()?.as_str() {
+                        "query" => Operation::Query,
+                        "recursion" => Operation::Recursion,
+                        "transfer" => Operation::Transfer,
+                        "update" => Operation::Update,
+                        "notify" => Operation::Notify,
+                        operation => anyhow::bail!("Unknown ACL operation {}", operation),
+                    };
+                    let action = match value()?.as_str() {
+                        "allow" => AclAction::Allow,
+                        "refuse" => AclAction::Refuse,
+                        "drop" => AclAction::Drop,
+                        action => anyhow::bail!("Unknown ACL action {}", action),
+                    };
+                    let prefix = value()?.parse::<Cidr>()?;
+                    config.acl.push(AclRule {
+                        operation,
+                        action,
+                        prefix,
+                    });
+                }
+                "--dns64-prefix" => {
+                    let prefix = value()?.parse::<Cidr>()?;
+                    match prefix {
+                        Cidr {
+                            address: IpAddr::V6(address),
+                            length: 96,
+                        } => config.dns64.prefix = Some(address),
+                        _ => anyhow::bail!("--dns64-prefix needs an IPv6 /96 prefix"),
+                    }
+                }
+                "--dns64-exclude" => config.dns64.exclude.push(value()?.parse::<Cidr>()?),
+                "--server-version" => config.identity.version = optional_text(value()?),
+                "--server-id" => config.identity.server_id = optional_text(value()?),
+                "--nsid" => config.identity.nsid = Some(value()?.as_bytes().to_vec()),
+                _ => anyhow::bail!("Unknown argument {}", arg),
+            }
+        }
+        if !config.resolvers.is_empty() && config.root_hints.is_some() {
+            anyhow::bail!("--resolver and --root-hints are mutually exclusive");
+        }
+        if config.root_hints.is_none() && config.forward_zones.iter().any(|zone| !zone.only) {
+            anyhow::bail!("--forward-zone first needs --root-hints to resolve on failure");
+        }
+        if config.cache.min_ttl > config.cache.max_ttl {
+            anyhow::bail!("--cache-min-ttl must not exceed --cache-max-ttl");
+        }
+        if config.rate_limit.window == 0 {
+            anyhow::bail!("--rrl-window must be at least one second");
+        }
+        if config.dns64.prefix.is_none() && !config.dns64.exclude.is_empty() {
+            anyhow::bail!("--dns64-exclude needs --dns64-prefix");
+        }
+        if !(0.0..1.0).contains(&config.cache.prefetch) {
+            anyhow::bail!("--cache-prefetch must be a fraction between 0 and 1");
+        }
+        Ok(config)
+    }
+
+    /// Forwarded domains, with the root for the default upstreams
+    pub fn forward_routes(&self) -> Vec<ForwardZoneConfig> {
+        let mut routes = self.forward_zones.clone();
+        if !self.resolvers.is_empty() {
+            routes.push(ForwardZoneConfig {
+                domain: vec![],
+                upstreams: self.resolvers.clone(),
+                only: true,
+            });
+        }
+        routes
+    }
+
+    fn last_zone(&mut self, arg: &str) -> Result<&mut ZoneConfig> {
+        self.zones
+            .last_mut()
+            .ok_or_else(|| anyhow::anyhow!("{} must follow a --zone argument", arg))
+    }
+}
+
+/// Text of an option, none for "none"
+fn optional_text(value: &str) -> Option<String> {
+    match value {
+        "none" => None,
+        text => Some(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_config_from_args() -> Result<()> {
+        let config = Config::from_args(&args(
+            "--resolver 8.8.8.8:53 --resolver [2001:4860:4860::8888]:53 --zone a.zone --zone-key Ka --nsec3 aabb 5 \
+             --signature-validity 1d --zone b.zone --zone-key-dir keys --trust-anchor root.key \
+             --cache-size 100 --cache-max-ttl 1h --cache-max-negative-ttl 5m --cache-serve-stale 0 \
+             --cache-prefetch 0.2 --cache-prefetch-hits 10",
+        ))?;
+        assert_eq!(
+            config.resolvers,
+            vec!["8.8.8.8:53".parse()?, "[2001:4860:4860::8888]:53".parse()?]
+        );
+        assert_eq!(config.trust_anchors, vec!["root.key".to_string()]);
+        assert_eq!(
+            config.cache,
+            CacheConfig {
+                capacity: 100,
+                min_ttl: 0,
+                max_ttl: 3600,
+                max_negative_ttl: 300,
+                serve_stale: 0,
+                prefetch: 0.2,
+                prefetch_hits: 10,
+            }
+        );
+        assert_eq!(
+            config.zones,
+            vec![
+                ZoneConfig {
+                    file: "a.zone".to_string(),
+                    keys: vec!["Ka".to_string()],
+                    key_dir: None,
+                    nsec3: Some((vec![0xaa, 0xbb], 5)),
+                    signature_validity: 86400,
+                },
+                ZoneConfig {
+                    file: "b.zone".to_string(),
+                    keys: vec![],
+                    key_dir: Some("keys".to_string()),
+                    nsec3: None,
+                    signature_validity: DEFAULT_SIGNATURE_VALIDITY,
+                }
+            ]
+        );
+        assert!(Config::from_args(&args("--zone-key Ka")).is_err());
+        assert!(Config::from_args(&args("--resolver")).is_err());
+        assert!(Config::from_args(&args("--cache-prefetch 1.5")).is_err());
+
+        let config = Config::from_args(&args("--root-hints named.root"))?;
+        assert_eq!(config.root_hints, Some("named.root".to_string()));
+        assert!(Config::from_args(&args("--resolver 8.8.8.8:53 --root-hints named.root")).is_err());
+
+        let config = Config::from_args(&args(
+            "--resolver 9.9.9.9:53 --forward-zone corp.example 10.0.0.53:53,10.0.0.54:53 only",
+        ))?;
+        let routes = config.forward_routes();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].domain, labels_from_str("corp.example"));
+        assert_eq!(routes[0].upstreams.len(), 2);
+        assert!(routes[0].only);
+        assert!(routes[1].domain.is_empty());
+        assert!(
+            Config::from_args(&args("--forward-zone corp.example 10.0.0.53:53 first")).is_err()
+        );
+        assert!(
+            Config::from_args(&args("--forward-zone corp.example 10.0.0.53:53 never")).is_err()
+        );
+
+        let config = Config::from_args(&args(
+            "--blocklist hosts --blocklist ads.txt --allowlist allow.txt --block-response 0.0.0.0",
+        ))?;
+        assert_eq!(
+            config.blocklists,
+            vec!["hosts".to_string(), "ads.txt".to_string()]
+        );
+        assert_eq!(config.allowlists, vec!["allow.txt".to_string()]);
+        assert_eq!(
+            config.block_response,
+            BlockResponse::Sinkhole("0.0.0.0".parse()?)
+        );
+        let config = Config::from_args(&args("--block-response nodata"))?;
+        assert_eq!(config.block_response, BlockResponse::NoData);
+        assert!(Config::from_args(&args("--block-response drop")).is_err());
+
+        let config = Config::from_args(&args(
+            "--rpz local.rpz --rpz-transfer rpz.example 192.0.2.53:53",
+        ))?;
+        assert_eq!(
+            config.policy_zones,
+            vec![
+                PolicyZoneConfig::File("local.rpz".to_string()),
+                PolicyZoneConfig::Transfer {
+                    zone: labels_from_str("rpz.example"),
+                    primary: "192.0.2.53:53".parse()?,
+                }
+            ]
+        );
+        assert!(Config::from_args(&args("--rpz-transfer rpz.example")).is_err());
+
+        let mut arguments = args("--hosts /etc/hosts --static-record");
+        arguments.push("foo.test 60 A 10.0.0.1".to_string());
+        let config = Config::from_args(&arguments)?;
+        assert_eq!(config.hosts_files, vec!["/etc/hosts".to_string()]);
+        assert_eq!(config.static_records.len(), 1);
+        assert_eq!(config.static_records[0].r_name, labels_from_str("foo.test"));
+        assert_eq!(config.static_records[0].r_data, vec![10, 0, 0, 1]);
+        assert_eq!(config.rate_limit, RateLimitConfig::default());
+        arguments.push("--static-record".to_string());
+        arguments.push("foo.test A".to_string());
+        assert!(Config::from_args(&arguments).is_err());
+
+        let config = Config::from_args(&args(
+            "--rrl-responses-per-second 10 --rrl-window 5 --rrl-slip 3",
+        ))?;
+        assert_eq!(
+            config.rate_limit,
+            RateLimitConfig {
+                responses_per_second: 10,
+                window: 5,
+                slip: 3,
+            }
+        );
+        assert!(Config::from_args(&args("--rrl-window 0")).is_err());
+
+        let config = Config::from_args(&args(
+            "--acl recursion allow 10.0.0.0/8 --acl recursion refuse 0.0.0.0/0 --acl transfer drop ::/0",
+        ))?;
+        assert_eq!(config.acl.len(), 3);
+        assert_eq!(
+            config.acl[0],
+            AclRule {
+                operation: Operation::Recursion,
+                action: AclAction::Allow,
+                prefix: "10.0.0.0/8".parse()?,
+            }
+        );
+        assert_eq!(config.acl[2].action, AclAction::Drop);
+        assert!(Config::from_args(&args("--acl query deny 0.0.0.0/0")).is_err());
+        assert!(Config::from_args(&args("--acl query refuse 10.0.0.0/40")).is_err());
+
+        let config = Config::from_args(&args(
+            "--synthetic-zone preview.example.com --synthetic-ttl 1m --synthetic-zone nip.test",
+        ))?;
+        assert_eq!(
+            config.synthetic_zones,
+            vec![
+                SyntheticZoneConfig {
+                    domain: labels_from_str("preview.example.com"),
+                    ttl: 60,
+                },
+                SyntheticZoneConfig {
+                    domain: labels_from_str("nip.test"),
+                    ttl: DEFAULT_SYNTHETIC_TTL,
+                },
+            ]
+        );
+        assert!(Config::from_args(&args("--synthetic-ttl 60")).is_err());
+
+        assert_eq!(config.identity, IdentityConfig::default());
+        let config = Config::from_args(&args(
+            "--server-version none --server-id ns1.example --nsid ns1",
+        ))?;
+        assert_eq!(
+            config.identity,
+            IdentityConfig {
+                version: None,
+                server_id: Some("ns1.example".to_string()),
+                nsid: Some(b"ns1".to_vec()),
+            }
+        );
+
+        let config = Config::from_args(&args(
+            "--dns64-prefix 64:ff9b::/96 --dns64-exclude 2001:db8::/32 --dns64-exclude 10.0.0.0/8",
+        ))?;
+        assert_eq!(config.dns64.prefix, Some("64:ff9b::".parse()?));
+        assert_eq!(config.dns64.exclude.len(), 2);
+        assert!(Config::from_args(&args("--dns64-prefix 64:ff9b::/64")).is_err());
+        assert!(Config::from_args(&args("--dns64-prefix 10.0.0.0/96")).is_err());
+        assert!(Config::from_args(&args("--dns64-exclude 10.0.0.0/8")).is_err());
+
+        let config = Config::from_args(&args(
+            "--resolver 9.9.9.9:53 --view internal 10.0.0.0/8,192.168.0.0/16 --zone internal.zone \
+             --view-listener 10.0.0.1:53 --view external any --resolver 8.8.8.8:53",
+        ))?;
+        assert_eq!(config.listen, vec![DEFAULT_LISTEN.parse()?]);
+        assert_eq!(config.resolvers, vec!["9.9.9.9:53".parse()?]);
+        assert_eq!(config.views.len(), 2);
+        let internal = &config.views[0];
+        assert_eq!(internal.name, "internal");
+        assert_eq!(internal.clients.len(), 2);
+        assert_eq!(internal.listeners, vec!["10.0.0.1:53".parse()?]);
+        assert_eq!(internal.config.zones[0].file, "internal.zone");
+        assert!(internal.config.resolvers.is_empty());
+        let external = &config.views[1];
+        assert_eq!(
+            external.clients,
+            vec!["0.0.0.0/0".parse()?, "::/0".parse()?]
+        );
+        assert_eq!(external.config.resolvers, vec!["8.8.8.8:53".parse()?]);
+        assert!(Config::from_args(&args("--view internal")).is_err());
+        assert!(Config::from_args(&args("--view internal any --listen 10.0.0.1:53")).is_err());
+        assert!(Config::from_args(&args("--view-listener 10.0.0.1:53")).is_err());
+        Ok(())
+    }
+}
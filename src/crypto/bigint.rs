@@ -0,0 +1,278 @@
+use std::cmp::Ordering;
+
+/// Unsigned 256 bits integer, stored as 4 little endian u64 limbs.
+/// Only the operations needed by the elliptic curves are implemented.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct U256(pub [u64; 4]);
+
+impl U256 {
+    pub const ZERO: Self = Self([0; 4]);
+    pub const ONE: Self = Self([1, 0, 0, 0]);
+
+    /// Big endian hex string, as found in the curve specifications
+    pub const fn from_hex(hex: &str) -> Self {
+        let bytes = hex.as_bytes();
+        let mut limbs = [0u64; 4];
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[bytes.len() - 1 - i];
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => panic!("invalid hex digit"),
+            } as u64;
+            limbs[i / 16] |= digit << ((i % 16) * 4);
+            i += 1;
+        }
+        Self(limbs)
+    }
+
+    /// Accepts at most 32 bytes, shorter slices are left padded with zeros
+    pub fn from_be_slice(bytes: &[u8]) -> Self {
+        assert!(bytes.len() <= 32, "U256 can not hold more than 32 bytes");
+        let mut limbs = [0u64; 4];
+        for (i, byte) in bytes.iter().rev().enumerate() {
+            limbs[i / 8] |= (*byte as u64) << ((i % 8) * 8);
+        }
+        Self(limbs)
+    }
+
+    pub fn from_le_slice(bytes: &[u8]) -> Self {
+        let reversed: Vec<u8> = bytes.iter().rev().copied().collect();
+        Self::from_be_slice(&reversed)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[24 - i * 8..32 - i * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 32] {
+        let mut bytes = self.to_be_bytes();
+        bytes.reverse();
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        (self.0[index / 64] >> (index % 64)) & 1 == 1
+    }
+
+    /// `a` when `choice` is false, `b` when it is true, without branching
+    pub fn select(a: &Self, b: &Self, choice: bool) -> Self {
+        let mask = (choice as u64).wrapping_neg();
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = (a.0[i] & !mask) | (b.0[i] & mask);
+        }
+        Self(limbs)
+    }
+
+    /// Returns the sum and whether it overflowed
+    pub fn overflowing_add(&self, other: &Self) -> (Self, bool) {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (s1, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (s2, c2) = s1.overflowing_add(carry as u64);
+            *limb = s2;
+            carry = c1 | c2;
+        }
+        (Self(limbs), carry)
+    }
+
+    /// Returns the difference and whether it underflowed
+    pub fn overflowing_sub(&self, other: &Self) -> (Self, bool) {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (d1, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (d2, b2) = d1.overflowing_sub(borrow as u64);
+            *limb = d2;
+            borrow = b1 | b2;
+        }
+        (Self(limbs), borrow)
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/// Arithmetic modulo an odd modulus smaller than 2^256.
+/// Values handled by `mul`, `square`, `pow` and `inv` are in montgomery form,
+/// see `to_mont` and `leave_mont`.
+/// https://en.wikipedia.org/wiki/Montgomery_modular_multiplication
+#[derive(Debug, Clone)]
+pub struct Modulus {
+    m: U256,
+    /// -m^-1 mod 2^64
+    m_inv: u64,
+    /// 2^512 mod m, used to enter the montgomery domain
+    r2: U256,
+}
+
+impl Modulus {
+    pub fn new(m: U256) -> Self {
+        assert!(m.0[0] & 1 == 1, "Montgomery arithmetic needs an odd modulus");
+
+        // Newton iteration: each step doubles the number of correct bits
+        let mut inv: u64 = 1;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(m.0[0].wrapping_mul(inv)));
+        }
+        let m_inv = inv.wrapping_neg();
+
+        let mut modulus = Self {
+            m,
+            m_inv,
+            r2: U256::ZERO,
+        };
+        let mut r2 = modulus.reduce_once(U256::ONE, false);
+        for _ in 0..512 {
+            r2 = modulus.add(&r2, &r2);
+        }
+        modulus.r2 = r2;
+        modulus
+    }
+
+    /// Brings a value that may exceed the modulus (by less than m) back in range
+    fn reduce_once(&self, value: U256, overflowed: bool) -> U256 {
+        let (reduced, borrow) = value.overflowing_sub(&self.m);
+        U256::select(&value, &reduced, overflowed | !borrow)
+    }
+
+    pub fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = a.overflowing_add(b);
+        self.reduce_once(sum, carry)
+    }
+
+    pub fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (diff, borrow) = a.overflowing_sub(b);
+        U256::select(&diff, &diff.overflowing_add(&self.m).0, borrow)
+    }
+
+    /// Montgomery product a * b * 2^-256 mod m (CIOS method)
+    pub fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let m = self.m.0;
+        let mut t = [0u64; 6];
+        for i in 0..4 {
+            let mut carry: u128 = 0;
+            for (t_j, a_j) in t.iter_mut().zip(a.0) {
+                let s = *t_j as u128 + (a_j as u128) * (b.0[i] as u128) + carry;
+                *t_j = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[4] as u128 + carry;
+            t[4] = s as u64;
+            t[5] = (s >> 64) as u64;
+
+            let u = t[0].wrapping_mul(self.m_inv);
+            let s = t[0] as u128 + (u as u128) * (m[0] as u128);
+            let mut carry = s >> 64;
+            for j in 1..4 {
+                let s = t[j] as u128 + (u as u128) * (m[j] as u128) + carry;
+                t[j - 1] = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[4] as u128 + carry;
+            t[3] = s as u64;
+            t[4] = t[5] + (s >> 64) as u64;
+            t[5] = 0;
+        }
+        self.reduce_once(U256([t[0], t[1], t[2], t[3]]), t[4] != 0)
+    }
+
+    pub fn square(&self, a: &U256) -> U256 {
+        self.mul(a, a)
+    }
+
+    /// Any value below 2^256 is accepted
+    pub fn to_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    pub fn leave_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &U256::ONE)
+    }
+
+    /// Reduces a 512 bits value given as (high, low) halves.
+    /// The result is in normal form.
+    pub fn reduce_wide(&self, high: &U256, low: &U256) -> U256 {
+        // mul(high, r2) = high * 2^256 mod m
+        let high_part = self.mul(high, &self.r2);
+        let low_part = self.leave_mont(&self.to_mont(low));
+        self.add(&high_part, &low_part)
+    }
+
+    /// Exponent is in normal form, base and result in montgomery form. The
+    /// time taken depends on the exponent, which must be public.
+    pub fn pow(&self, base: &U256, exponent: &U256) -> U256 {
+        let mut result = self.to_mont(&U256::ONE);
+        for i in (0..256).rev() {
+            result = self.square(&result);
+            if exponent.bit(i) {
+                result = self.mul(&result, base);
+            }
+        }
+        result
+    }
+
+    /// Inverse through Fermat's little theorem, only valid for a prime modulus
+    pub fn inv(&self, a: &U256) -> U256 {
+        let exponent = self.m.overflowing_sub(&U256([2, 0, 0, 0])).0;
+        self.pow(a, &exponent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modulus_arithmetic() {
+        let modulus = Modulus::new(U256::from_hex("fffffffb"));
+        let a = modulus.to_mont(&U256::from_hex("123456789"));
+        let b = modulus.to_mont(&U256::from_hex("abcdef"));
+
+        // 0x123456789 mod 0xfffffffb = 0x2345678e
+        assert_eq!(
+            modulus.leave_mont(&a),
+            U256::from_hex("2345678e"),
+        );
+        let product = modulus.leave_mont(&modulus.mul(&a, &b));
+        assert_eq!(product, U256([(0x2345678eu64 * 0xabcdef) % 0xfffffffb, 0, 0, 0]));
+
+        let inverse = modulus.inv(&b);
+        assert_eq!(
+            modulus.leave_mont(&modulus.mul(&inverse, &b)),
+            U256::ONE
+        );
+        assert_eq!(
+            modulus.reduce_wide(&U256::ONE, &U256::ZERO),
+            // 2^256 mod 0xfffffffb = 5^8
+            U256([390625, 0, 0, 0])
+        );
+    }
+}
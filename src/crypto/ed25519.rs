@@ -0,0 +1,305 @@
+//! Ed25519 signatures, DNSSEC algorithm 15
+//! https://datatracker.ietf.org/doc/html/rfc8032#section-5.1
+//! https://datatracker.ietf.org/doc/html/rfc8080
+use std::sync::OnceLock;
+
+use rand::RngCore;
+
+use crate::crypto::bigint::{Modulus, U256};
+use crate::crypto::sha::sha512;
+use crate::Result;
+
+/// 2^255 - 19
+const P: U256 =
+    U256::from_hex("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffed");
+/// Order of the base point, 2^252 + 27742317777372353535851937790883648493
+const L: U256 =
+    U256::from_hex("1000000000000000000000000000000014def9dea2f79cd65812631a5cf5d3ed");
+/// -121665 / 121666
+const D: U256 =
+    U256::from_hex("52036cee2b6ffe738cc740797779e89800700a4d4141d8ab75eb4dca135978a3");
+/// sqrt(-1) = 2^((p - 1) / 4)
+const SQRT_M1: U256 =
+    U256::from_hex("2b8324804fc1df0b2b4d00993dfbd7a72f431806ad2fe478c4ee1b274a0ea0b0");
+/// (p - 5) / 8
+const SQRT_EXPONENT: U256 =
+    U256::from_hex("0ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffd");
+const BX: U256 =
+    U256::from_hex("216936d3cd6e53fec0a4e231fdd6dc5c692cc7609525a7b2c9562d608f25d51a");
+const BY: U256 =
+    U256::from_hex("6666666666666666666666666666666666666666666666666666666666666658");
+
+fn field() -> &'static Modulus {
+    static FIELD: OnceLock<Modulus> = OnceLock::new();
+    FIELD.get_or_init(|| Modulus::new(P))
+}
+
+fn order() -> &'static Modulus {
+    static ORDER: OnceLock<Modulus> = OnceLock::new();
+    ORDER.get_or_init(|| Modulus::new(L))
+}
+
+/// Point in extended coordinates (X/Z, Y/Z, T = XY/Z), coordinates in montgomery form
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+    t: U256,
+}
+
+impl Point {
+    fn neutral() -> Self {
+        let f = field();
+        Self {
+            x: U256::ZERO,
+            y: f.to_mont(&U256::ONE),
+            z: f.to_mont(&U256::ONE),
+            t: U256::ZERO,
+        }
+    }
+
+    fn base() -> Self {
+        let f = field();
+        let x = f.to_mont(&BX);
+        let y = f.to_mont(&BY);
+        Self {
+            x,
+            y,
+            z: f.to_mont(&U256::ONE),
+            t: f.mul(&x, &y),
+        }
+    }
+
+    /// add-2008-hwcd-3, unified so it also works for doubling
+    fn add(&self, other: &Self) -> Self {
+        let f = field();
+        let d2 = f.to_mont(&f.add(&D, &D));
+        let a = f.mul(&f.sub(&self.y, &self.x), &f.sub(&other.y, &other.x));
+        let b = f.mul(&f.add(&self.y, &self.x), &f.add(&other.y, &other.x));
+        let c = f.mul(&f.mul(&self.t, &d2), &other.t);
+        let d = f.mul(&self.z, &f.add(&other.z, &other.z));
+        let e = f.sub(&b, &a);
+        let ff = f.sub(&d, &c);
+        let g = f.add(&d, &c);
+        let h = f.add(&b, &a);
+        Self {
+            x: f.mul(&e, &ff),
+            y: f.mul(&g, &h),
+            t: f.mul(&e, &h),
+            z: f.mul(&ff, &g),
+        }
+    }
+
+    /// Exchanges the points when `choice` is set, without branching
+    fn swap(a: &mut Self, b: &mut Self, choice: bool) {
+        let swapped = |a: &Self, b: &Self| Self {
+            x: U256::select(&a.x, &b.x, choice),
+            y: U256::select(&a.y, &b.y, choice),
+            z: U256::select(&a.z, &b.z, choice),
+            t: U256::select(&a.t, &b.t, choice),
+        };
+        (*a, *b) = (swapped(a, b), swapped(b, a));
+    }
+
+    /// Scalar is in normal form. Montgomery ladder running the same
+    /// operations whatever the bits of the scalar, the unified addition
+    /// having no special case.
+    fn mul(&self, scalar: &U256) -> Self {
+        let mut r0 = Self::neutral();
+        let mut r1 = *self;
+        for i in (0..256).rev() {
+            let bit = scalar.bit(i);
+            Self::swap(&mut r0, &mut r1, bit);
+            r1 = r0.add(&r1);
+            r0 = r0.add(&r0);
+            Self::swap(&mut r0, &mut r1, bit);
+        }
+        r0
+    }
+
+    /// Recovers x from y and its parity
+    /// https://datatracker.ietf.org/doc/html/rfc8032#section-5.1.3
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut bytes: [u8; 32] = bytes.try_into().ok()?;
+        let x_parity = bytes[31] & 0x80 != 0;
+        bytes[31] &= 0x7f;
+        let y = U256::from_le_slice(&bytes);
+        if y >= P {
+            return None;
+        }
+
+        let f = field();
+        let one = f.to_mont(&U256::ONE);
+        let y = f.to_mont(&y);
+        let y2 = f.square(&y);
+        let u = f.sub(&y2, &one);
+        let v = f.add(&f.mul(&f.to_mont(&D), &y2), &one);
+
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let v3 = f.mul(&f.square(&v), &v);
+        let v7 = f.mul(&f.square(&v3), &v);
+        let mut x = f.mul(&f.mul(&u, &v3), &f.pow(&f.mul(&u, &v7), &SQRT_EXPONENT));
+        let vx2 = f.mul(&v, &f.square(&x));
+        if vx2 != u {
+            if vx2 != f.sub(&U256::ZERO, &u) {
+                return None;
+            }
+            x = f.mul(&x, &f.to_mont(&SQRT_M1));
+        }
+        let x_normal = f.leave_mont(&x);
+        if x_normal.is_zero() && x_parity {
+            return None;
+        }
+        if x_normal.bit(0) != x_parity {
+            x = f.sub(&U256::ZERO, &x);
+        }
+        Some(Self {
+            x,
+            y,
+            z: one,
+            t: f.mul(&x, &y),
+        })
+    }
+
+    /// Little endian y, with the parity of x in the most significant bit
+    fn encode(&self) -> [u8; 32] {
+        let f = field();
+        let z_inv = f.inv(&self.z);
+        let x = f.leave_mont(&f.mul(&self.x, &z_inv));
+        let y = f.leave_mont(&f.mul(&self.y, &z_inv));
+        let mut bytes = y.to_le_bytes();
+        if x.bit(0) {
+            bytes[31] |= 0x80;
+        }
+        bytes
+    }
+}
+
+/// Reduces a 64 bytes little endian digest modulo L, result in normal form
+fn reduce_digest(digest: &[u8; 64]) -> U256 {
+    let low = U256::from_le_slice(&digest[..32]);
+    let high = U256::from_le_slice(&digest[32..]);
+    order().reduce_wide(&high, &low)
+}
+
+/// The 32 bytes seed from which the signing scalar and nonce prefix are derived
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivateKey([u8; 32]);
+
+impl PrivateKey {
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self(seed)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let seed: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Ed25519 private key should be 32 bytes long"))?;
+        Ok(Self(seed))
+    }
+
+    /// Clamped scalar and nonce prefix
+    fn expand(&self) -> (U256, [u8; 32]) {
+        let digest = sha512(&self.0);
+        let mut scalar_bytes = [0u8; 32];
+        scalar_bytes.copy_from_slice(&digest[..32]);
+        scalar_bytes[0] &= 248;
+        scalar_bytes[31] &= 127;
+        scalar_bytes[31] |= 64;
+        let mut prefix = [0u8; 32];
+        prefix.copy_from_slice(&digest[32..]);
+        (U256::from_le_slice(&scalar_bytes), prefix)
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        let (scalar, _) = self.expand();
+        Point::base().mul(&scalar).encode()
+    }
+
+    /// Signature is R || S, 32 bytes each
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let (scalar, prefix) = self.expand();
+        let public_key = Point::base().mul(&scalar).encode();
+
+        let r = reduce_digest(&sha512(&[&prefix[..], message].concat()));
+        let big_r = Point::base().mul(&r).encode();
+
+        let k = reduce_digest(&sha512(&[&big_r[..], &public_key[..], message].concat()));
+
+        let n = order();
+        let s = n.add(
+            &n.to_mont(&r),
+            &n.mul(&n.to_mont(&k), &n.to_mont(&scalar)),
+        );
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&big_r);
+        signature[32..].copy_from_slice(&n.leave_mont(&s).to_le_bytes());
+        signature
+    }
+}
+
+/// Checks [S]B = R + [k]A, with k = SHA-512(R || A || message)
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Some(a) = Point::decode(public_key) else {
+        return false;
+    };
+    if signature.len() != 64 {
+        return false;
+    }
+    let Some(r) = Point::decode(&signature[..32]) else {
+        return false;
+    };
+    let s = U256::from_le_slice(&signature[32..]);
+    if s >= L {
+        return false;
+    }
+    let k = reduce_digest(&sha512(&[&signature[..32], public_key, message].concat()));
+    Point::base().mul(&s).encode() == r.add(&a.mul(&k)).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rfc8032_vector() -> Result<()> {
+        // https://datatracker.ietf.org/doc/html/rfc8032#section-7.1 TEST 2
+        let private_key = PrivateKey::from_bytes(&from_hex(
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+        ))?;
+        assert_eq!(
+            private_key.public_key().to_vec(),
+            from_hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c")
+        );
+        assert_eq!(
+            private_key.sign(&[0x72]).to_vec(),
+            from_hex(
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+            )
+        );
+
+        let public_key = private_key.public_key();
+        let signature = private_key.sign(&[0x72]);
+        assert!(verify(&public_key, &[0x72], &signature));
+        assert!(!verify(&public_key, &[0x73], &signature));
+        let mut tampered = signature;
+        tampered[10] ^= 1;
+        assert!(!verify(&public_key, &[0x72], &tampered));
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+//! Cryptographic primitives needed by DNSSEC, implemented in place since
+//! the dependency list is fixed.
+//!
+//! Side channels: the scalar multiplications are Montgomery ladders with
+//! branchless conditional swaps, and the modular additions, subtractions and
+//! reductions pick their result with masks, so the time taken to derive a
+//! public key or to sign does not depend on the private key or the nonce.
+//! No more is guaranteed: the code is not audited, the compiler may turn the
+//! masks back into branches, nothing protects against cache or power
+//! analysis, and verification, which only handles public values, takes a
+//! time depending on its input.
+pub mod bigint;
+pub mod ed25519;
+pub mod p256;
+pub mod sha;
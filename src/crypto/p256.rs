@@ -0,0 +1,345 @@
+//! ECDSA on the NIST P-256 curve with SHA-256, DNSSEC algorithm 13
+//! https://datatracker.ietf.org/doc/html/rfc6605
+use std::sync::OnceLock;
+
+use rand::RngCore;
+
+use crate::crypto::bigint::{Modulus, U256};
+use crate::crypto::sha::sha256;
+use crate::Result;
+
+const P: U256 =
+    U256::from_hex("ffffffff00000001000000000000000000000000ffffffffffffffffffffffff");
+const N: U256 =
+    U256::from_hex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+const B: U256 =
+    U256::from_hex("5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b");
+const GX: U256 =
+    U256::from_hex("6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296");
+const GY: U256 =
+    U256::from_hex("4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5");
+
+fn field() -> &'static Modulus {
+    static FIELD: OnceLock<Modulus> = OnceLock::new();
+    FIELD.get_or_init(|| Modulus::new(P))
+}
+
+fn order() -> &'static Modulus {
+    static ORDER: OnceLock<Modulus> = OnceLock::new();
+    ORDER.get_or_init(|| Modulus::new(N))
+}
+
+/// Point in jacobian coordinates (X/Z^2, Y/Z^3), coordinates in montgomery form.
+/// Z = 0 is the point at infinity.
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+impl Point {
+    fn infinity() -> Self {
+        Self {
+            x: U256::ZERO,
+            y: U256::ZERO,
+            z: U256::ZERO,
+        }
+    }
+
+    fn from_affine(x: &U256, y: &U256) -> Self {
+        let f = field();
+        Self {
+            x: f.to_mont(x),
+            y: f.to_mont(y),
+            z: f.to_mont(&U256::ONE),
+        }
+    }
+
+    /// Parses X || Y and checks that the point is on the curve y^2 = x^3 - 3x + b
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 64 {
+            return None;
+        }
+        let x = U256::from_be_slice(&bytes[..32]);
+        let y = U256::from_be_slice(&bytes[32..]);
+        if x >= P || y >= P {
+            return None;
+        }
+        let f = field();
+        let point = Self::from_affine(&x, &y);
+        let x3 = f.mul(&f.square(&point.x), &point.x);
+        let x3 = f.add(&f.sub(&x3, &point.x), &f.sub(&f.to_mont(&B), &f.add(&point.x, &point.x)));
+        match f.square(&point.y) == x3 {
+            true => Some(point),
+            false => None,
+        }
+    }
+
+    fn generator() -> Self {
+        Self::from_affine(&GX, &GY)
+    }
+
+    fn is_infinity(&self) -> bool {
+        self.z.is_zero()
+    }
+
+    /// Returns the affine coordinates in normal form
+    fn to_affine(self) -> Option<(U256, U256)> {
+        if self.is_infinity() {
+            return None;
+        }
+        let f = field();
+        let z_inv = f.inv(&self.z);
+        let z_inv2 = f.square(&z_inv);
+        let z_inv3 = f.mul(&z_inv2, &z_inv);
+        Some((
+            f.leave_mont(&f.mul(&self.x, &z_inv2)),
+            f.leave_mont(&f.mul(&self.y, &z_inv3)),
+        ))
+    }
+
+    /// dbl-2001-b, valid because a = -3
+    fn double(&self) -> Self {
+        if self.is_infinity() {
+            return *self;
+        }
+        let f = field();
+        let delta = f.square(&self.z);
+        let gamma = f.square(&self.y);
+        let beta = f.mul(&self.x, &gamma);
+        let t = f.mul(&f.sub(&self.x, &delta), &f.add(&self.x, &delta));
+        let alpha = f.add(&f.add(&t, &t), &t);
+
+        let beta4 = f.add(&beta, &beta);
+        let beta4 = f.add(&beta4, &beta4);
+        let beta8 = f.add(&beta4, &beta4);
+        let x = f.sub(&f.square(&alpha), &beta8);
+
+        let z = f.sub(
+            &f.sub(&f.square(&f.add(&self.y, &self.z)), &gamma),
+            &delta,
+        );
+
+        let gamma2 = f.square(&gamma);
+        let gamma2_2 = f.add(&gamma2, &gamma2);
+        let gamma2_4 = f.add(&gamma2_2, &gamma2_2);
+        let gamma2_8 = f.add(&gamma2_4, &gamma2_4);
+        let y = f.sub(&f.mul(&alpha, &f.sub(&beta4, &x)), &gamma2_8);
+        Self { x, y, z }
+    }
+
+    /// add-2007-bl
+    fn add(&self, other: &Self) -> Self {
+        if self.is_infinity() {
+            return *other;
+        }
+        if other.is_infinity() {
+            return *self;
+        }
+        let f = field();
+        let z1z1 = f.square(&self.z);
+        let z2z2 = f.square(&other.z);
+        let u1 = f.mul(&self.x, &z2z2);
+        let u2 = f.mul(&other.x, &z1z1);
+        let s1 = f.mul(&f.mul(&self.y, &other.z), &z2z2);
+        let s2 = f.mul(&f.mul(&other.y, &self.z), &z1z1);
+        let h = f.sub(&u2, &u1);
+        let r = f.sub(&s2, &s1);
+        if h.is_zero() {
+            if r.is_zero() {
+                return self.double();
+            }
+            return Self::infinity();
+        }
+        let r = f.add(&r, &r);
+        let h2 = f.add(&h, &h);
+        let i = f.square(&h2);
+        let j = f.mul(&h, &i);
+        let v = f.mul(&u1, &i);
+        let x = f.sub(&f.sub(&f.square(&r), &j), &f.add(&v, &v));
+        let s1j = f.mul(&s1, &j);
+        let y = f.sub(&f.mul(&r, &f.sub(&v, &x)), &f.add(&s1j, &s1j));
+        let z = f.mul(
+            &f.sub(&f.sub(&f.square(&f.add(&self.z, &other.z)), &z1z1), &z2z2),
+            &h,
+        );
+        Self { x, y, z }
+    }
+
+    /// Exchanges the points when `choice` is set, without branching
+    fn swap(a: &mut Self, b: &mut Self, choice: bool) {
+        let swapped = |a: &Self, b: &Self| Self {
+            x: U256::select(&a.x, &b.x, choice),
+            y: U256::select(&a.y, &b.y, choice),
+            z: U256::select(&a.z, &b.z, choice),
+        };
+        (*a, *b) = (swapped(a, b), swapped(b, a));
+    }
+
+    /// Scalar is in normal form, below n. Montgomery ladder running the same
+    /// operations whatever the bits of the scalar. The scalar is raised by n
+    /// or 2n, which does not change the result as n is the order of every
+    /// point, so that its bit 256 is set: the ladder starts from the point
+    /// itself and never goes through the infinity, which the formulas handle
+    /// with branches.
+    fn mul(&self, scalar: &U256) -> Self {
+        let (plus_n, carry) = scalar.overflowing_add(&N);
+        let plus_2n = plus_n.overflowing_add(&N).0;
+        let scalar = U256::select(&plus_2n, &plus_n, carry);
+        let mut r0 = *self;
+        let mut r1 = self.double();
+        for i in (0..256).rev() {
+            let bit = scalar.bit(i);
+            Self::swap(&mut r0, &mut r1, bit);
+            r1 = r0.add(&r1);
+            r0 = r0.double();
+            Self::swap(&mut r0, &mut r1, bit);
+        }
+        r0
+    }
+}
+
+/// Private scalar, kept in normal form
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivateKey(U256);
+
+impl PrivateKey {
+    pub fn generate() -> Self {
+        loop {
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let scalar = U256::from_be_slice(&bytes);
+            if !scalar.is_zero() && scalar < N {
+                return Self(scalar);
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 32 {
+            anyhow::bail!("P-256 private key should be 32 bytes long");
+        }
+        let scalar = U256::from_be_slice(bytes);
+        if scalar.is_zero() || scalar >= N {
+            anyhow::bail!("P-256 private key out of range");
+        }
+        Ok(Self(scalar))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_be_bytes()
+    }
+
+    /// Uncompressed X || Y, without the 0x04 prefix, as DNSKEY expects it
+    pub fn public_key(&self) -> [u8; 64] {
+        let (x, y) = Point::generator()
+            .mul(&self.0)
+            .to_affine()
+            .expect("private scalar is in [1, n-1]");
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&x.to_be_bytes());
+        bytes[32..].copy_from_slice(&y.to_be_bytes());
+        bytes
+    }
+
+    /// Signature is r || s, 32 bytes each
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let n = order();
+        let digest = U256::from_be_slice(&sha256(message));
+        let e = n.to_mont(&digest);
+        let d = n.to_mont(&self.0);
+        loop {
+            let k = Self::generate().0;
+            let Some((rx, _)) = Point::generator().mul(&k).to_affine() else {
+                continue;
+            };
+            let r = n.to_mont(&rx);
+            if r.is_zero() {
+                continue;
+            }
+            let k_inv = n.inv(&n.to_mont(&k));
+            let s = n.mul(&k_inv, &n.add(&e, &n.mul(&r, &d)));
+            if s.is_zero() {
+                continue;
+            }
+            let mut signature = [0u8; 64];
+            signature[..32].copy_from_slice(&n.leave_mont(&r).to_be_bytes());
+            signature[32..].copy_from_slice(&n.leave_mont(&s).to_be_bytes());
+            return signature;
+        }
+    }
+}
+
+/// Checks a r || s signature of `message` against a X || Y public key
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Some(public_point) = Point::decode(public_key) else {
+        return false;
+    };
+    if signature.len() != 64 {
+        return false;
+    }
+    let r = U256::from_be_slice(&signature[..32]);
+    let s = U256::from_be_slice(&signature[32..]);
+    if r.is_zero() || r >= N || s.is_zero() || s >= N {
+        return false;
+    }
+
+    let n = order();
+    let e = n.to_mont(&U256::from_be_slice(&sha256(message)));
+    let w = n.inv(&n.to_mont(&s));
+    let u1 = n.leave_mont(&n.mul(&e, &w));
+    let u2 = n.leave_mont(&n.mul(&n.to_mont(&r), &w));
+    let point = Point::generator().mul(&u1).add(&public_point.mul(&u2));
+    match point.to_affine() {
+        // x is below p and may exceed n
+        Some((x, _)) => n.leave_mont(&n.to_mont(&x)) == r,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_key_derivation() -> Result<()> {
+        // https://datatracker.ietf.org/doc/html/rfc6979#appendix-A.2.5
+        let private_key = PrivateKey::from_bytes(
+            &U256::from_hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721")
+                .to_be_bytes(),
+        )?;
+        let public_key = private_key.public_key();
+        assert_eq!(
+            U256::from_be_slice(&public_key[..32]),
+            U256::from_hex("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6")
+        );
+        assert_eq!(
+            U256::from_be_slice(&public_key[32..]),
+            U256::from_hex("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299")
+        );
+
+        let signature = private_key.sign(b"sample");
+        assert!(verify(&public_key, b"sample", &signature));
+        assert!(!verify(&public_key, b"other", &signature));
+        let mut tampered = signature;
+        tampered[40] ^= 1;
+        assert!(!verify(&public_key, b"sample", &tampered));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_multiplication() {
+        // 1 + n stays below 2^256 and is raised by 2n, n - 1 is raised by n
+        let (x, y) = Point::generator().mul(&U256::ONE).to_affine().unwrap();
+        assert_eq!((x, y), (GX, GY));
+        let minus_one = N.overflowing_sub(&U256::ONE).0;
+        let (x, y) = Point::generator().mul(&minus_one).to_affine().unwrap();
+        assert_eq!((x, y), (GX, P.overflowing_sub(&GY).0));
+        let two = U256([2, 0, 0, 0]);
+        assert_eq!(
+            Point::generator().mul(&two).to_affine(),
+            Point::generator().double().to_affine()
+        );
+    }
+}
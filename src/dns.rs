@@ -1,18 +1,47 @@
 use std::io::{Cursor, Read};
 
-use crate::dns_header::{OpCode, RCode};
+use crate::dns_edns::{Edns, CLASSIC_UDP_SIZE};
+use crate::dns_header::{DnsHeaderFourthByte, DnsHeaderThirdByte, OpCode, RCode};
+use crate::dns_type::QType;
 use crate::{dns_answer::DnsAnswer, dns_header::DnsHeader, dns_question::DnsQuestion};
 use crate::{Error, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DnsRequest {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
+    /// Mostly used to carry the EDNS OPT record
+    pub additionals: Vec<DnsAnswer>,
 }
 
 impl DnsRequest {
-    /// NOTE: all questions will have same packet id,
-    /// not really tested
+    /// Recursive query for a single question, with an OPT record carrying
+    /// the DO bit when `dnssec_ok` is set
+    pub fn query(packet_id: u16, question: DnsQuestion, dnssec_ok: bool) -> Self {
+        let mut header = DnsHeader {
+            packet_id,
+            third_byte: DnsHeaderThirdByte::from(0),
+            fourth_byte: DnsHeaderFourthByte::from(0),
+            question_count: 1,
+            answer_record_count: 0,
+            authority_record_count: 0,
+            additional_record_count: 0,
+        };
+        header.third_byte.recursion_desired = true;
+        let mut additionals = Vec::new();
+        if dnssec_ok {
+            header.additional_record_count = 1;
+            additionals.push(Edns::new(true).into());
+        }
+        Self {
+            header,
+            questions: vec![question],
+            additionals,
+        }
+    }
+
+    /// One request per question, all with the packet id of the client. The
+    /// upstream exchanges give each its own id.
     pub fn split_questions(self) -> Vec<Self> {
         let mut dns_requests = Vec::new();
         for question in self.questions {
@@ -22,24 +51,37 @@ impl DnsRequest {
             dns_requests.push(Self {
                 header,
                 questions: vec![question],
+                additionals: self.additionals.clone(),
             });
         }
         dns_requests
     }
+
+    pub fn edns(&self) -> Option<Edns> {
+        Edns::find(&self.additionals)
+    }
+
+    /// Largest UDP reply the sender of the request accepts
+    pub fn max_udp_size(&self) -> usize {
+        match self.edns() {
+            Some(edns) => (edns.udp_payload_size as usize).max(CLASSIC_UDP_SIZE),
+            None => CLASSIC_UDP_SIZE,
+        }
+    }
 }
 
 impl TryFrom<&[u8]> for DnsRequest {
     type Error = Error;
 
     fn try_from(buf: &[u8]) -> Result<Self> {
-        let mut reader = Cursor::new(&buf[..]);
+        let mut reader = Cursor::new(buf);
         let mut header_buf = [0u8; 12];
         reader
             .read_exact(&mut header_buf)
-            .expect("Could not fill header");
+            .map_err(|_| anyhow::anyhow!("Message shorter than its header"))?;
 
         // contains the header of the request
-        let header = DnsHeader::try_from(&header_buf[..])?;
+        let mut header = DnsHeader::try_from(&header_buf[..])?;
 
         if header.third_byte.query_response_ind {
             anyhow::bail!("Header corresponds to a reply packet");
@@ -50,7 +92,23 @@ impl TryFrom<&[u8]> for DnsRequest {
             let dns_question = DnsQuestion::try_from(&mut reader)?;
             questions.push(dns_question);
         }
-        Ok(Self { header, questions })
+        // queries do not carry answer or authority records, they are skipped
+        for _ in 0..(header.answer_record_count as u32 + header.authority_record_count as u32) {
+            DnsAnswer::try_from(&mut reader)?;
+        }
+        header.answer_record_count = 0;
+        header.authority_record_count = 0;
+
+        let mut additionals = Vec::new();
+        for _ in 0..header.additional_record_count {
+            let dns_additional = DnsAnswer::try_from(&mut reader)?;
+            additionals.push(dns_additional);
+        }
+        Ok(Self {
+            header,
+            questions,
+            additionals,
+        })
     }
 }
 
@@ -61,6 +119,9 @@ impl From<DnsRequest> for Vec<u8> {
         for question in dns_request.questions {
             bytes.extend::<Vec<u8>>(question.into());
         }
+        for additional in dns_request.additionals {
+            bytes.extend::<Vec<u8>>(additional.into());
+        }
         bytes
     }
 }
@@ -70,27 +131,86 @@ pub struct DnsReply {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>,
 }
 
 impl DnsReply {
-    /// Hypothesis 1 answer per question,
-    /// no error handling
-    /// TODO: tests
-    pub fn merge_replies(replies: &[Self]) -> Self {
-        let mut header = replies[0].header.clone();
-        header.question_count = replies.len() as u16;
-        header.answer_record_count = replies.len() as u16;
-        let mut questions = Vec::new();
-        let mut answers = Vec::new();
+    /// Combines the replies to single questions into the reply to the
+    /// request that asked them all. Records are kept once, with a single OPT
+    /// record. The most severe response code wins, NXDOMAIN only over
+    /// NOERROR, and AA and AD are only kept when set on every reply.
+    pub fn merge_replies(replies: &[Self]) -> Result<Self> {
+        let Some(first) = replies.first() else {
+            anyhow::bail!("No reply to merge");
+        };
+        let mut merged = Self {
+            header: first.header.clone(),
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let severity = |rcode: &RCode| match rcode {
+            RCode::NoError => 0,
+            RCode::NameError => 1,
+            _ => 2,
+        };
         for dns_reply in replies {
-            questions.extend(dns_reply.questions.clone());
-            answers.extend(dns_reply.answers.clone());
-        }
-        Self {
-            header,
-            questions,
-            answers,
+            let header = &dns_reply.header;
+            let rcode = &header.fourth_byte.response_code;
+            if severity(rcode) > severity(&merged.header.fourth_byte.response_code) {
+                merged.header.fourth_byte.response_code = rcode.clone();
+            }
+            merged.header.third_byte.authoritative_answer &= header.third_byte.authoritative_answer;
+            merged.header.third_byte.truncation |= header.third_byte.truncation;
+            let authentic_data = merged.header.fourth_byte.authentic_data();
+            merged
+                .header
+                .fourth_byte
+                .set_authentic_data(authentic_data && header.fourth_byte.authentic_data());
+
+            merged.questions.extend(dns_reply.questions.iter().cloned());
+            let sections = [
+                (&mut merged.answers, &dns_reply.answers),
+                (&mut merged.authorities, &dns_reply.authorities),
+                (&mut merged.additionals, &dns_reply.additionals),
+            ];
+            for (merged_section, section) in sections {
+                for record in section {
+                    let has_opt = record.r_type == QType::Opt
+                        && merged_section.iter().any(|r| r.r_type == QType::Opt);
+                    if !has_opt && !merged_section.contains(record) {
+                        merged_section.push(record.clone());
+                    }
+                }
+            }
         }
+        merged.update_counts();
+        Ok(merged)
+    }
+
+    pub fn edns(&self) -> Option<Edns> {
+        Edns::find(&self.additionals)
+    }
+
+    /// Reply with the TC bit set and only the question and OPT records,
+    /// telling the client to retry over TCP
+    pub fn truncated(mut self) -> Self {
+        self.header.third_byte.truncation = true;
+        self.answers.clear();
+        self.authorities.clear();
+        self.additionals.retain(|record| record.r_type == QType::Opt);
+        self.update_counts();
+        self
+    }
+
+    /// Sets the header counts from the actual sections
+    pub fn update_counts(&mut self) {
+        self.header.question_count = self.questions.len() as u16;
+        self.header.answer_record_count = self.answers.len() as u16;
+        self.header.authority_record_count = self.authorities.len() as u16;
+        self.header.additional_record_count = self.additionals.len() as u16;
     }
 }
 
@@ -98,11 +218,11 @@ impl TryFrom<&[u8]> for DnsReply {
     type Error = Error;
 
     fn try_from(buf: &[u8]) -> Result<Self> {
-        let mut reader = Cursor::new(&buf[..]);
+        let mut reader = Cursor::new(buf);
         let mut header_buf = [0u8; 12];
         reader
             .read_exact(&mut header_buf)
-            .expect("Could not fill header");
+            .map_err(|_| anyhow::anyhow!("Message shorter than its header"))?;
 
         // contains the header of the reply
         let header = DnsHeader::try_from(&header_buf[..])?;
@@ -121,10 +241,22 @@ impl TryFrom<&[u8]> for DnsReply {
             let dns_answer = DnsAnswer::try_from(&mut reader)?;
             answers.push(dns_answer);
         }
+        let mut authorities = Vec::new();
+        for _ in 0..header.authority_record_count {
+            let dns_authority = DnsAnswer::try_from(&mut reader)?;
+            authorities.push(dns_authority);
+        }
+        let mut additionals = Vec::new();
+        for _ in 0..header.additional_record_count {
+            let dns_additional = DnsAnswer::try_from(&mut reader)?;
+            additionals.push(dns_additional);
+        }
         Ok(Self {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
         })
     }
 }
@@ -170,6 +302,8 @@ impl TryFrom<DnsRequest> for DnsReply {
             header,
             questions,
             answers,
+            authorities: vec![],
+            additionals: vec![],
         })
     }
 }
@@ -184,6 +318,89 @@ impl From<DnsReply> for Vec<u8> {
         for answer in dns_reply.answers {
             bytes.extend::<Vec<u8>>(answer.into());
         }
+        for authority in dns_reply.authorities {
+            bytes.extend::<Vec<u8>>(authority.into());
+        }
+        for additional in dns_reply.additionals {
+            bytes.extend::<Vec<u8>>(additional.into());
+        }
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_class::QClass;
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+
+    fn reply(name: &str, rcode: RCode, records: &str) -> Result<DnsReply> {
+        let question = DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let mut dns_reply = DnsReply {
+            header: DnsRequest::query(5, question.clone(), true).header,
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        dns_reply.header.third_byte.query_response_ind = true;
+        dns_reply.header.fourth_byte.response_code = rcode;
+        dns_reply.header.fourth_byte.set_authentic_data(true);
+        for record in master_file::parse(records, &[])? {
+            match record.r_type {
+                QType::Soa => dns_reply.authorities.push(record),
+                _ => dns_reply.answers.push(record),
+            }
+        }
+        dns_reply.additionals.push(Edns::new(true).into());
+        dns_reply.update_counts();
+        Ok(dns_reply)
+    }
+
+    #[test]
+    fn test_merge_replies() -> Result<()> {
+        assert!(DnsReply::merge_replies(&[]).is_err());
+
+        let www = reply(
+            "www.example",
+            RCode::NoError,
+            "www.example. 60 CNAME web.example.\nweb.example. 60 A 192.0.2.1\nweb.example. 60 A 192.0.2.2",
+        )?;
+        let soa = "example. 60 SOA ns1.example. hostmaster.example. 1 7200 3600 1209600 300";
+        let mut nx = reply("nx.example", RCode::NameError, soa)?;
+        let nodata = reply("empty.example", RCode::NoError, soa)?;
+        let merged = DnsReply::merge_replies(&[www.clone(), nx.clone(), nodata.clone()])?;
+        assert_eq!(merged.header.fourth_byte.response_code, RCode::NameError);
+        assert!(merged.header.fourth_byte.authentic_data());
+        assert_eq!(merged.questions.len(), 3);
+        assert_eq!(merged.answers.len(), 3);
+        assert_eq!(merged.authorities.len(), 1);
+        assert_eq!(merged.additionals.len(), 1);
+        assert_eq!(merged.header.question_count, 3);
+        assert_eq!(merged.header.answer_record_count, 3);
+        assert_eq!(merged.header.authority_record_count, 1);
+        assert_eq!(merged.header.additional_record_count, 1);
+
+        nx.header.fourth_byte.response_code = RCode::ServerFailure;
+        nx.header.fourth_byte.set_authentic_data(false);
+        let merged = DnsReply::merge_replies(&[www, nx, nodata])?;
+        assert_eq!(
+            merged.header.fourth_byte.response_code,
+            RCode::ServerFailure
+        );
+        assert!(!merged.header.fourth_byte.authentic_data());
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_message() {
+        let bytes = [0u8, 1, 2, 3];
+        assert!(DnsRequest::try_from(&bytes[..]).is_err());
+        assert!(DnsReply::try_from(&bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! DNS64: IPv6-only clients reach the IPv4 hosts through a NAT64 gateway,
+//! so the names without usable AAAA records are given AAAA records embedding
+//! their IPv4 addresses in the prefix of the gateway. The PTR questions for
+//! the addresses of the prefix are answered with a CNAME to the reverse name
+//! of the embedded IPv4 address.
+//! https://datatracker.ietf.org/doc/html/rfc6147
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::acl::Cidr;
+use crate::cache::CachedAnswer;
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{labels_to_bytes, labels_to_key, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dns_zone::negative_ttl;
+use crate::hosts::reverse_name;
+
+/// IPv4-mapped addresses are never usable by IPv6-only clients
+/// https://datatracker.ietf.org/doc/html/rfc6147#section-5.1.4
+const MAPPED_PREFIX: Cidr = Cidr {
+    address: IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0)),
+    length: 96,
+};
+
+/// Address of the ip6.arpa name of a PTR question, none for other names
+fn reverse_address(name: &[DnsLabel]) -> Option<Ipv6Addr> {
+    let key = labels_to_key(name);
+    let nibbles = key.strip_suffix(".ip6.arpa.")?;
+    let nibbles: Vec<&str> = nibbles.split('.').collect();
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut address = 0u128;
+    for nibble in nibbles.iter().rev() {
+        if nibble.len() != 1 {
+            return None;
+        }
+        address = address << 4 | u128::from_str_radix(nibble, 16).ok()?;
+    }
+    Some(Ipv6Addr::from(address))
+}
+
+#[derive(Debug)]
+pub struct Dns64 {
+    /// Network of the NAT64 gateway, only its first 96 bits are used
+    prefix: Ipv6Addr,
+    exclude: Vec<Cidr>,
+}
+
+impl Dns64 {
+    pub fn new(prefix: Ipv6Addr, exclude: &[Cidr]) -> Self {
+        let mut exclude = exclude.to_vec();
+        exclude.push(MAPPED_PREFIX);
+        Self { prefix, exclude }
+    }
+
+    fn excluded(&self, address: IpAddr) -> bool {
+        self.exclude.iter().any(|prefix| prefix.contains(&address))
+    }
+
+    /// Address of the prefix embedding the IPv4 address in its last 32 bits
+    fn synthesize_address(&self, address: Ipv4Addr) -> Ipv6Addr {
+        let prefix = u128::from(self.prefix) & !u128::from(u32::MAX);
+        Ipv6Addr::from(prefix | u128::from(u32::from(address)))
+    }
+
+    /// The reply to an AAAA question is synthesized when it has no error and
+    /// no AAAA record outside of the excluded prefixes. Validating clients
+    /// asking with DO and CD get the reply as it is, as they would reject
+    /// the unsigned records.
+    /// https://datatracker.ietf.org/doc/html/rfc6147#section-5.5
+    pub fn needs_synthesis(&self, dns_request: &DnsRequest, dns_reply: &DnsReply) -> bool {
+        let question = &dns_request.questions[0];
+        let validating = dns_request.edns().is_some_and(|edns| edns.dnssec_ok)
+            && dns_request.header.fourth_byte.checking_disabled();
+        question.q_type == QType::Aaaa
+            && question.q_class == QClass::In
+            && !validating
+            && dns_reply.header.fourth_byte.response_code == RCode::NoError
+            && !dns_reply.answers.iter().any(|record| {
+                record.r_type == QType::Aaaa
+                    && <[u8; 16]>::try_from(&record.r_data[..])
+                        .is_ok_and(|octets| !self.excluded(IpAddr::from(octets)))
+            })
+    }
+
+    /// Answer to the AAAA question from the reply to the A question of the
+    /// same name: its CNAME chain and an AAAA record for each A record
+    /// outside of the excluded prefixes. The TTL is at most the negative TTL
+    /// of the AAAA reply. None when there is no A record to synthesize from.
+    /// https://datatracker.ietf.org/doc/html/rfc6147#section-5.1.7
+    pub fn synthesize(&self, aaaa_reply: &DnsReply, a_reply: &DnsReply) -> Option<CachedAnswer> {
+        let max_ttl = aaaa_reply
+            .authorities
+            .iter()
+            .find(|record| record.r_type == QType::Soa)
+            .map_or(u32::MAX, negative_ttl);
+        let mut answers = Vec::new();
+        let mut synthesized = false;
+        for record in &a_reply.answers {
+            match record.r_type {
+                QType::Cname => answers.push(record.clone()),
+                QType::A => {
+                    let Ok(octets) = <[u8; 4]>::try_from(&record.r_data[..]) else {
+                        continue;
+                    };
+                    let address = Ipv4Addr::from(octets);
+                    if self.excluded(IpAddr::V4(address)) {
+                        continue;
+                    }
+                    let r_data = self.synthesize_address(address).octets().to_vec();
+                    answers.push(DnsAnswer {
+                        r_name: record.r_name.clone(),
+                        r_type: QType::Aaaa,
+                        r_class: record.r_class.clone(),
+                        ttl: record.ttl.min(max_ttl),
+                        rd_length: r_data.len() as u16,
+                        r_data,
+                    });
+                    synthesized = true;
+                }
+                _ => {}
+            }
+        }
+        synthesized.then_some(CachedAnswer {
+            rcode: RCode::NoError,
+            answers,
+            authorities: vec![],
+            secure: false,
+            prefetch: false,
+        })
+    }
+
+    /// Reverse name of the IPv4 address embedded in the name of a PTR
+    /// question for an address of the prefix
+    /// https://datatracker.ietf.org/doc/html/rfc6147#section-5.3.1
+    pub fn reverse_target(&self, question: &DnsQuestion) -> Option<Vec<DnsLabel>> {
+        if question.q_type != QType::Ptr {
+            return None;
+        }
+        let address = u128::from(reverse_address(&question.q_name)?);
+        let prefix = u128::from(self.prefix);
+        if address >> 32 != prefix >> 32 {
+            return None;
+        }
+        Some(reverse_name(&IpAddr::V4(Ipv4Addr::from(address as u32))))
+    }
+
+    /// Answer to the PTR question: a CNAME to the reverse name of the IPv4
+    /// address, followed by the reply to the question for that name
+    pub fn reverse_answer(
+        question: &DnsQuestion,
+        target: &[DnsLabel],
+        ptr_reply: DnsReply,
+    ) -> CachedAnswer {
+        let r_data = labels_to_bytes(target);
+        let cname = DnsAnswer {
+            r_name: question.q_name.clone(),
+            r_type: QType::Cname,
+            r_class: QClass::In,
+            ttl: ptr_reply
+                .answers
+                .iter()
+                .map(|record| record.ttl)
+                .min()
+                .unwrap_or_default(),
+            rd_length: r_data.len() as u16,
+            r_data,
+        };
+        let mut answers = vec![cname];
+        answers.extend(ptr_reply.answers);
+        CachedAnswer {
+            rcode: ptr_reply.header.fourth_byte.response_code,
+            answers,
+            authorities: ptr_reply.authorities,
+            secure: false,
+            prefetch: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+    use crate::Result;
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    fn reply(q_type: QType, records: &str) -> Result<DnsReply> {
+        let request = DnsRequest::query(1, question("www.example", q_type), false);
+        let mut reply = DnsReply::try_from(request)?;
+        reply.answers = master_file::parse(records, &[])?;
+        Ok(reply)
+    }
+
+    #[test]
+    fn test_synthesize() -> Result<()> {
+        let dns64 = Dns64::new("64:ff9b::".parse()?, &["10.0.0.0/8".parse()?]);
+        let request = DnsRequest::query(1, question("www.example", QType::Aaaa), false);
+
+        let aaaa_reply = reply(QType::Aaaa, "www.example. 60 IN CNAME v4.example.")?;
+        assert!(dns64.needs_synthesis(&request, &aaaa_reply));
+        let mapped = reply(QType::Aaaa, "www.example. 60 IN AAAA ::ffff:192.0.2.1")?;
+        assert!(dns64.needs_synthesis(&request, &mapped));
+        let native = reply(QType::Aaaa, "www.example. 60 IN AAAA 2001:db8::1")?;
+        assert!(!dns64.needs_synthesis(&request, &native));
+
+        let a_reply = reply(
+            QType::A,
+            "www.example. 60 IN CNAME v4.example.\n\
+             v4.example. 300 IN A 192.0.2.33\n\
+             v4.example. 300 IN A 10.0.0.1",
+        )?;
+        let answer = dns64.synthesize(&aaaa_reply, &a_reply).unwrap();
+        assert_eq!(answer.answers.len(), 2);
+        assert_eq!(answer.answers[0].r_type, QType::Cname);
+        let synthesized = &answer.answers[1];
+        assert_eq!(synthesized.r_type, QType::Aaaa);
+        assert_eq!(synthesized.r_name, labels_from_str("v4.example"));
+        let address: Ipv6Addr = "64:ff9b::192.0.2.33".parse()?;
+        assert_eq!(synthesized.r_data, address.octets().to_vec());
+
+        let excluded = reply(QType::A, "www.example. 60 IN A 10.1.2.3")?;
+        assert!(dns64.synthesize(&aaaa_reply, &excluded).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_target() -> Result<()> {
+        let dns64 = Dns64::new("64:ff9b::".parse()?, &[]);
+        let name = reverse_name(&"64:ff9b::c000:221".parse()?);
+        let mut ptr = question("", QType::Ptr);
+        ptr.q_name = name;
+        assert_eq!(
+            labels_to_key(&dns64.reverse_target(&ptr).unwrap()),
+            "33.2.0.192.in-addr.arpa."
+        );
+        ptr.q_name = reverse_name(&"2001:db8::c000:221".parse()?);
+        assert!(dns64.reverse_target(&ptr).is_none());
+        ptr.q_name = labels_from_str("1.0.0.ip6.arpa");
+        assert!(dns64.reverse_target(&ptr).is_none());
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::io::{Cursor, Read};
 
 use crate::dns_class::QClass;
-use crate::dns_label::DnsLabel;
+use crate::dns_label::{labels_to_bytes, DnsLabel};
 use crate::dns_question::DnsQuestion;
 use crate::dns_type::QType;
 
@@ -22,25 +22,55 @@ pub struct DnsAnswer {
     pub r_data: Vec<u8>,
 }
 
+/// Types defined in RFC 1035 whose record data may hold compressed names
+/// https://datatracker.ietf.org/doc/html/rfc3597#section-4
+fn has_compressible_names(r_type: &QType) -> bool {
+    matches!(
+        r_type,
+        QType::Ns
+            | QType::Md
+            | QType::Mf
+            | QType::Cname
+            | QType::Soa
+            | QType::Mb
+            | QType::Mg
+            | QType::Mr
+            | QType::Ptr
+            | QType::Minfo
+            | QType::Mx
+    )
+}
+
+/// Record data with its names expanded, read from the message up to `end`
+fn decompress_r_data(r_type: &QType, reader: &mut Cursor<&[u8]>, end: u64) -> Result<Vec<u8>> {
+    let mut r_data = Vec::new();
+    let (names, fixed_before) = match r_type {
+        QType::Soa | QType::Minfo => (2, 0),
+        QType::Mx => (1, 2),
+        _ => (1, 0),
+    };
+    let mut fixed = vec![0u8; fixed_before];
+    reader.read_exact(&mut fixed)?;
+    r_data.extend(fixed);
+    for _ in 0..names {
+        r_data.extend(labels_to_bytes(&DnsQuestion::get_q_name(reader)?));
+    }
+    // SOA serial and timers
+    let pos = reader.position();
+    if pos > end {
+        anyhow::bail!("Name overflows the record data");
+    }
+    let mut rest = vec![0u8; (end - pos) as usize];
+    reader.read_exact(&mut rest)?;
+    r_data.extend(rest);
+    Ok(r_data)
+}
+
 impl TryFrom<&mut Cursor<&[u8]>> for DnsAnswer {
     type Error = Error;
 
     fn try_from(reader: &mut Cursor<&[u8]>) -> Result<Self> {
-        let mut one_byte_buf = [0u8; 1];
-        let mut r_name = Vec::new();
-        loop {
-            reader.read_exact(&mut one_byte_buf)?;
-            let length = one_byte_buf[0];
-            // null byte
-            if length == 0 {
-                break;
-            }
-            let mut content_buf = vec![0u8; length as usize];
-            reader.read_exact(&mut content_buf)?;
-
-            let label = String::from_utf8(content_buf)?;
-            r_name.push(DnsLabel { length, label });
-        }
+        let r_name = DnsQuestion::get_q_name(reader)?;
         let mut two_byte_buf = [0u8; 2];
         reader.read_exact(&mut two_byte_buf)?;
         let r_type_val = u16::from_be_bytes(two_byte_buf);
@@ -58,9 +88,20 @@ impl TryFrom<&mut Cursor<&[u8]>> for DnsAnswer {
         reader.read_exact(&mut two_byte_buf)?;
         let rd_length = u16::from_be_bytes(two_byte_buf);
 
+        let r_data_pos = reader.position();
         let mut r_data = vec![0u8; rd_length as usize];
 
         reader.read_exact(&mut r_data)?;
+        if has_compressible_names(&r_type) {
+            // data that does not parse is kept as is
+            let end = reader.position();
+            reader.set_position(r_data_pos);
+            if let Ok(expanded) = decompress_r_data(&r_type, reader, end) {
+                r_data = expanded;
+            }
+            reader.set_position(end);
+        }
+        let rd_length = r_data.len() as u16;
 
         Ok(DnsAnswer {
             r_name,
@@ -114,13 +155,14 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::dns_label::labels_from_str;
 
     #[test]
     fn test_dns_answer_from_bytes() -> Result<()> {
         let domain_name = "query.example.com";
 
         let mut bytes = Vec::new();
-        for s in domain_name.split(|x| x == '.') {
+        for s in domain_name.split('.') {
             bytes.push(s.len() as u8);
             bytes.extend(s.as_bytes());
         }
@@ -166,4 +208,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_dns_answer_compressed_r_data() -> Result<()> {
+        // "example.com" at offset 0, then an MX record using pointers to it
+        let mut bytes = vec![7];
+        bytes.extend(b"example");
+        bytes.push(3);
+        bytes.extend(b"com");
+        bytes.push(0);
+        bytes.extend([0b11000000, 0]);
+        bytes.extend([0, 15, 0, 1, 0, 0, 0, 60, 0, 9]);
+        bytes.extend([0, 10, 4]);
+        bytes.extend(b"mail");
+        bytes.extend([0b11000000, 0]);
+
+        let mut reader = Cursor::new(&bytes[..]);
+        reader.set_position(13);
+        let dns_answer = DnsAnswer::try_from(&mut reader)?;
+        assert_eq!(dns_answer.r_name, labels_from_str("example.com"));
+        let mut r_data = vec![0, 10];
+        r_data.extend(labels_to_bytes(&labels_from_str("mail.example.com")));
+        assert_eq!(dns_answer.rd_length as usize, r_data.len());
+        assert_eq!(dns_answer.r_data, r_data);
+        assert_eq!(reader.position(), bytes.len() as u64);
+        Ok(())
+    }
 }
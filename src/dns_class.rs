@@ -3,7 +3,7 @@
 /// queries and answers even though some of the values are specific to questions
 use crate::{Error, Result};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum QClass {
     /// 1 the Internet
     In,
@@ -15,6 +15,8 @@ pub enum QClass {
     Hs,
     /// 255 any class
     StarSign,
+    /// Any other value. OPT records reuse the class field for the UDP payload size
+    Unknown(u16),
 }
 
 impl TryFrom<u16> for QClass {
@@ -27,7 +29,7 @@ impl TryFrom<u16> for QClass {
             3 => Self::Ch,
             4 => Self::Hs,
             255 => Self::StarSign,
-            _ => anyhow::bail!("Invalid QClass"),
+            _ => Self::Unknown(value),
         };
         Ok(q_class)
     }
@@ -41,6 +43,7 @@ impl From<QClass> for u16 {
             QClass::Ch => 3,
             QClass::Hs => 4,
             QClass::StarSign => 255,
+            QClass::Unknown(value) => value,
         }
     }
 }
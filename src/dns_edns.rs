@@ -0,0 +1,128 @@
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_type::QType;
+use crate::{Error, Result};
+
+/// Payload size we advertise and accept
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+/// Size limit of UDP messages without EDNS
+pub const CLASSIC_UDP_SIZE: usize = 512;
+
+/// EDNS(0) information carried by the OPT pseudo record
+/// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edns {
+    /// Stored in the CLASS field
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the 12 bits RCODE
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// (DO) The requester is able to handle DNSSEC records
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl Edns {
+    pub fn new(dnssec_ok: bool) -> Self {
+        Self {
+            udp_payload_size: UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok,
+            options: vec![],
+        }
+    }
+
+    /// Finds the OPT record among the additional records, if any
+    pub fn find(additionals: &[DnsAnswer]) -> Option<Self> {
+        additionals
+            .iter()
+            .find(|record| record.r_type == QType::Opt)
+            .and_then(|record| Self::try_from(record).ok())
+    }
+}
+
+impl TryFrom<&DnsAnswer> for Edns {
+    type Error = Error;
+
+    fn try_from(record: &DnsAnswer) -> Result<Self> {
+        if record.r_type != QType::Opt {
+            anyhow::bail!("EDNS is only carried by OPT records");
+        }
+        let udp_payload_size: u16 = record.r_class.clone().into();
+        let [extended_rcode, version, flags, _] = record.ttl.to_be_bytes();
+
+        let mut options = Vec::new();
+        let mut data = &record.r_data[..];
+        while !data.is_empty() {
+            if data.len() < 4 {
+                anyhow::bail!("Truncated EDNS option");
+            }
+            let code = u16::from_be_bytes([data[0], data[1]]);
+            let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+            if data.len() < 4 + length {
+                anyhow::bail!("Truncated EDNS option");
+            }
+            options.push(EdnsOption {
+                code,
+                data: data[4..4 + length].to_vec(),
+            });
+            data = &data[4 + length..];
+        }
+
+        Ok(Self {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok: flags & 0x80 != 0,
+            options,
+        })
+    }
+}
+
+impl From<Edns> for DnsAnswer {
+    fn from(edns: Edns) -> Self {
+        let flags: u8 = if edns.dnssec_ok { 0x80 } else { 0 };
+        let ttl = u32::from_be_bytes([edns.extended_rcode, edns.version, flags, 0]);
+
+        let mut r_data = Vec::new();
+        for option in edns.options {
+            r_data.extend(option.code.to_be_bytes());
+            r_data.extend((option.data.len() as u16).to_be_bytes());
+            r_data.extend(option.data);
+        }
+        DnsAnswer {
+            r_name: vec![],
+            r_type: QType::Opt,
+            r_class: QClass::try_from(edns.udp_payload_size).expect("every class value is valid"),
+            ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edns_roundtrip() -> Result<()> {
+        let mut edns = Edns::new(true);
+        edns.options.push(EdnsOption {
+            code: 3,
+            data: vec![1, 2, 3],
+        });
+        let record = DnsAnswer::from(edns.clone());
+        assert_eq!(record.ttl, 0x8000);
+        assert_eq!(record.r_data, vec![0, 3, 0, 3, 1, 2, 3]);
+        assert_eq!(Edns::try_from(&record)?, edns);
+        Ok(())
+    }
+}
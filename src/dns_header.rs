@@ -92,7 +92,9 @@ impl From<u8> for DnsHeaderThirdByte {
             0 => OpCode::Query,
             1 => OpCode::Iquery,
             2 => OpCode::Status,
-            3..=15 => OpCode::Reserved,
+            4 => OpCode::Notify,
+            5 => OpCode::Update,
+            3 | 6..=15 => OpCode::Reserved,
             _ => unreachable!(),
         };
 
@@ -121,6 +123,8 @@ impl From<DnsHeaderThirdByte> for u8 {
             OpCode::Iquery => 1,
             OpCode::Status => 2,
             OpCode::Reserved => 3,
+            OpCode::Notify => 4,
+            OpCode::Update => 5,
         };
 
         value += opcode_val << 3;
@@ -147,7 +151,11 @@ pub enum OpCode {
     Iquery,
     /// 2:  a server status request (STATUS)
     Status,
-    /// 3-15 reserved for future use;
+    /// 4: a zone change notification (NOTIFY, RFC 1996)
+    Notify,
+    /// 5: a dynamic update (UPDATE, RFC 2136)
+    Update,
+    /// 3, 6-15 reserved for future use;
     Reserved,
 }
 
@@ -161,6 +169,37 @@ pub struct DnsHeaderFourthByte {
     pub response_code: RCode,
 }
 
+/// Bits of the Z field defined by DNSSEC
+/// https://datatracker.ietf.org/doc/html/rfc4035#section-3.2
+pub const AUTHENTIC_DATA: u8 = 0b010;
+pub const CHECKING_DISABLED: u8 = 0b001;
+
+impl DnsHeaderFourthByte {
+    /// (AD) All the records of the reply were validated
+    pub fn authentic_data(&self) -> bool {
+        self.reserved & AUTHENTIC_DATA != 0
+    }
+
+    pub fn set_authentic_data(&mut self, value: bool) {
+        match value {
+            true => self.reserved |= AUTHENTIC_DATA,
+            false => self.reserved &= !AUTHENTIC_DATA,
+        }
+    }
+
+    /// (CD) The client does its own validation
+    pub fn checking_disabled(&self) -> bool {
+        self.reserved & CHECKING_DISABLED != 0
+    }
+
+    pub fn set_checking_disabled(&mut self, value: bool) {
+        match value {
+            true => self.reserved |= CHECKING_DISABLED,
+            false => self.reserved &= !CHECKING_DISABLED,
+        }
+    }
+}
+
 impl From<u8> for DnsHeaderFourthByte {
     fn from(value: u8) -> Self {
         let recursion_available = (value >> 7) == 1;
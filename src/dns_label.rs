@@ -1,5 +1,118 @@
+use std::cmp::Ordering;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct DnsLabel {
     pub length: u8,
     pub label: String,
 }
+
+impl DnsLabel {
+    pub fn new(label: &str) -> Self {
+        Self {
+            length: label.len() as u8,
+            label: label.to_string(),
+        }
+    }
+}
+
+/// Splits a dotted name such as "www.example.com." into labels.
+/// The trailing dot is optional and "." or "" is the root.
+pub fn labels_from_str(name: &str) -> Vec<DnsLabel> {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .map(DnsLabel::new)
+        .collect()
+}
+
+/// Fully qualified dotted form, "." for the root
+pub fn labels_to_string(labels: &[DnsLabel]) -> String {
+    if labels.is_empty() {
+        return ".".to_string();
+    }
+    let mut name = String::new();
+    for dns_label in labels {
+        name.push_str(&dns_label.label);
+        name.push('.');
+    }
+    name
+}
+
+/// Lowercase dotted form, usable as a map key
+pub fn labels_to_key(labels: &[DnsLabel]) -> String {
+    labels_to_string(labels).to_ascii_lowercase()
+}
+
+/// Uncompressed wire format, including the terminating null byte
+pub fn labels_to_bytes(labels: &[DnsLabel]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for dns_label in labels {
+        bytes.push(dns_label.length);
+        bytes.extend(dns_label.label.as_bytes());
+    }
+    bytes.push(0);
+    bytes
+}
+
+/// Names are compared case insensitively
+pub fn labels_eq(a: &[DnsLabel], b: &[DnsLabel]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(x, y)| x.label.eq_ignore_ascii_case(&y.label))
+}
+
+/// True if `name` is `parent` or below it
+pub fn is_subdomain(name: &[DnsLabel], parent: &[DnsLabel]) -> bool {
+    name.len() >= parent.len() && labels_eq(&name[name.len() - parent.len()..], parent)
+}
+
+/// Canonical DNS name order: labels are compared from the rightmost one,
+/// as lowercase octet strings.
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-6.1
+pub fn canonical_cmp(a: &[DnsLabel], b: &[DnsLabel]) -> Ordering {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        let ordering = x
+            .label
+            .to_ascii_lowercase()
+            .as_bytes()
+            .cmp(y.label.to_ascii_lowercase().as_bytes());
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_order() {
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-6.1
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                canonical_cmp(&labels_from_str(pair[0]), &labels_from_str(pair[1])),
+                Ordering::Less,
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert!(is_subdomain(
+            &labels_from_str("www.Example.com"),
+            &labels_from_str("example.com.")
+        ));
+        assert_eq!(labels_to_string(&labels_from_str("a.b")), "a.b.");
+    }
+}
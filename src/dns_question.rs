@@ -14,11 +14,21 @@ pub struct DnsQuestion {
 }
 
 impl DnsQuestion {
-    /// TODO: better tests to make sure that compression works
-    fn get_q_name(reader: &mut Cursor<&[u8]>) -> Result<Vec<DnsLabel>> {
+    /// Reads a possibly compressed name. Each pointer must point before the
+    /// target of the previous one, so that following them always ends, and
+    /// the name can not exceed 255 bytes.
+    /// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+    pub fn get_q_name(reader: &mut Cursor<&[u8]>) -> Result<Vec<DnsLabel>> {
         let mut one_byte_buf = [0u8; 1];
         let mut q_name = Vec::new();
+        // wire length of the name, with its terminating null byte
+        let mut name_length = 1;
+        // position after the first pointer, where the reader ends up
+        let mut end_pos = None;
+        // pointers must point strictly before this position
+        let mut limit = u64::MAX;
         loop {
+            let label_pos = reader.stream_position()?;
             reader.read_exact(&mut one_byte_buf)?;
             let length = one_byte_buf[0];
             // null byte
@@ -34,20 +44,30 @@ impl DnsQuestion {
                 let small_end = one_byte_buf[0] as u64;
 
                 let offset: u64 = (big_end << 8) + small_end;
-                let current_pos = reader.stream_position()?;
+                if offset >= label_pos.min(limit) {
+                    anyhow::bail!("Compression pointer does not point backward");
+                }
+                limit = offset;
+                if end_pos.is_none() {
+                    end_pos = Some(reader.stream_position()?);
+                }
                 reader.seek(SeekFrom::Start(offset))?;
-                let prev_labels = Self::get_q_name(reader)?;
-                q_name.extend(prev_labels);
-                reader.seek(SeekFrom::Start(current_pos))?;
-                break;
+                continue;
             }
 
+            name_length += 1 + length as usize;
+            if name_length > 255 {
+                anyhow::bail!("Name longer than 255 bytes");
+            }
             let mut content_buf = vec![0u8; length as usize];
             reader.read_exact(&mut content_buf)?;
 
             let label = String::from_utf8(content_buf)?;
             q_name.push(DnsLabel { length, label });
         }
+        if let Some(end_pos) = end_pos {
+            reader.seek(SeekFrom::Start(end_pos))?;
+        }
         Ok(q_name)
     }
 }
@@ -96,13 +116,14 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::dns_label::labels_from_str;
 
     #[test]
     fn test_dns_question_from_bytes() -> Result<()> {
         let domain_name = "query.example.com";
 
         let mut bytes = Vec::new();
-        for s in domain_name.split(|x| x == '.') {
+        for s in domain_name.split('.') {
             bytes.push(s.len() as u8);
             bytes.extend(s.as_bytes());
         }
@@ -141,4 +162,60 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_dns_question_compressed_name() -> Result<()> {
+        // "example.com" at offset 0, then "www" pointing to it
+        let mut bytes = vec![7];
+        bytes.extend(b"example");
+        bytes.push(3);
+        bytes.extend(b"com");
+        bytes.push(0);
+        bytes.push(3);
+        bytes.extend(b"www");
+        bytes.extend([0b11000000, 0]);
+        bytes.extend([0, 1, 0, 1]);
+
+        let mut reader = Cursor::new(&bytes[..]);
+        reader.set_position(13);
+        let dns_question = DnsQuestion::try_from(&mut reader)?;
+        assert_eq!(dns_question.q_name, labels_from_str("www.example.com"));
+        assert_eq!(dns_question.q_type, QType::A);
+        assert_eq!(reader.position(), bytes.len() as u64);
+
+        // a pointer to itself would loop forever
+        let bytes = [0b11000000, 0, 0, 1, 0, 1];
+        assert!(DnsQuestion::try_from(&mut Cursor::new(&bytes[..])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_loop() -> Result<()> {
+        // the pointer goes back to a label leading to the same pointer
+        let bytes = [1, b'a', 0xC0, 0x00, 0, 1, 0, 1];
+        let mut reader = Cursor::new(&bytes[..]);
+        reader.seek(SeekFrom::Start(2))?;
+        assert!(DnsQuestion::get_q_name(&mut reader).is_err());
+        let mut reader = Cursor::new(&bytes[..]);
+        assert!(DnsQuestion::get_q_name(&mut reader).is_err());
+
+        // pointers to pointers are fine as long as they go backward
+        let bytes = [1, b'a', 0, 1, b'b', 0xC0, 0x00, 0xC0, 0x03, 0, 1, 0, 1];
+        let mut reader = Cursor::new(&bytes[..]);
+        reader.seek(SeekFrom::Start(7))?;
+        assert_eq!(
+            DnsQuestion::get_q_name(&mut reader)?,
+            labels_from_str("b.a")
+        );
+        assert_eq!(reader.position(), 9);
+
+        let mut long = Vec::new();
+        for _ in 0..5 {
+            long.push(63);
+            long.extend([b'x'; 63]);
+        }
+        long.push(0);
+        assert!(DnsQuestion::get_q_name(&mut Cursor::new(&long[..])).is_err());
+        Ok(())
+    }
 }
@@ -1,9 +1,12 @@
 /// https://www.rfc-editor.org/rfc/rfc1035#section-3.2.2
 /// This is a superset of TYPE, but we will use it for both
 /// queries and answers even though some of the values are specific to questions
+use std::fmt;
+use std::str::FromStr;
+
 use crate::{Error, Result};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum QType {
     /// 1 a host address
     A,
@@ -37,6 +40,22 @@ pub enum QType {
     Mx,
     /// 16 text strings
     Txt,
+    /// 28 an IPv6 host address (RFC 3596)
+    Aaaa,
+    /// 41 EDNS pseudo record (RFC 6891)
+    Opt,
+    /// 43 delegation signer (RFC 4034)
+    Ds,
+    /// 46 signature over an RRset (RFC 4034)
+    Rrsig,
+    /// 47 next secure, authenticated denial of existence (RFC 4034)
+    Nsec,
+    /// 48 zone signing public key (RFC 4034)
+    Dnskey,
+    /// 50 hashed authenticated denial of existence (RFC 5155)
+    Nsec3,
+    /// 51 parameters of the NSEC3 chain of a zone (RFC 5155)
+    Nsec3param,
     /// 252 A request for a transfer of an entire zone
     Axfr,
     /// 253 A request for mailbox-related records (MB, MG or MR)
@@ -68,6 +87,14 @@ impl TryFrom<u16> for QType {
             14 => Self::Minfo,
             15 => Self::Mx,
             16 => Self::Txt,
+            28 => Self::Aaaa,
+            41 => Self::Opt,
+            43 => Self::Ds,
+            46 => Self::Rrsig,
+            47 => Self::Nsec,
+            48 => Self::Dnskey,
+            50 => Self::Nsec3,
+            51 => Self::Nsec3param,
             252 => Self::Axfr,
             253 => Self::Mailb,
             254 => Self::Maila,
@@ -97,6 +124,14 @@ impl From<QType> for u16 {
             QType::Minfo => 14,
             QType::Mx => 15,
             QType::Txt => 16,
+            QType::Aaaa => 28,
+            QType::Opt => 41,
+            QType::Ds => 43,
+            QType::Rrsig => 46,
+            QType::Nsec => 47,
+            QType::Dnskey => 48,
+            QType::Nsec3 => 50,
+            QType::Nsec3param => 51,
             QType::Axfr => 252,
             QType::Mailb => 253,
             QType::Maila => 254,
@@ -104,3 +139,63 @@ impl From<QType> for u16 {
         }
     }
 }
+
+/// Mnemonics used in master files
+const MNEMONICS: [(QType, &str); 28] = [
+    (QType::A, "A"),
+    (QType::Ns, "NS"),
+    (QType::Md, "MD"),
+    (QType::Mf, "MF"),
+    (QType::Cname, "CNAME"),
+    (QType::Soa, "SOA"),
+    (QType::Mb, "MB"),
+    (QType::Mg, "MG"),
+    (QType::Mr, "MR"),
+    (QType::Null, "NULL"),
+    (QType::Wks, "WKS"),
+    (QType::Ptr, "PTR"),
+    (QType::Hinfo, "HINFO"),
+    (QType::Minfo, "MINFO"),
+    (QType::Mx, "MX"),
+    (QType::Txt, "TXT"),
+    (QType::Aaaa, "AAAA"),
+    (QType::Opt, "OPT"),
+    (QType::Ds, "DS"),
+    (QType::Rrsig, "RRSIG"),
+    (QType::Nsec, "NSEC"),
+    (QType::Dnskey, "DNSKEY"),
+    (QType::Nsec3, "NSEC3"),
+    (QType::Nsec3param, "NSEC3PARAM"),
+    (QType::Axfr, "AXFR"),
+    (QType::Mailb, "MAILB"),
+    (QType::Maila, "MAILA"),
+    (QType::StarSign, "ANY"),
+];
+
+impl fmt::Display for QType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, mnemonic) = MNEMONICS
+            .iter()
+            .find(|(q_type, _)| q_type == self)
+            .expect("every QType has a mnemonic");
+        write!(f, "{}", mnemonic)
+    }
+}
+
+impl FromStr for QType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((q_type, _)) = MNEMONICS
+            .iter()
+            .find(|(_, mnemonic)| mnemonic.eq_ignore_ascii_case(s))
+        {
+            return Ok(q_type.clone());
+        }
+        // generic TYPEnnn notation (RFC 3597)
+        match s.to_ascii_uppercase().strip_prefix("TYPE") {
+            Some(value) => QType::try_from(value.parse::<u16>()?),
+            None => anyhow::bail!("Unknown type mnemonic {}", s),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use crate::dns_answer::DnsAnswer;
+use crate::dns_label::{canonical_cmp, is_subdomain, labels_eq, labels_to_key, DnsLabel};
+use crate::dns_type::QType;
+use crate::dnssec::read_name;
+use crate::master_file;
+use crate::Result;
+
+/// Bound on the CNAME chain followed inside a zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Result of looking a question up in a zone
+#[derive(Debug, PartialEq, Clone)]
+pub enum ZoneLookup {
+    /// The records answering the question, possibly preceded by CNAMEs
+    Answer(Vec<DnsAnswer>),
+    /// The name is below a zone cut, the child zone is authoritative
+    Delegation {
+        ns: Vec<DnsAnswer>,
+        ds: Vec<DnsAnswer>,
+        glue: Vec<DnsAnswer>,
+    },
+    /// The name exists but has no record of that type
+    NoData,
+    NxDomain,
+}
+
+/// Authoritative data of a zone, kept in memory
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: Vec<DnsLabel>,
+    /// Records grouped by lowercase owner name
+    nodes: HashMap<String, Vec<DnsAnswer>>,
+}
+
+impl Zone {
+    pub fn new(origin: Vec<DnsLabel>) -> Self {
+        Self {
+            origin,
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// The origin is taken from the first SOA record when not given
+    pub fn load(path: &str, origin: Option<Vec<DnsLabel>>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read zone file {}: {}", path, e))?;
+        let records = master_file::parse(&content, origin.as_deref().unwrap_or(&[]))
+            .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        let origin = match origin {
+            Some(origin) => origin,
+            None => match records.iter().find(|r| r.r_type == QType::Soa) {
+                Some(soa) => soa.r_name.clone(),
+                None => anyhow::bail!("{}: no SOA record to take the origin from", path),
+            },
+        };
+        Self::from_records(origin, records)
+    }
+
+    pub fn from_records(origin: Vec<DnsLabel>, records: Vec<DnsAnswer>) -> Result<Self> {
+        let mut zone = Self::new(origin);
+        for record in records {
+            zone.add_record(record)?;
+        }
+        if zone.soa().is_none() {
+            anyhow::bail!("Zone has no SOA record at its apex");
+        }
+        Ok(zone)
+    }
+
+    pub fn add_record(&mut self, record: DnsAnswer) -> Result<()> {
+        if !is_subdomain(&record.r_name, &self.origin) {
+            anyhow::bail!("Record is outside of the zone origin");
+        }
+        let records = self.nodes.entry(labels_to_key(&record.r_name)).or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+        Ok(())
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &DnsAnswer> {
+        self.nodes.values().flatten()
+    }
+
+    /// Keeps only the records for which `keep` returns true
+    pub fn retain(&mut self, keep: impl Fn(&DnsAnswer) -> bool) {
+        for records in self.nodes.values_mut() {
+            records.retain(&keep);
+        }
+        self.nodes.retain(|_, records| !records.is_empty());
+    }
+
+    pub fn soa(&self) -> Option<&DnsAnswer> {
+        self.node(&self.origin)
+            .iter()
+            .find(|record| record.r_type == QType::Soa)
+    }
+
+    /// TTL of negative answers, see [`negative_ttl`]
+    pub fn negative_ttl(&self) -> u32 {
+        self.soa().map(negative_ttl).unwrap_or_default()
+    }
+
+    pub fn node(&self, name: &[DnsLabel]) -> &[DnsAnswer] {
+        self.nodes
+            .get(&labels_to_key(name))
+            .map(|records| &records[..])
+            .unwrap_or(&[])
+    }
+
+    pub fn rrset(&self, name: &[DnsLabel], q_type: &QType) -> Vec<DnsAnswer> {
+        self.node(name)
+            .iter()
+            .filter(|record| &record.r_type == q_type)
+            .cloned()
+            .collect()
+    }
+
+    pub fn types_at(&self, name: &[DnsLabel]) -> Vec<QType> {
+        let mut types: Vec<QType> = Vec::new();
+        for record in self.node(name) {
+            if !types.contains(&record.r_type) {
+                types.push(record.r_type.clone());
+            }
+        }
+        types
+    }
+
+    /// Owner names holding records, in canonical order
+    pub fn owner_names(&self) -> Vec<Vec<DnsLabel>> {
+        let mut names: Vec<Vec<DnsLabel>> = self
+            .nodes
+            .values()
+            .filter(|records| !records.is_empty())
+            .map(|records| records[0].r_name.clone())
+            .collect();
+        names.sort_by(|a, b| canonical_cmp(a, b));
+        names
+    }
+
+    /// Names without records that have descendants with records
+    pub fn empty_non_terminals(&self) -> Vec<Vec<DnsLabel>> {
+        let mut names: Vec<Vec<DnsLabel>> = Vec::new();
+        for owner in self.owner_names() {
+            for depth in self.origin.len() + 1..owner.len() {
+                let ancestor = owner[owner.len() - depth..].to_vec();
+                if self.node(&ancestor).is_empty()
+                    && !names.iter().any(|name| labels_eq(name, &ancestor))
+                {
+                    names.push(ancestor);
+                }
+            }
+        }
+        names.sort_by(|a, b| canonical_cmp(a, b));
+        names
+    }
+
+    /// True if the name holds records or is an empty non-terminal
+    pub fn name_exists(&self, name: &[DnsLabel]) -> bool {
+        if !self.node(name).is_empty() {
+            return true;
+        }
+        self.nodes
+            .values()
+            .flatten()
+            .any(|record| record.r_name.len() > name.len() && is_subdomain(&record.r_name, name))
+    }
+
+    /// Zone cut at or above `name`, the apex excluded
+    pub fn delegation_point(&self, name: &[DnsLabel]) -> Option<Vec<DnsLabel>> {
+        if !is_subdomain(name, &self.origin) {
+            return None;
+        }
+        (self.origin.len() + 1..=name.len())
+            .map(|depth| name[name.len() - depth..].to_vec())
+            .find(|ancestor| !self.rrset(ancestor, &QType::Ns).is_empty())
+    }
+
+    /// Names strictly below a zone cut are glue or occluded data, not authoritative
+    pub fn is_authoritative(&self, name: &[DnsLabel]) -> bool {
+        match self.delegation_point(name) {
+            Some(cut) => labels_eq(&cut, name),
+            None => is_subdomain(name, &self.origin),
+        }
+    }
+
+    /// Longest existing ancestor of `name`, possibly `name` itself
+    pub fn closest_encloser(&self, name: &[DnsLabel]) -> Vec<DnsLabel> {
+        (self.origin.len()..=name.len())
+            .rev()
+            .map(|depth| name[name.len() - depth..].to_vec())
+            .find(|ancestor| self.name_exists(ancestor))
+            .unwrap_or_else(|| self.origin.clone())
+    }
+
+    /// A and AAAA records for the name servers that live inside the zone
+    fn glue(&self, ns_records: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let mut glue = Vec::new();
+        for ns in ns_records {
+            let Ok((target, _)) = read_name(&ns.r_data, 0) else {
+                continue;
+            };
+            if is_subdomain(&target, &self.origin) {
+                glue.extend(self.rrset(&target, &QType::A));
+                glue.extend(self.rrset(&target, &QType::Aaaa));
+            }
+        }
+        glue
+    }
+
+    pub fn lookup(&self, q_name: &[DnsLabel], q_type: &QType) -> ZoneLookup {
+        self.lookup_with_chain(q_name, q_type, 0)
+    }
+
+    fn lookup_with_chain(&self, q_name: &[DnsLabel], q_type: &QType, depth: usize) -> ZoneLookup {
+        if let Some(cut) = self.delegation_point(q_name) {
+            // DS records live on the parent side of the cut
+            if !(labels_eq(&cut, q_name) && q_type == &QType::Ds) {
+                let ns = self.rrset(&cut, &QType::Ns);
+                return ZoneLookup::Delegation {
+                    glue: self.glue(&ns),
+                    ds: self.rrset(&cut, &QType::Ds),
+                    ns,
+                };
+            }
+        }
+
+        let node = self.node(q_name);
+        if node.is_empty() {
+            return match self.name_exists(q_name) {
+                true => ZoneLookup::NoData,
+                false => ZoneLookup::NxDomain,
+            };
+        }
+
+        let answers: Vec<DnsAnswer> = match q_type {
+            QType::StarSign => node.to_vec(),
+            _ => self.rrset(q_name, q_type),
+        };
+        if !answers.is_empty() {
+            return ZoneLookup::Answer(answers);
+        }
+
+        let cname = self.rrset(q_name, &QType::Cname);
+        if let Some(record) = cname.first() {
+            let mut answers = cname.clone();
+            if let Ok((target, _)) = read_name(&record.r_data, 0) {
+                if depth < MAX_CNAME_CHAIN && is_subdomain(&target, &self.origin) {
+                    if let ZoneLookup::Answer(chained) =
+                        self.lookup_with_chain(&target, q_type, depth + 1)
+                    {
+                        answers.extend(chained);
+                    }
+                }
+            }
+            return ZoneLookup::Answer(answers);
+        }
+        ZoneLookup::NoData
+    }
+}
+
+/// TTL of negative answers: the smaller of the SOA TTL and its MINIMUM field
+/// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+pub fn negative_ttl(soa: &DnsAnswer) -> u32 {
+    let r_data = &soa.r_data;
+    let minimum = match r_data.get(r_data.len().saturating_sub(4)..) {
+        Some(bytes) if bytes.len() == 4 => {
+            u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        }
+        _ => 0,
+    };
+    soa.ttl.min(minimum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 3600
+@       SOA ns1 hostmaster 1 7200 3600 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.2
+alias   CNAME www
+a.b.c   TXT "deep"
+sub     NS  ns.sub
+ns.sub  A   192.0.2.3
+"#;
+
+    fn zone() -> Result<Zone> {
+        let records = master_file::parse(ZONE, &[])?;
+        Zone::from_records(labels_from_str("example.com"), records)
+    }
+
+    #[test]
+    fn test_zone_lookup() -> Result<()> {
+        let zone = zone()?;
+        assert_eq!(zone.negative_ttl(), 300);
+
+        let ZoneLookup::Answer(answers) =
+            zone.lookup(&labels_from_str("WWW.example.com"), &QType::A)
+        else {
+            panic!("www should have an A record");
+        };
+        assert_eq!(answers[0].r_data, vec![192, 0, 2, 2]);
+
+        let ZoneLookup::Answer(answers) =
+            zone.lookup(&labels_from_str("alias.example.com"), &QType::A)
+        else {
+            panic!("alias should be answered through its CNAME");
+        };
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].r_type, QType::Cname);
+
+        assert_eq!(
+            zone.lookup(&labels_from_str("www.example.com"), &QType::Mx),
+            ZoneLookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&labels_from_str("b.c.example.com"), &QType::A),
+            ZoneLookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&labels_from_str("nope.example.com"), &QType::A),
+            ZoneLookup::NxDomain
+        );
+
+        let ZoneLookup::Delegation { ns, glue, ds } =
+            zone.lookup(&labels_from_str("host.sub.example.com"), &QType::A)
+        else {
+            panic!("sub is delegated");
+        };
+        assert_eq!(ns.len(), 1);
+        assert_eq!(glue[0].r_data, vec![192, 0, 2, 3]);
+        assert!(ds.is_empty());
+
+        assert_eq!(
+            zone.empty_non_terminals(),
+            vec![
+                labels_from_str("c.example.com"),
+                labels_from_str("b.c.example.com")
+            ]
+        );
+        assert!(!zone.is_authoritative(&labels_from_str("ns.sub.example.com")));
+        Ok(())
+    }
+}
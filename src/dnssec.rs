@@ -0,0 +1,597 @@
+//! DNSSEC record data and canonical forms
+//! https://datatracker.ietf.org/doc/html/rfc4034
+//! https://datatracker.ietf.org/doc/html/rfc5155
+use crate::crypto::sha::{sha1, sha256};
+use crate::crypto::{ed25519, p256};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_label::{canonical_cmp, labels_to_bytes, DnsLabel};
+use crate::dns_type::QType;
+use crate::{Error, Result};
+
+pub const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+pub const ALGORITHM_ED25519: u8 = 15;
+
+pub const NSEC3_HASH_SHA1: u8 = 1;
+/// NSEC3 flag: the span may contain unsigned delegations
+pub const NSEC3_OPT_OUT: u8 = 0x01;
+
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+
+/// DNSKEY flags
+pub const DNSKEY_ZONE: u16 = 0x0100;
+/// Secure Entry Point, set on key signing keys
+pub const DNSKEY_SEP: u16 = 0x0001;
+
+/// Reads an uncompressed name starting at `pos`, returns it with the position after it
+pub fn read_name(bytes: &[u8], mut pos: usize) -> Result<(Vec<DnsLabel>, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let Some(&length) = bytes.get(pos) else {
+            anyhow::bail!("Truncated name in record data");
+        };
+        pos += 1;
+        if length == 0 {
+            return Ok((labels, pos));
+        }
+        if length >> 6 > 0 {
+            anyhow::bail!("Compressed name in record data");
+        }
+        let Some(content) = bytes.get(pos..pos + length as usize) else {
+            anyhow::bail!("Truncated name in record data");
+        };
+        labels.push(DnsLabel {
+            length,
+            label: String::from_utf8(content.to_vec())?,
+        });
+        pos += length as usize;
+    }
+}
+
+pub fn read_u16(bytes: &[u8], pos: usize) -> Result<u16> {
+    match bytes.get(pos..pos + 2) {
+        Some(value) => Ok(u16::from_be_bytes([value[0], value[1]])),
+        None => anyhow::bail!("Truncated record data"),
+    }
+}
+
+pub fn read_u32(bytes: &[u8], pos: usize) -> Result<u32> {
+    match bytes.get(pos..pos + 4) {
+        Some(value) => Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]])),
+        None => anyhow::bail!("Truncated record data"),
+    }
+}
+
+/// Type bit maps used by NSEC and NSEC3
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-4.1.2
+pub fn encode_type_bitmap(types: &[QType]) -> Vec<u8> {
+    let mut values: Vec<u16> = types.iter().map(|t| t.clone().into()).collect();
+    values.sort();
+    values.dedup();
+
+    let mut bytes = Vec::new();
+    let mut window_start = 0;
+    while window_start < values.len() {
+        let window = values[window_start] >> 8;
+        let mut bitmap = [0u8; 32];
+        let mut length = 0;
+        let mut i = window_start;
+        while i < values.len() && values[i] >> 8 == window {
+            let low = (values[i] & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            length = low / 8 + 1;
+            i += 1;
+        }
+        bytes.push(window as u8);
+        bytes.push(length as u8);
+        bytes.extend(&bitmap[..length]);
+        window_start = i;
+    }
+    bytes
+}
+
+/// Types we do not know about are skipped
+pub fn decode_type_bitmap(bytes: &[u8]) -> Result<Vec<QType>> {
+    let mut types = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos + 2 > bytes.len() {
+            anyhow::bail!("Truncated type bitmap");
+        }
+        let window = bytes[pos] as u16;
+        let length = bytes[pos + 1] as usize;
+        let Some(bitmap) = bytes.get(pos + 2..pos + 2 + length) else {
+            anyhow::bail!("Truncated type bitmap");
+        };
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let value = (window << 8) | (i * 8 + bit) as u16;
+                    if let Ok(q_type) = QType::try_from(value) {
+                        types.push(q_type);
+                    }
+                }
+            }
+        }
+        pos += 2 + length;
+    }
+    Ok(types)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dnskey {
+    pub flags: u16,
+    /// Always 3
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    /// https://datatracker.ietf.org/doc/html/rfc4034#appendix-B
+    pub fn key_tag(&self) -> u16 {
+        let r_data: Vec<u8> = self.clone().into();
+        let mut accumulator: u32 = 0;
+        for (i, byte) in r_data.iter().enumerate() {
+            if i & 1 == 1 {
+                accumulator += *byte as u32;
+            } else {
+                accumulator += (*byte as u32) << 8;
+            }
+        }
+        accumulator += (accumulator >> 16) & 0xffff;
+        (accumulator & 0xffff) as u16
+    }
+
+    pub fn is_ksk(&self) -> bool {
+        self.flags & DNSKEY_SEP != 0
+    }
+
+    /// Only keys with the zone flag may verify zone data
+    pub fn is_zone_key(&self) -> bool {
+        self.flags & DNSKEY_ZONE != 0
+    }
+
+    pub fn is_supported(&self) -> bool {
+        matches!(
+            self.algorithm,
+            ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ED25519
+        )
+    }
+
+    /// Checks a signature made with the private part of this key
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self.algorithm {
+            ALGORITHM_ECDSAP256SHA256 => p256::verify(&self.public_key, data, signature),
+            ALGORITHM_ED25519 => ed25519::verify(&self.public_key, data, signature),
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Dnskey {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            anyhow::bail!("DNSKEY record data too short");
+        }
+        Ok(Self {
+            flags: read_u16(bytes, 0)?,
+            protocol: bytes[2],
+            algorithm: bytes[3],
+            public_key: bytes[4..].to_vec(),
+        })
+    }
+}
+
+impl From<Dnskey> for Vec<u8> {
+    fn from(dnskey: Dnskey) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend(dnskey.flags.to_be_bytes());
+        bytes.push(dnskey.protocol);
+        bytes.push(dnskey.algorithm);
+        bytes.extend(dnskey.public_key);
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rrsig {
+    pub type_covered: QType,
+    pub algorithm: u8,
+    /// Number of labels of the owner name, without the root and a leading wildcard
+    pub labels: u8,
+    pub original_ttl: u32,
+    /// Seconds since epoch, serial number arithmetic
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer_name: Vec<DnsLabel>,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    /// Record data without the signature, with the signer name in canonical form.
+    /// This is the first part of the signed data.
+    pub fn signed_prefix(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let type_covered: u16 = self.type_covered.clone().into();
+        bytes.extend(type_covered.to_be_bytes());
+        bytes.push(self.algorithm);
+        bytes.push(self.labels);
+        bytes.extend(self.original_ttl.to_be_bytes());
+        bytes.extend(self.expiration.to_be_bytes());
+        bytes.extend(self.inception.to_be_bytes());
+        bytes.extend(self.key_tag.to_be_bytes());
+        bytes.extend(labels_to_bytes(&self.signer_name).to_ascii_lowercase());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Rrsig {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 18 {
+            anyhow::bail!("RRSIG record data too short");
+        }
+        let type_covered = QType::try_from(read_u16(bytes, 0)?)?;
+        let (signer_name, pos) = read_name(bytes, 18)?;
+        Ok(Self {
+            type_covered,
+            algorithm: bytes[2],
+            labels: bytes[3],
+            original_ttl: read_u32(bytes, 4)?,
+            expiration: read_u32(bytes, 8)?,
+            inception: read_u32(bytes, 12)?,
+            key_tag: read_u16(bytes, 16)?,
+            signer_name,
+            signature: bytes[pos..].to_vec(),
+        })
+    }
+}
+
+impl From<Rrsig> for Vec<u8> {
+    fn from(rrsig: Rrsig) -> Self {
+        let mut bytes = Vec::new();
+        let type_covered: u16 = rrsig.type_covered.into();
+        bytes.extend(type_covered.to_be_bytes());
+        bytes.push(rrsig.algorithm);
+        bytes.push(rrsig.labels);
+        bytes.extend(rrsig.original_ttl.to_be_bytes());
+        bytes.extend(rrsig.expiration.to_be_bytes());
+        bytes.extend(rrsig.inception.to_be_bytes());
+        bytes.extend(rrsig.key_tag.to_be_bytes());
+        bytes.extend(labels_to_bytes(&rrsig.signer_name));
+        bytes.extend(rrsig.signature);
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Nsec {
+    pub next_domain: Vec<DnsLabel>,
+    pub types: Vec<QType>,
+}
+
+impl TryFrom<&[u8]> for Nsec {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let (next_domain, pos) = read_name(bytes, 0)?;
+        Ok(Self {
+            next_domain,
+            types: decode_type_bitmap(&bytes[pos..])?,
+        })
+    }
+}
+
+impl From<Nsec> for Vec<u8> {
+    fn from(nsec: Nsec) -> Self {
+        let mut bytes = labels_to_bytes(&nsec.next_domain);
+        bytes.extend(encode_type_bitmap(&nsec.types));
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    /// Raw hash, the owner name carries the base32hex form
+    pub next_hashed_owner: Vec<u8>,
+    pub types: Vec<QType>,
+}
+
+impl TryFrom<&[u8]> for Nsec3 {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 5 {
+            anyhow::bail!("NSEC3 record data too short");
+        }
+        let salt_length = bytes[4] as usize;
+        let Some(salt) = bytes.get(5..5 + salt_length) else {
+            anyhow::bail!("Truncated NSEC3 salt");
+        };
+        let pos = 5 + salt_length;
+        let Some(&hash_length) = bytes.get(pos) else {
+            anyhow::bail!("Truncated NSEC3 record data");
+        };
+        let Some(next_hashed_owner) = bytes.get(pos + 1..pos + 1 + hash_length as usize) else {
+            anyhow::bail!("Truncated NSEC3 next hashed owner");
+        };
+        Ok(Self {
+            hash_algorithm: bytes[0],
+            flags: bytes[1],
+            iterations: read_u16(bytes, 2)?,
+            salt: salt.to_vec(),
+            next_hashed_owner: next_hashed_owner.to_vec(),
+            types: decode_type_bitmap(&bytes[pos + 1 + hash_length as usize..])?,
+        })
+    }
+}
+
+impl From<Nsec3> for Vec<u8> {
+    fn from(nsec3: Nsec3) -> Self {
+        let mut bytes = vec![nsec3.hash_algorithm, nsec3.flags];
+        bytes.extend(nsec3.iterations.to_be_bytes());
+        bytes.push(nsec3.salt.len() as u8);
+        bytes.extend(nsec3.salt);
+        bytes.push(nsec3.next_hashed_owner.len() as u8);
+        bytes.extend(nsec3.next_hashed_owner);
+        bytes.extend(encode_type_bitmap(&nsec3.types));
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Nsec3Param {
+    pub hash_algorithm: u8,
+    /// Must be zero in the NSEC3PARAM record
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+}
+
+impl Nsec3Param {
+    pub fn hash(&self, name: &[DnsLabel]) -> Vec<u8> {
+        nsec3_hash(name, &self.salt, self.iterations)
+    }
+}
+
+impl TryFrom<&[u8]> for Nsec3Param {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 5 {
+            anyhow::bail!("NSEC3PARAM record data too short");
+        }
+        let salt_length = bytes[4] as usize;
+        let Some(salt) = bytes.get(5..5 + salt_length) else {
+            anyhow::bail!("Truncated NSEC3PARAM salt");
+        };
+        Ok(Self {
+            hash_algorithm: bytes[0],
+            flags: bytes[1],
+            iterations: read_u16(bytes, 2)?,
+            salt: salt.to_vec(),
+        })
+    }
+}
+
+impl From<Nsec3Param> for Vec<u8> {
+    fn from(nsec3_param: Nsec3Param) -> Self {
+        let mut bytes = vec![nsec3_param.hash_algorithm, nsec3_param.flags];
+        bytes.extend(nsec3_param.iterations.to_be_bytes());
+        bytes.push(nsec3_param.salt.len() as u8);
+        bytes.extend(nsec3_param.salt);
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    /// DS record for a DNSKEY: digest of the canonical owner name followed by
+    /// the DNSKEY record data
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-5.1.4
+    pub fn from_dnskey(owner: &[DnsLabel], dnskey: &Dnskey, digest_type: u8) -> Result<Self> {
+        let mut data = labels_to_bytes(owner).to_ascii_lowercase();
+        let r_data: Vec<u8> = dnskey.clone().into();
+        data.extend(r_data);
+        let digest = match digest_type {
+            DIGEST_SHA1 => sha1(&data).to_vec(),
+            DIGEST_SHA256 => sha256(&data).to_vec(),
+            _ => anyhow::bail!("Unsupported DS digest type {}", digest_type),
+        };
+        Ok(Self {
+            key_tag: dnskey.key_tag(),
+            algorithm: dnskey.algorithm,
+            digest_type,
+            digest,
+        })
+    }
+}
+
+impl TryFrom<&[u8]> for Ds {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            anyhow::bail!("DS record data too short");
+        }
+        Ok(Self {
+            key_tag: read_u16(bytes, 0)?,
+            algorithm: bytes[2],
+            digest_type: bytes[3],
+            digest: bytes[4..].to_vec(),
+        })
+    }
+}
+
+impl From<Ds> for Vec<u8> {
+    fn from(ds: Ds) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend(ds.key_tag.to_be_bytes());
+        bytes.push(ds.algorithm);
+        bytes.push(ds.digest_type);
+        bytes.extend(ds.digest);
+        bytes
+    }
+}
+
+/// Iterated and salted SHA-1 of the canonical owner name
+/// https://datatracker.ietf.org/doc/html/rfc5155#section-5
+pub fn nsec3_hash(name: &[DnsLabel], salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut digest = sha1(&[&labels_to_bytes(name).to_ascii_lowercase()[..], salt].concat());
+    for _ in 0..iterations {
+        digest = sha1(&[&digest[..], salt].concat());
+    }
+    digest.to_vec()
+}
+
+/// Value of the RRSIG labels field for an owner name
+pub fn rrsig_labels(owner: &[DnsLabel]) -> u8 {
+    match owner.first() {
+        Some(first) if first.label == "*" => (owner.len() - 1) as u8,
+        _ => owner.len() as u8,
+    }
+}
+
+/// Record data with the embedded domain names in lowercase
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-6.2
+/// NSEC is excluded as clarified by RFC 6840 section 5.1
+pub fn canonical_r_data(r_type: &QType, r_data: &[u8]) -> Vec<u8> {
+    // offsets of the embedded names, all records handled here are uncompressed
+    let name_offsets: Vec<usize> = match r_type {
+        QType::Ns
+        | QType::Md
+        | QType::Mf
+        | QType::Cname
+        | QType::Mb
+        | QType::Mg
+        | QType::Mr
+        | QType::Ptr => vec![0],
+        QType::Mx => vec![2],
+        QType::Rrsig => vec![18],
+        QType::Soa | QType::Minfo => match read_name(r_data, 0) {
+            Ok((_, second)) => vec![0, second],
+            Err(_) => vec![],
+        },
+        _ => vec![],
+    };
+    let mut bytes = r_data.to_vec();
+    for offset in name_offsets {
+        if let Ok((_, end)) = read_name(r_data, offset) {
+            bytes[offset..end].make_ascii_lowercase();
+        }
+    }
+    bytes
+}
+
+/// Canonical RR ordering: by owner name, then type, then record data
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-6.3
+pub fn canonical_sort(records: &mut [DnsAnswer]) {
+    records.sort_by(|a, b| {
+        canonical_cmp(&a.r_name, &b.r_name)
+            .then_with(|| u16::from(a.r_type.clone()).cmp(&u16::from(b.r_type.clone())))
+            .then_with(|| {
+                canonical_r_data(&a.r_type, &a.r_data).cmp(&canonical_r_data(&b.r_type, &b.r_data))
+            })
+    });
+}
+
+/// Canonical wire form of a record, with the given TTL
+fn canonical_record(record: &DnsAnswer, ttl: u32) -> Vec<u8> {
+    let mut bytes = labels_to_bytes(&record.r_name).to_ascii_lowercase();
+    let r_type: u16 = record.r_type.clone().into();
+    bytes.extend(r_type.to_be_bytes());
+    let r_class: u16 = record.r_class.clone().into();
+    bytes.extend(r_class.to_be_bytes());
+    bytes.extend(ttl.to_be_bytes());
+    let r_data = canonical_r_data(&record.r_type, &record.r_data);
+    bytes.extend((r_data.len() as u16).to_be_bytes());
+    bytes.extend(r_data);
+    bytes
+}
+
+/// Data covered by an RRSIG: its own record data followed by the RRset sorted
+/// by canonical record data, duplicates removed
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-3.1.8.1
+pub fn signed_data(rrsig: &Rrsig, rrset: &[DnsAnswer]) -> Vec<u8> {
+    let mut records: Vec<(Vec<u8>, &DnsAnswer)> = rrset
+        .iter()
+        .map(|record| (canonical_r_data(&record.r_type, &record.r_data), record))
+        .collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    records.dedup_by(|a, b| a.0 == b.0);
+
+    let mut bytes = rrsig.signed_prefix();
+    for (_, record) in records {
+        bytes.extend(canonical_record(record, rrsig.original_ttl));
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+    use crate::encoding::hex_decode;
+
+    #[test]
+    fn test_type_bitmap() -> Result<()> {
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-4.3
+        let types = vec![QType::A, QType::Mx, QType::Rrsig, QType::Nsec];
+        let bytes = encode_type_bitmap(&types);
+        assert_eq!(bytes, hex_decode("0006 40 01 00 00 00 03")?);
+        assert_eq!(decode_type_bitmap(&bytes)?, types);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec3_hash() -> Result<()> {
+        // https://datatracker.ietf.org/doc/html/rfc5155#appendix-A
+        // example hashes to 0p9mhaveqvm6t7vbl5lop2u3t2rp3tom
+        let hash = nsec3_hash(&labels_from_str("example"), &hex_decode("aabbccdd")?, 12);
+        assert_eq!(
+            hash,
+            hex_decode("065368abeed7ec6e9feba96b8c8bc3e8b791f716")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_tag_and_ds() -> Result<()> {
+        // DNSKEY of example.net in https://datatracker.ietf.org/doc/html/rfc6605#section-6.1
+        let dnskey = Dnskey {
+            flags: 257,
+            protocol: 3,
+            algorithm: ALGORITHM_ECDSAP256SHA256,
+            public_key: crate::encoding::base64_decode(
+                "GojIhhXUN/u4v54ZQqGSnyhWJwaubCvTmeexv7bR6edb \
+                 krSqQpF64cYbcB7wNcP+e+MAnLr+Wi9xMWyQLc8NAA==",
+            )?,
+        };
+        assert_eq!(dnskey.key_tag(), 55648);
+        let bytes: Vec<u8> = dnskey.clone().into();
+        assert_eq!(Dnskey::try_from(&bytes[..])?, dnskey);
+
+        let ds = Ds::from_dnskey(&labels_from_str("example.net"), &dnskey, DIGEST_SHA256)?;
+        assert_eq!(
+            ds.digest,
+            hex_decode("b4c8c1fe2e7477127b27115656ad6256f424625bf5c1e2770ce6d6e37df61d17")?
+        );
+        Ok(())
+    }
+}
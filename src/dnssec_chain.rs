@@ -0,0 +1,283 @@
+//! NSEC and NSEC3 chains used for authenticated denial of existence
+//! https://datatracker.ietf.org/doc/html/rfc4035#section-3.1.3
+//! https://datatracker.ietf.org/doc/html/rfc5155#section-7.2
+use std::cmp::Ordering;
+
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{canonical_cmp, is_subdomain, labels_eq, DnsLabel};
+use crate::dns_type::QType;
+use crate::dns_zone::Zone;
+use crate::dnssec::{Nsec, Nsec3, Nsec3Param, NSEC3_HASH_SHA1, NSEC3_OPT_OUT};
+use crate::encoding::base32hex_encode;
+
+/// Delegation without DS record: the child zone is unsigned
+fn is_insecure_cut(zone: &Zone, name: &[DnsLabel]) -> bool {
+    zone.delegation_point(name)
+        .is_some_and(|cut| labels_eq(&cut, name))
+        && zone.rrset(name, &QType::Ds).is_empty()
+}
+
+/// Types listed in the bitmap of the denial record at `name`.
+/// An insecure delegation has no signed RRset of its own.
+fn signed_types(zone: &Zone, name: &[DnsLabel]) -> Vec<QType> {
+    let mut types = zone.types_at(name);
+    if !types.is_empty() && !is_insecure_cut(zone, name) {
+        types.push(QType::Rrsig);
+    }
+    types
+}
+
+fn record(owner: Vec<DnsLabel>, r_type: QType, ttl: u32, r_data: Vec<u8>) -> DnsAnswer {
+    DnsAnswer {
+        r_name: owner,
+        r_type,
+        r_class: QClass::In,
+        ttl,
+        rd_length: r_data.len() as u16,
+        r_data,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NsecChain {
+    /// Sorted in canonical order of their owner names
+    records: Vec<DnsAnswer>,
+}
+
+impl NsecChain {
+    pub fn build(zone: &Zone) -> Self {
+        let names: Vec<Vec<DnsLabel>> = zone
+            .owner_names()
+            .into_iter()
+            .filter(|name| zone.is_authoritative(name))
+            .collect();
+        let ttl = zone.negative_ttl();
+
+        let mut records = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let next_domain = names[(i + 1) % names.len()].clone();
+            let mut types = zone.types_at(name);
+            types.push(QType::Rrsig);
+            types.push(QType::Nsec);
+            let r_data: Vec<u8> = Nsec { next_domain, types }.into();
+            records.push(record(name.clone(), QType::Nsec, ttl, r_data));
+        }
+        Self { records }
+    }
+
+    pub fn records(&self) -> &[DnsAnswer] {
+        &self.records
+    }
+
+    /// Index of the last record whose owner is smaller or equal to `name`
+    fn position(&self, name: &[DnsLabel]) -> Option<usize> {
+        let index = self
+            .records
+            .partition_point(|r| canonical_cmp(&r.r_name, name) != Ordering::Greater);
+        index.checked_sub(1)
+    }
+
+    pub fn matching(&self, name: &[DnsLabel]) -> Option<&DnsAnswer> {
+        self.position(name)
+            .map(|i| &self.records[i])
+            .filter(|r| labels_eq(&r.r_name, name))
+    }
+
+    /// Record whose owner sorts before `name` and whose next name sorts after it.
+    /// The last record of the chain covers everything after it.
+    pub fn covering(&self, name: &[DnsLabel]) -> Option<&DnsAnswer> {
+        let index = self
+            .position(name)
+            .unwrap_or(self.records.len().checked_sub(1)?);
+        let record = &self.records[index];
+        match labels_eq(&record.r_name, name) {
+            true => None,
+            false => Some(record),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Nsec3Chain {
+    pub params: Nsec3Param,
+    /// (hash, record) sorted by hash
+    records: Vec<(Vec<u8>, DnsAnswer)>,
+}
+
+impl Nsec3Chain {
+    /// Every authoritative name and empty non-terminal gets a record.
+    /// With opt-out, unsigned delegations and the empty non-terminals only
+    /// leading to them are left out of the chain.
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-6
+    pub fn build(zone: &Zone, params: Nsec3Param, opt_out: bool) -> Self {
+        let ttl = zone.negative_ttl();
+        let mut names: Vec<Vec<DnsLabel>> = zone
+            .owner_names()
+            .into_iter()
+            .filter(|name| zone.is_authoritative(name))
+            .filter(|name| !(opt_out && is_insecure_cut(zone, name)))
+            .collect();
+        let empty_non_terminals: Vec<Vec<DnsLabel>> = zone
+            .empty_non_terminals()
+            .into_iter()
+            .filter(|name| zone.is_authoritative(name))
+            .filter(|name| {
+                !opt_out
+                    || names
+                        .iter()
+                        .any(|owner| owner.len() > name.len() && is_subdomain(owner, name))
+            })
+            .collect();
+        names.extend(empty_non_terminals);
+
+        let mut hashed: Vec<(Vec<u8>, Vec<QType>)> = names
+            .iter()
+            .map(|name| (params.hash(name), signed_types(zone, name)))
+            .collect();
+        hashed.sort_by(|a, b| a.0.cmp(&b.0));
+        hashed.dedup_by(|a, b| a.0 == b.0);
+
+        let mut records = Vec::new();
+        for (i, (hash, types)) in hashed.iter().enumerate() {
+            let nsec3 = Nsec3 {
+                hash_algorithm: NSEC3_HASH_SHA1,
+                flags: if opt_out { NSEC3_OPT_OUT } else { 0 },
+                iterations: params.iterations,
+                salt: params.salt.clone(),
+                next_hashed_owner: hashed[(i + 1) % hashed.len()].0.clone(),
+                types: types.clone(),
+            };
+            let owner = Self::owner(&zone.origin, hash);
+            records.push((hash.clone(), record(owner, QType::Nsec3, ttl, nsec3.into())));
+        }
+        Self { params, records }
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &DnsAnswer> {
+        self.records.iter().map(|(_, record)| record)
+    }
+
+    /// `<base32hex hash>.<origin>`
+    fn owner(origin: &[DnsLabel], hash: &[u8]) -> Vec<DnsLabel> {
+        let mut owner = vec![DnsLabel::new(&base32hex_encode(hash))];
+        owner.extend(origin.iter().cloned());
+        owner
+    }
+
+    pub fn matching(&self, name: &[DnsLabel]) -> Option<&DnsAnswer> {
+        let hash = self.params.hash(name);
+        self.records
+            .binary_search_by(|(h, _)| h.cmp(&hash))
+            .ok()
+            .map(|i| &self.records[i].1)
+    }
+
+    /// Record whose hash range contains the hash of `name`
+    pub fn covering(&self, name: &[DnsLabel]) -> Option<&DnsAnswer> {
+        let hash = self.params.hash(name);
+        let index = match self.records.binary_search_by(|(h, _)| h.cmp(&hash)) {
+            Ok(_) => return None,
+            Err(0) => self.records.len().checked_sub(1)?,
+            Err(index) => index - 1,
+        };
+        Some(&self.records[index].1)
+    }
+
+    /// Proof that `name` does not exist: the closest encloser, the next closer
+    /// name and the wildcard at the closest encloser
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-7.2.2
+    pub fn name_error_proof(&self, zone: &Zone, name: &[DnsLabel]) -> Vec<DnsAnswer> {
+        let encloser = zone.closest_encloser(name);
+        if encloser.len() >= name.len() {
+            return self.matching(name).into_iter().cloned().collect();
+        }
+        let next_closer = name[name.len() - encloser.len() - 1..].to_vec();
+        let mut wildcard = vec![DnsLabel::new("*")];
+        wildcard.extend(encloser.iter().cloned());
+
+        let mut proof: Vec<DnsAnswer> = Vec::new();
+        for record in [
+            self.matching(&encloser),
+            self.covering(&next_closer),
+            self.covering(&wildcard),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !proof.contains(record) {
+                proof.push(record.clone());
+            }
+        }
+        proof
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+    use crate::Result;
+
+    const ZONE: &str = r#"
+$ORIGIN example.
+@       SOA ns1 hostmaster 1 7200 3600 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+b       A   192.0.2.2
+x.y     A   192.0.2.3
+"#;
+
+    fn zone() -> Result<Zone> {
+        Zone::from_records(labels_from_str("example"), master_file::parse(ZONE, &[])?)
+    }
+
+    #[test]
+    fn test_nsec_chain() -> Result<()> {
+        let chain = NsecChain::build(&zone()?);
+        assert_eq!(chain.records().len(), 4);
+
+        let covering = chain.covering(&labels_from_str("c.example")).unwrap();
+        assert_eq!(covering.r_name, labels_from_str("b.example"));
+        let nsec = Nsec::try_from(&covering.r_data[..])?;
+        assert_eq!(nsec.next_domain, labels_from_str("ns1.example"));
+        assert_eq!(nsec.types, vec![QType::A, QType::Rrsig, QType::Nsec]);
+
+        // after the last name the chain wraps to the apex
+        let covering = chain.covering(&labels_from_str("z.example")).unwrap();
+        assert_eq!(covering.r_name, labels_from_str("x.y.example"));
+        assert!(chain.matching(&labels_from_str("b.example")).is_some());
+        assert!(chain.covering(&labels_from_str("b.example")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec3_chain() -> Result<()> {
+        let zone = zone()?;
+        let params = Nsec3Param {
+            hash_algorithm: NSEC3_HASH_SHA1,
+            flags: 0,
+            iterations: 0,
+            salt: vec![],
+        };
+        let chain = Nsec3Chain::build(&zone, params.clone(), false);
+        // 4 owner names and the y.example empty non-terminal
+        assert_eq!(chain.records().count(), 5);
+        assert!(chain.matching(&labels_from_str("y.example")).is_some());
+
+        let proof = chain.name_error_proof(&zone, &labels_from_str("a.b.example"));
+        assert!(proof.contains(chain.matching(&labels_from_str("b.example")).unwrap()));
+        assert!(proof.contains(chain.covering(&labels_from_str("a.b.example")).unwrap()));
+
+        // the unsigned delegation at x.y and the y empty non-terminal are opted out
+        let mut zone = zone;
+        zone.add_record(master_file::parse("x.y.example. NS ns.other.", &[])?.remove(0))?;
+        let chain = Nsec3Chain::build(&zone, params, true);
+        assert_eq!(chain.records().count(), 3);
+        assert!(chain.matching(&labels_from_str("y.example")).is_none());
+        let nsec3 = Nsec3::try_from(&chain.records().next().unwrap().r_data[..])?;
+        assert_eq!(nsec3.flags, NSEC3_OPT_OUT);
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+//! Signing keys, stored in the BIND key file format:
+//! `K<zone>+<alg>+<tag>.key` holds the DNSKEY record and
+//! `K<zone>+<alg>+<tag>.private` holds the private key and its timing metadata
+use std::path::Path;
+
+use crate::crypto::{ed25519, p256};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{labels_to_string, DnsLabel};
+use crate::dns_type::QType;
+use crate::dnssec::{
+    rrsig_labels, signed_data, Dnskey, Rrsig, ALGORITHM_ECDSAP256SHA256, ALGORITHM_ED25519,
+    DNSKEY_SEP, DNSKEY_ZONE,
+};
+use crate::encoding::{base64_decode, base64_encode, format_timestamp, parse_timestamp};
+use crate::master_file;
+use crate::Result;
+
+/// TTL written in the `.key` files, the zone uses the SOA TTL when publishing
+const KEY_FILE_TTL: u32 = 3600;
+
+/// Lifecycle of a key, in seconds since epoch. Unset times mean the key is
+/// published and active from the start and never retired.
+/// https://datatracker.ietf.org/doc/html/rfc7583#section-3.1
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeyTiming {
+    pub created: Option<u32>,
+    /// Added to the DNSKEY RRset
+    pub publish: Option<u32>,
+    /// Starts signing
+    pub activate: Option<u32>,
+    /// Stops signing
+    pub inactive: Option<u32>,
+    /// Removed from the DNSKEY RRset
+    pub delete: Option<u32>,
+}
+
+impl KeyTiming {
+    /// Published and active from `now` on
+    pub fn starting(now: u32) -> Self {
+        Self {
+            created: Some(now),
+            publish: Some(now),
+            activate: Some(now),
+            inactive: None,
+            delete: None,
+        }
+    }
+
+    pub fn is_published(&self, now: u32) -> bool {
+        self.publish.is_none_or(|publish| publish <= now)
+            && self.delete.is_none_or(|delete| now < delete)
+    }
+
+    pub fn is_active(&self, now: u32) -> bool {
+        self.is_published(now)
+            && self.activate.is_none_or(|activate| activate <= now)
+            && self.inactive.is_none_or(|inactive| now < inactive)
+    }
+
+    /// Field names of the `.private` file
+    fn fields(&self) -> [(&'static str, Option<u32>); 5] {
+        [
+            ("Created", self.created),
+            ("Publish", self.publish),
+            ("Activate", self.activate),
+            ("Inactive", self.inactive),
+            ("Delete", self.delete),
+        ]
+    }
+
+    fn from_private_file(content: &str) -> Result<Self> {
+        let mut timing = Self::default();
+        for line in content.lines() {
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let time = || parse_timestamp(value.trim());
+            match field.trim() {
+                "Created" => timing.created = Some(time()?),
+                "Publish" => timing.publish = Some(time()?),
+                "Activate" => timing.activate = Some(time()?),
+                "Inactive" => timing.inactive = Some(time()?),
+                "Delete" => timing.delete = Some(time()?),
+                _ => {}
+            }
+        }
+        Ok(timing)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrivateKey {
+    EcdsaP256(p256::PrivateKey),
+    Ed25519(ed25519::PrivateKey),
+}
+
+impl PrivateKey {
+    pub fn generate(algorithm: u8) -> Result<Self> {
+        match algorithm {
+            ALGORITHM_ECDSAP256SHA256 => Ok(Self::EcdsaP256(p256::PrivateKey::generate())),
+            ALGORITHM_ED25519 => Ok(Self::Ed25519(ed25519::PrivateKey::generate())),
+            _ => anyhow::bail!("Unsupported DNSSEC algorithm {}", algorithm),
+        }
+    }
+
+    pub fn algorithm(&self) -> u8 {
+        match self {
+            Self::EcdsaP256(_) => ALGORITHM_ECDSAP256SHA256,
+            Self::Ed25519(_) => ALGORITHM_ED25519,
+        }
+    }
+
+    /// Public key as carried by the DNSKEY record
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            Self::EcdsaP256(key) => key.public_key().to_vec(),
+            Self::Ed25519(key) => key.public_key().to_vec(),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::EcdsaP256(key) => key.sign(data).to_vec(),
+            Self::Ed25519(key) => key.sign(data).to_vec(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::EcdsaP256(key) => key.to_bytes().to_vec(),
+            Self::Ed25519(key) => key.to_bytes().to_vec(),
+        }
+    }
+
+    fn algorithm_mnemonic(&self) -> &'static str {
+        match self {
+            Self::EcdsaP256(_) => "ECDSAP256SHA256",
+            Self::Ed25519(_) => "ED25519",
+        }
+    }
+
+    /// Parses the content of a `.private` file
+    pub fn from_private_file(content: &str) -> Result<Self> {
+        let mut algorithm = None;
+        let mut private_key = None;
+        for line in content.lines() {
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            match field.trim() {
+                // "13 (ECDSAP256SHA256)"
+                "Algorithm" => {
+                    let number = value.split_whitespace().next().unwrap_or_default();
+                    algorithm = Some(number.parse::<u8>()?);
+                }
+                "PrivateKey" => private_key = Some(base64_decode(value)?),
+                _ => {}
+            }
+        }
+        let (Some(algorithm), Some(private_key)) = (algorithm, private_key) else {
+            anyhow::bail!("Private key file needs Algorithm and PrivateKey fields");
+        };
+        match algorithm {
+            ALGORITHM_ECDSAP256SHA256 => Ok(Self::EcdsaP256(p256::PrivateKey::from_bytes(
+                &private_key,
+            )?)),
+            ALGORITHM_ED25519 => Ok(Self::Ed25519(ed25519::PrivateKey::from_bytes(
+                &private_key,
+            )?)),
+            _ => anyhow::bail!("Unsupported DNSSEC algorithm {}", algorithm),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKey {
+    /// Zone apex the key belongs to
+    pub owner: Vec<DnsLabel>,
+    pub dnskey: Dnskey,
+    pub private_key: PrivateKey,
+    pub timing: KeyTiming,
+}
+
+impl SigningKey {
+    /// New key pair, published and active from `now` on
+    pub fn generate(owner: Vec<DnsLabel>, algorithm: u8, ksk: bool, now: u32) -> Result<Self> {
+        let private_key = PrivateKey::generate(algorithm)?;
+        let flags = match ksk {
+            true => DNSKEY_ZONE | DNSKEY_SEP,
+            false => DNSKEY_ZONE,
+        };
+        Ok(Self {
+            owner,
+            dnskey: Dnskey {
+                flags,
+                protocol: 3,
+                algorithm,
+                public_key: private_key.public_key(),
+            },
+            private_key,
+            timing: KeyTiming::starting(now),
+        })
+    }
+
+    /// Loads `<prefix>.key` and `<prefix>.private`
+    pub fn load(prefix: &str) -> Result<Self> {
+        let prefix = prefix
+            .trim_end_matches(".key")
+            .trim_end_matches(".private");
+        let key_content = std::fs::read_to_string(format!("{}.key", prefix))?;
+        let private_content = std::fs::read_to_string(format!("{}.private", prefix))?;
+
+        let records = master_file::parse(&key_content, &[])?;
+        let Some(record) = records.into_iter().find(|r| r.r_type == QType::Dnskey) else {
+            anyhow::bail!("No DNSKEY record in {}.key", prefix);
+        };
+        let dnskey = Dnskey::try_from(&record.r_data[..])?;
+        let private_key = PrivateKey::from_private_file(&private_content)?;
+
+        if dnskey.algorithm != private_key.algorithm()
+            || dnskey.public_key != private_key.public_key()
+        {
+            anyhow::bail!("Public and private keys of {} do not match", prefix);
+        }
+        Ok(Self {
+            owner: record.r_name,
+            dnskey,
+            private_key,
+            timing: KeyTiming::from_private_file(&private_content)?,
+        })
+    }
+
+    /// Loads every key of the zone found in a directory
+    pub fn load_dir(dir: &str, owner: &[DnsLabel]) -> Result<Vec<Self>> {
+        let prefix = format!("k{}+", labels_to_string(owner).to_ascii_lowercase());
+        let mut keys = Vec::new();
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| anyhow::anyhow!("Could not read key directory {}: {}", dir, e))?;
+        for entry in entries {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.to_ascii_lowercase().starts_with(&prefix) && name.ends_with(".key") {
+                keys.push(Self::load(&path.to_string_lossy())?);
+            }
+        }
+        keys.sort_by_key(|key| (key.timing.created, key.key_tag()));
+        Ok(keys)
+    }
+
+    /// `K<zone>+<algorithm>+<key tag>`, as BIND names the files
+    pub fn file_prefix(&self) -> String {
+        format!(
+            "K{}+{:03}+{:05}",
+            labels_to_string(&self.owner),
+            self.dnskey.algorithm,
+            self.key_tag()
+        )
+    }
+
+    /// Content of the `.key` file
+    pub fn key_file(&self) -> String {
+        let role = match self.dnskey.is_ksk() {
+            true => "key-signing",
+            false => "zone-signing",
+        };
+        format!(
+            "; This is a {} key, keyid {}, for {}\n{}\n",
+            role,
+            self.key_tag(),
+            labels_to_string(&self.owner),
+            master_file::format_record(&self.dnskey_record(KEY_FILE_TTL))
+        )
+    }
+
+    /// Content of the `.private` file
+    pub fn private_file(&self) -> String {
+        let mut content = format!(
+            "Private-key-format: v1.3\nAlgorithm: {} ({})\nPrivateKey: {}\n",
+            self.dnskey.algorithm,
+            self.private_key.algorithm_mnemonic(),
+            base64_encode(&self.private_key.to_bytes())
+        );
+        for (field, time) in self.timing.fields() {
+            if let Some(time) = time {
+                content.push_str(&format!("{}: {}\n", field, format_timestamp(time)));
+            }
+        }
+        content
+    }
+
+    /// Writes the `.key` and `.private` files in `dir`, returns their path prefix
+    pub fn save(&self, dir: &str) -> Result<String> {
+        let prefix = Path::new(dir).join(self.file_prefix());
+        let prefix = prefix.to_string_lossy().to_string();
+        for (extension, content) in [("key", self.key_file()), ("private", self.private_file())] {
+            let path = format!("{}.{}", prefix, extension);
+            std::fs::write(&path, content)
+                .map_err(|e| anyhow::anyhow!("Could not write {}: {}", path, e))?;
+        }
+        Ok(prefix)
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        self.dnskey.key_tag()
+    }
+
+    pub fn dnskey_record(&self, ttl: u32) -> DnsAnswer {
+        let r_data: Vec<u8> = self.dnskey.clone().into();
+        DnsAnswer {
+            r_name: self.owner.clone(),
+            r_type: QType::Dnskey,
+            r_class: QClass::In,
+            ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        }
+    }
+
+    /// Produces the RRSIG record covering an RRset.
+    /// All records of `rrset` must share owner, type, class and TTL.
+    pub fn sign_rrset(&self, rrset: &[DnsAnswer], inception: u32, expiration: u32) -> DnsAnswer {
+        let first = &rrset[0];
+        let mut rrsig = Rrsig {
+            type_covered: first.r_type.clone(),
+            algorithm: self.dnskey.algorithm,
+            labels: rrsig_labels(&first.r_name),
+            original_ttl: first.ttl,
+            expiration,
+            inception,
+            key_tag: self.key_tag(),
+            signer_name: self.owner.clone(),
+            signature: vec![],
+        };
+        rrsig.signature = self.private_key.sign(&signed_data(&rrsig, rrset));
+
+        let r_data: Vec<u8> = rrsig.into();
+        DnsAnswer {
+            r_name: first.r_name.clone(),
+            r_type: QType::Rrsig,
+            r_class: first.r_class.clone(),
+            ttl: first.ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        }
+    }
+}
+
+/// Files the keys of a zone are read from: key file prefixes, and a
+/// directory holding every key of the zone
+#[derive(Debug, Clone, Default)]
+pub struct KeySource {
+    pub files: Vec<String>,
+    pub dir: Option<String>,
+}
+
+impl KeySource {
+    pub fn load(&self, owner: &[DnsLabel]) -> Result<Vec<SigningKey>> {
+        let mut keys = self
+            .files
+            .iter()
+            .map(|prefix| SigningKey::load(prefix))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(dir) = &self.dir {
+            keys.extend(SigningKey::load_dir(dir, owner)?);
+        }
+        Ok(keys)
+    }
+}
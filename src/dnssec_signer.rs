@@ -0,0 +1,429 @@
+//! Online signing: RRSIGs are produced when answering queries with the DO bit,
+//! and kept in a cache until they get close to their expiration.
+//! The keys are read again from their files every minute. When the keys
+//! published or active change, the DNSKEY RRset is published again and the
+//! cached signatures are dropped, so that key rollovers need no restart.
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{labels_eq, labels_to_key, labels_to_string, DnsLabel};
+use crate::dns_type::QType;
+use crate::dns_zone::Zone;
+use crate::dnssec::{Dnskey, Nsec3Param, NSEC3_HASH_SHA1};
+use crate::dnssec_chain::{Nsec3Chain, NsecChain};
+use crate::dnssec_key::{KeySource, SigningKey};
+
+/// Signatures start being valid a bit in the past to absorb clock skew
+pub const INCEPTION_OFFSET: u32 = 3600;
+/// Time between two readings of the key files
+const KEY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after 1970")
+        .as_secs() as u32
+}
+
+/// Splits records in RRsets, keeping the order of their first appearance
+pub fn group_rrsets(records: &[DnsAnswer]) -> Vec<Vec<DnsAnswer>> {
+    let mut rrsets: Vec<Vec<DnsAnswer>> = Vec::new();
+    for record in records {
+        match rrsets.iter_mut().find(|rrset| {
+            rrset[0].r_type == record.r_type
+                && rrset[0].r_class == record.r_class
+                && labels_eq(&rrset[0].r_name, &record.r_name)
+        }) {
+            Some(rrset) => rrset.push(record.clone()),
+            None => rrsets.push(vec![record.clone()]),
+        }
+    }
+    rrsets
+}
+
+/// Checks that the keys can sign the zone
+fn check_keys(zone: &Zone, keys: &[SigningKey]) -> crate::Result<()> {
+    if keys.is_empty() {
+        anyhow::bail!("Signing a zone needs at least one key");
+    }
+    for key in keys {
+        if !labels_eq(&key.owner, &zone.origin) {
+            anyhow::bail!("Key {} does not belong to the zone", key.key_tag());
+        }
+        if !key.dnskey.is_zone_key() {
+            anyhow::bail!("Key {} is not a zone key", key.key_tag());
+        }
+    }
+    Ok(())
+}
+
+/// Adds the DNSKEY records of the keys published at `now` at the apex, with the SOA TTL
+pub fn publish_keys(zone: &mut Zone, keys: &[SigningKey], now: u32) -> crate::Result<()> {
+    check_keys(zone, keys)?;
+    let ttl = zone.soa().map(|soa| soa.ttl).unwrap_or_default();
+    for key in keys {
+        if key.timing.is_published(now) {
+            zone.add_record(key.dnskey_record(ttl))?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the NSEC3PARAM record at the apex, with the negative caching TTL
+pub fn publish_nsec3_param(
+    zone: &mut Zone,
+    salt: Vec<u8>,
+    iterations: u16,
+) -> crate::Result<Nsec3Param> {
+    let params = Nsec3Param {
+        hash_algorithm: NSEC3_HASH_SHA1,
+        flags: 0,
+        iterations,
+        salt,
+    };
+    let r_data: Vec<u8> = params.clone().into();
+    zone.add_record(DnsAnswer {
+        r_name: zone.origin.clone(),
+        r_type: QType::Nsec3param,
+        r_class: QClass::In,
+        ttl: zone.negative_ttl(),
+        rd_length: r_data.len() as u16,
+        r_data,
+    })?;
+    Ok(params)
+}
+
+/// DNSKEY RRsets are signed by the key signing keys, everything else by the
+/// zone signing keys. A single key type signs everything. Only the keys
+/// active at `now` sign.
+pub fn keys_for<'a>(keys: &'a [SigningKey], r_type: &QType, now: u32) -> Vec<&'a SigningKey> {
+    let (ksks, zsks): (Vec<&SigningKey>, Vec<&SigningKey>) = keys
+        .iter()
+        .filter(|key| key.timing.is_active(now))
+        .partition(|key| key.dnskey.is_ksk());
+    match r_type {
+        QType::Dnskey if !ksks.is_empty() => ksks,
+        _ if !zsks.is_empty() => zsks,
+        _ => ksks,
+    }
+}
+
+/// Each key with whether it is published and active at `now`
+fn key_state(keys: &[SigningKey], now: u32) -> Vec<(Dnskey, bool, bool)> {
+    keys.iter()
+        .map(|key| {
+            let timing = &key.timing;
+            (
+                key.dnskey.clone(),
+                timing.is_published(now),
+                timing.is_active(now),
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+enum DenialChain {
+    Nsec(NsecChain),
+    Nsec3(Nsec3Chain),
+}
+
+#[derive(Debug, Clone)]
+struct CachedSignatures {
+    rrset: Vec<DnsAnswer>,
+    rrsigs: Vec<DnsAnswer>,
+    expiration: u32,
+}
+
+#[derive(Debug)]
+pub struct OnlineSigner {
+    keys: Vec<SigningKey>,
+    /// Validity period of the signatures in seconds
+    validity: u32,
+    chain: DenialChain,
+    /// Keyed by lowercase owner name and type
+    cache: HashMap<(String, u16), CachedSignatures>,
+    /// Files the keys are read again from, none to keep them
+    source: Option<KeySource>,
+    checked: Instant,
+    /// State of the keys when last checked
+    state: Vec<(Dnskey, bool, bool)>,
+}
+
+impl OnlineSigner {
+    /// Publishes the DNSKEY records (and NSEC3PARAM) in the zone, then builds
+    /// the denial chain over the final content of the zone
+    pub fn new(
+        zone: &mut Zone,
+        keys: Vec<SigningKey>,
+        nsec3: Option<(Vec<u8>, u16)>,
+        validity: u32,
+    ) -> crate::Result<Self> {
+        let now = now();
+        publish_keys(zone, &keys, now)?;
+        let chain = match nsec3 {
+            Some((salt, iterations)) => {
+                let params = publish_nsec3_param(zone, salt, iterations)?;
+                DenialChain::Nsec3(Nsec3Chain::build(zone, params, false))
+            }
+            None => DenialChain::Nsec(NsecChain::build(zone)),
+        };
+
+        Ok(Self {
+            state: key_state(&keys, now),
+            keys,
+            validity,
+            chain,
+            cache: HashMap::new(),
+            source: None,
+            checked: Instant::now(),
+        })
+    }
+
+    /// Reads the keys again from their files from time to time
+    pub fn watch_keys(mut self, source: KeySource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Reads the keys again once the check interval elapsed, keeping the
+    /// previous ones when they can not be read. When the keys published or
+    /// active changed, publishes the DNSKEY RRset again and drops the cached
+    /// signatures.
+    pub fn update_keys(&mut self, zone: &mut Zone) {
+        if self.checked.elapsed() < KEY_CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        if let Some(source) = &self.source {
+            let keys = source
+                .load(&zone.origin)
+                .and_then(|keys| check_keys(zone, &keys).map(|_| keys));
+            match keys {
+                Ok(keys) => self.keys = keys,
+                Err(e) => eprintln!(
+                    "Kept the previous keys of {}: {}",
+                    labels_to_string(&zone.origin),
+                    e
+                ),
+            }
+        }
+
+        let now = now();
+        let state = key_state(&self.keys, now);
+        if state == self.state {
+            return;
+        }
+        self.state = state;
+        let origin = zone.origin.clone();
+        zone.retain(|record| record.r_type != QType::Dnskey || !labels_eq(&record.r_name, &origin));
+        if let Err(e) = publish_keys(zone, &self.keys, now) {
+            eprintln!(
+                "Could not publish the keys of {}: {}",
+                labels_to_string(&origin),
+                e
+            );
+        }
+        self.cache.clear();
+    }
+
+    /// Returns the RRSIG records covering `rrset`, from the cache when possible
+    pub fn sign_rrset(&mut self, rrset: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let Some(first) = rrset.first() else {
+            return vec![];
+        };
+        let now = now();
+        let cache_key = (
+            labels_to_key(&first.r_name),
+            u16::from(first.r_type.clone()),
+        );
+        if let Some(cached) = self.cache.get(&cache_key) {
+            // refreshed once less than a quarter of the validity remains
+            let remaining = cached.expiration.saturating_sub(now);
+            if cached.rrset == rrset && remaining > self.validity / 4 {
+                return cached.rrsigs.clone();
+            }
+        }
+
+        let inception = now.saturating_sub(INCEPTION_OFFSET);
+        let expiration = now.saturating_add(self.validity);
+        let rrsigs: Vec<DnsAnswer> = keys_for(&self.keys, &first.r_type, now)
+            .iter()
+            .map(|key| key.sign_rrset(rrset, inception, expiration))
+            .collect();
+        self.cache.insert(
+            cache_key,
+            CachedSignatures {
+                rrset: rrset.to_vec(),
+                rrsigs: rrsigs.clone(),
+                expiration,
+            },
+        );
+        rrsigs
+    }
+
+    /// Adds the RRSIGs after each RRset of a section. RRSIG and OPT records
+    /// are never signed and are dropped to be regenerated.
+    pub fn sign_section(&mut self, records: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let mut signed = Vec::new();
+        let records: Vec<DnsAnswer> = records
+            .iter()
+            .filter(|r| r.r_type != QType::Rrsig && r.r_type != QType::Opt)
+            .cloned()
+            .collect();
+        for rrset in group_rrsets(&records) {
+            let rrsigs = self.sign_rrset(&rrset);
+            signed.extend(rrset);
+            signed.extend(rrsigs);
+        }
+        signed
+    }
+
+    /// Signed records proving that `name` does not exist
+    pub fn deny_name(&mut self, zone: &Zone, name: &[DnsLabel]) -> Vec<DnsAnswer> {
+        let records = match &self.chain {
+            DenialChain::Nsec(chain) => {
+                // the name itself and the wildcard at the closest encloser
+                let mut wildcard = vec![DnsLabel::new("*")];
+                wildcard.extend(zone.closest_encloser(name));
+                let mut records: Vec<DnsAnswer> = Vec::new();
+                for record in [chain.covering(name), chain.covering(&wildcard)]
+                    .into_iter()
+                    .flatten()
+                {
+                    if !records.contains(record) {
+                        records.push(record.clone());
+                    }
+                }
+                records
+            }
+            DenialChain::Nsec3(chain) => chain.name_error_proof(zone, name),
+        };
+        self.sign_section(&records)
+    }
+
+    /// Signed records proving that `name` has no record of the queried type
+    pub fn deny_type(&mut self, name: &[DnsLabel]) -> Vec<DnsAnswer> {
+        let record = match &self.chain {
+            // empty non-terminals have no NSEC, the covering one proves them
+            DenialChain::Nsec(chain) => chain.matching(name).or_else(|| chain.covering(name)),
+            DenialChain::Nsec3(chain) => chain.matching(name),
+        };
+        let records: Vec<DnsAnswer> = record.into_iter().cloned().collect();
+        self.sign_section(&records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519;
+    use crate::dns_label::labels_from_str;
+    use crate::dnssec::{Dnskey, Rrsig, ALGORITHM_ED25519, DNSKEY_SEP, DNSKEY_ZONE};
+    use crate::dnssec_key::{KeyTiming, PrivateKey};
+    use crate::master_file;
+    use crate::Result;
+
+    const ZONE: &str = r#"
+$ORIGIN example.
+@       SOA ns1 hostmaster 1 7200 3600 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.2
+"#;
+
+    fn test_key(seed: u8, flags: u16) -> Result<SigningKey> {
+        let private_key = PrivateKey::Ed25519(ed25519::PrivateKey::from_bytes(&[seed; 32])?);
+        Ok(SigningKey {
+            owner: labels_from_str("example"),
+            dnskey: Dnskey {
+                flags,
+                protocol: 3,
+                algorithm: ALGORITHM_ED25519,
+                public_key: private_key.public_key(),
+            },
+            private_key,
+            timing: KeyTiming::default(),
+        })
+    }
+
+    #[test]
+    fn test_online_signer() -> Result<()> {
+        let mut zone =
+            Zone::from_records(labels_from_str("example"), master_file::parse(ZONE, &[])?)?;
+        let ksk = test_key(1, DNSKEY_ZONE | DNSKEY_SEP)?;
+        let zsk = test_key(2, DNSKEY_ZONE)?;
+        let mut signer = OnlineSigner::new(&mut zone, vec![ksk.clone(), zsk.clone()], None, 86400)?;
+        assert_eq!(zone.rrset(&zone.origin.clone(), &QType::Dnskey).len(), 2);
+
+        let rrset = zone.rrset(&labels_from_str("www.example"), &QType::A);
+        let rrsigs = signer.sign_rrset(&rrset);
+        assert_eq!(rrsigs.len(), 1);
+        let rrsig = Rrsig::try_from(&rrsigs[0].r_data[..])?;
+        assert_eq!(rrsig.key_tag, zsk.key_tag());
+        assert_eq!(rrsig.labels, 2);
+        assert_eq!(rrsig.expiration - rrsig.inception, 86400 + INCEPTION_OFFSET);
+        // second call is served from the cache, ECDSA would differ but the
+        // cache must hand back the same records
+        assert_eq!(signer.sign_rrset(&rrset), rrsigs);
+
+        let dnskeys = zone.rrset(&labels_from_str("example"), &QType::Dnskey);
+        let rrsig = Rrsig::try_from(&signer.sign_rrset(&dnskeys)[0].r_data[..])?;
+        assert_eq!(rrsig.key_tag, ksk.key_tag());
+
+        let denial = signer.deny_name(&zone, &labels_from_str("x.example"));
+        // covering NSEC for the name (www -> apex) and for *.example (apex -> ns1)
+        assert_eq!(denial.iter().filter(|r| r.r_type == QType::Nsec).count(), 2);
+        assert_eq!(
+            denial.iter().filter(|r| r.r_type == QType::Rrsig).count(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("keys-test-{}", std::process::id()));
+        // left by a failed run of a process with the same ID
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let dir = dir.to_string_lossy().to_string();
+        let mut zone =
+            Zone::from_records(labels_from_str("example"), master_file::parse(ZONE, &[])?)?;
+        let ksk = test_key(1, DNSKEY_ZONE | DNSKEY_SEP)?;
+        let zsk = test_key(2, DNSKEY_ZONE)?;
+        ksk.save(&dir)?;
+        let source = KeySource {
+            files: vec![],
+            dir: Some(dir.clone()),
+        };
+        let keys = source.load(&zone.origin)?;
+        let mut signer = OnlineSigner::new(&mut zone, keys, None, 86400)?.watch_keys(source);
+
+        // the only key signs everything
+        let rrset = zone.rrset(&labels_from_str("www.example"), &QType::A);
+        let rrsig = Rrsig::try_from(&signer.sign_rrset(&rrset)[0].r_data[..])?;
+        assert_eq!(rrsig.key_tag, ksk.key_tag());
+
+        // nothing is read before the check interval
+        zsk.save(&dir)?;
+        signer.update_keys(&mut zone);
+        assert_eq!(zone.rrset(&zone.origin.clone(), &QType::Dnskey).len(), 1);
+
+        // the new key is published and the cached signature renewed
+        signer.checked -= KEY_CHECK_INTERVAL;
+        signer.update_keys(&mut zone);
+        assert_eq!(zone.rrset(&zone.origin.clone(), &QType::Dnskey).len(), 2);
+        let rrsig = Rrsig::try_from(&signer.sign_rrset(&rrset)[0].r_data[..])?;
+        assert_eq!(rrsig.key_tag, zsk.key_tag());
+
+        // unreadable keys are kept
+        std::fs::remove_dir_all(&dir)?;
+        signer.checked -= KEY_CHECK_INTERVAL;
+        signer.update_keys(&mut zone);
+        assert_eq!(zone.rrset(&zone.origin.clone(), &QType::Dnskey).len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,1009 @@
+//! DNSSEC validation of the replies obtained from the upstream resolver.
+//! The chain of trust is built from the configured trust anchors down to the
+//! zone that signed the data, with one DS and one DNSKEY lookup per zone cut.
+//! The walk down stops at the names proven to have nothing below them.
+//! https://datatracker.ietf.org/doc/html/rfc4035#section-5
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+
+use crate::dns::DnsReply;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_header::RCode;
+use crate::dns_label::{
+    canonical_cmp, is_subdomain, labels_eq, labels_to_key, labels_to_string, DnsLabel,
+};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dnssec::{
+    nsec3_hash, read_name, rrsig_labels, signed_data, Dnskey, Ds, Nsec, Nsec3, Rrsig, DIGEST_SHA1,
+    DIGEST_SHA256, NSEC3_OPT_OUT,
+};
+use crate::dnssec_signer::{group_rrsets, now};
+use crate::encoding::base32hex_decode;
+use crate::master_file;
+use crate::Result;
+
+/// NSEC3 chains with more iterations are treated as insecure
+/// https://datatracker.ietf.org/doc/html/rfc9276#section-3.2
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+/// Names whose trust is remembered, the oldest are forgotten beyond
+const MAX_TRUST_ENTRIES: usize = 10000;
+
+/// Queries the upstream for a name and type, with the DO bit set
+pub type Lookup<'a> = dyn FnMut(&[DnsLabel], QType) -> Result<DnsReply> + 'a;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Security {
+    /// Validated up to a trust anchor, the AD bit can be set
+    Secure,
+    /// Provably unsigned, or not below any trust anchor
+    Insecure,
+    /// Validation failed, the client gets SERVFAIL
+    Bogus(String),
+}
+
+#[derive(Debug, Clone)]
+enum ZoneTrust {
+    /// Validated keys of the closest enclosing zone
+    Secure {
+        zone: Vec<DnsLabel>,
+        keys: Vec<Dnskey>,
+    },
+    Insecure,
+}
+
+#[derive(Debug)]
+pub struct Validator {
+    /// DS or DNSKEY records
+    anchors: Vec<DnsAnswer>,
+    /// Trust established for a name, keyed by lowercase name, with its expiration
+    cache: HashMap<String, (ZoneTrust, u32)>,
+    /// Keys of the cache, oldest first
+    cached: VecDeque<String>,
+}
+
+/// RRSIG records of `section` covering the RRset
+fn rrsigs_for(rrset: &[DnsAnswer], section: &[DnsAnswer]) -> Vec<Rrsig> {
+    let owner = &rrset[0].r_name;
+    section
+        .iter()
+        .filter(|record| record.r_type == QType::Rrsig && labels_eq(&record.r_name, owner))
+        .filter_map(|record| Rrsig::try_from(&record.r_data[..]).ok())
+        .filter(|rrsig| rrsig.type_covered == rrset[0].r_type)
+        .collect()
+}
+
+/// Checks that one of the RRSIGs of `section` made by `zone` with one of the
+/// keys covers the RRset, returns that RRSIG
+fn verify_rrset(
+    rrset: &[DnsAnswer],
+    section: &[DnsAnswer],
+    zone: &[DnsLabel],
+    keys: &[Dnskey],
+) -> Result<Rrsig> {
+    let owner = &rrset[0].r_name;
+    let now = now();
+    let mut reason = "no signature";
+    for rrsig in rrsigs_for(rrset, section) {
+        if !labels_eq(&rrsig.signer_name, zone) || rrsig.labels > rrsig_labels(owner) {
+            continue;
+        }
+        // serial number arithmetic
+        if (now.wrapping_sub(rrsig.inception) as i32) < 0
+            || (rrsig.expiration.wrapping_sub(now) as i32) < 0
+        {
+            reason = "signature outside of its validity period";
+            continue;
+        }
+        // the signature of a wildcard expansion covers the wildcard owner
+        let mut signed_rrset = rrset.to_vec();
+        if rrsig.labels < rrsig_labels(owner) {
+            let mut wildcard = vec![DnsLabel::new("*")];
+            wildcard.extend_from_slice(&owner[owner.len() - rrsig.labels as usize..]);
+            for record in signed_rrset.iter_mut() {
+                record.r_name = wildcard.clone();
+            }
+        }
+        let data = signed_data(&rrsig, &signed_rrset);
+        if keys.iter().any(|key| {
+            key.key_tag() == rrsig.key_tag
+                && key.algorithm == rrsig.algorithm
+                && key.verify(&data, &rrsig.signature)
+        }) {
+            return Ok(rrsig);
+        }
+        reason = "invalid signature";
+    }
+    anyhow::bail!(
+        "{} {}: {}",
+        labels_to_string(owner),
+        rrset[0].r_type,
+        reason
+    )
+}
+
+/// Checks the signatures of the authority section, returns its NSEC and NSEC3 records
+fn verified_denial(reply: &DnsReply, zone: &[DnsLabel], keys: &[Dnskey]) -> Result<Vec<DnsAnswer>> {
+    let records: Vec<DnsAnswer> = reply
+        .authorities
+        .iter()
+        .filter(|record| record.r_type != QType::Rrsig)
+        .cloned()
+        .collect();
+    let mut denial = Vec::new();
+    for rrset in group_rrsets(&records) {
+        verify_rrset(&rrset, &reply.authorities, zone, keys)?;
+        if matches!(rrset[0].r_type, QType::Nsec | QType::Nsec3) {
+            denial.extend(rrset);
+        }
+    }
+    Ok(denial)
+}
+
+/// Longest common ancestor of two names
+fn common_ancestor(a: &[DnsLabel], b: &[DnsLabel]) -> Vec<DnsLabel> {
+    let depth = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x.label.eq_ignore_ascii_case(&y.label))
+        .count();
+    a[a.len() - depth..].to_vec()
+}
+
+fn wildcard_of(name: &[DnsLabel]) -> Vec<DnsLabel> {
+    let mut wildcard = vec![DnsLabel::new("*")];
+    wildcard.extend_from_slice(name);
+    wildcard
+}
+
+/// NSEC records of a denial, with their owner names
+struct NsecProof(Vec<(Vec<DnsLabel>, Nsec)>);
+
+impl NsecProof {
+    fn new(denial: &[DnsAnswer]) -> Self {
+        Self(
+            denial
+                .iter()
+                .filter(|record| record.r_type == QType::Nsec)
+                .filter_map(|record| {
+                    let nsec = Nsec::try_from(&record.r_data[..]).ok()?;
+                    Some((record.r_name.clone(), nsec))
+                })
+                .collect(),
+        )
+    }
+
+    fn matching(&self, name: &[DnsLabel]) -> Option<&Nsec> {
+        self.0
+            .iter()
+            .find(|(owner, _)| labels_eq(owner, name))
+            .map(|(_, nsec)| nsec)
+    }
+
+    /// NSEC whose span contains `name`. The last one of the chain points
+    /// back to the apex and covers every name after it.
+    fn covering(&self, name: &[DnsLabel]) -> Option<(&Vec<DnsLabel>, &Nsec)> {
+        self.0
+            .iter()
+            .find(|(owner, nsec)| {
+                let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+                let before_next = canonical_cmp(name, &nsec.next_domain) == Ordering::Less;
+                match canonical_cmp(owner, &nsec.next_domain) {
+                    Ordering::Less => after_owner && before_next,
+                    _ => after_owner,
+                }
+            })
+            .map(|(owner, nsec)| (owner, nsec))
+    }
+
+    fn proves_no_data(&self, name: &[DnsLabel], q_type: &QType) -> bool {
+        if let Some(nsec) = self.matching(name) {
+            return !nsec.types.contains(q_type) && !nsec.types.contains(&QType::Cname);
+        }
+        // empty non-terminal: the next name is below the queried one
+        self.covering(name).is_some_and(|(_, nsec)| {
+            nsec.next_domain.len() > name.len() && is_subdomain(&nsec.next_domain, name)
+        })
+    }
+
+    /// Closest encloser of a name proven not to exist, the deepest ancestor
+    /// shared with the names around it
+    fn closest_encloser(&self, name: &[DnsLabel]) -> Option<Vec<DnsLabel>> {
+        let (owner, nsec) = self.covering(name)?;
+        let from_owner = common_ancestor(name, owner);
+        let from_next = common_ancestor(name, &nsec.next_domain);
+        match from_owner.len() > from_next.len() {
+            true => Some(from_owner),
+            false => Some(from_next),
+        }
+    }
+
+    /// The name is covered and so is the wildcard at its closest encloser
+    fn proves_name_error(&self, name: &[DnsLabel]) -> bool {
+        self.closest_encloser(name)
+            .is_some_and(|encloser| self.covering(&wildcard_of(&encloser)).is_some())
+    }
+
+    /// The name is covered and the wildcard at its closest encloser has no
+    /// data of that type
+    /// https://datatracker.ietf.org/doc/html/rfc4035#section-3.1.3.4
+    fn proves_wildcard_no_data(&self, name: &[DnsLabel], q_type: &QType) -> bool {
+        let Some(encloser) = self.closest_encloser(name) else {
+            return false;
+        };
+        self.matching(&wildcard_of(&encloser))
+            .is_some_and(|nsec| !nsec.types.contains(q_type) && !nsec.types.contains(&QType::Cname))
+    }
+
+    fn proves_insecure_delegation(&self, name: &[DnsLabel]) -> bool {
+        self.matching(name).is_some_and(|nsec| {
+            nsec.types.contains(&QType::Ns)
+                && !nsec.types.contains(&QType::Ds)
+                && !nsec.types.contains(&QType::Soa)
+        })
+    }
+
+    /// The name is not a zone cut and has no name below it, so no zone cut
+    /// can be at or below it
+    fn proves_leaf(&self, name: &[DnsLabel]) -> bool {
+        self.matching(name).is_some_and(|nsec| {
+            !nsec.types.contains(&QType::Ns) && !is_subdomain(&nsec.next_domain, name)
+        })
+    }
+}
+
+/// NSEC3 records of a denial, with the hash taken from their owner names
+struct Nsec3Proof(Vec<(Vec<u8>, Nsec3)>);
+
+impl Nsec3Proof {
+    fn new(denial: &[DnsAnswer], zone: &[DnsLabel]) -> Self {
+        Self(
+            denial
+                .iter()
+                .filter(|record| record.r_type == QType::Nsec3)
+                .filter(|record| {
+                    record.r_name.len() == zone.len() + 1 && is_subdomain(&record.r_name, zone)
+                })
+                .filter_map(|record| {
+                    let hash = base32hex_decode(&record.r_name[0].label).ok()?;
+                    let nsec3 = Nsec3::try_from(&record.r_data[..]).ok()?;
+                    Some((hash, nsec3))
+                })
+                .collect(),
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Too many iterations to be worth hashing
+    fn is_too_expensive(&self) -> bool {
+        self.0
+            .iter()
+            .any(|(_, nsec3)| nsec3.iterations > MAX_NSEC3_ITERATIONS)
+    }
+
+    fn hash(&self, name: &[DnsLabel]) -> Option<Vec<u8>> {
+        let (_, nsec3) = self.0.first()?;
+        Some(nsec3_hash(name, &nsec3.salt, nsec3.iterations))
+    }
+
+    fn matching(&self, name: &[DnsLabel]) -> Option<&Nsec3> {
+        let hash = self.hash(name)?;
+        self.0
+            .iter()
+            .find(|(owner, _)| *owner == hash)
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    fn covering(&self, name: &[DnsLabel]) -> Option<&Nsec3> {
+        let hash = self.hash(name)?;
+        self.0
+            .iter()
+            .find(|(owner, nsec3)| {
+                let next = &nsec3.next_hashed_owner;
+                match owner.cmp(next) {
+                    Ordering::Less => *owner < hash && hash < *next,
+                    // last record of the chain
+                    _ => *owner < hash || hash < *next,
+                }
+            })
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    fn proves_no_data(&self, name: &[DnsLabel], q_type: &QType) -> bool {
+        match self.matching(name) {
+            Some(nsec3) => !nsec3.types.contains(q_type) && !nsec3.types.contains(&QType::Cname),
+            // DS of an unsigned delegation in an opt-out span
+            None => {
+                q_type == &QType::Ds
+                    && self
+                        .covering(name)
+                        .is_some_and(|nsec3| nsec3.flags & NSEC3_OPT_OUT != 0)
+            }
+        }
+    }
+
+    /// Closest encloser proof: the closest encloser is matched and the next
+    /// closer name covered
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-8.3
+    fn closest_encloser<'a>(
+        &self,
+        name: &'a [DnsLabel],
+        zone: &[DnsLabel],
+    ) -> Option<&'a [DnsLabel]> {
+        for depth in (zone.len()..name.len()).rev() {
+            let encloser = &name[name.len() - depth..];
+            if self.matching(encloser).is_some() {
+                let next_closer = &name[name.len() - depth - 1..];
+                return self.covering(next_closer).is_some().then_some(encloser);
+            }
+        }
+        None
+    }
+
+    /// Closest encloser proof and covered wildcard
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-8.4
+    fn proves_name_error(&self, name: &[DnsLabel], zone: &[DnsLabel]) -> bool {
+        self.closest_encloser(name, zone)
+            .is_some_and(|encloser| self.covering(&wildcard_of(encloser)).is_some())
+    }
+
+    /// Closest encloser proof and matched wildcard without the type
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-8.7
+    fn proves_wildcard_no_data(
+        &self,
+        name: &[DnsLabel],
+        q_type: &QType,
+        zone: &[DnsLabel],
+    ) -> bool {
+        let Some(encloser) = self.closest_encloser(name, zone) else {
+            return false;
+        };
+        self.matching(&wildcard_of(encloser)).is_some_and(|nsec3| {
+            !nsec3.types.contains(q_type) && !nsec3.types.contains(&QType::Cname)
+        })
+    }
+
+    fn proves_insecure_delegation(&self, name: &[DnsLabel]) -> bool {
+        match self.matching(name) {
+            Some(nsec3) => {
+                nsec3.types.contains(&QType::Ns)
+                    && !nsec3.types.contains(&QType::Ds)
+                    && !nsec3.types.contains(&QType::Soa)
+            }
+            None => self
+                .covering(name)
+                .is_some_and(|nsec3| nsec3.flags & NSEC3_OPT_OUT != 0),
+        }
+    }
+}
+
+impl Validator {
+    pub fn new(anchors: Vec<DnsAnswer>) -> Result<Self> {
+        if anchors.is_empty() {
+            anyhow::bail!("Validation needs at least one trust anchor");
+        }
+        if anchors
+            .iter()
+            .any(|record| record.r_type != QType::Ds && record.r_type != QType::Dnskey)
+        {
+            anyhow::bail!("Trust anchors must be DS or DNSKEY records");
+        }
+        Ok(Self {
+            anchors,
+            cache: HashMap::new(),
+            cached: VecDeque::new(),
+        })
+    }
+
+    /// Reads the trust anchors from master files
+    pub fn load(paths: &[String]) -> Result<Self> {
+        let mut anchors = Vec::new();
+        for path in paths {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Could not read trust anchor {}: {}", path, e))?;
+            anchors.extend(
+                master_file::parse(&content, &[])
+                    .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?,
+            );
+        }
+        Self::new(anchors)
+    }
+
+    pub fn validate(
+        &mut self,
+        question: &DnsQuestion,
+        reply: &DnsReply,
+        lookup: &mut Lookup,
+    ) -> Security {
+        match self.validate_reply(question, reply, lookup) {
+            Ok(security) => security,
+            Err(e) => Security::Bogus(e.to_string()),
+        }
+    }
+
+    fn validate_reply(
+        &mut self,
+        question: &DnsQuestion,
+        reply: &DnsReply,
+        lookup: &mut Lookup,
+    ) -> Result<Security> {
+        match reply.header.fourth_byte.response_code {
+            RCode::NoError | RCode::NameError => {}
+            // nothing to validate in failures
+            _ => return Ok(Security::Insecure),
+        }
+
+        let mut security = Security::Secure;
+        let mut target = question.q_name.clone();
+        let mut answered = false;
+        let records: Vec<DnsAnswer> = reply
+            .answers
+            .iter()
+            .filter(|record| record.r_type != QType::Rrsig)
+            .cloned()
+            .collect();
+        for rrset in group_rrsets(&records) {
+            if self.validate_rrset(&rrset, reply, lookup)? == Security::Insecure {
+                security = Security::Insecure;
+            }
+            let first = &rrset[0];
+            if labels_eq(&first.r_name, &target) {
+                if first.r_type == question.q_type || question.q_type == QType::StarSign {
+                    answered = true;
+                } else if first.r_type == QType::Cname {
+                    target = read_name(&first.r_data, 0)?.0;
+                }
+            }
+        }
+        if answered {
+            return Ok(security);
+        }
+
+        // the last name of the CNAME chain has no data, its absence must be proven
+        match self.validate_denial(&target, &question.q_type, reply, lookup)? {
+            Security::Secure => Ok(security),
+            other => Ok(other),
+        }
+    }
+
+    /// Validates an RRset of the answer section
+    fn validate_rrset(
+        &mut self,
+        rrset: &[DnsAnswer],
+        reply: &DnsReply,
+        lookup: &mut Lookup,
+    ) -> Result<Security> {
+        let owner = &rrset[0].r_name;
+        let signer = match rrsigs_for(rrset, &reply.answers).first() {
+            Some(rrsig) if is_subdomain(owner, &rrsig.signer_name) => rrsig.signer_name.clone(),
+            Some(_) => anyhow::bail!("Signer name is not an ancestor of the owner"),
+            None => owner.clone(),
+        };
+        let ZoneTrust::Secure { zone, keys } = self.trust_for(&signer, lookup)? else {
+            return Ok(Security::Insecure);
+        };
+        if !labels_eq(&zone, &signer) {
+            anyhow::bail!(
+                "{} {} is not signed by its zone",
+                labels_to_string(owner),
+                rrset[0].r_type
+            );
+        }
+        let rrsig = verify_rrset(rrset, &reply.answers, &zone, &keys)?;
+
+        // a wildcard expansion is only valid if the name itself does not exist
+        if rrsig.labels < rrsig_labels(owner) {
+            let denial = verified_denial(reply, &zone, &keys)?;
+            let next_closer = &owner[owner.len() - rrsig.labels as usize - 1..];
+            let nsec3 = Nsec3Proof::new(&denial, &zone);
+            let proven = NsecProof::new(&denial).covering(owner).is_some()
+                || nsec3.covering(next_closer).is_some();
+            if !proven {
+                anyhow::bail!("Wildcard expansion without proof of non-existence");
+            }
+        }
+        Ok(Security::Secure)
+    }
+
+    /// Validates the proof that `name` has no data of that type, or does not exist
+    fn validate_denial(
+        &mut self,
+        name: &[DnsLabel],
+        q_type: &QType,
+        reply: &DnsReply,
+        lookup: &mut Lookup,
+    ) -> Result<Security> {
+        let ZoneTrust::Secure { zone, keys } = self.trust_for(name, lookup)? else {
+            return Ok(Security::Insecure);
+        };
+        let denial = verified_denial(reply, &zone, &keys)?;
+        let nsec = NsecProof::new(&denial);
+        let nsec3 = Nsec3Proof::new(&denial, &zone);
+        if nsec3.is_too_expensive() {
+            return Ok(Security::Insecure);
+        }
+
+        let proven = match reply.header.fourth_byte.response_code {
+            RCode::NameError => {
+                nsec.proves_name_error(name) || nsec3.proves_name_error(name, &zone)
+            }
+            _ => {
+                nsec.proves_no_data(name, q_type)
+                    || nsec.proves_wildcard_no_data(name, q_type)
+                    || (!nsec3.is_empty() && nsec3.proves_no_data(name, q_type))
+                    || nsec3.proves_wildcard_no_data(name, q_type, &zone)
+            }
+        };
+        match proven {
+            true => Ok(Security::Secure),
+            false => anyhow::bail!(
+                "Missing proof of non-existence for {}",
+                labels_to_string(name)
+            ),
+        }
+    }
+
+    /// Trust of the closest zone enclosing `name`, walking down the zone cuts
+    /// from the closest trust anchor
+    fn trust_for(&mut self, name: &[DnsLabel], lookup: &mut Lookup) -> Result<ZoneTrust> {
+        let Some(anchor_depth) = self
+            .anchors
+            .iter()
+            .filter(|anchor| is_subdomain(name, &anchor.r_name))
+            .map(|anchor| anchor.r_name.len())
+            .max()
+        else {
+            return Ok(ZoneTrust::Insecure);
+        };
+
+        // resume from the deepest name whose trust is already known
+        let now = now();
+        let cached = (anchor_depth..=name.len()).rev().find_map(|depth| {
+            let key = labels_to_key(&name[name.len() - depth..]);
+            match self.cache.get(&key) {
+                Some((trust, expiration)) if *expiration > now => {
+                    Some((depth, trust.clone(), *expiration))
+                }
+                _ => None,
+            }
+        });
+        let (start, mut trust, mut expiration) = match cached {
+            Some(cached) => cached,
+            None => {
+                let anchor_zone = name[name.len() - anchor_depth..].to_vec();
+                let (trust, ttl) = self.anchor_trust(&anchor_zone, lookup)?;
+                let expiration = now.saturating_add(ttl);
+                self.remember(&anchor_zone, trust.clone(), expiration);
+                (anchor_depth, trust, expiration)
+            }
+        };
+
+        for depth in start + 1..=name.len() {
+            let ZoneTrust::Secure { zone, keys } = &trust else {
+                break;
+            };
+            let child = name[name.len() - depth..].to_vec();
+            let reply = lookup(&child, QType::Ds)?;
+            let ds_rrset: Vec<DnsAnswer> = reply
+                .answers
+                .iter()
+                .filter(|record| record.r_type == QType::Ds && labels_eq(&record.r_name, &child))
+                .cloned()
+                .collect();
+
+            // set when no zone cut can be below this name
+            let mut last = false;
+            if !ds_rrset.is_empty() {
+                verify_rrset(&ds_rrset, &reply.answers, zone, keys)?;
+                let (child_trust, ttl) = self.zone_keys(&child, &ds_rrset, &[], lookup)?;
+                let ttl = ds_rrset.iter().map(|r| r.ttl).fold(ttl, u32::min);
+                trust = child_trust;
+                expiration = expiration.min(now.saturating_add(ttl));
+            } else if reply.header.fourth_byte.response_code == RCode::NameError {
+                // nothing exists below, the current zone is the closest one
+                last = true;
+            } else if !reply.answers.is_empty() {
+                // a CNAME, this name can not be a zone cut
+            } else {
+                let denial = verified_denial(&reply, zone, keys)?;
+                let nsec = NsecProof::new(&denial);
+                let nsec3 = Nsec3Proof::new(&denial, zone);
+                if nsec3.is_too_expensive()
+                    || nsec.proves_insecure_delegation(&child)
+                    || nsec3.proves_insecure_delegation(&child)
+                {
+                    trust = ZoneTrust::Insecure;
+                }
+                last = nsec.proves_leaf(&child);
+            }
+            self.remember(&child, trust.clone(), expiration);
+            if last {
+                break;
+            }
+        }
+        Ok(trust)
+    }
+
+    /// Caches the trust of a name, forgetting the oldest name when full
+    fn remember(&mut self, name: &[DnsLabel], trust: ZoneTrust, expiration: u32) {
+        let key = labels_to_key(name);
+        if self
+            .cache
+            .insert(key.clone(), (trust, expiration))
+            .is_none()
+        {
+            self.cached.push_back(key);
+        }
+        if self.cache.len() > MAX_TRUST_ENTRIES {
+            if let Some(oldest) = self.cached.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+    }
+
+    fn anchor_trust(&self, zone: &[DnsLabel], lookup: &mut Lookup) -> Result<(ZoneTrust, u32)> {
+        let anchors: Vec<&DnsAnswer> = self
+            .anchors
+            .iter()
+            .filter(|anchor| labels_eq(&anchor.r_name, zone))
+            .collect();
+        let ds_rrset: Vec<DnsAnswer> = anchors
+            .iter()
+            .filter(|anchor| anchor.r_type == QType::Ds)
+            .map(|anchor| (*anchor).clone())
+            .collect();
+        let anchor_keys: Vec<Dnskey> = anchors
+            .iter()
+            .filter(|anchor| anchor.r_type == QType::Dnskey)
+            .map(|anchor| Dnskey::try_from(&anchor.r_data[..]))
+            .collect::<Result<Vec<_>>>()?;
+        self.zone_keys(zone, &ds_rrset, &anchor_keys, lookup)
+    }
+
+    /// Fetches the DNSKEY RRset of a zone and checks that it is signed by a key
+    /// matching one of the DS records or trusted keys. Returns the trust with its TTL.
+    fn zone_keys(
+        &self,
+        zone: &[DnsLabel],
+        ds_rrset: &[DnsAnswer],
+        trusted_keys: &[Dnskey],
+        lookup: &mut Lookup,
+    ) -> Result<(ZoneTrust, u32)> {
+        let ds_records: Vec<Ds> = ds_rrset
+            .iter()
+            .filter_map(|record| Ds::try_from(&record.r_data[..]).ok())
+            .filter(|ds| matches!(ds.digest_type, DIGEST_SHA1 | DIGEST_SHA256))
+            .collect();
+        let supported = |algorithm: u8| {
+            Dnskey {
+                flags: 0,
+                protocol: 3,
+                algorithm,
+                public_key: vec![],
+            }
+            .is_supported()
+        };
+        // unknown algorithms or digests make the zone insecure, not bogus
+        // https://datatracker.ietf.org/doc/html/rfc4035#section-5.2
+        if !ds_records.iter().any(|ds| supported(ds.algorithm))
+            && !trusted_keys.iter().any(Dnskey::is_supported)
+        {
+            let ttl = ds_rrset.iter().map(|r| r.ttl).min().unwrap_or_default();
+            return Ok((ZoneTrust::Insecure, ttl));
+        }
+
+        let reply = lookup(zone, QType::Dnskey)?;
+        let dnskey_rrset: Vec<DnsAnswer> = reply
+            .answers
+            .iter()
+            .filter(|record| record.r_type == QType::Dnskey && labels_eq(&record.r_name, zone))
+            .cloned()
+            .collect();
+        let keys: Vec<Dnskey> = dnskey_rrset
+            .iter()
+            .filter_map(|record| Dnskey::try_from(&record.r_data[..]).ok())
+            .filter(Dnskey::is_zone_key)
+            .collect();
+        let entry_keys: Vec<Dnskey> = keys
+            .iter()
+            .filter(|key| {
+                trusted_keys.contains(key)
+                    || ds_records.iter().any(|ds| {
+                        ds.key_tag == key.key_tag()
+                            && ds.algorithm == key.algorithm
+                            && Ds::from_dnskey(zone, key, ds.digest_type)
+                                .is_ok_and(|computed| computed.digest == ds.digest)
+                    })
+            })
+            .cloned()
+            .collect();
+        if entry_keys.is_empty() {
+            anyhow::bail!(
+                "No DNSKEY of {} matches its DS records or trust anchor",
+                labels_to_string(zone)
+            );
+        }
+        verify_rrset(&dnskey_rrset, &reply.answers, zone, &entry_keys)?;
+
+        let ttl = dnskey_rrset.iter().map(|r| r.ttl).min().unwrap_or_default();
+        Ok((
+            ZoneTrust::Secure {
+                zone: zone.to_vec(),
+                keys,
+            },
+            ttl,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{ed25519, p256};
+    use crate::dns::DnsRequest;
+    use crate::dns_class::QClass;
+    use crate::dns_label::labels_from_str;
+    use crate::dns_zone::Zone;
+    use crate::dnssec::{
+        Dnskey, ALGORITHM_ECDSAP256SHA256, ALGORITHM_ED25519, DNSKEY_SEP, DNSKEY_ZONE,
+    };
+    use crate::dnssec_key::{KeyTiming, PrivateKey, SigningKey};
+    use crate::dnssec_signer::OnlineSigner;
+    use crate::server::AuthoritativeZone;
+    use crate::zone_signer::ds_records;
+
+    const PARENT: &str = r#"
+$ORIGIN example.
+$TTL 3600
+@           SOA ns1 hostmaster 1 7200 3600 1209600 300
+            NS  ns1
+ns1         A   192.0.2.1
+www         A   192.0.2.2
+*.wild      A   192.0.2.6
+secure      NS  ns1.secure
+ns1.secure  A   192.0.2.3
+insecure    NS  ns1.insecure
+ns1.insecure A  192.0.2.4
+bogus       NS  ns1.bogus
+ns1.bogus   A   192.0.2.5
+"#;
+
+    fn child(origin: &str) -> String {
+        format!(
+            "$ORIGIN {}.\n$TTL 3600\n@ SOA ns1 hostmaster 1 7200 3600 1209600 300\n\
+             @ NS ns1\nns1 A 192.0.2.3\nwww A 192.0.2.10\n*.wild A 192.0.2.11\n",
+            origin
+        )
+    }
+
+    fn key(owner: &str, private_key: PrivateKey, flags: u16) -> SigningKey {
+        SigningKey {
+            owner: labels_from_str(owner),
+            dnskey: Dnskey {
+                flags,
+                protocol: 3,
+                algorithm: private_key.algorithm(),
+                public_key: private_key.public_key(),
+            },
+            private_key,
+            timing: KeyTiming::default(),
+        }
+    }
+
+    fn ed25519_key(owner: &str, seed: u8, flags: u16) -> Result<SigningKey> {
+        let private_key = PrivateKey::Ed25519(ed25519::PrivateKey::from_bytes(&[seed; 32])?);
+        Ok(key(owner, private_key, flags))
+    }
+
+    /// example. signed with NSEC, delegating to secure.example. (P-256, NSEC3),
+    /// insecure.example. (unsigned) and bogus.example. (DS of another key)
+    fn zones() -> Result<(Vec<AuthoritativeZone>, Vec<DnsAnswer>)> {
+        let ksk = ed25519_key("example", 1, DNSKEY_ZONE | DNSKEY_SEP)?;
+        let zsk = ed25519_key("example", 2, DNSKEY_ZONE)?;
+        let secure_key = key(
+            "secure.example",
+            PrivateKey::EcdsaP256(p256::PrivateKey::from_bytes(&[3; 32])?),
+            DNSKEY_ZONE | DNSKEY_SEP,
+        );
+        assert_eq!(secure_key.dnskey.algorithm, ALGORITHM_ECDSAP256SHA256);
+        let bogus_key = ed25519_key("bogus.example", 4, DNSKEY_ZONE | DNSKEY_SEP)?;
+        let other_key = ed25519_key("bogus.example", 5, DNSKEY_ZONE | DNSKEY_SEP)?;
+        assert_eq!(ksk.dnskey.algorithm, ALGORITHM_ED25519);
+
+        let mut parent =
+            Zone::from_records(labels_from_str("example"), master_file::parse(PARENT, &[])?)?;
+        for ds in ds_records(&[secure_key.clone(), other_key], 3600, 0)? {
+            parent.add_record(ds)?;
+        }
+        let mut zones = Vec::new();
+        let signer = OnlineSigner::new(&mut parent, vec![ksk.clone(), zsk], None, 86400)?;
+        zones.push(AuthoritativeZone {
+            zone: parent,
+            signer: Some(signer),
+        });
+        for (origin, key, nsec3) in [
+            ("secure.example", secure_key, Some((vec![0xaa, 0xbb], 1))),
+            ("bogus.example", bogus_key, None),
+        ] {
+            let mut zone = Zone::from_records(
+                labels_from_str(origin),
+                master_file::parse(&child(origin), &[])?,
+            )?;
+            let signer = OnlineSigner::new(&mut zone, vec![key], nsec3, 86400)?;
+            zones.push(AuthoritativeZone {
+                zone,
+                signer: Some(signer),
+            });
+        }
+        let insecure = Zone::from_records(
+            labels_from_str("insecure.example"),
+            master_file::parse(&child("insecure.example"), &[])?,
+        )?;
+        zones.push(AuthoritativeZone {
+            zone: insecure,
+            signer: None,
+        });
+        Ok((zones, ds_records(&[ksk], 3600, 0)?))
+    }
+
+    /// Answers from the most specific zone, the parent one for DS questions
+    fn ask(zones: &mut [AuthoritativeZone], name: &[DnsLabel], q_type: QType) -> DnsReply {
+        let question = DnsQuestion {
+            q_name: name.to_vec(),
+            q_type: q_type.clone(),
+            q_class: QClass::In,
+        };
+        let authoritative = zones
+            .iter_mut()
+            .filter(|authoritative| is_subdomain(name, &authoritative.zone.origin))
+            .filter(|authoritative| {
+                !(q_type == QType::Ds && labels_eq(name, &authoritative.zone.origin))
+            })
+            .max_by_key(|authoritative| authoritative.zone.origin.len())
+            .expect("the name is in one of the zones");
+        authoritative.answer(&DnsRequest::query(1, question, true))
+    }
+
+    fn validate(
+        validator: &mut Validator,
+        zones: &mut [AuthoritativeZone],
+        name: &str,
+        q_type: QType,
+        tamper: impl Fn(&mut DnsReply),
+    ) -> Security {
+        let name = labels_from_str(name);
+        let mut reply = ask(zones, &name, q_type.clone());
+        tamper(&mut reply);
+        let question = DnsQuestion {
+            q_name: name,
+            q_type,
+            q_class: QClass::In,
+        };
+        validator.validate(&question, &reply, &mut |name, q_type| {
+            Ok(ask(zones, name, q_type))
+        })
+    }
+
+    #[test]
+    fn test_validator() -> Result<()> {
+        let (mut zones, anchors) = zones()?;
+        let mut validator = Validator::new(anchors)?;
+        let zones = &mut zones;
+        let untouched = |_: &mut DnsReply| {};
+
+        for (name, q_type) in [
+            ("www.example", QType::A),
+            ("nope.example", QType::A),
+            ("www.example", QType::Mx),
+            ("secure.example", QType::Ds),
+            ("www.secure.example", QType::A),
+            ("nope.secure.example", QType::A),
+            ("www.secure.example", QType::Mx),
+        ] {
+            let security = validate(&mut validator, zones, name, q_type.clone(), untouched);
+            assert_eq!(security, Security::Secure, "{} {}", name, q_type);
+        }
+        assert_eq!(
+            validate(
+                &mut validator,
+                zones,
+                "www.insecure.example",
+                QType::A,
+                untouched
+            ),
+            Security::Insecure
+        );
+        assert!(matches!(
+            validate(
+                &mut validator,
+                zones,
+                "www.bogus.example",
+                QType::A,
+                untouched
+            ),
+            Security::Bogus(_)
+        ));
+
+        // altered data, stripped signatures and missing proofs
+        let altered = |reply: &mut DnsReply| reply.answers[0].r_data = vec![192, 0, 2, 99];
+        let unsigned = |reply: &mut DnsReply| reply.answers.retain(|r| r.r_type != QType::Rrsig);
+        let unproven = |reply: &mut DnsReply| reply.authorities.retain(|r| r.r_type == QType::Soa);
+        for tamper in [altered, unsigned] {
+            for name in ["www.example", "www.secure.example"] {
+                let security = validate(&mut validator, zones, name, QType::A, tamper);
+                assert!(matches!(security, Security::Bogus(_)), "{}", name);
+            }
+        }
+        for name in ["nope.example", "nope.secure.example"] {
+            let security = validate(&mut validator, zones, name, QType::A, unproven);
+            assert!(matches!(security, Security::Bogus(_)), "{}", name);
+        }
+
+        // wildcard NODATA: the name is denied and the wildcard has no MX
+        for (index, zone) in [(0, "example"), (1, "secure.example")] {
+            let wildcard = labels_from_str(&format!("*.wild.{}", zone));
+            let signer = zones[index].signer.as_mut().unwrap();
+            let wildcard_proof = signer.deny_type(&wildcard);
+            let wildcard_no_data = |reply: &mut DnsReply| {
+                reply.header.fourth_byte.response_code = RCode::NoError;
+                for record in &wildcard_proof {
+                    if !reply.authorities.contains(record) {
+                        reply.authorities.push(record.clone());
+                    }
+                }
+            };
+            let name = format!("host.wild.{}", zone);
+            let security = validate(&mut validator, zones, &name, QType::Mx, wildcard_no_data);
+            assert_eq!(security, Security::Secure, "{}", name);
+            // the wildcard has A records, and without its NSEC nothing is proven
+            let security = validate(&mut validator, zones, &name, QType::A, wildcard_no_data);
+            assert!(matches!(security, Security::Bogus(_)), "{}", name);
+            let without_wildcard = |reply: &mut DnsReply| {
+                reply.header.fourth_byte.response_code = RCode::NoError;
+                reply
+                    .authorities
+                    .retain(|record| !wildcard_proof.contains(record));
+            };
+            let security = validate(&mut validator, zones, &name, QType::Mx, without_wildcard);
+            assert!(matches!(security, Security::Bogus(_)), "{}", name);
+        }
+
+        // the walk down stops at a name without anything below it, and the
+        // trust cache is bounded
+        let mut validator = Validator::new(validator.anchors.clone())?;
+        let mut ds_lookups = 0;
+        let name = labels_from_str("a.b.www.example");
+        let reply = ask(zones, &name, QType::A);
+        let question = DnsQuestion {
+            q_name: name,
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let security = validator.validate(&question, &reply, &mut |name, q_type| {
+            ds_lookups += (q_type == QType::Ds) as u32;
+            Ok(ask(zones, name, q_type))
+        });
+        assert_eq!(security, Security::Secure);
+        assert_eq!(ds_lookups, 1);
+        for index in 0..MAX_TRUST_ENTRIES + 1 {
+            let name = labels_from_str(&format!("n{}.example", index));
+            validator.remember(&name, ZoneTrust::Insecure, u32::MAX);
+        }
+        assert_eq!(validator.cache.len(), MAX_TRUST_ENTRIES);
+        assert_eq!(validator.cached.len(), MAX_TRUST_ENTRIES);
+        assert!(!validator.cache.contains_key("example."));
+
+        // outside of the trust anchors nothing can be validated
+        let mut validator = Validator::new(master_file::parse("other. DS 1 15 2 00112233", &[])?)?;
+        assert_eq!(
+            validate(&mut validator, zones, "www.example", QType::A, untouched),
+            Security::Insecure
+        );
+        assert!(Validator::new(vec![]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Text encodings used by the presentation format of DNSSEC records
+use crate::Result;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let value = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[((value >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Whitespace is ignored so that multi-line master file values can be passed as is
+pub fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut accumulator: u32 = 0;
+    let mut nb_bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            break;
+        }
+        let Some(value) = BASE64_ALPHABET.iter().position(|x| *x == c) else {
+            anyhow::bail!("Invalid base64 character {}", c as char);
+        };
+        accumulator = (accumulator << 6) | value as u32;
+        nb_bits += 6;
+        if nb_bits >= 8 {
+            nb_bits -= 8;
+            bytes.push((accumulator >> nb_bits) as u8);
+            accumulator &= (1 << nb_bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+const BASE32HEX_ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+/// Base32 with the extended hex alphabet and no padding, used for NSEC3 owner names
+/// https://datatracker.ietf.org/doc/html/rfc4648#section-7
+pub fn base32hex_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut accumulator: u32 = 0;
+    let mut nb_bits = 0;
+    for byte in bytes {
+        accumulator = (accumulator << 8) | *byte as u32;
+        nb_bits += 8;
+        while nb_bits >= 5 {
+            nb_bits -= 5;
+            text.push(BASE32HEX_ALPHABET[((accumulator >> nb_bits) & 0x1f) as usize] as char);
+        }
+        accumulator &= (1 << nb_bits) - 1;
+    }
+    if nb_bits > 0 {
+        text.push(BASE32HEX_ALPHABET[((accumulator << (5 - nb_bits)) & 0x1f) as usize] as char);
+    }
+    text
+}
+
+pub fn base32hex_decode(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut accumulator: u32 = 0;
+    let mut nb_bits = 0;
+    for c in text.bytes() {
+        let Some(value) = BASE32HEX_ALPHABET
+            .iter()
+            .position(|x| *x == c.to_ascii_lowercase())
+        else {
+            anyhow::bail!("Invalid base32hex character {}", c as char);
+        };
+        accumulator = (accumulator << 5) | value as u32;
+        nb_bits += 5;
+        if nb_bits >= 8 {
+            nb_bits -= 8;
+            bytes.push((accumulator >> nb_bits) as u8);
+            accumulator &= (1 << nb_bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hex_decode(text: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        anyhow::bail!("Hex string should have an even number of digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// RRSIG timestamps are shown as YYYYMMDDHHmmSS in UTC
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-3.2
+pub fn format_timestamp(timestamp: u32) -> String {
+    let seconds = timestamp as i64;
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+
+    // inverse of days_from_civil
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Accepts the YYYYMMDDHHmmSS form or a plain number of seconds since epoch
+pub fn parse_timestamp(text: &str) -> Result<u32> {
+    if text.len() != 14 {
+        return Ok(text.parse::<u32>()?);
+    }
+    let field = |range: std::ops::Range<usize>| -> Result<i64> {
+        match text.get(range) {
+            Some(digits) if digits.bytes().all(|c| c.is_ascii_digit()) => Ok(digits.parse()?),
+            _ => anyhow::bail!("Invalid timestamp {}", text),
+        }
+    };
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        anyhow::bail!("Invalid timestamp {}", text);
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    // serial number arithmetic: the value wraps around every 136 years
+    Ok(seconds as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() -> Result<()> {
+        assert_eq!(base64_decode("Zm9v YmE=")?, b"fooba");
+        assert_eq!(base64_decode("Zm9vYmFy")?, b"foobar");
+        assert_eq!(hex_decode("00ff 1A")?, vec![0, 255, 26]);
+        assert!(hex_decode("abc").is_err());
+        assert_eq!(
+            base32hex_encode(&hex_decode("065368abeed7ec6e9feba96b8c8bc3e8b791f716")?),
+            "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(hex_encode(&[0, 255, 26]), "00ff1a");
+        assert_eq!(
+            hex_encode(&base32hex_decode("0P9MHAVEQVM6T7VBL5LOP2U3T2RP3TOM")?),
+            "065368abeed7ec6e9feba96b8c8bc3e8b791f716"
+        );
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-3.3
+        assert_eq!(parse_timestamp("20030322173103")?, 1048354263);
+        assert_eq!(format_timestamp(1048354263), "20030322173103");
+        assert_eq!(format_timestamp(0), "19700101000000");
+        assert_eq!(parse_timestamp("1048354263")?, 1048354263);
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! Local records answered before forwarding: names from hosts files and
+//! static records from the configuration, with the PTR records of their
+//! addresses. Static records override the hosts files for their names.
+//! The hosts files are read again when they change.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cache::CachedAnswer;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{labels_from_str, labels_to_bytes, labels_to_key, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::Result;
+
+/// TTL of the hosts file records, not cached so that edits are seen at once
+const HOSTS_TTL: u32 = 0;
+/// Time between checks of the modification time of the hosts files
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reverse lookup name of an address, under in-addr.arpa or ip6.arpa
+/// https://datatracker.ietf.org/doc/html/rfc3596#section-2.5
+pub fn reverse_name(address: &IpAddr) -> Vec<DnsLabel> {
+    let name = match address {
+        IpAddr::V4(address) => {
+            let octets = address.octets();
+            let octets = octets.iter().rev().map(u8::to_string);
+            format!("{}.in-addr.arpa", octets.collect::<Vec<_>>().join("."))
+        }
+        IpAddr::V6(address) => {
+            let octets = address.octets();
+            let nibbles = octets
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0xf, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble));
+            format!("{}.ip6.arpa", nibbles.collect::<Vec<_>>().join("."))
+        }
+    };
+    labels_from_str(&name)
+}
+
+fn record(r_name: Vec<DnsLabel>, r_type: QType, r_data: Vec<u8>) -> DnsAnswer {
+    DnsAnswer {
+        r_name,
+        r_type,
+        r_class: QClass::In,
+        ttl: HOSTS_TTL,
+        rd_length: r_data.len() as u16,
+        r_data,
+    }
+}
+
+/// Records of a hosts file, by lowercase owner name: an A or AAAA record for
+/// each name of a line, and a PTR record from the address to the first name
+/// it is given
+fn parse_hosts(content: &str) -> HashMap<String, Vec<DnsAnswer>> {
+    let mut records: HashMap<String, Vec<DnsAnswer>> = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(Ok(address)) = tokens.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        let (r_type, r_data) = match address {
+            IpAddr::V4(address) => (QType::A, address.octets().to_vec()),
+            IpAddr::V6(address) => (QType::Aaaa, address.octets().to_vec()),
+        };
+        let names: Vec<Vec<DnsLabel>> = tokens.map(labels_from_str).collect();
+        let Some(canonical) = names.first() else {
+            continue;
+        };
+        let reverse = reverse_name(&address);
+        records
+            .entry(labels_to_key(&reverse))
+            .or_insert_with(|| vec![record(reverse, QType::Ptr, labels_to_bytes(canonical))]);
+        for name in names {
+            let rrset = records.entry(labels_to_key(&name)).or_default();
+            if !rrset.iter().any(|existing| existing.r_data == r_data) {
+                rrset.push(record(name, r_type.clone(), r_data.clone()));
+            }
+        }
+    }
+    records
+}
+
+/// Last modification time of the file, none when it can not be read
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[derive(Debug)]
+struct HostsFile {
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub struct LocalRecords {
+    files: Vec<HostsFile>,
+    /// Records of the hosts files, by lowercase owner name
+    hosts: HashMap<String, Vec<DnsAnswer>>,
+    /// Records of the configuration, by lowercase owner name
+    statics: HashMap<String, Vec<DnsAnswer>>,
+    checked: Instant,
+}
+
+impl LocalRecords {
+    pub fn new(static_records: &[DnsAnswer]) -> Self {
+        let mut statics: HashMap<String, Vec<DnsAnswer>> = HashMap::new();
+        for record in static_records {
+            statics
+                .entry(labels_to_key(&record.r_name))
+                .or_default()
+                .push(record.clone());
+        }
+        // the addresses point back to the first name they are given, unless
+        // the configuration has records for their reverse names
+        for static_record in static_records {
+            let address = match static_record.r_type {
+                QType::A => <[u8; 4]>::try_from(static_record.r_data.as_slice()).map(IpAddr::from),
+                QType::Aaaa => {
+                    <[u8; 16]>::try_from(static_record.r_data.as_slice()).map(IpAddr::from)
+                }
+                _ => continue,
+            };
+            let Ok(address) = address else {
+                continue;
+            };
+            let reverse = reverse_name(&address);
+            statics.entry(labels_to_key(&reverse)).or_insert_with(|| {
+                let r_data = labels_to_bytes(&static_record.r_name);
+                let mut ptr = record(reverse, QType::Ptr, r_data);
+                ptr.ttl = static_record.ttl;
+                vec![ptr]
+            });
+        }
+        Self {
+            files: vec![],
+            hosts: HashMap::new(),
+            statics,
+            checked: Instant::now(),
+        }
+    }
+
+    pub fn load(hosts_files: &[String], static_records: &[DnsAnswer]) -> Result<Self> {
+        let mut local_records = Self::new(static_records);
+        local_records.files = hosts_files
+            .iter()
+            .map(|path| HostsFile {
+                path: path.clone(),
+                modified: modified(path),
+            })
+            .collect();
+        local_records.hosts = local_records.read_files()?;
+        Ok(local_records)
+    }
+
+    fn read_files(&self) -> Result<HashMap<String, Vec<DnsAnswer>>> {
+        let mut hosts: HashMap<String, Vec<DnsAnswer>> = HashMap::new();
+        for file in &self.files {
+            let content = std::fs::read_to_string(&file.path)
+                .map_err(|e| anyhow::anyhow!("Could not read hosts file {}: {}", file.path, e))?;
+            for (name, records) in parse_hosts(&content) {
+                // the first file giving a name wins, as for the lines of a file
+                hosts.entry(name).or_insert(records);
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// Reads the hosts files again when one of them changed since the last
+    /// read. The previous records are kept when they can not be read.
+    pub fn reload_if_changed(&mut self) {
+        if self.checked.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        let mut changed = false;
+        for file in self.files.iter_mut() {
+            let modified = modified(&file.path);
+            changed |= modified != file.modified;
+            file.modified = modified;
+        }
+        if !changed {
+            return;
+        }
+        match self.read_files() {
+            Ok(hosts) => self.hosts = hosts,
+            Err(e) => eprintln!("Kept the previous hosts records: {}", e),
+        }
+    }
+
+    pub fn contains(&self, name: &[DnsLabel]) -> bool {
+        let key = labels_to_key(name);
+        self.statics.contains_key(&key) || self.hosts.contains_key(&key)
+    }
+
+    /// Records of the type at the question name, or its CNAME, NODATA when
+    /// the name has none. None for names we have no records for.
+    pub fn answer(&self, question: &DnsQuestion) -> Option<CachedAnswer> {
+        let key = labels_to_key(&question.q_name);
+        let records = self.statics.get(&key).or_else(|| self.hosts.get(&key))?;
+        let answers = records
+            .iter()
+            .filter(|record| {
+                record.r_type == question.q_type
+                    || record.r_type == QType::Cname
+                    || question.q_type == QType::StarSign
+            })
+            .cloned()
+            .collect();
+        Some(CachedAnswer {
+            rcode: RCode::NoError,
+            answers,
+            authorities: vec![],
+            secure: false,
+            prefetch: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::master_file;
+
+    const HOSTS: &str = "
+127.0.0.1 localhost
+192.168.1.10 laptop.test foo.test   # dev box
+192.168.1.11 foo.test
+fe80::1 laptop.test
+";
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    #[test]
+    fn test_reverse_name() -> Result<()> {
+        assert_eq!(
+            labels_to_key(&reverse_name(&"192.0.2.1".parse()?)),
+            "1.2.0.192.in-addr.arpa."
+        );
+        let ipv6 = labels_to_key(&reverse_name(&"2001:db8::1".parse()?));
+        assert!(ipv6.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(ipv6.ends_with("8.b.d.0.1.0.0.2.ip6.arpa."));
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_records() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("hosts-test-{}", std::process::id()));
+        std::fs::write(&path, HOSTS)?;
+        let path = path.to_string_lossy().to_string();
+        let statics = master_file::parse("api.test. 60 IN CNAME laptop.test.", &[])?;
+        let mut local = LocalRecords::load(std::slice::from_ref(&path), &statics)?;
+
+        let answer = local.answer(&question("FOO.test", QType::A)).unwrap();
+        assert_eq!(answer.answers.len(), 2);
+        assert_eq!(answer.answers[0].r_data, vec![192, 168, 1, 10]);
+        let answer = local.answer(&question("laptop.test", QType::Aaaa)).unwrap();
+        assert_eq!(answer.answers.len(), 1);
+        let answer = local.answer(&question("foo.test", QType::Mx)).unwrap();
+        assert!(answer.answers.is_empty());
+        let answer = local.answer(&question("api.test", QType::A)).unwrap();
+        assert_eq!(answer.answers[0].r_type, QType::Cname);
+        assert!(local.answer(&question("other.test", QType::A)).is_none());
+
+        // the address points back to its first name
+        let answer = local
+            .answer(&question("10.1.168.192.in-addr.arpa", QType::Ptr))
+            .unwrap();
+        assert_eq!(
+            answer.answers[0].r_data,
+            labels_to_bytes(&labels_from_str("laptop.test"))
+        );
+
+        // and so do the static addresses, unless given their own PTR record
+        let statics = master_file::parse(
+            "static.test. 60 IN A 10.0.0.1\n\
+             other.test. 60 IN A 10.0.0.1\n\
+             static.test. 60 IN AAAA 2001:db8::1\n\
+             named.test. 60 IN A 10.0.0.3\n\
+             3.0.0.10.in-addr.arpa. 60 IN PTR configured.test.",
+            &[],
+        )?;
+        let statics = LocalRecords::new(&statics);
+        let answer = statics
+            .answer(&question("1.0.0.10.in-addr.arpa", QType::Ptr))
+            .unwrap();
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.answers[0].ttl, 60);
+        assert_eq!(
+            answer.answers[0].r_data,
+            labels_to_bytes(&labels_from_str("static.test"))
+        );
+        let reverse = reverse_name(&"2001:db8::1".parse()?);
+        assert!(statics.contains(&reverse));
+        let answer = statics
+            .answer(&question("3.0.0.10.in-addr.arpa", QType::Ptr))
+            .unwrap();
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(
+            answer.answers[0].r_data,
+            labels_to_bytes(&labels_from_str("configured.test"))
+        );
+
+        std::fs::write(&path, "10.0.0.2 new.test\n")?;
+        local.files[0].modified = None;
+        local.checked -= CHECK_INTERVAL;
+        local.reload_if_changed();
+        assert!(local.contains(&labels_from_str("new.test")));
+        assert!(!local.contains(&labels_from_str("foo.test")));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
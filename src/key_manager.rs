@@ -0,0 +1,469 @@
+//! Key management commands: `keygen` creates key pairs, `dsfromkey` prints
+//! the DS records of a key, and `keymgr` schedules the rollovers of a zone.
+//! ZSKs are rolled with the pre-publish method and KSKs with the
+//! double-signature method.
+//! https://datatracker.ietf.org/doc/html/rfc7583#section-3.2
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{labels_to_string, DnsLabel};
+use crate::dns_type::QType;
+use crate::dnssec::{Ds, ALGORITHM_ECDSAP256SHA256, ALGORITHM_ED25519, DIGEST_SHA1, DIGEST_SHA256};
+use crate::dnssec_key::SigningKey;
+use crate::dnssec_signer::now;
+use crate::encoding::format_timestamp;
+use crate::master_file::{self, parse_name, parse_ttl};
+use crate::zone_signer::write_records;
+use crate::Result;
+
+/// "13", "ECDSAP256SHA256", "15" or "ED25519"
+fn parse_algorithm(text: &str) -> Result<u8> {
+    match text.to_ascii_uppercase().as_str() {
+        "13" | "ECDSAP256SHA256" => Ok(ALGORITHM_ECDSAP256SHA256),
+        "15" | "ED25519" => Ok(ALGORITHM_ED25519),
+        _ => anyhow::bail!("Unsupported algorithm {}", text),
+    }
+}
+
+/// DS record of a key for the given digest type
+fn ds_record(key: &SigningKey, digest_type: u8, ttl: u32) -> Result<DnsAnswer> {
+    let r_data: Vec<u8> = Ds::from_dnskey(&key.owner, &key.dnskey, digest_type)?.into();
+    Ok(DnsAnswer {
+        r_name: key.owner.clone(),
+        r_type: QType::Ds,
+        r_class: QClass::In,
+        ttl,
+        rd_length: r_data.len() as u16,
+        r_data,
+    })
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyPolicy {
+    /// Algorithm of the generated keys
+    pub algorithm: u8,
+    pub zsk_lifetime: u32,
+    pub ksk_lifetime: u32,
+    /// TTL of the DNSKEY RRset
+    pub dnskey_ttl: u32,
+    /// TTL of the DS RRset in the parent zone
+    pub ds_ttl: u32,
+    /// Largest TTL of the zone, bounds how long old signatures stay in caches
+    pub max_zone_ttl: u32,
+    /// Time for a change of the zone to reach every name server
+    pub propagation_delay: u32,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: ALGORITHM_ECDSAP256SHA256,
+            zsk_lifetime: 90 * 86400,
+            ksk_lifetime: 365 * 86400,
+            dnskey_ttl: 3600,
+            ds_ttl: 86400,
+            max_zone_ttl: 86400,
+            propagation_delay: 3600,
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Time for a newly published DNSKEY to be known by every resolver
+    fn publish_safety(&self) -> u32 {
+        self.dnskey_ttl + self.propagation_delay
+    }
+
+    fn lifetime(&self, ksk: bool) -> u32 {
+        match ksk {
+            true => self.ksk_lifetime,
+            false => self.zsk_lifetime,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KeyManagerConfig {
+    pub origin: Vec<DnsLabel>,
+    /// Where the key files are kept
+    pub dir: String,
+    pub policy: KeyPolicy,
+}
+
+impl KeyManagerConfig {
+    /// --zone <origin>
+    /// --dir <dir>                         (defaults to the current directory)
+    /// --algorithm <13|ECDSAP256SHA256|15|ED25519>
+    /// --zsk-lifetime <duration>
+    /// --ksk-lifetime <duration>
+    /// --dnskey-ttl <duration>
+    /// --ds-ttl <duration>
+    /// --max-zone-ttl <duration>
+    /// --propagation-delay <duration>
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut origin = None;
+        let mut dir = ".".to_string();
+        let mut policy = KeyPolicy::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--zone" => origin = Some(parse_name(value()?, &[])?),
+                "--dir" => dir = value()?.to_string(),
+                "--algorithm" => policy.algorithm = parse_algorithm(value()?)?,
+                "--zsk-lifetime" => policy.zsk_lifetime = parse_ttl(value()?)?,
+                "--ksk-lifetime" => policy.ksk_lifetime = parse_ttl(value()?)?,
+                "--dnskey-ttl" => policy.dnskey_ttl = parse_ttl(value()?)?,
+                "--ds-ttl" => policy.ds_ttl = parse_ttl(value()?)?,
+                "--max-zone-ttl" => policy.max_zone_ttl = parse_ttl(value()?)?,
+                "--propagation-delay" => policy.propagation_delay = parse_ttl(value()?)?,
+                _ => anyhow::bail!("Unknown argument {}", arg),
+            }
+        }
+        let Some(origin) = origin else {
+            anyhow::bail!("--zone is required");
+        };
+        // a rollover must be over before the next one starts
+        for lifetime in [policy.zsk_lifetime, policy.ksk_lifetime] {
+            if lifetime <= 2 * (policy.publish_safety() + policy.ds_ttl + policy.max_zone_ttl) {
+                anyhow::bail!("Key lifetimes are too short for the configured TTLs");
+            }
+        }
+        Ok(Self {
+            origin,
+            dir,
+            policy,
+        })
+    }
+}
+
+/// Brings the keys of a zone to the state required at `now`: a KSK and a ZSK
+/// exist, and the successor of a key ending its lifetime is introduced in time.
+/// Changed and new keys are flagged in the returned list of indexes, along
+/// with messages describing what was done.
+///
+/// Pre-publish ZSK rollover: the successor is published, becomes active when
+/// the old key goes inactive, and the old key is removed once its signatures
+/// expired from caches.
+///
+/// Double-signature KSK rollover: the successor is published and signs the
+/// DNSKEY RRset right away, the DS is swapped at the parent once the new
+/// DNSKEY RRset is known everywhere, and the old key is removed once the old
+/// DS expired from caches.
+pub fn schedule(
+    origin: &[DnsLabel],
+    keys: &mut Vec<SigningKey>,
+    policy: &KeyPolicy,
+    now: u32,
+) -> Result<(Vec<usize>, Vec<String>)> {
+    let mut changed = Vec::new();
+    let mut messages = Vec::new();
+    for ksk in [true, false] {
+        let role = if ksk { "KSK" } else { "ZSK" };
+        // the newest key of the role, successors are activated after their predecessor
+        let latest = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| key.dnskey.is_ksk() == ksk)
+            .filter(|(_, key)| key.timing.delete.is_none_or(|delete| now < delete))
+            .max_by_key(|(_, key)| key.timing.activate.unwrap_or_default())
+            .map(|(index, _)| index);
+
+        let Some(index) = latest else {
+            keys.push(SigningKey::generate(
+                origin.to_vec(),
+                policy.algorithm,
+                ksk,
+                now,
+            )?);
+            changed.push(keys.len() - 1);
+            messages.push(format!(
+                "Generated {} {} for {}",
+                role,
+                keys[keys.len() - 1].key_tag(),
+                labels_to_string(origin)
+            ));
+            continue;
+        };
+
+        let current = &keys[index];
+        let activate = current
+            .timing
+            .activate
+            .or(current.timing.created)
+            .unwrap_or(now);
+        let retire = activate.saturating_add(policy.lifetime(ksk));
+        if current.timing.inactive.is_some() || now.saturating_add(policy.publish_safety()) < retire
+        {
+            continue;
+        }
+
+        // the successor must be known by resolvers before it takes over
+        let takeover = retire.max(now + policy.publish_safety());
+        let mut successor = SigningKey::generate(origin.to_vec(), policy.algorithm, ksk, now)?;
+        let current = &mut keys[index];
+        match ksk {
+            true => {
+                // both keys sign the DNSKEY RRset until the old DS is gone
+                let inactive = takeover + policy.ds_ttl + policy.propagation_delay;
+                current.timing.inactive = Some(inactive);
+                current.timing.delete = Some(inactive);
+                messages.push(format!(
+                    "KSK {} replaces KSK {}: submit its DS to the parent at {}, \
+                     the old DS can be removed then",
+                    successor.key_tag(),
+                    current.key_tag(),
+                    format_timestamp(takeover)
+                ));
+            }
+            false => {
+                successor.timing.activate = Some(takeover);
+                current.timing.inactive = Some(takeover);
+                current.timing.delete =
+                    Some(takeover + policy.max_zone_ttl + policy.propagation_delay);
+                messages.push(format!(
+                    "ZSK {} published, it replaces ZSK {} at {}",
+                    successor.key_tag(),
+                    current.key_tag(),
+                    format_timestamp(takeover)
+                ));
+            }
+        }
+        changed.push(index);
+        keys.push(successor);
+        changed.push(keys.len() - 1);
+    }
+    Ok((changed, messages))
+}
+
+/// DS records the parent should hold: those of the KSKs still signing the
+/// DNSKEY RRset, so that during a rollover the old DS stays next to the new
+/// one until the old KSK is retired
+fn ds_set(keys: &[SigningKey], ttl: u32, now: u32) -> Result<Vec<DnsAnswer>> {
+    keys.iter()
+        .filter(|key| key.dnskey.is_ksk())
+        .filter(|key| key.timing.inactive.is_none_or(|inactive| now < inactive))
+        .map(|key| ds_record(key, DIGEST_SHA256, ttl))
+        .collect()
+}
+
+/// Entry point of the `keygen` command
+/// --zone <origin> [--algorithm <algorithm>] [--ksk] [--dir <dir>]
+pub fn run_keygen(args: &[String]) -> Result<()> {
+    let mut origin = None;
+    let mut algorithm = ALGORITHM_ECDSAP256SHA256;
+    let mut ksk = false;
+    let mut dir = ".".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--zone" => origin = Some(parse_name(value()?, &[])?),
+            "--algorithm" => algorithm = parse_algorithm(value()?)?,
+            "--ksk" => ksk = true,
+            "--dir" => dir = value()?.to_string(),
+            _ => anyhow::bail!("Unknown argument {}", arg),
+        }
+    }
+    let Some(origin) = origin else {
+        anyhow::bail!("--zone is required");
+    };
+    let key = SigningKey::generate(origin, algorithm, ksk, now())?;
+    println!("{}", key.save(&dir)?);
+    Ok(())
+}
+
+/// Entry point of the `dsfromkey` command
+/// <key file> [--digest <1|2>]...
+pub fn run_dsfromkey(args: &[String]) -> Result<()> {
+    let mut prefixes = Vec::new();
+    let mut digest_types = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--digest" => {
+                let Some(value) = args.next() else {
+                    anyhow::bail!("{} needs a value", arg);
+                };
+                match value.parse::<u8>()? {
+                    digest_type @ (DIGEST_SHA1 | DIGEST_SHA256) => digest_types.push(digest_type),
+                    digest_type => anyhow::bail!("Unsupported digest type {}", digest_type),
+                }
+            }
+            prefix => prefixes.push(prefix.to_string()),
+        }
+    }
+    if prefixes.is_empty() {
+        anyhow::bail!("A key file is required");
+    }
+    if digest_types.is_empty() {
+        digest_types.push(DIGEST_SHA256);
+    }
+    for prefix in prefixes {
+        let key = SigningKey::load(&prefix)?;
+        for digest_type in &digest_types {
+            let record = ds_record(&key, *digest_type, 3600)?;
+            println!("{}", master_file::format_record(&record));
+        }
+    }
+    Ok(())
+}
+
+/// Entry point of the `keymgr` command, meant to be run periodically.
+/// New and rescheduled keys are written back to the key directory and the DS
+/// records the parent should hold are written to `dsset-<origin>`.
+pub fn run_keymgr(args: &[String]) -> Result<()> {
+    let config = KeyManagerConfig::from_args(args)?;
+    let mut keys = SigningKey::load_dir(&config.dir, &config.origin)?;
+    let (changed, messages) = schedule(&config.origin, &mut keys, &config.policy, now())?;
+    for index in changed {
+        keys[index].save(&config.dir)?;
+    }
+    for message in messages {
+        println!("{}", message);
+    }
+
+    let ds_records = ds_set(&keys, config.policy.ds_ttl, now())?;
+    let ds_output = std::path::Path::new(&config.dir)
+        .join(format!("dsset-{}", labels_to_string(&config.origin)));
+    write_records(&ds_output.to_string_lossy(), &ds_records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_key_manager_config() -> Result<()> {
+        let config = KeyManagerConfig::from_args(&args(
+            "--zone example --algorithm ED25519 --zsk-lifetime 30d --dnskey-ttl 2h",
+        ))?;
+        assert_eq!(config.origin, labels_from_str("example"));
+        assert_eq!(config.dir, ".");
+        assert_eq!(config.policy.algorithm, ALGORITHM_ED25519);
+        assert_eq!(config.policy.zsk_lifetime, 30 * 86400);
+        assert_eq!(config.policy.dnskey_ttl, 7200);
+        assert!(KeyManagerConfig::from_args(&args("--dir keys")).is_err());
+        assert!(KeyManagerConfig::from_args(&args("--zone example --zsk-lifetime 1d")).is_err());
+        assert!(KeyManagerConfig::from_args(&args("--zone example --algorithm 8")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_schedule_rollovers() -> Result<()> {
+        let origin = labels_from_str("example");
+        let policy = KeyPolicy {
+            algorithm: ALGORITHM_ED25519,
+            ..KeyPolicy::default()
+        };
+        let start = 1_000_000;
+
+        let mut keys = Vec::new();
+        let (changed, _) = schedule(&origin, &mut keys, &policy, start)?;
+        assert_eq!(changed, vec![0, 1]);
+        assert!(keys[0].dnskey.is_ksk() && !keys[1].dnskey.is_ksk());
+        assert!(keys.iter().all(|key| key.timing.is_active(start)));
+
+        // nothing to do in the middle of the lifetimes
+        let (changed, _) = schedule(&origin, &mut keys, &policy, start + 30 * 86400)?;
+        assert!(changed.is_empty());
+
+        // ZSK pre-publication: the successor is published before it signs
+        let zsk_retire = start + policy.zsk_lifetime;
+        let now = zsk_retire - policy.publish_safety();
+        let (changed, _) = schedule(&origin, &mut keys, &policy, now)?;
+        assert_eq!(changed, vec![1, 2]);
+        let (old, new) = (&keys[1].timing, &keys[2].timing);
+        assert!(new.is_published(now) && !new.is_active(now));
+        assert_eq!(old.inactive, Some(zsk_retire));
+        assert!(!old.is_active(zsk_retire) && new.is_active(zsk_retire));
+        // the old key stays published while its signatures can be cached
+        assert!(old.is_published(zsk_retire + policy.max_zone_ttl));
+        assert!(!old.is_published(zsk_retire + policy.max_zone_ttl + policy.propagation_delay));
+        let (changed, _) = schedule(&origin, &mut keys, &policy, now + 60)?;
+        assert!(changed.is_empty());
+
+        // KSK double signature, run late: the takeover is postponed
+        let now = start + policy.ksk_lifetime + 86400;
+        let (changed, messages) = schedule(&origin, &mut keys, &policy, now)?;
+        assert_eq!(changed[..2], [0, 3]);
+        assert!(keys[3].dnskey.is_ksk());
+        assert!(messages[0].starts_with(&format!("KSK {}", keys[3].key_tag())));
+        let (old, new) = (&keys[0].timing, &keys[3].timing);
+        assert!(old.is_active(now) && new.is_active(now));
+        let takeover = now + policy.publish_safety();
+        assert_eq!(
+            old.delete,
+            Some(takeover + policy.ds_ttl + policy.propagation_delay)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ds_set() -> Result<()> {
+        let origin = labels_from_str("example");
+        let policy = KeyPolicy {
+            algorithm: ALGORITHM_ED25519,
+            ..KeyPolicy::default()
+        };
+        let start = 1_000_000;
+        let mut keys = Vec::new();
+        schedule(&origin, &mut keys, &policy, start)?;
+        let ds_of = |key: &SigningKey| ds_record(key, DIGEST_SHA256, policy.ds_ttl);
+        let old = ds_of(&keys[0])?;
+        assert_eq!(ds_set(&keys, policy.ds_ttl, start)?, vec![old.clone()]);
+
+        // the old DS is kept while the old KSK signs, next to the new one
+        let now = start + policy.ksk_lifetime - policy.publish_safety();
+        schedule(&origin, &mut keys, &policy, now)?;
+        let new = ds_of(&keys[2])?;
+        assert!(keys[2].dnskey.is_ksk());
+        let both = vec![old.clone(), new.clone()];
+        assert_eq!(ds_set(&keys, policy.ds_ttl, now)?, both);
+        let inactive = keys[0].timing.inactive.unwrap();
+        assert_eq!(ds_set(&keys, policy.ds_ttl, inactive - 1)?, both);
+        assert_eq!(ds_set(&keys, policy.ds_ttl, inactive)?, vec![new]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load_keys() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("keymgr-test-{}", std::process::id()));
+        // left by a failed run of a process with the same ID
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let dir = dir.to_string_lossy().to_string();
+
+        let origin = labels_from_str("Example");
+        let mut keys = Vec::new();
+        for (algorithm, ksk) in [
+            (ALGORITHM_ECDSAP256SHA256, true),
+            (ALGORITHM_ED25519, false),
+        ] {
+            let mut key = SigningKey::generate(origin.clone(), algorithm, ksk, 1_000_000)?;
+            key.timing.inactive = Some(2_000_000);
+            let prefix = key.save(&dir)?;
+            assert!(prefix.ends_with(&format!("KExample.+{:03}+{:05}", algorithm, key.key_tag())));
+            keys.push(key);
+        }
+        let mut loaded = SigningKey::load_dir(&dir, &labels_from_str("example"))?;
+        loaded.sort_by_key(|key| !key.dnskey.is_ksk());
+        assert_eq!(loaded, keys);
+        assert!(SigningKey::load_dir(&dir, &labels_from_str("other"))?.is_empty());
+
+        let ds = ds_record(&keys[0], DIGEST_SHA1, 3600)?;
+        assert_eq!(Ds::try_from(&ds.r_data[..])?.digest.len(), 20);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,76 +1,102 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::net::UdpSocket;
+use std::thread;
+mod acl;
+mod blocklist;
+mod cache;
+mod chaos;
+mod config;
+mod crypto;
 mod dns;
+mod dns64;
 mod dns_answer;
 mod dns_class;
+mod dns_edns;
 mod dns_header;
 mod dns_label;
 mod dns_question;
 mod dns_type;
+mod dns_zone;
+mod dnssec;
+mod dnssec_chain;
+mod dnssec_key;
+mod dnssec_signer;
+mod dnssec_validator;
+mod encoding;
 mod error;
+mod hosts;
+mod key_manager;
+mod master_file;
+mod refresh;
+mod resolver;
+mod rpz;
+mod rrl;
+mod server;
+mod synthetic;
+mod upstream;
+mod view;
+mod zone_signer;
 
 pub use error::{Error, Result};
 
-use dns::{DnsReply, DnsRequest};
+use config::Config;
+use dns_edns::UDP_PAYLOAD_SIZE;
+use view::Views;
 
 fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    // the tools are subcommands rather than binaries under src/bin: a second
+    // binary would need `default-run` in Cargo.toml for `cargo run` to work
+    match args.get(1).map(String::as_str) {
+        Some("signzone") => return zone_signer::run(&args[2..]),
+        Some("keygen") => return key_manager::run_keygen(&args[2..]),
+        Some("dsfromkey") => return key_manager::run_dsfromkey(&args[2..]),
+        Some("keymgr") => return key_manager::run_keymgr(&args[2..]),
+        _ => {}
+    }
     // You can use print statements as follows for debugging, they'll be visible when running tests.
     println!("Logs from your program will appear here!");
-    let mut should_forward = false;
-    let args: Vec<String> = std::env::args().collect();
+    let config = Config::from_args(&args[1..])?;
 
-    // Uncomment this block to pass the first stage
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
+    let sockets = config
+        .listen
+        .iter()
+        .map(|address| UdpSocket::bind(address).expect("Failed to bind to address"))
+        .collect::<Vec<_>>();
 
-    let udp_socket_forwarder =
-        UdpSocket::bind("127.0.0.1:2054").expect("Failed to bind to address");
+    let views = Views::new(&config)?;
 
-    if args.len() == 3 && args[1] == "--resolver".to_string() {
-        should_forward = true;
-        let server_to_forward_to = args[2].to_string();
-        let server = server_to_forward_to.parse::<SocketAddr>()?;
-        udp_socket_forwarder.connect(server)?;
-    }
+    thread::scope(|scope| {
+        for udp_socket in sockets {
+            let views = &views;
+            scope.spawn(move || serve(udp_socket, views));
+        }
+    });
+    Ok(())
+}
 
-    let mut buf = [0; 512];
+/// Answers the requests received on the socket until it fails
+fn serve(udp_socket: UdpSocket, views: &Views) {
+    let listener = udp_socket.local_addr().expect("Bound socket has an address");
+    // requests with EDNS can be as large as the payload size we advertise
+    let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
 
     loop {
         // receives data and fill the buffer
         match udp_socket.recv_from(&mut buf) {
             Ok((size, source)) => {
-                let dns_request = DnsRequest::try_from(&buf[..size])?;
-                dbg!(&dns_request);
-                let response = match should_forward {
-                    false => {
-                        let dns_reply = DnsReply::try_from(dns_request)?;
-                        let response: Vec<u8> = dns_reply.into();
-                        response
-                    }
-                    true => {
-                        let dns_requests = dns_request.split_questions();
-                        let mut dns_replies = Vec::new();
-                        for req in dns_requests {
-                            let bytes: Vec<u8> = req.into();
-                            let mut new_buf = [0; 512];
-
-                            new_buf[..bytes.len()].copy_from_slice(&bytes);
-                            udp_socket_forwarder.send(&new_buf)?;
-
-                            let mut final_buf = [0; 512];
-                            udp_socket_forwarder.recv(&mut final_buf)?;
-                            let reply = DnsReply::try_from(&final_buf[..])?;
-
-                            dns_replies.push(reply);
-                        }
-                        let final_reply = DnsReply::merge_replies(&dns_replies);
-                        dbg!(&final_reply);
-                        let response: Vec<u8> = final_reply.into();
-                        response
+                let response = match views.handle(&buf[..size], source.ip(), listener) {
+                    Ok(Some(response)) => response,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("Error handling request from {}: {}", source, e);
+                        continue;
                     }
                 };
 
-                udp_socket
-                    .send_to(&response, source)
-                    .expect("Failed to send response");
+                // the client may be unreachable for a while, others are not
+                if let Err(e) = udp_socket.send_to(&response, source) {
+                    eprintln!("Error sending the response to {}: {}", source, e);
+                }
             }
             Err(e) => {
                 eprintln!("Error receiving data: {}", e);
@@ -78,5 +104,4 @@ fn main() -> Result<()> {
             }
         }
     }
-    Ok(())
 }
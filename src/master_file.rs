@@ -0,0 +1,660 @@
+//! Master file (zone file) parsing
+//! https://datatracker.ietf.org/doc/html/rfc1035#section-5
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{labels_from_str, labels_to_bytes, labels_to_string, DnsLabel};
+use crate::dns_type::QType;
+use crate::dnssec::{read_name, read_u16, read_u32, Dnskey, Ds, Nsec, Nsec3, Nsec3Param, Rrsig};
+use crate::encoding::{
+    base32hex_decode, base32hex_encode, base64_decode, base64_encode, format_timestamp, hex_decode,
+    hex_encode, parse_timestamp,
+};
+use crate::Result;
+
+/// TTL used when neither $TTL nor an explicit TTL is given
+const DEFAULT_TTL: u32 = 3600;
+
+/// A logical entry: the tokens of one record once parentheses are resolved
+struct Entry {
+    /// The line started with a blank, the previous owner is reused
+    inherits_owner: bool,
+    tokens: Vec<String>,
+    line_number: usize,
+}
+
+/// Splits the content in entries, handling comments, quotes and parentheses
+fn tokenize(content: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (index, line) in content.lines().enumerate() {
+        if current.is_none() {
+            current = Some(Entry {
+                inherits_owner: line.starts_with(|c: char| c.is_whitespace()),
+                tokens: vec![],
+                line_number: index + 1,
+            });
+        }
+        let entry = current.as_mut().expect("entry was just created");
+
+        let mut chars = line.chars();
+        let mut token = String::new();
+        let mut in_quotes = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    token.push(c);
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                }
+                '"' => {
+                    token.push(c);
+                    in_quotes = !in_quotes;
+                    if !in_quotes {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                }
+                _ if in_quotes => token.push(c),
+                ';' => break,
+                '(' | ')' => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                    depth += if c == '(' { 1 } else { -1 };
+                }
+                c if c.is_whitespace() => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                }
+                _ => token.push(c),
+            }
+        }
+        if in_quotes {
+            anyhow::bail!("Unterminated quote on line {}", index + 1);
+        }
+        if !token.is_empty() {
+            entry.tokens.push(token);
+        }
+        if depth < 0 {
+            anyhow::bail!("Unbalanced parenthesis on line {}", index + 1);
+        }
+        if depth == 0 {
+            let entry = current.take().expect("entry exists");
+            if !entry.tokens.is_empty() {
+                entries.push(entry);
+            }
+        }
+    }
+    if depth != 0 {
+        anyhow::bail!("Unbalanced parenthesis at end of file");
+    }
+    Ok(entries)
+}
+
+/// Absolute names end with a dot, others are relative to the origin
+pub fn parse_name(text: &str, origin: &[DnsLabel]) -> Result<Vec<DnsLabel>> {
+    if text == "@" {
+        return Ok(origin.to_vec());
+    }
+    let mut labels = labels_from_str(text);
+    if labels.iter().any(|label| label.label.len() > 63) {
+        anyhow::bail!("Label too long in {}", text);
+    }
+    if !text.ends_with('.') {
+        labels.extend(origin.iter().cloned());
+    }
+    Ok(labels)
+}
+
+/// TTLs are either plain seconds or BIND style durations such as 1h30m
+pub fn parse_ttl(text: &str) -> Result<u32> {
+    if let Ok(seconds) = text.parse::<u32>() {
+        return Ok(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => anyhow::bail!("Invalid duration {}", text),
+        };
+        if number.is_empty() {
+            anyhow::bail!("Invalid duration {}", text);
+        }
+        total += number.parse::<u32>()? * multiplier;
+        number.clear();
+    }
+    if !number.is_empty() {
+        anyhow::bail!("Invalid duration {}", text);
+    }
+    Ok(total)
+}
+
+fn class_mnemonic(r_class: &QClass) -> String {
+    match r_class {
+        QClass::In => "IN".to_string(),
+        QClass::Cs => "CS".to_string(),
+        QClass::Ch => "CH".to_string(),
+        QClass::Hs => "HS".to_string(),
+        QClass::StarSign => "ANY".to_string(),
+        QClass::Unknown(value) => format!("CLASS{}", value),
+    }
+}
+
+pub fn parse_class(text: &str) -> Option<QClass> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(QClass::In),
+        "CS" => Some(QClass::Cs),
+        "CH" => Some(QClass::Ch),
+        "HS" => Some(QClass::Hs),
+        _ => None,
+    }
+}
+
+/// Turns a possibly quoted token into a <character-string>
+fn character_string(token: &str) -> Result<Vec<u8>> {
+    let text = token
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(token);
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        // \DDD decimal escape or escaped character
+        let Some(next) = chars.next() else {
+            anyhow::bail!("Dangling escape in {}", token);
+        };
+        if next.is_ascii_digit() {
+            let digits: String = std::iter::once(next)
+                .chain(chars.by_ref().take(2))
+                .collect();
+            bytes.push(digits.parse::<u8>()?);
+        } else {
+            bytes.push(next as u8);
+        }
+    }
+    if bytes.len() > 255 {
+        anyhow::bail!("Character string longer than 255 bytes");
+    }
+    let mut encoded = vec![bytes.len() as u8];
+    encoded.extend(bytes);
+    Ok(encoded)
+}
+
+/// Quoted form of a <character-string>, the inverse of `character_string`
+fn quoted_string(bytes: &[u8]) -> String {
+    let mut text = String::from('"');
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(*byte as char);
+            }
+            0x20..=0x7e => text.push(*byte as char),
+            _ => {
+                let _ = write!(text, "\\{:03}", byte);
+            }
+        }
+    }
+    text.push('"');
+    text
+}
+
+fn parse_salt(token: &str) -> Result<Vec<u8>> {
+    match token {
+        "-" => Ok(vec![]),
+        salt => hex_decode(salt),
+    }
+}
+
+fn format_salt(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => "-".to_string(),
+        false => hex_encode(salt).to_ascii_uppercase(),
+    }
+}
+
+fn parse_types(tokens: &[String]) -> Result<Vec<QType>> {
+    tokens.iter().map(|token| token.parse::<QType>()).collect()
+}
+
+fn format_types(types: &[QType]) -> String {
+    types
+        .iter()
+        .map(|q_type| q_type.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn expect_tokens(tokens: &[String], count: usize, r_type: &QType) -> Result<()> {
+    if tokens.len() < count {
+        anyhow::bail!("{} record needs at least {} fields", r_type, count);
+    }
+    Ok(())
+}
+
+/// Converts the presentation form of the record data to wire format
+pub fn parse_r_data(r_type: &QType, tokens: &[String], origin: &[DnsLabel]) -> Result<Vec<u8>> {
+    // generic encoding: \# <length> <hex>
+    if tokens.first().map(String::as_str) == Some("\\#") {
+        expect_tokens(tokens, 2, r_type)?;
+        let r_data = hex_decode(&tokens[2..].concat())?;
+        if r_data.len() != tokens[1].parse::<usize>()? {
+            anyhow::bail!("Generic record data length mismatch");
+        }
+        return Ok(r_data);
+    }
+
+    let r_data = match r_type {
+        QType::A => {
+            expect_tokens(tokens, 1, r_type)?;
+            tokens[0].parse::<Ipv4Addr>()?.octets().to_vec()
+        }
+        QType::Aaaa => {
+            expect_tokens(tokens, 1, r_type)?;
+            tokens[0].parse::<Ipv6Addr>()?.octets().to_vec()
+        }
+        QType::Ns
+        | QType::Md
+        | QType::Mf
+        | QType::Cname
+        | QType::Mb
+        | QType::Mg
+        | QType::Mr
+        | QType::Ptr => {
+            expect_tokens(tokens, 1, r_type)?;
+            labels_to_bytes(&parse_name(&tokens[0], origin)?)
+        }
+        QType::Mx => {
+            expect_tokens(tokens, 2, r_type)?;
+            let mut bytes = tokens[0].parse::<u16>()?.to_be_bytes().to_vec();
+            bytes.extend(labels_to_bytes(&parse_name(&tokens[1], origin)?));
+            bytes
+        }
+        QType::Txt => {
+            expect_tokens(tokens, 1, r_type)?;
+            let mut bytes = Vec::new();
+            for token in tokens {
+                bytes.extend(character_string(token)?);
+            }
+            bytes
+        }
+        QType::Soa => {
+            expect_tokens(tokens, 7, r_type)?;
+            let mut bytes = labels_to_bytes(&parse_name(&tokens[0], origin)?);
+            bytes.extend(labels_to_bytes(&parse_name(&tokens[1], origin)?));
+            bytes.extend(tokens[2].parse::<u32>()?.to_be_bytes());
+            for token in &tokens[3..7] {
+                bytes.extend(parse_ttl(token)?.to_be_bytes());
+            }
+            bytes
+        }
+        QType::Dnskey => {
+            expect_tokens(tokens, 4, r_type)?;
+            Dnskey {
+                flags: tokens[0].parse()?,
+                protocol: tokens[1].parse()?,
+                algorithm: tokens[2].parse()?,
+                public_key: base64_decode(&tokens[3..].concat())?,
+            }
+            .into()
+        }
+        QType::Ds => {
+            expect_tokens(tokens, 4, r_type)?;
+            Ds {
+                key_tag: tokens[0].parse()?,
+                algorithm: tokens[1].parse()?,
+                digest_type: tokens[2].parse()?,
+                digest: hex_decode(&tokens[3..].concat())?,
+            }
+            .into()
+        }
+        QType::Rrsig => {
+            expect_tokens(tokens, 9, r_type)?;
+            Rrsig {
+                type_covered: tokens[0].parse()?,
+                algorithm: tokens[1].parse()?,
+                labels: tokens[2].parse()?,
+                original_ttl: parse_ttl(&tokens[3])?,
+                expiration: parse_timestamp(&tokens[4])?,
+                inception: parse_timestamp(&tokens[5])?,
+                key_tag: tokens[6].parse()?,
+                signer_name: parse_name(&tokens[7], origin)?,
+                signature: base64_decode(&tokens[8..].concat())?,
+            }
+            .into()
+        }
+        QType::Nsec => {
+            expect_tokens(tokens, 1, r_type)?;
+            Nsec {
+                next_domain: parse_name(&tokens[0], origin)?,
+                types: parse_types(&tokens[1..])?,
+            }
+            .into()
+        }
+        QType::Nsec3 => {
+            expect_tokens(tokens, 5, r_type)?;
+            Nsec3 {
+                hash_algorithm: tokens[0].parse()?,
+                flags: tokens[1].parse()?,
+                iterations: tokens[2].parse()?,
+                salt: parse_salt(&tokens[3])?,
+                next_hashed_owner: base32hex_decode(&tokens[4])?,
+                types: parse_types(&tokens[5..])?,
+            }
+            .into()
+        }
+        QType::Nsec3param => {
+            expect_tokens(tokens, 4, r_type)?;
+            Nsec3Param {
+                hash_algorithm: tokens[0].parse()?,
+                flags: tokens[1].parse()?,
+                iterations: tokens[2].parse()?,
+                salt: parse_salt(&tokens[3])?,
+            }
+            .into()
+        }
+        _ => anyhow::bail!("Presentation format of {} records is not supported", r_type),
+    };
+    Ok(r_data)
+}
+
+/// Presentation form of the record data. Types without a dedicated form, or
+/// with malformed data, use the generic encoding of RFC 3597.
+pub fn format_r_data(r_type: &QType, r_data: &[u8]) -> String {
+    let generic = || format!("\\# {} {}", r_data.len(), hex_encode(r_data));
+    let text: Result<String> = (|| {
+        let text = match r_type {
+            QType::A => Ipv4Addr::from(<[u8; 4]>::try_from(r_data)?).to_string(),
+            QType::Aaaa => Ipv6Addr::from(<[u8; 16]>::try_from(r_data)?).to_string(),
+            QType::Ns
+            | QType::Md
+            | QType::Mf
+            | QType::Cname
+            | QType::Mb
+            | QType::Mg
+            | QType::Mr
+            | QType::Ptr => labels_to_string(&read_name(r_data, 0)?.0),
+            QType::Mx => format!(
+                "{} {}",
+                read_u16(r_data, 0)?,
+                labels_to_string(&read_name(r_data, 2)?.0)
+            ),
+            QType::Txt => {
+                let mut strings = Vec::new();
+                let mut pos = 0;
+                while pos < r_data.len() {
+                    let end = pos + 1 + r_data[pos] as usize;
+                    let Some(bytes) = r_data.get(pos + 1..end) else {
+                        anyhow::bail!("Truncated character string");
+                    };
+                    strings.push(quoted_string(bytes));
+                    pos = end;
+                }
+                strings.join(" ")
+            }
+            QType::Soa => {
+                let (mname, pos) = read_name(r_data, 0)?;
+                let (rname, pos) = read_name(r_data, pos)?;
+                let mut text = format!("{} {}", labels_to_string(&mname), labels_to_string(&rname));
+                for i in 0..5 {
+                    let _ = write!(text, " {}", read_u32(r_data, pos + 4 * i)?);
+                }
+                text
+            }
+            QType::Dnskey => {
+                let dnskey = Dnskey::try_from(r_data)?;
+                format!(
+                    "{} {} {} {}",
+                    dnskey.flags,
+                    dnskey.protocol,
+                    dnskey.algorithm,
+                    base64_encode(&dnskey.public_key)
+                )
+            }
+            QType::Ds => {
+                let ds = Ds::try_from(r_data)?;
+                format!(
+                    "{} {} {} {}",
+                    ds.key_tag,
+                    ds.algorithm,
+                    ds.digest_type,
+                    hex_encode(&ds.digest).to_ascii_uppercase()
+                )
+            }
+            QType::Rrsig => {
+                let rrsig = Rrsig::try_from(r_data)?;
+                format!(
+                    "{} {} {} {} {} {} {} {} {}",
+                    rrsig.type_covered,
+                    rrsig.algorithm,
+                    rrsig.labels,
+                    rrsig.original_ttl,
+                    format_timestamp(rrsig.expiration),
+                    format_timestamp(rrsig.inception),
+                    rrsig.key_tag,
+                    labels_to_string(&rrsig.signer_name),
+                    base64_encode(&rrsig.signature)
+                )
+            }
+            QType::Nsec => {
+                let nsec = Nsec::try_from(r_data)?;
+                format!(
+                    "{} {}",
+                    labels_to_string(&nsec.next_domain),
+                    format_types(&nsec.types)
+                )
+            }
+            QType::Nsec3 => {
+                let nsec3 = Nsec3::try_from(r_data)?;
+                format!(
+                    "{} {} {} {} {} {}",
+                    nsec3.hash_algorithm,
+                    nsec3.flags,
+                    nsec3.iterations,
+                    format_salt(&nsec3.salt),
+                    base32hex_encode(&nsec3.next_hashed_owner).to_ascii_uppercase(),
+                    format_types(&nsec3.types)
+                )
+            }
+            QType::Nsec3param => {
+                let nsec3_param = Nsec3Param::try_from(r_data)?;
+                format!(
+                    "{} {} {} {}",
+                    nsec3_param.hash_algorithm,
+                    nsec3_param.flags,
+                    nsec3_param.iterations,
+                    format_salt(&nsec3_param.salt)
+                )
+            }
+            _ => generic(),
+        };
+        Ok(text)
+    })();
+    text.map(|text| text.trim_end().to_string())
+        .unwrap_or_else(|_| generic())
+}
+
+/// One line of master file with absolute names
+pub fn format_record(record: &DnsAnswer) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        labels_to_string(&record.r_name),
+        record.ttl,
+        class_mnemonic(&record.r_class),
+        record.r_type,
+        format_r_data(&record.r_type, &record.r_data)
+    )
+}
+
+/// Parses a master file, relative names are completed with `origin`
+/// unless an $ORIGIN directive overrides it
+pub fn parse(content: &str, origin: &[DnsLabel]) -> Result<Vec<DnsAnswer>> {
+    let mut origin = origin.to_vec();
+    let mut default_ttl: Option<u32> = None;
+    let mut last_owner: Option<Vec<DnsLabel>> = None;
+    let mut last_ttl: Option<u32> = None;
+    let mut records = Vec::new();
+
+    for entry in tokenize(content)? {
+        let add_context = |e: anyhow::Error| anyhow::anyhow!("line {}: {}", entry.line_number, e);
+        let tokens = &entry.tokens;
+
+        let directive = tokens[0].to_ascii_uppercase();
+        if directive.starts_with('$') && tokens.len() < 2 {
+            return Err(add_context(anyhow::anyhow!("{} needs a value", directive)));
+        }
+        match directive.as_str() {
+            "$ORIGIN" => {
+                origin = parse_name(&tokens[1], &origin).map_err(add_context)?;
+                continue;
+            }
+            "$TTL" => {
+                default_ttl = Some(parse_ttl(&tokens[1]).map_err(add_context)?);
+                continue;
+            }
+            _ if directive.starts_with('$') => {
+                return Err(add_context(anyhow::anyhow!(
+                    "Unsupported directive {}",
+                    directive
+                )));
+            }
+            _ => {}
+        }
+
+        let mut index = 0;
+        let r_name = if entry.inherits_owner {
+            match &last_owner {
+                Some(owner) => owner.clone(),
+                None => return Err(add_context(anyhow::anyhow!("No previous owner name"))),
+            }
+        } else {
+            index += 1;
+            parse_name(&tokens[0], &origin).map_err(add_context)?
+        };
+
+        // TTL and class are both optional and may come in any order
+        let mut ttl = None;
+        let mut r_class = QClass::In;
+        while index < tokens.len() {
+            if let Some(class) = parse_class(&tokens[index]) {
+                r_class = class;
+            } else if tokens[index].starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&tokens[index]).map_err(add_context)?);
+            } else {
+                break;
+            }
+            index += 1;
+        }
+        let Some(type_token) = tokens.get(index) else {
+            return Err(add_context(anyhow::anyhow!("Missing record type")));
+        };
+        let r_type: QType = type_token.parse().map_err(add_context)?;
+        let r_data = parse_r_data(&r_type, &tokens[index + 1..], &origin).map_err(add_context)?;
+
+        let ttl = ttl.or(default_ttl).or(last_ttl).unwrap_or(DEFAULT_TTL);
+        last_owner = Some(r_name.clone());
+        last_ttl = Some(ttl);
+        records.push(DnsAnswer {
+            r_name,
+            r_type,
+            r_class,
+            ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_master_file() -> Result<()> {
+        let content = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            7200 3600 1209600 300 )
+    IN  NS  ns1
+ns1     A   192.0.2.1
+www 60  IN  AAAA 2001:db8::1
+txt     TXT "hello world" "a\"b"
+"#;
+        let records = parse(content, &[])?;
+        assert_eq!(records.len(), 5);
+
+        assert_eq!(records[0].r_type, QType::Soa);
+        assert_eq!(records[0].r_name, labels_from_str("example.com"));
+        assert_eq!(records[0].ttl, 3600);
+        assert_eq!(
+            &records[0].r_data[records[0].r_data.len() - 4..],
+            &300u32.to_be_bytes()
+        );
+
+        assert_eq!(records[1].r_type, QType::Ns);
+        assert_eq!(records[1].r_name, labels_from_str("example.com"));
+        assert_eq!(
+            records[1].r_data,
+            labels_to_bytes(&labels_from_str("ns1.example.com"))
+        );
+
+        assert_eq!(records[2].r_data, vec![192, 0, 2, 1]);
+        assert_eq!(records[3].ttl, 60);
+        assert_eq!(records[3].r_type, QType::Aaaa);
+
+        let mut expected_txt = vec![11];
+        expected_txt.extend(b"hello world");
+        expected_txt.extend([3, b'a', b'"', b'b']);
+        assert_eq!(records[4].r_data, expected_txt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_record_roundtrip() -> Result<()> {
+        let content = r#"
+example.    300 IN  SOA ns1.example. hostmaster.example. 1 7200 3600 1209600 300
+example.    300 IN  MX  10 mail.example.
+example.    300 IN  TXT "a \"quoted\" \\ string" "\009"
+example.    300 IN  NSEC    a.example. NS SOA MX TXT RRSIG NSEC DNSKEY
+example.    300 IN  NSEC3PARAM  1 0 12 AABBCCDD
+0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example. 300 IN NSEC3 1 1 12 AABBCCDD (
+        2T7B4G4VSA5SMI47K61MV5BV1A22BOJR MX DNSKEY NS SOA NSEC3PARAM RRSIG )
+example.    300 IN  RRSIG   MX 13 1 300 20260101000000 20251201000000 12345 example. AAECAw==
+example.    300 IN  HINFO   \# 2 abcd
+"#;
+        let records = parse(content, &[])?;
+        assert_eq!(records.len(), 8);
+        for record in &records {
+            let line = format_record(record);
+            assert_eq!(parse(&line, &[])?, vec![record.clone()], "{}", line);
+        }
+        assert_eq!(
+            format_record(&records[6]),
+            "example.\t300\tIN\tRRSIG\tMX 13 1 300 20260101000000 20251201000000 12345 example. AAECAw=="
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Background queries to the upstream resolvers, used to refresh cache entries
+//! without making clients wait. Each query runs on its own thread, and the
+//! replies are picked up by the server between two requests, with what the
+//! upstreams did so that the server keeps measuring them.
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use crate::dns::DnsReply;
+use crate::dns_class::QClass;
+use crate::dns_label::labels_to_key;
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::resolver::Resolver;
+use crate::upstream::{exchange, query_request, Report};
+
+type RefreshKey = (String, QType, QClass);
+
+fn refresh_key(question: &DnsQuestion) -> RefreshKey {
+    (
+        labels_to_key(&question.q_name),
+        question.q_type.clone(),
+        question.q_class.clone(),
+    )
+}
+
+/// Where a question is refreshed
+#[derive(Debug)]
+pub enum RefreshRoute {
+    /// Upstreams of the forwarder of that index, in their order of preference
+    Forwarder(usize, Vec<(usize, SocketAddr)>),
+    /// Resolved from the root servers by a copy of the resolver, the zone
+    /// cuts it learns are not kept
+    Resolver(Box<Resolver>),
+}
+
+/// Reply of a refresh, none when it failed, and the report of the upstreams
+/// of the forwarder that was asked
+type Outcome = (DnsQuestion, Option<DnsReply>, Option<(usize, Report)>);
+
+/// Refreshes completed since the last call
+#[derive(Debug, Default)]
+pub struct Completed {
+    pub replies: Vec<(DnsQuestion, DnsReply)>,
+    /// Reports of the upstreams, by index of the forwarder they belong to
+    pub reports: Vec<(usize, Report)>,
+}
+
+#[derive(Debug)]
+pub struct Refresher {
+    /// Replies are validated by the server, the CD bit is set on the queries
+    /// and the DO bit on the recursive ones
+    checking_disabled: bool,
+    sender: Sender<Outcome>,
+    receiver: Receiver<Outcome>,
+    /// Questions whose refresh has not completed yet
+    pending: HashSet<RefreshKey>,
+}
+
+impl Refresher {
+    pub fn new(checking_disabled: bool) -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            checking_disabled,
+            sender,
+            receiver,
+            pending: HashSet::new(),
+        }
+    }
+
+    pub fn is_pending(&self, question: &DnsQuestion) -> bool {
+        self.pending.contains(&refresh_key(question))
+    }
+
+    /// Asks the question in the background, unless it is already being asked
+    pub fn refresh(&mut self, question: &DnsQuestion, route: RefreshRoute) {
+        if !self.pending.insert(refresh_key(question)) {
+            return;
+        }
+        let question = question.clone();
+        let sender = self.sender.clone();
+        let checking_disabled = self.checking_disabled;
+        thread::spawn(move || {
+            let outcome = match route {
+                RefreshRoute::Forwarder(index, order) => {
+                    let request = query_request(&question, checking_disabled);
+                    let (reply, report) = exchange(&order, &request);
+                    (question, reply.ok(), Some((index, report)))
+                }
+                RefreshRoute::Resolver(mut resolver) => {
+                    let reply = resolver.resolve(&question, checking_disabled).ok();
+                    (question, reply, None)
+                }
+            };
+            let _ = sender.send(outcome);
+        });
+    }
+
+    /// Replies of the refreshes completed since the last call, and the
+    /// reports of the forwarders they asked. Failed refreshes are forgotten
+    /// so that they can be tried again.
+    pub fn completed(&mut self) -> Completed {
+        let mut completed = Completed::default();
+        while let Ok((question, reply, report)) = self.receiver.try_recv() {
+            self.pending.remove(&refresh_key(&question));
+            completed.reports.extend(report);
+            if let Some(reply) = reply {
+                completed.replies.push((question, reply));
+            }
+        }
+        completed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::dns::DnsRequest;
+    use crate::dns_edns::UDP_PAYLOAD_SIZE;
+    use crate::dns_label::labels_from_str;
+    use crate::dns_zone::Zone;
+    use crate::master_file;
+    use crate::server::AuthoritativeZone;
+    use crate::upstream::Upstreams;
+    use crate::Result;
+
+    #[test]
+    fn test_refresher() -> Result<()> {
+        let zone = "example. 300 SOA ns1.example. hostmaster.example. 1 7200 3600 1209600 300\n\
+                    www.example. 300 A 192.0.2.2";
+        let mut authoritative = AuthoritativeZone {
+            zone: Zone::from_records(labels_from_str("example"), master_file::parse(zone, &[])?)?,
+            signer: None,
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let upstream = socket.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let request = DnsRequest::try_from(&buf[..size]).unwrap();
+                let reply: Vec<u8> = authoritative.answer(&request).into();
+                let _ = socket.send_to(&reply, source);
+            }
+        });
+
+        let mut upstreams = Upstreams::new(&[upstream])?;
+        let mut refresher = Refresher::new(false);
+        let question = DnsQuestion {
+            q_name: labels_from_str("www.example"),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let route = || RefreshRoute::Forwarder(0, upstreams.ordered_addresses());
+        refresher.refresh(&question, route());
+        refresher.refresh(&question, route());
+        assert!(refresher.is_pending(&question));
+
+        let mut completed = Completed::default();
+        for _ in 0..100 {
+            completed = refresher.completed();
+            if !completed.replies.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let Completed { replies, reports } = completed;
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.answers[0].r_data, vec![192, 0, 2, 2]);
+        assert!(!refresher.is_pending(&question));
+        // the round trip of the refresh is reported for the upstream
+        assert_eq!(reports.len(), 1);
+        for (_, report) in reports {
+            upstreams.apply(report);
+        }
+        Ok(())
+    }
+}
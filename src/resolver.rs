@@ -0,0 +1,725 @@
+//! Iterative resolution starting from the root servers: referrals are followed
+//! down to the servers authoritative for the name, their addresses are taken
+//! from the glue or resolved when missing, and CNAMEs are chased across zones.
+//! The delegations learnt on the way are cached, up to `MAX_DELEGATIONS`.
+//! https://datatracker.ietf.org/doc/html/rfc1034#section-5.3.3
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{is_subdomain, labels_eq, labels_to_key, labels_to_string, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dnssec::read_name;
+use crate::dnssec_signer::now;
+use crate::master_file;
+use crate::upstream::exchange_tcp;
+use crate::Result;
+
+const DNS_PORT: u16 = 53;
+/// Time each authoritative server has to answer, whatever it sends meanwhile
+const QUERY_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Bound on the referrals followed for a single name
+const MAX_REFERRALS: usize = 16;
+/// Bound on the CNAME chain chased across zones
+const MAX_CNAME_CHAIN: usize = 8;
+/// Bound on the nested resolutions of name server addresses
+const MAX_DEPTH: usize = 4;
+/// Bound on the minimised queries for a single name
+/// https://datatracker.ietf.org/doc/html/rfc9156#section-2.3
+const MAX_MINIMISE_COUNT: usize = 10;
+/// Minimised queries adding a single label before bigger steps are taken
+const MINIMISE_ONE_LAB: usize = 4;
+/// Bound on the cached delegations, the names asked can be made up
+const MAX_DELEGATIONS: usize = 10000;
+
+/// Zone cut, with the servers of the child zone
+#[derive(Debug, Clone)]
+struct Delegation {
+    zone: Vec<DnsLabel>,
+    /// Name server names
+    servers: Vec<Vec<DnsLabel>>,
+    /// From the glue, or resolved when needed
+    addresses: Vec<IpAddr>,
+    expires: u32,
+}
+
+/// What an authoritative server told us
+enum Step {
+    /// Final answer, positive or negative
+    Answer(DnsReply),
+    /// The name is in a child zone
+    Referral(Delegation),
+}
+
+/// Addresses held by the A and AAAA records
+fn addresses(records: &[DnsAnswer], name: Option<&[DnsLabel]>) -> Vec<IpAddr> {
+    records
+        .iter()
+        .filter(|record| name.is_none_or(|name| labels_eq(&record.r_name, name)))
+        .filter_map(|record| match record.r_type {
+            QType::A => <[u8; 4]>::try_from(&record.r_data[..])
+                .ok()
+                .map(IpAddr::from),
+            QType::Aaaa => <[u8; 16]>::try_from(&record.r_data[..])
+                .ok()
+                .map(IpAddr::from),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Targets of the NS records owned by `zone`
+fn name_servers(records: &[DnsAnswer], zone: &[DnsLabel]) -> Vec<Vec<DnsLabel>> {
+    records
+        .iter()
+        .filter(|record| record.r_type == QType::Ns && labels_eq(&record.r_name, zone))
+        .filter_map(|record| read_name(&record.r_data, 0).ok())
+        .map(|(target, _)| target)
+        .collect()
+}
+
+/// Labels of the next minimised name: one more label for the first queries,
+/// then the labels left are spread over the queries left
+fn minimised_len(asked: usize, target: usize, count: usize) -> usize {
+    let step = match count < MINIMISE_ONE_LAB {
+        true => 1,
+        false => {
+            let queries_left = MAX_MINIMISE_COUNT.saturating_sub(count).max(1);
+            target.saturating_sub(asked).div_ceil(queries_left)
+        }
+    };
+    (asked + step).min(target)
+}
+
+/// Sorts out the reply of a server of `zone`, none when the server is lame.
+/// The answer records outside of the zone are dropped, as the server has no
+/// authority over them: the CNAME targets it followed into other zones are
+/// resolved again.
+fn step(mut reply: DnsReply, question: &DnsQuestion, zone: &[DnsLabel]) -> Option<Step> {
+    reply
+        .answers
+        .retain(|record| is_subdomain(&record.r_name, zone));
+    reply.update_counts();
+    let rcode = &reply.header.fourth_byte.response_code;
+    if !matches!(rcode, RCode::NoError | RCode::NameError) {
+        return None;
+    }
+    let answered = reply
+        .answers
+        .iter()
+        .any(|record| labels_eq(&record.r_name, &question.q_name));
+    if rcode == &RCode::NameError || answered {
+        return Some(Step::Answer(reply));
+    }
+
+    let Some(cut) = reply
+        .authorities
+        .iter()
+        .find(|record| record.r_type == QType::Ns)
+        .map(|record| record.r_name.clone())
+    else {
+        return Some(Step::Answer(reply));
+    };
+    let closer =
+        cut.len() > zone.len() && is_subdomain(&cut, zone) && is_subdomain(&question.q_name, &cut);
+    if closer {
+        let servers = name_servers(&reply.authorities, &cut);
+        let ttl = reply
+            .authorities
+            .iter()
+            .filter(|record| record.r_type == QType::Ns)
+            .map(|record| record.ttl)
+            .min()
+            .unwrap_or_default();
+        // glue is only trusted for servers in the zone we asked
+        let addresses = servers
+            .iter()
+            .filter(|server| is_subdomain(server, zone))
+            .flat_map(|server| addresses(&reply.additionals, Some(server)))
+            .collect();
+        return Some(Step::Referral(Delegation {
+            zone: cut,
+            servers,
+            addresses,
+            expires: now().saturating_add(ttl),
+        }));
+    }
+    // a referral upward or sideways
+    match labels_eq(&cut, zone) {
+        true => Some(Step::Answer(reply)),
+        false => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    /// Port the authoritative servers listen on
+    port: u16,
+    /// Root servers, from the hints
+    root: Delegation,
+    /// Zone cuts learnt from referrals, by lowercase zone name
+    delegations: HashMap<String, Delegation>,
+    /// Cached zones, oldest first
+    learnt: VecDeque<String>,
+}
+
+impl Resolver {
+    /// The hints hold the NS records of the root and the addresses of those
+    /// servers, as in the `named.root` file
+    pub fn new(hints: &[DnsAnswer]) -> Result<Self> {
+        let servers = name_servers(hints, &[]);
+        let addresses: Vec<IpAddr> = servers
+            .iter()
+            .flat_map(|server| addresses(hints, Some(server)))
+            .collect();
+        if addresses.is_empty() {
+            anyhow::bail!("Root hints give no address for the root servers");
+        }
+        Ok(Self {
+            port: DNS_PORT,
+            root: Delegation {
+                zone: vec![],
+                servers,
+                addresses,
+                expires: u32::MAX,
+            },
+            delegations: HashMap::new(),
+            learnt: VecDeque::new(),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read root hints {}: {}", path, e))?;
+        let hints =
+            master_file::parse(&content, &[]).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        Self::new(&hints)
+    }
+
+    /// Final reply for the question, with the CNAME chain leading to the
+    /// answer. Without DO, the servers are asked without EDNS.
+    pub fn resolve(&mut self, question: &DnsQuestion, dnssec_ok: bool) -> Result<DnsReply> {
+        self.resolve_at_depth(question, dnssec_ok, 0)
+    }
+
+    /// Names of the name servers of the deepest known zone containing the name
+    pub fn name_servers_of(&self, name: &[DnsLabel]) -> Vec<Vec<DnsLabel>> {
+        let question = DnsQuestion {
+            q_name: name.to_vec(),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        self.closest_delegation(&question).servers
+    }
+
+    fn resolve_at_depth(
+        &mut self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DnsReply> {
+        if depth > MAX_DEPTH {
+            anyhow::bail!("Too many nested resolutions");
+        }
+        let mut chain = Vec::new();
+        let mut name = question.q_name.clone();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let current = DnsQuestion {
+                q_name: name.clone(),
+                ..question.clone()
+            };
+            let mut reply = self.resolve_name(&current, dnssec_ok, depth)?;
+
+            // the server may have followed part of the chain itself
+            let mut target = name.clone();
+            if question.q_type != QType::Cname {
+                for _ in 0..MAX_CNAME_CHAIN {
+                    let Some((next, _)) = reply
+                        .answers
+                        .iter()
+                        .find(|r| r.r_type == QType::Cname && labels_eq(&r.r_name, &target))
+                        .and_then(|cname| read_name(&cname.r_data, 0).ok())
+                    else {
+                        break;
+                    };
+                    target = next;
+                }
+            }
+            let answered = reply
+                .answers
+                .iter()
+                .any(|r| r.r_type == question.q_type && labels_eq(&r.r_name, &target));
+            let negative = reply.header.fourth_byte.response_code == RCode::NameError
+                || reply.authorities.iter().any(|r| r.r_type == QType::Soa);
+            chain.append(&mut reply.answers);
+            if answered || negative || labels_eq(&target, &name) {
+                reply.answers = chain;
+                reply.update_counts();
+                return Ok(reply);
+            }
+            name = target;
+        }
+        anyhow::bail!(
+            "CNAME chain of {} is too long",
+            labels_to_string(&question.q_name)
+        )
+    }
+
+    /// Follows the referrals from the closest known zone cut down to the
+    /// servers that answer for the name. The servers are only shown the name
+    /// down to a few labels below their zone, with the A type, until the zone
+    /// holding the name is found. Minimisation is given up for the name when
+    /// a server denies a name on the way, as broken servers do for empty
+    /// non-terminals, or does not answer the minimised query.
+    /// https://datatracker.ietf.org/doc/html/rfc9156#section-3
+    fn resolve_name(
+        &mut self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DnsReply> {
+        let target = question.q_name.len();
+        let mut delegation = self.closest_delegation(question);
+        let mut asked_len = delegation.zone.len();
+        let mut minimise = true;
+        let mut minimised_count = 0;
+        let mut referrals = 0;
+        loop {
+            let servers = self.server_addresses(&mut delegation, depth)?;
+            let next_len = minimised_len(asked_len, target, minimised_count);
+            let minimised = minimise && next_len < target;
+            let asked = match minimised {
+                true => {
+                    minimised_count += 1;
+                    DnsQuestion {
+                        q_name: question.q_name[target - next_len..].to_vec(),
+                        q_type: QType::A,
+                        q_class: question.q_class.clone(),
+                    }
+                }
+                false => question.clone(),
+            };
+            let step = servers.iter().find_map(|server| {
+                let reply = self.query(*server, &asked, dnssec_ok).ok()?;
+                step(reply, &asked, &delegation.zone)
+            });
+            match step {
+                Some(Step::Referral(next)) => {
+                    referrals += 1;
+                    if referrals > MAX_REFERRALS {
+                        anyhow::bail!(
+                            "Too many referrals for {}",
+                            labels_to_string(&question.q_name)
+                        );
+                    }
+                    self.remember(next.clone());
+                    asked_len = next.zone.len();
+                    delegation = next;
+                }
+                Some(Step::Answer(reply)) if !minimised => return Ok(reply),
+                Some(Step::Answer(reply))
+                    if reply.header.fourth_byte.response_code == RCode::NameError =>
+                {
+                    minimise = false
+                }
+                // the name asked is in the zone, one step further
+                Some(Step::Answer(_)) => asked_len = next_len,
+                None if minimised => minimise = false,
+                None => anyhow::bail!(
+                    "No server of {} answered for {}",
+                    labels_to_string(&delegation.zone),
+                    labels_to_string(&question.q_name)
+                ),
+            }
+        }
+    }
+
+    /// Deepest unexpired zone cut above the name. DS records are asked to
+    /// the parent side of the cut.
+    fn closest_delegation(&self, question: &DnsQuestion) -> Delegation {
+        let name = &question.q_name;
+        let longest = match question.q_type {
+            QType::Ds => name.len().saturating_sub(1),
+            _ => name.len(),
+        };
+        let now = now();
+        (1..=longest)
+            .rev()
+            .find_map(|depth| {
+                let zone = &name[name.len() - depth..];
+                self.delegations
+                    .get(&labels_to_key(zone))
+                    .filter(|delegation| delegation.expires > now)
+            })
+            .unwrap_or(&self.root)
+            .clone()
+    }
+
+    /// Caches a zone cut. When full, the expired cuts are forgotten, then the
+    /// oldest one.
+    fn remember(&mut self, delegation: Delegation) {
+        if self.delegations.len() >= MAX_DELEGATIONS {
+            let now = now();
+            self.delegations.retain(|_, cached| cached.expires > now);
+            let delegations = &self.delegations;
+            self.learnt.retain(|zone| delegations.contains_key(zone));
+        }
+        let zone = labels_to_key(&delegation.zone);
+        if self.delegations.insert(zone.clone(), delegation).is_none() {
+            self.learnt.push_back(zone);
+        }
+        if self.delegations.len() > MAX_DELEGATIONS {
+            if let Some(oldest) = self.learnt.pop_front() {
+                self.delegations.remove(&oldest);
+            }
+        }
+    }
+
+    /// Addresses of the servers of a zone, resolving the names of the servers
+    /// when the referral came without glue
+    fn server_addresses(
+        &mut self,
+        delegation: &mut Delegation,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>> {
+        if delegation.addresses.is_empty() {
+            for server in delegation.servers.clone() {
+                // a server inside the zone needs glue, resolving it would loop
+                if is_subdomain(&server, &delegation.zone) {
+                    continue;
+                }
+                let question = DnsQuestion {
+                    q_name: server,
+                    q_type: QType::A,
+                    q_class: QClass::In,
+                };
+                if let Ok(reply) = self.resolve_at_depth(&question, false, depth + 1) {
+                    delegation.addresses.extend(addresses(&reply.answers, None));
+                }
+                if !delegation.addresses.is_empty() {
+                    break;
+                }
+            }
+            if delegation.addresses.is_empty() {
+                anyhow::bail!(
+                    "No address for the servers of {}",
+                    labels_to_string(&delegation.zone)
+                );
+            }
+            self.remember(delegation.clone());
+        }
+        Ok(delegation
+            .addresses
+            .iter()
+            .map(|address| SocketAddr::new(*address, self.port))
+            .collect())
+    }
+
+    /// Non recursive query to an authoritative server. Only a reply with the
+    /// same source, ID and question is accepted, within `QUERY_TIMEOUT` of
+    /// the query.
+    fn query(
+        &self,
+        server: SocketAddr,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+    ) -> Result<DnsReply> {
+        let socket = match server {
+            SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+            SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+        };
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let packet_id = rand::random::<u16>();
+        let mut request = DnsRequest::query(packet_id, question.clone(), dnssec_ok);
+        request.header.third_byte.recursion_desired = false;
+        let bytes: Vec<u8> = request.clone().into();
+        socket.send_to(&bytes, server)?;
+
+        let mut buf = vec![0; request.max_udp_size()];
+        loop {
+            // the datagrams not matching the query do not extend the wait
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                anyhow::bail!("No reply from {} within {:?}", server, QUERY_TIMEOUT);
+            }
+            socket.set_read_timeout(Some(left))?;
+            let (size, source) = socket.recv_from(&mut buf)?;
+            if source != server {
+                continue;
+            }
+            let Ok(reply) = DnsReply::try_from(&buf[..size]) else {
+                continue;
+            };
+            let same_question = reply.questions.first().is_some_and(|asked| {
+                labels_eq(&asked.q_name, &question.q_name)
+                    && asked.q_type == question.q_type
+                    && asked.q_class == question.q_class
+            });
+            if reply.header.packet_id != packet_id || !same_question {
+                continue;
+            }
+            return match reply.header.third_byte.truncation {
+                true => exchange_tcp(server, &request),
+                false => Ok(reply),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+    use crate::dns_edns::UDP_PAYLOAD_SIZE;
+    use crate::dns_label::labels_from_str;
+    use crate::dns_zone::Zone;
+    use crate::refresh::{RefreshRoute, Refresher};
+    use crate::server::AuthoritativeZone;
+
+    const ROOT: &str = "
+. 3600 SOA ns.root. hostmaster.root. 1 7200 3600 1209600 300
+. 3600 NS ns.root.
+ns.root. 3600 A 127.0.0.1
+example. 3600 NS ns1.example.
+ns1.example. 3600 A 127.0.0.2
+test. 3600 NS ns.example.
+";
+
+    const EXAMPLE: &str = "
+example. 3600 SOA ns1.example. hostmaster.example. 1 7200 3600 1209600 300
+example. 3600 NS ns1.example.
+ns1.example. 3600 A 127.0.0.2
+ns.example. 3600 A 127.0.0.3
+www.example. 3600 A 192.0.2.2
+alias.example. 3600 CNAME www.test.
+www.deep.sub.example. 3600 A 192.0.2.4
+";
+
+    const TEST: &str = "
+test. 3600 SOA ns.example. hostmaster.test. 1 7200 3600 1209600 300
+test. 3600 NS ns.example.
+www.test. 3600 A 192.0.2.3
+";
+
+    /// Names asked to each stand-in
+    type QueryLog = Arc<Mutex<Vec<(IpAddr, String)>>>;
+
+    /// Serves a zone on a loopback address, in the background. A broken
+    /// server denies the names it has no data for, empty non-terminals too.
+    fn spawn_zone(
+        address: SocketAddr,
+        origin: &str,
+        records: &str,
+        broken: bool,
+        log: &QueryLog,
+    ) -> Result<()> {
+        let records = master_file::parse(records, &[])?;
+        let mut authoritative = AuthoritativeZone {
+            zone: Zone::from_records(labels_from_str(origin), records)?,
+            signer: None,
+        };
+        let socket = UdpSocket::bind(address)?;
+        let log = log.clone();
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let Ok(request) = DnsRequest::try_from(&buf[..size]) else {
+                    continue;
+                };
+                let name = labels_to_key(&request.questions[0].q_name);
+                log.lock().unwrap().push((address.ip(), name));
+                let mut reply = authoritative.answer(&request);
+                let no_data = reply.answers.is_empty()
+                    && !reply.authorities.iter().any(|r| r.r_type == QType::Ns);
+                if broken && no_data {
+                    reply.header.fourth_byte.response_code = RCode::NameError;
+                }
+                let reply: Vec<u8> = reply.into();
+                let _ = socket.send_to(&reply, source);
+            }
+        });
+        Ok(())
+    }
+
+    /// Root, TLD and leaf stand-ins on 127.0.0.1, .2 and .3, sharing a port
+    fn spawn_hierarchy(broken: bool) -> Result<(Resolver, QueryLog)> {
+        let log = QueryLog::default();
+        let port = UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        let address = |last| SocketAddr::from(([127, 0, 0, last], port));
+        spawn_zone(address(1), ".", ROOT, false, &log)?;
+        spawn_zone(address(2), "example", EXAMPLE, broken, &log)?;
+        spawn_zone(address(3), "test", TEST, false, &log)?;
+        let hints = master_file::parse(". 3600000 NS ns.root.\nns.root. 3600000 A 127.0.0.1", &[])?;
+        let mut resolver = Resolver::new(&hints)?;
+        resolver.port = port;
+        Ok((resolver, log))
+    }
+
+    /// Names asked to the stand-in at 127.0.0.`last`
+    fn asked(log: &QueryLog, last: u8) -> Vec<String> {
+        let server = IpAddr::from([127, 0, 0, last]);
+        log.lock()
+            .unwrap()
+            .iter()
+            .filter(|(address, _)| address == &server)
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    #[test]
+    fn test_resolver() -> Result<()> {
+        let (mut resolver, _) = spawn_hierarchy(false)?;
+
+        let reply = resolver.resolve(&question("www.example", QType::A), false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+        assert!(resolver.delegations.contains_key("example."));
+
+        // the CNAME leads to a zone whose server has no glue
+        let reply = resolver.resolve(&question("alias.example", QType::A), false)?;
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].r_type, QType::Cname);
+        assert_eq!(reply.answers[1].r_data, vec![192, 0, 2, 3]);
+        let test = &resolver.delegations["test."];
+        assert_eq!(test.addresses, vec![IpAddr::from([127, 0, 0, 3])]);
+
+        let reply = resolver.resolve(&question("nx.example", QType::A), false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NameError);
+        assert_eq!(reply.authorities[0].r_type, QType::Soa);
+
+        let reply = resolver.resolve(&question("www.test", QType::Aaaa), false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.authorities[0].r_type, QType::Soa);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_timeout() -> Result<()> {
+        // a server sending junk until the query gives up
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let address = server.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            let Ok((_, source)) = server.recv_from(&mut buf) else {
+                return;
+            };
+            while server.send_to(&[0; 12], source).is_ok() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let (resolver, _) = spawn_hierarchy(false)?;
+        let started = Instant::now();
+        assert!(resolver
+            .query(address, &question("www.example", QType::A), false)
+            .is_err());
+        assert!(started.elapsed() < QUERY_TIMEOUT + Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegation_bound() -> Result<()> {
+        let (mut resolver, _) = spawn_hierarchy(false)?;
+        let delegation = |name: &str, expires| Delegation {
+            zone: labels_from_str(name),
+            servers: vec![],
+            addresses: vec![],
+            expires,
+        };
+        resolver.remember(delegation("expired.example", 0));
+        for index in 0..MAX_DELEGATIONS {
+            resolver.remember(delegation(&format!("n{}.example", index), u32::MAX));
+        }
+        // the expired cut makes room first, then the oldest
+        assert_eq!(resolver.delegations.len(), MAX_DELEGATIONS);
+        assert!(!resolver.delegations.contains_key("expired.example."));
+        assert!(resolver.delegations.contains_key("n0.example."));
+        resolver.remember(delegation("new.example", u32::MAX));
+        assert_eq!(resolver.delegations.len(), MAX_DELEGATIONS);
+        assert_eq!(resolver.learnt.len(), MAX_DELEGATIONS);
+        assert!(!resolver.delegations.contains_key("n0.example."));
+        assert!(resolver.delegations.contains_key("new.example."));
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_out_of_zone() -> Result<()> {
+        let asked = question("alias.example", QType::A);
+        let mut reply = DnsReply::try_from(DnsRequest::query(1, asked.clone(), false))?;
+        reply.answers = master_file::parse(
+            "alias.example. 3600 CNAME www.test.\n\
+             www.test. 3600 A 192.0.2.66\n\
+             www.bank. 3600 A 192.0.2.66",
+            &[],
+        )?;
+        let Some(Step::Answer(reply)) = step(reply, &asked, &labels_from_str("example")) else {
+            panic!("the server answered");
+        };
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_type, QType::Cname);
+        assert_eq!(reply.header.answer_record_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_qname_minimisation() -> Result<()> {
+        let name = question("www.deep.sub.example", QType::A);
+        let (mut resolver, log) = spawn_hierarchy(false)?;
+        let reply = resolver.resolve(&name, false)?;
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 4]);
+        assert_eq!(asked(&log, 1), vec!["example."]);
+        assert_eq!(
+            asked(&log, 2),
+            vec!["sub.example.", "deep.sub.example.", "www.deep.sub.example."]
+        );
+
+        // the empty non-terminal is denied, the full name is asked instead
+        let (mut resolver, log) = spawn_hierarchy(true)?;
+        let reply = resolver.resolve(&name, false)?;
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 4]);
+        assert_eq!(
+            asked(&log, 2),
+            vec!["sub.example.", "www.deep.sub.example."]
+        );
+
+        assert_eq!(minimised_len(1, 3, 0), 2);
+        assert_eq!(minimised_len(4, 20, 4), 7);
+        assert_eq!(minimised_len(19, 20, 9), 20);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_from_the_root() -> Result<()> {
+        let (resolver, _) = spawn_hierarchy(false)?;
+        let mut refresher = Refresher::new(false);
+        let question = question("www.example", QType::A);
+        refresher.refresh(&question, RefreshRoute::Resolver(Box::new(resolver)));
+        let mut replies = vec![];
+        for _ in 0..100 {
+            replies = refresher.completed().replies;
+            if !replies.is_empty() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.answers[0].r_data, vec![192, 0, 2, 2]);
+        Ok(())
+    }
+}
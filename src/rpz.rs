@@ -0,0 +1,497 @@
+//! Response policy zones rewriting the answers of the recursive service.
+//! Policy zones are checked in order and the first one with a matching
+//! trigger decides. Within a zone, the client address comes first, then the
+//! question name, the addresses in the answer and the names of the name
+//! servers of the domain. The client and question triggers are applied
+//! before the question is resolved, so that blocked names are never asked,
+//! unless an earlier zone has triggers on the answer: the question is then
+//! resolved and all the triggers are checked in order on the reply.
+//! https://datatracker.ietf.org/doc/html/draft-vixie-dnsop-dns-rpz-00
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::acl::Cidr;
+use crate::cache::CachedAnswer;
+use crate::config::PolicyZoneConfig;
+use crate::dns::DnsReply;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_header::RCode;
+use crate::dns_label::{is_subdomain, labels_to_key, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dns_zone::Zone;
+use crate::dnssec::read_name;
+use crate::upstream::transfer;
+use crate::Result;
+
+/// Labels introducing the triggers other than the question name
+const CLIENT_IP_LABEL: &str = "rpz-client-ip";
+const IP_LABEL: &str = "rpz-ip";
+const NSDNAME_LABEL: &str = "rpz-nsdname";
+const NSIP_LABEL: &str = "rpz-nsip";
+
+/// What a policy does with the question
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyAction {
+    NxDomain,
+    NoData,
+    /// Answered as usual, the later policies are not applied
+    Passthru,
+    /// No response is sent
+    Drop,
+    /// The records of the policy answer the question
+    LocalData(Vec<DnsAnswer>),
+}
+
+impl PolicyAction {
+    /// Answer given instead of the resolved one, none for PASSTHRU and DROP
+    pub fn answer(&self, question: &DnsQuestion) -> Option<CachedAnswer> {
+        let mut answer = CachedAnswer {
+            rcode: RCode::NoError,
+            answers: vec![],
+            authorities: vec![],
+            secure: false,
+            prefetch: false,
+        };
+        match self {
+            Self::NxDomain => answer.rcode = RCode::NameError,
+            Self::NoData => {}
+            Self::Passthru | Self::Drop => return None,
+            // a CNAME answers any type
+            Self::LocalData(records) => {
+                answer.answers = records
+                    .iter()
+                    .filter(|record| {
+                        record.r_type == question.q_type
+                            || record.r_type == QType::Cname
+                            || question.q_type == QType::StarSign
+                    })
+                    .map(|record| DnsAnswer {
+                        r_name: question.q_name.clone(),
+                        ..record.clone()
+                    })
+                    .collect()
+            }
+        }
+        Some(answer)
+    }
+}
+
+/// Action of the records at a trigger name, none for unsupported actions.
+/// Special CNAME targets select the actions without data.
+fn action(records: &[DnsAnswer]) -> Option<PolicyAction> {
+    let records: Vec<DnsAnswer> = records
+        .iter()
+        .filter(|record| !matches!(record.r_type, QType::Rrsig | QType::Nsec | QType::Nsec3))
+        .cloned()
+        .collect();
+    let cname = records.iter().find(|record| record.r_type == QType::Cname);
+    let Some(cname) = cname else {
+        return match records.is_empty() {
+            true => None,
+            false => Some(PolicyAction::LocalData(records)),
+        };
+    };
+    let (target, _) = read_name(&cname.r_data, 0).ok()?;
+    match labels_to_key(&target).as_str() {
+        "." => Some(PolicyAction::NxDomain),
+        "*." => Some(PolicyAction::NoData),
+        "rpz-passthru." => Some(PolicyAction::Passthru),
+        "rpz-drop." => Some(PolicyAction::Drop),
+        target if target.starts_with("rpz-") => None,
+        _ => Some(PolicyAction::LocalData(vec![cname.clone()])),
+    }
+}
+
+/// Address prefix of an IP trigger, written as the prefix length followed by
+/// the reversed address: 24.0.2.0.192 is 192.0.2.0/24, and 48.zz.db8.2001 is
+/// 2001:db8::/48 with zz for the longest run of zero groups
+fn parse_prefix(labels: &[DnsLabel]) -> Option<Cidr> {
+    let (length, address) = labels.split_first()?;
+    let length = length.label.parse::<u8>().ok()?;
+    let parts: Vec<&str> = address
+        .iter()
+        .rev()
+        .map(|label| label.label.as_str())
+        .collect();
+
+    let ipv4 = parts.len() == 4 && parts.iter().all(|part| part.parse::<u8>().is_ok());
+    let address = match ipv4 {
+        true => parts.join(".").parse::<IpAddr>().ok()?,
+        false => {
+            let mut groups = Vec::new();
+            for part in &parts {
+                match *part {
+                    "zz" => groups.extend(std::iter::repeat_n(0, 9usize.checked_sub(parts.len())?)),
+                    part => groups.push(u16::from_str_radix(part, 16).ok()?),
+                }
+            }
+            let groups: [u16; 8] = groups.try_into().ok()?;
+            IpAddr::from(groups)
+        }
+    };
+    Cidr::new(address, length)
+}
+
+/// Triggers on a name, exact or on its subdomains with a wildcard
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: HashMap<String, PolicyAction>,
+    /// By the domain the wildcard is under
+    wildcards: HashMap<String, PolicyAction>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &[DnsLabel], action: PolicyAction) {
+        match name.split_first() {
+            Some((first, domain)) if first.label == "*" => {
+                self.wildcards.insert(labels_to_key(domain), action)
+            }
+            _ => self.exact.insert(labels_to_key(name), action),
+        };
+    }
+
+    /// Exact match, otherwise the wildcard of the closest enclosing domain
+    fn find(&self, name: &[DnsLabel]) -> Option<&PolicyAction> {
+        self.exact.get(&labels_to_key(name)).or_else(|| {
+            (1..=name.len()).find_map(|start| self.wildcards.get(&labels_to_key(&name[start..])))
+        })
+    }
+}
+
+/// Triggers on addresses, the longest matching prefix wins
+#[derive(Debug, Default)]
+struct IpTriggers(Vec<(Cidr, PolicyAction)>);
+
+impl IpTriggers {
+    fn find(&self, address: &IpAddr) -> Option<(u8, &PolicyAction)> {
+        self.0
+            .iter()
+            .filter(|(prefix, _)| prefix.contains(address))
+            .max_by_key(|(prefix, _)| prefix.length)
+            .map(|(prefix, action)| (prefix.length, action))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PolicyZone {
+    client_ips: IpTriggers,
+    q_names: NameTriggers,
+    response_ips: IpTriggers,
+    ns_names: NameTriggers,
+}
+
+impl PolicyZone {
+    /// Triggers of the zone data, the apex records and the unsupported
+    /// triggers are ignored
+    pub fn new(zone: &Zone) -> Self {
+        let mut policy_zone = Self::default();
+        let origin = &zone.origin;
+        for owner in zone.owner_names() {
+            if !is_subdomain(&owner, origin) || owner.len() == origin.len() {
+                continue;
+            }
+            let Some(action) = action(zone.node(&owner)) else {
+                eprintln!("Unsupported policy at {}", labels_to_key(&owner));
+                continue;
+            };
+            let trigger = &owner[..owner.len() - origin.len()];
+            let (kind, name) = trigger.split_last().expect("below the origin");
+            match kind.label.to_ascii_lowercase().as_str() {
+                CLIENT_IP_LABEL | IP_LABEL => {
+                    let Some(prefix) = parse_prefix(name) else {
+                        eprintln!("Invalid IP trigger {}", labels_to_key(&owner));
+                        continue;
+                    };
+                    let triggers = match kind.label.eq_ignore_ascii_case(IP_LABEL) {
+                        true => &mut policy_zone.response_ips,
+                        false => &mut policy_zone.client_ips,
+                    };
+                    triggers.0.push((prefix, action));
+                }
+                NSDNAME_LABEL => policy_zone.ns_names.insert(name, action),
+                NSIP_LABEL => eprintln!("Unsupported NSIP trigger {}", labels_to_key(&owner)),
+                _ => policy_zone.q_names.insert(trigger, action),
+            }
+        }
+        policy_zone
+    }
+
+    /// Action triggered by the client address or the question name
+    fn query_action(&self, question: &DnsQuestion, client: IpAddr) -> Option<&PolicyAction> {
+        self.client_ips
+            .find(&client)
+            .map(|(_, action)| action)
+            .or_else(|| self.q_names.find(&question.q_name))
+    }
+
+    /// Action triggered by the addresses in the answer, or by the names of
+    /// the name servers of the domain
+    fn reply_action(
+        &self,
+        addresses: &[IpAddr],
+        name_servers: &[Vec<DnsLabel>],
+    ) -> Option<&PolicyAction> {
+        addresses
+            .iter()
+            .filter_map(|address| self.response_ips.find(address))
+            .max_by_key(|(length, _)| *length)
+            .map(|(_, action)| action)
+            .or_else(|| {
+                name_servers
+                    .iter()
+                    .find_map(|name| self.ns_names.find(name))
+            })
+    }
+
+    fn has_reply_triggers(&self) -> bool {
+        !self.response_ips.0.is_empty()
+            || !self.ns_names.exact.is_empty()
+            || !self.ns_names.wildcards.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ResponsePolicy {
+    zones: Vec<PolicyZone>,
+}
+
+impl ResponsePolicy {
+    pub fn new(zones: Vec<PolicyZone>) -> Self {
+        Self { zones }
+    }
+
+    /// Loads the policy zones from their files or from their primaries
+    pub fn load(configs: &[PolicyZoneConfig]) -> Result<Self> {
+        let mut zones = Vec::new();
+        for config in configs {
+            let zone = match config {
+                PolicyZoneConfig::File(path) => Zone::load(path, None)?,
+                PolicyZoneConfig::Transfer { zone, primary } => {
+                    let records = transfer(*primary, zone).map_err(|e| {
+                        anyhow::anyhow!("Transfer of {} failed: {}", labels_to_key(zone), e)
+                    })?;
+                    Zone::from_records(zone.clone(), records)?
+                }
+            };
+            zones.push(PolicyZone::new(&zone));
+        }
+        Ok(Self::new(zones))
+    }
+
+    /// Action triggered by the client address or the question name, when
+    /// no earlier zone has triggers on the answer. None when the question
+    /// has to be resolved first.
+    pub fn query_action(&self, question: &DnsQuestion, client: IpAddr) -> Option<PolicyAction> {
+        for zone in &self.zones {
+            if let Some(action) = zone.query_action(question, client) {
+                return Some(action.clone());
+            }
+            if zone.has_reply_triggers() {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Action of the first zone with a trigger on the client address, the
+    /// question name, the addresses in the answer or the names of the name
+    /// servers of the domain
+    pub fn reply_action(
+        &self,
+        question: &DnsQuestion,
+        client: IpAddr,
+        dns_reply: &DnsReply,
+        name_servers: &[Vec<DnsLabel>],
+    ) -> Option<PolicyAction> {
+        let addresses: Vec<IpAddr> = dns_reply
+            .answers
+            .iter()
+            .filter_map(|record| match record.r_type {
+                QType::A => <[u8; 4]>::try_from(&record.r_data[..])
+                    .ok()
+                    .map(IpAddr::from),
+                QType::Aaaa => <[u8; 16]>::try_from(&record.r_data[..])
+                    .ok()
+                    .map(IpAddr::from),
+                _ => None,
+            })
+            .collect();
+        self.zones.iter().find_map(|zone| {
+            zone.query_action(question, client)
+                .or_else(|| zone.reply_action(&addresses, name_servers))
+                .cloned()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsRequest;
+    use crate::dns_class::QClass;
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+
+    const POLICY: &str = "
+@ 300 IN SOA ns.rpz.example. admin.rpz.example. 1 3600 600 86400 300
+@ 300 IN NS ns.rpz.example.
+bad.example 300 IN CNAME .
+*.bad.example 300 IN CNAME .
+ok.bad.example 300 IN CNAME rpz-passthru.
+empty.example 300 IN CNAME *.
+silent.example 300 IN CNAME rpz-drop.
+tcp.example 300 IN CNAME rpz-tcp-only.
+local.example 300 IN A 192.0.2.80
+local.example 300 IN TXT \"blocked\"
+alias.example 300 IN CNAME walled.garden.example.
+32.1.2.0.192.rpz-client-ip 300 IN CNAME rpz-drop.
+24.0.113.0.203.rpz-ip 300 IN CNAME .
+32.9.113.0.203.rpz-ip 300 IN CNAME rpz-passthru.
+48.zz.db8.2001.rpz-ip 300 IN CNAME *.
+ns.evil.example.rpz-nsdname 300 IN CNAME .
+";
+
+    fn policy_zone(records: &str) -> Result<PolicyZone> {
+        let origin = labels_from_str("rpz.example");
+        let zone = Zone::from_records(origin.clone(), master_file::parse(records, &origin)?)?;
+        Ok(PolicyZone::new(&zone))
+    }
+
+    fn policy() -> Result<ResponsePolicy> {
+        Ok(ResponsePolicy::new(vec![policy_zone(POLICY)?]))
+    }
+
+    fn question(name: &str, q_type: QType) -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type,
+            q_class: QClass::In,
+        }
+    }
+
+    fn reply_with(records: &str) -> Result<DnsReply> {
+        let mut reply = DnsReply {
+            header: DnsRequest::query(1, question("www.example", QType::A), false).header,
+            questions: vec![question("www.example", QType::A)],
+            answers: master_file::parse(records, &[])?,
+            authorities: vec![],
+            additionals: vec![],
+        };
+        reply.update_counts();
+        Ok(reply)
+    }
+
+    #[test]
+    fn test_parse_prefix() -> Result<()> {
+        let prefix = |name: &str| parse_prefix(&labels_from_str(name));
+        assert_eq!(prefix("24.0.2.0.192"), Some("192.0.2.0/24".parse()?));
+        assert_eq!(prefix("48.zz.db8.2001"), Some("2001:db8::/48".parse()?));
+        assert_eq!(prefix("128.1.zz.db8.2001"), Some("2001:db8::1".parse()?));
+        assert_eq!(prefix("33.0.2.0.192"), None);
+        assert_eq!(prefix("24.0.2.192"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_triggers() -> Result<()> {
+        let policy = policy()?;
+        let client: IpAddr = "198.51.100.1".parse()?;
+        let action = |name: &str| policy.query_action(&question(name, QType::A), client);
+        assert_eq!(action("bad.example"), Some(PolicyAction::NxDomain));
+        assert_eq!(action("www.BAD.example"), Some(PolicyAction::NxDomain));
+        assert_eq!(action("ok.bad.example"), Some(PolicyAction::Passthru));
+        assert_eq!(action("empty.example"), Some(PolicyAction::NoData));
+        assert_eq!(action("silent.example"), Some(PolicyAction::Drop));
+        assert_eq!(action("tcp.example"), None);
+        assert_eq!(action("www.example"), None);
+        let drop = policy.query_action(&question("www.example", QType::A), "192.0.2.1".parse()?);
+        assert_eq!(drop, Some(PolicyAction::Drop));
+
+        // local data is renamed to the question and filtered by type
+        let local = action("local.example").unwrap();
+        let answer = local.answer(&question("local.example", QType::A)).unwrap();
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.answers[0].r_data, vec![192, 0, 2, 80]);
+        let answer = local.answer(&question("local.example", QType::Mx)).unwrap();
+        assert!(answer.answers.is_empty());
+        let alias = action("alias.example").unwrap();
+        let answer = alias
+            .answer(&question("alias.example", QType::Aaaa))
+            .unwrap();
+        assert_eq!(answer.answers[0].r_type, QType::Cname);
+        assert_eq!(answer.answers[0].r_name, labels_from_str("alias.example"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_triggers() -> Result<()> {
+        let policy = policy()?;
+        let (question, client) = (question("www.example", QType::A), "198.51.100.1".parse()?);
+        let action = |records: &str| -> Result<_> {
+            Ok(policy.reply_action(&question, client, &reply_with(records)?, &[]))
+        };
+        assert_eq!(action("www.example. 60 IN A 192.0.2.1")?, None);
+        assert_eq!(
+            action("www.example. 60 IN A 203.0.113.8")?,
+            Some(PolicyAction::NxDomain)
+        );
+        // the longest prefix wins
+        assert_eq!(
+            action("www.example. 60 IN A 203.0.113.9")?,
+            Some(PolicyAction::Passthru)
+        );
+        assert_eq!(
+            action("www.example. 60 IN AAAA 2001:db8::5")?,
+            Some(PolicyAction::NoData)
+        );
+
+        let reply = reply_with("www.example. 60 IN A 192.0.2.1")?;
+        let name_servers = [labels_from_str("ns.EVIL.example")];
+        assert_eq!(
+            policy.reply_action(&question, client, &reply, &name_servers),
+            Some(PolicyAction::NxDomain)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_zone_order() -> Result<()> {
+        const ANSWER_ZONE: &str = "
+@ 300 IN SOA ns.rpz.example. admin.rpz.example. 1 3600 600 86400 300
+24.0.113.0.203.rpz-ip 300 IN CNAME *.
+";
+        const NAME_ZONE: &str = "
+@ 300 IN SOA ns.rpz.example. admin.rpz.example. 1 3600 600 86400 300
+www.example 300 IN CNAME .
+";
+        let question = question("www.example", QType::A);
+        let client: IpAddr = "198.51.100.1".parse()?;
+        let listed = reply_with("www.example. 60 IN A 203.0.113.8")?;
+        let unlisted = reply_with("www.example. 60 IN A 192.0.2.1")?;
+
+        // the answer triggers of the first zone come before the question
+        // name triggers of the second one, the question is resolved first
+        let policy = ResponsePolicy::new(vec![policy_zone(ANSWER_ZONE)?, policy_zone(NAME_ZONE)?]);
+        assert_eq!(policy.query_action(&question, client), None);
+        assert_eq!(
+            policy.reply_action(&question, client, &listed, &[]),
+            Some(PolicyAction::NoData)
+        );
+        assert_eq!(
+            policy.reply_action(&question, client, &unlisted, &[]),
+            Some(PolicyAction::NxDomain)
+        );
+
+        let policy = ResponsePolicy::new(vec![policy_zone(NAME_ZONE)?, policy_zone(ANSWER_ZONE)?]);
+        assert_eq!(
+            policy.query_action(&question, client),
+            Some(PolicyAction::NxDomain)
+        );
+        assert_eq!(
+            policy.reply_action(&question, client, &listed, &[]),
+            Some(PolicyAction::NxDomain)
+        );
+        Ok(())
+    }
+}
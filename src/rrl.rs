@@ -0,0 +1,255 @@
+//! Response rate limiting, so that the UDP service can not be used to
+//! reflect and amplify traffic toward a spoofed address. Responses are
+//! accounted per client prefix and response class. Each account earns the
+//! configured number of responses per second, and once it is spent the
+//! responses are dropped, except one in `slip` that is sent truncated so
+//! that legitimate clients retry over TCP. An account can go into debt for
+//! `window` seconds of responses, so a flood keeps being limited for up to
+//! the window after it stops.
+//! https://kb.isc.org/docs/aa-00994
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+use crate::dns_header::RCode;
+
+/// Prefixes clients are grouped by, as a client can use any address of its
+/// network
+const IPV4_PREFIX: u32 = 24;
+const IPV6_PREFIX: u32 = 56;
+/// Accounts kept at most, the oldest are forgotten beyond, even in debt
+const MAX_ACCOUNTS: usize = 100000;
+
+/// Responses accounted separately, so that a flood of errors does not limit
+/// the answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseClass {
+    Answer,
+    NxDomain,
+    Error,
+}
+
+impl From<&RCode> for ResponseClass {
+    fn from(rcode: &RCode) -> Self {
+        match rcode {
+            RCode::NoError => Self::Answer,
+            RCode::NameError => Self::NxDomain,
+            _ => Self::Error,
+        }
+    }
+}
+
+/// What to do with a response
+#[derive(Debug, PartialEq)]
+pub enum RateLimit {
+    Send,
+    /// Sent truncated, without records
+    Slip,
+    Drop,
+}
+
+/// Network of the client: the address with the bits after the prefix cleared
+fn client_prefix(client: &IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX << (32 - IPV4_PREFIX);
+            IpAddr::from((u32::from(*address) & mask).to_be_bytes())
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX << (128 - IPV6_PREFIX);
+            IpAddr::from((u128::from(*address) & mask).to_be_bytes())
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Account {
+    /// Responses left, negative once they are limited
+    balance: f64,
+    updated: Instant,
+    /// Responses limited so far, every `slip`th one is truncated
+    limited: u32,
+}
+
+/// Accounts are kept in two generations: the accounts used since the last
+/// rotation, and the ones used during the generation before, moved to the
+/// current one when used again. The generations rotate once an account
+/// unused for a whole generation is back to full credit, so forgetting it
+/// changes nothing, or earlier when the current one is full. Each check is
+/// then O(1), the previous generation being dropped at once.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    current: HashMap<(IpAddr, ResponseClass), Account>,
+    previous: HashMap<(IpAddr, ResponseClass), Account>,
+    rotated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            current: HashMap::new(),
+            previous: HashMap::new(),
+            rotated: Instant::now(),
+        }
+    }
+
+    /// Accounts a response of the class to the client
+    pub fn check(&mut self, client: &IpAddr, class: ResponseClass, now: Instant) -> RateLimit {
+        let rate = self.config.responses_per_second as f64;
+        let floor = -rate * self.config.window as f64;
+        // the debt is paid back in `window` seconds, the full credit one later
+        let generation = Duration::from_secs(self.config.window as u64 + 1);
+        if now.saturating_duration_since(self.rotated) >= generation
+            || self.current.len() >= MAX_ACCOUNTS / 2
+        {
+            self.previous = std::mem::take(&mut self.current);
+            self.rotated = now;
+        }
+
+        let key = (client_prefix(client), class);
+        let account = match self.current.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.previous.remove(&key).unwrap_or(Account {
+                balance: rate,
+                updated: now,
+                limited: 0,
+            })),
+        };
+        let elapsed = now.saturating_duration_since(account.updated);
+        account.balance = (account.balance + elapsed.as_secs_f64() * rate).min(rate);
+        account.updated = now;
+        account.balance = (account.balance - 1.0).max(floor);
+        if account.balance >= 0.0 {
+            account.limited = 0;
+            return RateLimit::Send;
+        }
+
+        account.limited += 1;
+        let slip = self.config.slip;
+        match slip > 0 && account.limited.is_multiple_of(slip) {
+            true => RateLimit::Slip,
+            false => RateLimit::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+
+    #[test]
+    fn test_rate_limiter() -> Result<()> {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            responses_per_second: 5,
+            window: 2,
+            slip: 2,
+        });
+        let start = Instant::now();
+        let client: IpAddr = "198.51.100.7".parse()?;
+        let neighbour: IpAddr = "198.51.100.200".parse()?;
+        let other: IpAddr = "203.0.113.1".parse()?;
+
+        for _ in 0..5 {
+            assert_eq!(
+                limiter.check(&client, ResponseClass::Answer, start),
+                RateLimit::Send
+            );
+        }
+        // the same /24 shares the account, every other limited response slips
+        assert_eq!(
+            limiter.check(&neighbour, ResponseClass::Answer, start),
+            RateLimit::Drop
+        );
+        assert_eq!(
+            limiter.check(&client, ResponseClass::Answer, start),
+            RateLimit::Slip
+        );
+        assert_eq!(
+            limiter.check(&client, ResponseClass::Answer, start),
+            RateLimit::Drop
+        );
+        assert_eq!(
+            limiter.check(&client, ResponseClass::NxDomain, start),
+            RateLimit::Send
+        );
+        assert_eq!(
+            limiter.check(&other, ResponseClass::Answer, start),
+            RateLimit::Send
+        );
+
+        // the debt is bounded by the window: 10 responses here
+        for _ in 0..100 {
+            limiter.check(&client, ResponseClass::Answer, start);
+        }
+        let later = start + Duration::from_millis(1900);
+        assert_ne!(
+            limiter.check(&client, ResponseClass::Answer, later),
+            RateLimit::Send
+        );
+        let later = start + Duration::from_millis(2500);
+        assert_eq!(
+            limiter.check(&client, ResponseClass::Answer, later),
+            RateLimit::Send
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generations() -> Result<()> {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            responses_per_second: 1,
+            window: 2,
+            slip: 0,
+        });
+        let start = limiter.rotated;
+        let client: IpAddr = "198.51.100.7".parse()?;
+        for _ in 0..3 {
+            limiter.check(&client, ResponseClass::Answer, start);
+        }
+
+        // the account in debt is carried over to the next generation
+        let later = start + Duration::from_secs(3);
+        assert_eq!(
+            limiter.check(&client, ResponseClass::Answer, later),
+            RateLimit::Send
+        );
+        assert_eq!(
+            limiter.check(&client, ResponseClass::Answer, later),
+            RateLimit::Drop
+        );
+        assert_eq!(limiter.previous.len(), 0);
+        assert_eq!(limiter.current.len(), 1);
+
+        // and forgotten after a generation without responses
+        let later = later + Duration::from_secs(6);
+        limiter.check(&"203.0.113.1".parse()?, ResponseClass::Answer, later);
+        let later = later + Duration::from_secs(3);
+        limiter.check(&"203.0.113.1".parse()?, ResponseClass::Answer, later);
+        assert_eq!(limiter.previous.len() + limiter.current.len(), 1);
+
+        for network in 0..MAX_ACCOUNTS as u32 {
+            let client = IpAddr::from((network << 8).to_be_bytes());
+            limiter.check(&client, ResponseClass::Answer, later);
+        }
+        assert!(limiter.previous.len() + limiter.current.len() <= MAX_ACCOUNTS);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_prefix() -> Result<()> {
+        assert_eq!(
+            client_prefix(&"192.0.2.77".parse()?),
+            "192.0.2.0".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            client_prefix(&"2001:db8:1:2345::1".parse()?),
+            "2001:db8:1:2300::".parse::<IpAddr>()?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1433 @@
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::acl::{self, Acl};
+use crate::blocklist::Blocklist;
+use crate::cache::{Cache, CachedAnswer};
+use crate::chaos::Identity;
+use crate::config::{AclAction, Config, Operation};
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns64::Dns64;
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_edns::{Edns, UDP_PAYLOAD_SIZE};
+use crate::dns_header::{OpCode, RCode};
+use crate::dns_label::{is_subdomain, labels_eq, labels_to_string, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::dns_zone::{Zone, ZoneLookup};
+use crate::dnssec::read_name;
+use crate::dnssec_key::KeySource;
+use crate::dnssec_signer::{now, OnlineSigner};
+use crate::dnssec_validator::{Security, Validator};
+use crate::hosts::LocalRecords;
+use crate::refresh::{RefreshRoute, Refresher};
+use crate::resolver::Resolver;
+use crate::rpz::{PolicyAction, ResponsePolicy};
+use crate::rrl::{RateLimit, RateLimiter, ResponseClass};
+use crate::synthetic::SyntheticZone;
+use crate::upstream::{route, Upstreams};
+use crate::Result;
+
+/// A zone we answer for, signed online when keys were given
+#[derive(Debug)]
+pub struct AuthoritativeZone {
+    pub zone: Zone,
+    pub signer: Option<OnlineSigner>,
+}
+
+impl AuthoritativeZone {
+    /// Answers the questions from the zone data. With the DO bit and a signer,
+    /// RRSIGs are added and negative answers carry NSEC or NSEC3 proofs.
+    pub fn answer(&mut self, dns_request: &DnsRequest) -> DnsReply {
+        if let Some(signer) = self.signer.as_mut() {
+            signer.update_keys(&mut self.zone);
+        }
+        let edns = dns_request.edns();
+        let dnssec_ok = edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        let mut signer = match dnssec_ok {
+            true => self.signer.as_mut(),
+            false => None,
+        };
+
+        let mut header = dns_request.header.clone();
+        header.third_byte.query_response_ind = true;
+        header.third_byte.authoritative_answer = true;
+        header.third_byte.truncation = false;
+        header.fourth_byte.recursion_available = false;
+        header.fourth_byte.reserved = 0;
+        header.fourth_byte.response_code = RCode::NoError;
+
+        let mut dns_reply = DnsReply {
+            header,
+            questions: dns_request.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        if dns_request.header.third_byte.operation_code != OpCode::Query {
+            dns_reply.header.fourth_byte.response_code = RCode::NotImplemented;
+        }
+
+        for question in dns_request.questions.iter() {
+            if dns_reply.header.fourth_byte.response_code != RCode::NoError {
+                break;
+            }
+            let q_name = &question.q_name;
+            match self.zone.lookup(q_name, &question.q_type) {
+                ZoneLookup::Answer(records) => {
+                    let records = match signer.as_deref_mut() {
+                        Some(signer) => signer.sign_section(&records),
+                        None => records,
+                    };
+                    dns_reply.answers.extend(records);
+                }
+                ZoneLookup::Delegation { ns, ds, glue } => {
+                    // a cut without NS records can only come from a malformed zone
+                    let Some(cut) = ns.first().map(|record| record.r_name.clone()) else {
+                        dns_reply.header.fourth_byte.response_code = RCode::ServerFailure;
+                        break;
+                    };
+                    dns_reply.header.third_byte.authoritative_answer = false;
+                    dns_reply.authorities.extend(ns);
+                    if let Some(signer) = signer.as_deref_mut() {
+                        // a signed DS or the proof that the child is unsigned
+                        match ds.is_empty() {
+                            true => dns_reply.authorities.extend(signer.deny_type(&cut)),
+                            false => dns_reply.authorities.extend(signer.sign_section(&ds)),
+                        }
+                    } else {
+                        dns_reply.authorities.extend(ds);
+                    }
+                    dns_reply.additionals.extend(glue);
+                }
+                ZoneLookup::NoData => {
+                    dns_reply
+                        .authorities
+                        .extend(negative_soa(&self.zone, signer.as_deref_mut()));
+                    if let Some(signer) = signer.as_deref_mut() {
+                        dns_reply.authorities.extend(signer.deny_type(q_name));
+                    }
+                }
+                ZoneLookup::NxDomain => {
+                    dns_reply.header.fourth_byte.response_code = RCode::NameError;
+                    dns_reply
+                        .authorities
+                        .extend(negative_soa(&self.zone, signer.as_deref_mut()));
+                    if let Some(signer) = signer.as_deref_mut() {
+                        dns_reply
+                            .authorities
+                            .extend(signer.deny_name(&self.zone, q_name));
+                    }
+                }
+            }
+        }
+
+        if edns.is_some() {
+            dns_reply.additionals.push(Edns::new(dnssec_ok).into());
+        }
+        dns_reply.update_counts();
+        dns_reply
+    }
+}
+
+/// SOA record of negative answers, with the negative caching TTL
+fn negative_soa(zone: &Zone, signer: Option<&mut OnlineSigner>) -> Vec<DnsAnswer> {
+    let Some(soa) = zone.soa() else {
+        return vec![];
+    };
+    let mut records = vec![soa.clone()];
+    if let Some(signer) = signer {
+        records.extend(signer.sign_rrset(&records));
+    }
+    let ttl = zone.negative_ttl();
+    for record in records.iter_mut() {
+        record.ttl = ttl;
+    }
+    records
+}
+
+/// Sets the DO bit, adding an OPT record when there is none
+fn set_dnssec_ok(additionals: &mut Vec<DnsAnswer>) {
+    let mut edns = Edns::find(additionals).unwrap_or_else(|| Edns::new(true));
+    edns.dnssec_ok = true;
+    additionals.retain(|record| record.r_type != QType::Opt);
+    additionals.push(edns.into());
+}
+
+/// Removes the DNSSEC records a client without the DO bit did not ask for
+/// https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.1
+fn strip_dnssec(dns_reply: &mut DnsReply, q_type: &QType) {
+    let keep = |record: &DnsAnswer| {
+        &record.r_type == q_type
+            || !matches!(record.r_type, QType::Rrsig | QType::Nsec | QType::Nsec3)
+    };
+    dns_reply.answers.retain(keep);
+    dns_reply.authorities.retain(keep);
+    dns_reply.additionals.retain(keep);
+}
+
+/// Upstreams of a forwarded domain, the root for the default upstreams
+#[derive(Debug)]
+struct Forwarder {
+    domain: Vec<DnsLabel>,
+    upstreams: Upstreams,
+    /// When the upstreams fail, the question is not resolved from the root
+    /// servers instead
+    only: bool,
+}
+
+pub struct Server {
+    zones: Vec<AuthoritativeZone>,
+    /// Zones answering the addresses embedded in the names
+    synthetic_zones: Vec<SyntheticZone>,
+    /// Questions are forwarded to the forwarder of the longest domain
+    /// containing the name
+    forwarders: Vec<Forwarder>,
+    /// Resolves the questions from the root servers when root hints are given
+    resolver: Option<Resolver>,
+    /// Validates the forwarded replies when trust anchors are configured
+    validator: Option<Validator>,
+    /// Forwarded answers
+    cache: Cache,
+    /// Refreshes stale cache entries when forwarding is enabled
+    refresher: Option<Refresher>,
+    /// Answers the questions for blocked names instead of the upstreams
+    blocklist: Option<Blocklist>,
+    /// Response policy zones rewriting the recursive answers
+    policy: Option<ResponsePolicy>,
+    /// Hosts files and static records answered before forwarding
+    local_records: Option<LocalRecords>,
+    /// Limits the responses to each client when configured
+    rate_limiter: Option<RateLimiter>,
+    /// Operations allowed to each client, all of them by default
+    acl: Acl,
+    /// Synthesizes AAAA records for IPv6-only clients when configured
+    dns64: Option<Dns64>,
+    /// Answers the CHAOS identity questions and NSID
+    identity: Identity,
+}
+
+impl Server {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut zones = Vec::new();
+        for zone_config in &config.zones {
+            let mut zone = Zone::load(&zone_config.file, None)?;
+            let source = KeySource {
+                files: zone_config.keys.clone(),
+                dir: zone_config.key_dir.clone(),
+            };
+            let keys = source.load(&zone.origin)?;
+            let signer = match keys.is_empty() {
+                true => None,
+                false => Some(
+                    OnlineSigner::new(
+                        &mut zone,
+                        keys,
+                        zone_config.nsec3.clone(),
+                        zone_config.signature_validity,
+                    )?
+                    .watch_keys(source),
+                ),
+            };
+            zones.push(AuthoritativeZone { zone, signer });
+        }
+
+        let validator = match config.trust_anchors.is_empty() {
+            true => None,
+            false => Some(Validator::load(&config.trust_anchors)?),
+        };
+        let routes = config.forward_routes();
+        let forwarders = routes
+            .iter()
+            .map(|route| {
+                Ok(Forwarder {
+                    domain: route.domain.clone(),
+                    upstreams: Upstreams::new(&route.upstreams)?,
+                    only: route.only,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let resolver = match &config.root_hints {
+            Some(path) => Some(Resolver::load(path)?),
+            None => None,
+        };
+        let refresher = match routes.is_empty() && resolver.is_none() {
+            true => None,
+            false => Some(Refresher::new(validator.is_some())),
+        };
+        let blocklist = match config.blocklists.is_empty() {
+            true => None,
+            false => Some(Blocklist::load(
+                &config.blocklists,
+                &config.allowlists,
+                config.block_response.clone(),
+            )?),
+        };
+        let policy = match config.policy_zones.is_empty() {
+            true => None,
+            false => Some(ResponsePolicy::load(&config.policy_zones)?),
+        };
+        let local_records = match config.hosts_files.is_empty() && config.static_records.is_empty()
+        {
+            true => None,
+            false => Some(LocalRecords::load(
+                &config.hosts_files,
+                &config.static_records,
+            )?),
+        };
+        let rate_limiter = match config.rate_limit.responses_per_second {
+            0 => None,
+            _ => Some(RateLimiter::new(config.rate_limit.clone())),
+        };
+
+        Ok(Self {
+            zones,
+            synthetic_zones: config
+                .synthetic_zones
+                .iter()
+                .map(SyntheticZone::new)
+                .collect(),
+            forwarders,
+            resolver,
+            validator,
+            cache: Cache::new(config.cache.clone()),
+            refresher,
+            blocklist,
+            policy,
+            local_records,
+            rate_limiter,
+            acl: Acl::new(config.acl.clone()),
+            dns64: config
+                .dns64
+                .prefix
+                .map(|prefix| Dns64::new(prefix, &config.dns64.exclude)),
+            identity: Identity::new(config.identity.clone()),
+        })
+    }
+
+    /// Most specific zone containing the name. DS records are served by the
+    /// parent zone when we also have it.
+    fn find_zone(&self, name: &[DnsLabel], q_type: &QType) -> Option<usize> {
+        self.zones
+            .iter()
+            .enumerate()
+            .filter(|(_, authoritative)| is_subdomain(name, &authoritative.zone.origin))
+            .max_by_key(|(_, authoritative)| {
+                let origin = &authoritative.zone.origin;
+                let child_apex = q_type == &QType::Ds && labels_eq(name, origin);
+                (!child_apex, origin.len())
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Builds the response to a raw request from the client, none when no
+    /// response is sent
+    pub fn handle(&mut self, buf: &[u8], client: IpAddr) -> Result<Option<Vec<u8>>> {
+        // IPv4 clients of a dual-stack listener are seen as ::ffff:a.b.c.d
+        let client = client.to_canonical();
+        let dns_request = DnsRequest::try_from(buf)?;
+
+        // no larger than the payload size we advertise, whatever the client's
+        let max_size = dns_request.max_udp_size().min(UDP_PAYLOAD_SIZE as usize);
+        let client_edns = dns_request.edns();
+
+        let question = dns_request.questions.first();
+        // server identity questions, whatever their name
+        let chaos = question.is_some_and(|question| question.q_class == QClass::Ch);
+        let zone_index =
+            question.and_then(|question| self.find_zone(&question.q_name, &question.q_type));
+        // the zones loaded from files take precedence over the synthetic ones
+        let synthetic_index = match zone_index {
+            Some(_) => None,
+            None => question.and_then(|question| {
+                self.synthetic_zones
+                    .iter()
+                    .position(|zone| zone.contains(&question.q_name))
+            }),
+        };
+        let forwarded =
+            question.is_some_and(|question| self.forwarder_for(&question.q_name).is_some());
+        if let Some(local_records) = self.local_records.as_mut() {
+            local_records.reload_if_changed();
+        }
+        let local = question.is_some_and(|question| {
+            self.local_records
+                .as_ref()
+                .is_some_and(|local_records| local_records.contains(&question.q_name))
+        });
+
+        // PTR questions of the DNS64 prefix are asked for the IPv4 address
+        let synthesized = question.is_some_and(|question| {
+            self.dns64
+                .as_ref()
+                .is_some_and(|dns64| dns64.reverse_target(question).is_some())
+        });
+
+        let recursive = forwarded || local || synthesized || self.resolver.is_some();
+        let operation = acl::operation(&dns_request);
+        let mut access = self.acl.check(&operation, &client);
+        // recursion also needs the query to be allowed
+        let authoritative = chaos || zone_index.is_some() || synthetic_index.is_some();
+        let recursion = operation == Operation::Query && !authoritative && recursive;
+        if access == AclAction::Allow && recursion {
+            access = self.acl.check(&Operation::Recursion, &client);
+        }
+        let mut dns_reply = match (access, zone_index, synthetic_index, recursive) {
+            (AclAction::Drop, _, _, _) => return Ok(None),
+            (AclAction::Refuse, _, _, _) => refused_reply(&dns_request),
+            (AclAction::Allow, _, _, _) if chaos => self.identity.answer(&dns_request),
+            (AclAction::Allow, Some(index), _, _) => self.zones[index].answer(&dns_request),
+            (AclAction::Allow, None, Some(index), _) => {
+                self.synthetic_zones[index].answer(&dns_request)
+            }
+            (AclAction::Allow, None, None, true) => match self.forward(dns_request, client)? {
+                Some(dns_reply) => dns_reply,
+                // dropped by the response policy
+                None => return Ok(None),
+            },
+            (AclAction::Allow, None, None, false) => DnsReply::try_from(dns_request)?,
+        };
+        self.identity.add_nsid(client_edns.as_ref(), &mut dns_reply);
+
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            let class = ResponseClass::from(&dns_reply.header.fourth_byte.response_code);
+            match rate_limiter.check(&client, class, Instant::now()) {
+                RateLimit::Send => {}
+                RateLimit::Slip => return Ok(Some(dns_reply.truncated().into())),
+                RateLimit::Drop => return Ok(None),
+            }
+        }
+
+        let response: Vec<u8> = dns_reply.clone().into();
+        if response.len() > max_size {
+            return Ok(Some(dns_reply.truncated().into()));
+        }
+        Ok(Some(response))
+    }
+
+    /// Index of the forwarder for the name
+    fn forwarder_for(&self, name: &[DnsLabel]) -> Option<usize> {
+        let domains = self
+            .forwarders
+            .iter()
+            .map(|forwarder| &forwarder.domain[..]);
+        route(domains, name)
+    }
+
+    /// Sends the requests to the upstreams of their domain, concurrently for
+    /// each forwarder, and waits for their replies. The other questions, and
+    /// those the upstreams of a "forward first" domain failed to answer, are
+    /// resolved from the root servers one after the other. The replies are in
+    /// the order of the requests.
+    fn ask_upstream(&mut self, dns_requests: Vec<DnsRequest>) -> Vec<Result<DnsReply>> {
+        let routes: Vec<Option<usize>> = dns_requests
+            .iter()
+            .map(|dns_request| self.forwarder_for(&dns_request.questions[0].q_name))
+            .collect();
+        let can_recurse = self.resolver.is_some();
+        let mut replies: Vec<Option<Result<DnsReply>>> =
+            dns_requests.iter().map(|_| None).collect();
+        for (index, forwarder) in self.forwarders.iter_mut().enumerate() {
+            let positions: Vec<usize> = (0..dns_requests.len())
+                .filter(|&position| routes[position] == Some(index))
+                .collect();
+            if positions.is_empty() {
+                continue;
+            }
+            let requests: Vec<DnsRequest> = positions
+                .iter()
+                .map(|&position| dns_requests[position].clone())
+                .collect();
+            let forwarded = forwarder.upstreams.exchange_all(&requests);
+            for (position, reply) in positions.into_iter().zip(forwarded) {
+                match reply {
+                    Err(e) if !forwarder.only && can_recurse => eprintln!(
+                        "Forwarding to the upstreams of {} failed, recursing: {}",
+                        labels_to_string(&forwarder.domain),
+                        e
+                    ),
+                    reply => replies[position] = Some(reply),
+                }
+            }
+        }
+        dns_requests
+            .iter()
+            .zip(replies)
+            .map(|(dns_request, reply)| match reply {
+                Some(reply) => reply,
+                None => self.resolve(dns_request),
+            })
+            .collect()
+    }
+
+    /// Reply to a single question resolved from the root servers
+    fn resolve(&mut self, dns_request: &DnsRequest) -> Result<DnsReply> {
+        let Some(resolver) = self.resolver.as_mut() else {
+            anyhow::bail!("No upstream resolver");
+        };
+        let dnssec_ok = dns_request.edns().is_some_and(|edns| edns.dnssec_ok);
+        let resolved = resolver.resolve(&dns_request.questions[0], dnssec_ok)?;
+        let answer = CachedAnswer {
+            rcode: resolved.header.fourth_byte.response_code,
+            answers: resolved.answers,
+            authorities: resolved.authorities,
+            secure: false,
+            prefetch: false,
+        };
+        Ok(cached_reply(dns_request, answer))
+    }
+
+    /// Checks an upstream reply with the validator unless checking is
+    /// disabled, then caches it when it is not bogus. None without validator.
+    fn check_and_cache(
+        &mut self,
+        question: &DnsQuestion,
+        reply: &DnsReply,
+        checking_disabled: bool,
+    ) -> Option<Security> {
+        let (resolver, forwarders) = (&mut self.resolver, &mut self.forwarders);
+        let security = match self.validator.as_mut() {
+            Some(_) if checking_disabled => Some(Security::Insecure),
+            Some(validator) => Some(validator.validate(question, reply, &mut |name, q_type| {
+                let question = DnsQuestion {
+                    q_name: name.to_vec(),
+                    q_type,
+                    q_class: QClass::In,
+                };
+                let domains = forwarders.iter().map(|forwarder| &forwarder.domain[..]);
+                match (route(domains, name), resolver.as_mut()) {
+                    (Some(index), _) => forwarders[index].upstreams.query(&question, true),
+                    (None, Some(resolver)) => resolver.resolve(&question, true),
+                    (None, None) => anyhow::bail!("No upstream resolver"),
+                }
+            })),
+            None => None,
+        };
+        let secure = match &security {
+            Some(security) => security == &Security::Secure,
+            None => reply.header.fourth_byte.authentic_data(),
+        };
+        // unchecked replies are not cached, they could be bogus
+        let unchecked = self.validator.is_some() && checking_disabled;
+        if !unchecked && !matches!(security, Some(Security::Bogus(_))) {
+            self.cache.insert_reply(question, reply, secure, now());
+        }
+        security
+    }
+
+    /// Refreshes the question in the background, through the upstreams of
+    /// its forwarder or from the root servers
+    fn refresh(&mut self, question: &DnsQuestion) {
+        let route = match (self.forwarder_for(&question.q_name), &self.resolver) {
+            (Some(index), _) => {
+                let order = self.forwarders[index].upstreams.ordered_addresses();
+                RefreshRoute::Forwarder(index, order)
+            }
+            (None, Some(resolver)) => RefreshRoute::Resolver(Box::new(resolver.clone())),
+            (None, None) => return,
+        };
+        if let Some(refresher) = self.refresher.as_mut() {
+            refresher.refresh(question, route);
+        }
+    }
+
+    /// Caches the replies of the background refreshes completed so far, and
+    /// accounts what the upstreams did during them
+    fn apply_refreshes(&mut self) {
+        let completed = match self.refresher.as_mut() {
+            Some(refresher) => refresher.completed(),
+            None => return,
+        };
+        for (index, report) in completed.reports {
+            self.forwarders[index].upstreams.apply(report);
+        }
+        for (question, reply) in completed.replies {
+            self.check_and_cache(&question, &reply, false);
+        }
+    }
+
+    /// Answers from the local records, applies the response policy and the
+    /// blocklist, then answers from the cache when possible, otherwise asks the upstream and
+    /// caches the reply. The questions missing from the cache are asked
+    /// concurrently. With a validator, upstream replies are asked with DO
+    /// and CD, checked, and turned into SERVFAIL when bogus unless the client
+    /// set CD itself. When the upstream does not answer, expired entries are
+    /// served while they are refreshed in the background. None when the
+    /// response policy drops the request.
+    fn forward(&mut self, dns_request: DnsRequest, client: IpAddr) -> Result<Option<DnsReply>> {
+        self.apply_refreshes();
+        let dns_requests = dns_request.split_questions();
+        // none for the questions asked upstream
+        let mut local_replies = Vec::new();
+        // questions a policy already decided on
+        let mut decided = Vec::new();
+        let mut upstream_reqs = Vec::new();
+        for req in dns_requests.iter() {
+            let question = &req.questions[0];
+            let client_dnssec_ok = req.edns().is_some_and(|edns| edns.dnssec_ok);
+
+            let target = self
+                .dns64
+                .as_ref()
+                .and_then(|dns64| dns64.reverse_target(question));
+            if let Some(target) = target {
+                let mut ptr_req = req.clone();
+                ptr_req.questions[0].q_name = target.clone();
+                let Some(ptr_reply) = self.forward(ptr_req, client)? else {
+                    return Ok(None);
+                };
+                let answer = Dns64::reverse_answer(question, &target, ptr_reply);
+                decided.push(true);
+                local_replies.push(Some(cached_reply(req, answer)));
+                continue;
+            }
+            let local_answer = self
+                .local_records
+                .as_ref()
+                .and_then(|local_records| local_records.answer(question));
+            if let Some(answer) = local_answer {
+                decided.push(true);
+                local_replies.push(Some(cached_reply(req, answer)));
+                continue;
+            }
+            let action = self
+                .policy
+                .as_ref()
+                .and_then(|policy| policy.query_action(question, client));
+            if action == Some(PolicyAction::Drop) {
+                return Ok(None);
+            }
+            let policy_answer = match &action {
+                Some(action) => action.answer(question),
+                None => self
+                    .blocklist
+                    .as_ref()
+                    .and_then(|list| list.answer(question)),
+            };
+            decided.push(action.is_some() || policy_answer.is_some());
+            if let Some(answer) = policy_answer {
+                local_replies.push(Some(cached_reply(req, answer)));
+                continue;
+            }
+            if let Some(cached) = self.cache.lookup(question, client_dnssec_ok, now()) {
+                if cached.prefetch {
+                    self.refresh(question);
+                }
+                local_replies.push(Some(cached_reply(req, cached)));
+                continue;
+            }
+            // a pending refresh means the upstream did not answer recently,
+            // clients get the stale answer without waiting for it again
+            // https://datatracker.ietf.org/doc/html/rfc8767#section-5
+            let refreshing = self
+                .refresher
+                .as_ref()
+                .is_some_and(|refresher| refresher.is_pending(question));
+            if refreshing {
+                if let Some(stale) = self.cache.lookup_stale(question, client_dnssec_ok, now()) {
+                    local_replies.push(Some(cached_reply(req, stale)));
+                    continue;
+                }
+            }
+
+            // the signatures are always asked for, the reply is cached for
+            // the clients with DO as well
+            let mut upstream_req = req.clone();
+            if self.validator.is_some() {
+                upstream_req.header.fourth_byte.set_checking_disabled(true);
+            }
+            set_dnssec_ok(&mut upstream_req.additionals);
+            upstream_req.header.additional_record_count = upstream_req.additionals.len() as u16;
+            upstream_reqs.push(upstream_req);
+            local_replies.push(None);
+        }
+
+        let mut upstream_replies = self.ask_upstream(upstream_reqs).into_iter();
+        let mut dns_replies = Vec::new();
+        for ((req, local_reply), decided) in dns_requests.iter().zip(local_replies).zip(decided) {
+            let reply = match local_reply {
+                Some(reply) => reply,
+                None => {
+                    let upstream_reply = upstream_replies
+                        .next()
+                        .unwrap_or_else(|| Err(anyhow::anyhow!("Missing upstream reply")));
+                    self.upstream_reply(req, upstream_reply)
+                }
+            };
+            let reply = match decided {
+                true => reply,
+                false => match self.apply_reply_policy(req, reply, client) {
+                    Some(reply) => reply,
+                    None => return Ok(None),
+                },
+            };
+            let Some(reply) = self.apply_dns64(req, reply, client)? else {
+                return Ok(None);
+            };
+            dns_replies.push(reply);
+        }
+        Ok(Some(DnsReply::merge_replies(&dns_replies)?))
+    }
+
+    /// Rewrites the reply to a single question when the client, the question
+    /// name, its addresses or the name servers of its domain trigger a
+    /// policy, none when it is dropped.
+    /// The name servers are those of the reply and, when resolving from the
+    /// root servers, those of the deepest known zone.
+    fn apply_reply_policy(
+        &self,
+        req: &DnsRequest,
+        dns_reply: DnsReply,
+        client: IpAddr,
+    ) -> Option<DnsReply> {
+        let Some(policy) = self.policy.as_ref() else {
+            return Some(dns_reply);
+        };
+        let question = &req.questions[0];
+        let mut name_servers: Vec<Vec<DnsLabel>> = dns_reply
+            .answers
+            .iter()
+            .chain(&dns_reply.authorities)
+            .filter(|record| {
+                record.r_type == QType::Ns && is_subdomain(&question.q_name, &record.r_name)
+            })
+            .filter_map(|record| read_name(&record.r_data, 0).ok())
+            .map(|(name, _)| name)
+            .collect();
+        if let Some(resolver) = self.resolver.as_ref() {
+            name_servers.extend(resolver.name_servers_of(&question.q_name));
+        }
+        match policy.reply_action(question, client, &dns_reply, &name_servers) {
+            Some(PolicyAction::Drop) => None,
+            Some(action) => match action.answer(question) {
+                Some(answer) => Some(cached_reply(req, answer)),
+                None => Some(dns_reply),
+            },
+            None => Some(dns_reply),
+        }
+    }
+
+    /// Synthesizes the AAAA records of the reply to a single question from
+    /// the A records of its name when it has none, none when the question
+    /// for the A records is dropped
+    fn apply_dns64(
+        &mut self,
+        req: &DnsRequest,
+        dns_reply: DnsReply,
+        client: IpAddr,
+    ) -> Result<Option<DnsReply>> {
+        let needed = self
+            .dns64
+            .as_ref()
+            .is_some_and(|dns64| dns64.needs_synthesis(req, &dns_reply));
+        if !needed {
+            return Ok(Some(dns_reply));
+        }
+        let mut a_req = req.clone();
+        a_req.questions[0].q_type = QType::A;
+        let Some(a_reply) = self.forward(a_req, client)? else {
+            return Ok(None);
+        };
+        let answer = self
+            .dns64
+            .as_ref()
+            .and_then(|dns64| dns64.synthesize(&dns_reply, &a_reply));
+        Ok(Some(match answer {
+            Some(answer) => cached_reply(req, answer),
+            None => dns_reply,
+        }))
+    }
+
+    /// Reply to the client for a single question asked upstream. Bogus
+    /// replies become SERVFAIL, and failures are answered from the stale
+    /// entries while they are refreshed.
+    fn upstream_reply(&mut self, req: &DnsRequest, upstream_reply: Result<DnsReply>) -> DnsReply {
+        let question = &req.questions[0];
+        let client_edns = req.edns();
+        let client_dnssec_ok = client_edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        let checking_disabled = req.header.fourth_byte.checking_disabled();
+        let wants_ad = client_dnssec_ok || req.header.fourth_byte.authentic_data();
+
+        let mut reply = match upstream_reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!(
+                    "Upstream query for {} failed: {}",
+                    labels_to_string(&question.q_name),
+                    e
+                );
+                self.refresh(question);
+                let stale = self.cache.lookup_stale(question, client_dnssec_ok, now());
+                return cached_reply(req, stale.unwrap_or(SERVER_FAILURE));
+            }
+        };
+
+        match self.check_and_cache(question, &reply, checking_disabled) {
+            // no validator, the upstream answer is passed on
+            None => {}
+            Some(Security::Secure) => {
+                // only to clients showing they understand it
+                reply.header.fourth_byte.set_authentic_data(wants_ad);
+            }
+            Some(Security::Insecure) => reply.header.fourth_byte.set_authentic_data(false),
+            Some(Security::Bogus(reason)) => {
+                eprintln!(
+                    "Bogus reply for {}: {}",
+                    labels_to_string(&question.q_name),
+                    reason
+                );
+                reply.header.fourth_byte.set_authentic_data(false);
+                reply.header.fourth_byte.response_code = RCode::ServerFailure;
+                reply.answers.clear();
+                reply.authorities.clear();
+                reply
+                    .additionals
+                    .retain(|record| record.r_type == QType::Opt);
+            }
+        }
+        reply
+            .header
+            .fourth_byte
+            .set_checking_disabled(checking_disabled);
+        if !client_dnssec_ok {
+            strip_dnssec(&mut reply, &question.q_type);
+            // DO was set for the upstream, not by the client
+            if let Some(mut edns) = Edns::find(&reply.additionals) {
+                edns.dnssec_ok = false;
+                reply
+                    .additionals
+                    .retain(|record| record.r_type != QType::Opt);
+                reply.additionals.push(edns.into());
+            }
+        }
+        if client_edns.is_none() {
+            reply
+                .additionals
+                .retain(|record| record.r_type != QType::Opt);
+        }
+        reply.update_counts();
+        reply
+    }
+}
+
+/// SERVFAIL answer given when the upstream cannot be reached
+const SERVER_FAILURE: CachedAnswer = CachedAnswer {
+    rcode: RCode::ServerFailure,
+    answers: vec![],
+    authorities: vec![],
+    secure: false,
+    prefetch: false,
+};
+
+/// Reply refusing the request, to the clients the access rules keep out
+fn refused_reply(dns_request: &DnsRequest) -> DnsReply {
+    let mut header = dns_request.header.clone();
+    header.third_byte.query_response_ind = true;
+    header.third_byte.authoritative_answer = false;
+    header.third_byte.truncation = false;
+    header.fourth_byte.recursion_available = false;
+    header.fourth_byte.reserved = 0;
+    header.fourth_byte.response_code = RCode::Refused;
+
+    let mut dns_reply = DnsReply {
+        header,
+        questions: dns_request.questions.clone(),
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+    };
+    if let Some(edns) = dns_request.edns() {
+        dns_reply.additionals.push(Edns::new(edns.dnssec_ok).into());
+    }
+    dns_reply.update_counts();
+    dns_reply
+}
+
+/// Reply to a single question built from cached or resolved records. AD is
+/// only set for clients showing they understand it.
+fn cached_reply(dns_request: &DnsRequest, cached: CachedAnswer) -> DnsReply {
+    let edns = dns_request.edns();
+    let dnssec_ok = edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+    let wants_ad = dnssec_ok || dns_request.header.fourth_byte.authentic_data();
+
+    let mut header = dns_request.header.clone();
+    header.third_byte.query_response_ind = true;
+    header.third_byte.authoritative_answer = false;
+    header.third_byte.truncation = false;
+    header.fourth_byte.recursion_available = true;
+    header
+        .fourth_byte
+        .set_authentic_data(cached.secure && wants_ad);
+    header.fourth_byte.response_code = cached.rcode;
+
+    let mut dns_reply = DnsReply {
+        header,
+        questions: dns_request.questions.clone(),
+        answers: cached.answers,
+        authorities: cached.authorities,
+        additionals: vec![],
+    };
+    if edns.is_some() {
+        dns_reply.additionals.push(Edns::new(dnssec_ok).into());
+    }
+    dns_reply.update_counts();
+    dns_reply
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::cache::STALE_TTL;
+    use crate::config::{
+        AclRule, BlockResponse, CacheConfig, ForwardZoneConfig, SyntheticZoneConfig,
+    };
+    use crate::crypto::ed25519;
+    use crate::dns_edns::EdnsOption;
+    use crate::dns_label::labels_from_str;
+    use crate::dnssec::{Dnskey, DNSKEY_SEP, DNSKEY_ZONE};
+    use crate::dnssec_key::{KeyTiming, PrivateKey, SigningKey};
+    use crate::hosts::reverse_name;
+    use crate::master_file;
+    use crate::rpz::PolicyZone;
+    use crate::zone_signer::ds_records;
+
+    const ZONE: &str = r#"
+$ORIGIN example.
+$TTL 3600
+@       SOA ns1 hostmaster 1 7200 3600 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.2
+"#;
+
+    fn test_key(seed: u8) -> Result<SigningKey> {
+        let private_key = PrivateKey::Ed25519(ed25519::PrivateKey::from_bytes(&[seed; 32])?);
+        Ok(SigningKey {
+            owner: labels_from_str("example"),
+            dnskey: Dnskey {
+                flags: DNSKEY_ZONE | DNSKEY_SEP,
+                protocol: 3,
+                algorithm: private_key.algorithm(),
+                public_key: private_key.public_key(),
+            },
+            private_key,
+            timing: KeyTiming::default(),
+        })
+    }
+
+    /// Serves a signed example. zone on a loopback port, in the background,
+    /// for at most `max_requests` requests
+    fn spawn_authoritative(key: SigningKey, max_requests: usize) -> Result<SocketAddr> {
+        let mut zone =
+            Zone::from_records(labels_from_str("example"), master_file::parse(ZONE, &[])?)?;
+        let signer = OnlineSigner::new(&mut zone, vec![key], None, 86400)?;
+        let mut server = Server::new(&Config::default())?;
+        server.zones.push(AuthoritativeZone {
+            zone,
+            signer: Some(signer),
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let address = socket.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            for _ in 0..max_requests {
+                let Ok((size, source)) = socket.recv_from(&mut buf) else {
+                    break;
+                };
+                if let Ok(Some(response)) = server.handle(&buf[..size], source.ip()) {
+                    let _ = socket.send_to(&response, source);
+                }
+            }
+        });
+        Ok(address)
+    }
+
+    fn forwarder(upstream: SocketAddr, cache: CacheConfig) -> Result<Server> {
+        let config = Config {
+            resolvers: vec![upstream],
+            cache,
+            ..Config::default()
+        };
+        Server::new(&config)
+    }
+
+    fn validating_forwarder(upstream: SocketAddr, anchor: &SigningKey) -> Result<Server> {
+        let anchors = ds_records(std::slice::from_ref(anchor), 3600, 0)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        server.validator = Some(Validator::new(anchors)?);
+        server.refresher = Some(Refresher::new(true));
+        Ok(server)
+    }
+
+    /// Reply of the server to a request from the local client
+    fn handle_request(server: &mut Server, request: &[u8]) -> Result<DnsReply> {
+        let client = IpAddr::from([127, 0, 0, 1]);
+        match server.handle(request, client)? {
+            Some(response) => DnsReply::try_from(&response[..]),
+            None => anyhow::bail!("No response to the request"),
+        }
+    }
+
+    fn query(server: &mut Server, dnssec_ok: bool, checking_disabled: bool) -> Result<DnsReply> {
+        let question = DnsQuestion {
+            q_name: labels_from_str("www.example"),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let mut request = DnsRequest::query(7, question, dnssec_ok);
+        request
+            .header
+            .fourth_byte
+            .set_checking_disabled(checking_disabled);
+        let bytes: Vec<u8> = request.into();
+        handle_request(server, &bytes)
+    }
+
+    #[test]
+    fn test_forward_with_validation() -> Result<()> {
+        let key = test_key(1)?;
+        let upstream = spawn_authoritative(key.clone(), usize::MAX)?;
+
+        let mut server = validating_forwarder(upstream, &key)?;
+        let reply = query(&mut server, true, false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert!(reply.header.fourth_byte.authentic_data());
+        assert!(reply.answers.iter().any(|r| r.r_type == QType::Rrsig));
+
+        // without DO the signatures are left out and AD is not set
+        let reply = query(&mut server, false, false)?;
+        assert!(!reply.header.fourth_byte.authentic_data());
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+
+        // an anchor for another key makes the zone bogus, unless the client
+        // disabled checking
+        let mut server = validating_forwarder(upstream, &test_key(2)?)?;
+        let reply = query(&mut server, true, false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::ServerFailure);
+        assert!(reply.answers.is_empty());
+        let reply = query(&mut server, true, true)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert!(reply.header.fourth_byte.checking_disabled());
+        assert!(!reply.header.fourth_byte.authentic_data());
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_from_cache() -> Result<()> {
+        let key = test_key(1)?;
+        let upstream = spawn_authoritative(key, 1)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        let reply = query(&mut server, true, false)?;
+        assert_eq!(reply.answers.len(), 2);
+
+        // the upstream is gone, the answer comes from the cache with its
+        // signatures
+        let cached = query(&mut server, true, false)?;
+        assert_eq!(cached.header.packet_id, 7);
+        assert_eq!(cached.header.fourth_byte.response_code, RCode::NoError);
+        assert!(cached.header.fourth_byte.recursion_available);
+        assert!(!cached.header.fourth_byte.authentic_data());
+        assert_eq!(cached.answers.len(), 2);
+        assert_eq!(cached.answers[0].r_data, vec![192, 0, 2, 2]);
+        assert_eq!(cached.answers[1].r_type, QType::Rrsig);
+
+        let cached = query(&mut server, false, false)?;
+        assert_eq!(cached.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_signatures() -> Result<()> {
+        let upstream = spawn_authoritative(test_key(1)?, 1)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        let reply = query(&mut server, false, false)?;
+        assert_eq!(reply.answers.len(), 1);
+        assert!(reply.edns().is_none_or(|edns| !edns.dnssec_ok));
+
+        // the answer fetched for a client without DO has its signatures for
+        // the clients with DO
+        let cached = query(&mut server, true, false)?;
+        assert_eq!(cached.answers.len(), 2);
+        assert_eq!(cached.answers[1].r_type, QType::Rrsig);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_stale() -> Result<()> {
+        let upstream = spawn_authoritative(test_key(1)?, 1)?;
+        let cache = CacheConfig {
+            max_ttl: 1,
+            ..CacheConfig::default()
+        };
+        let mut server = forwarder(upstream, cache)?;
+        let reply = query(&mut server, false, false)?;
+        assert_eq!(reply.answers[0].ttl, 3600);
+
+        // the entry expired and the upstream is gone, the stale answer is
+        // served while a refresh is attempted
+        thread::sleep(Duration::from_millis(1100));
+        let stale = query(&mut server, false, false)?;
+        assert_eq!(stale.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(stale.answers[0].r_data, vec![192, 0, 2, 2]);
+        assert_eq!(stale.answers[0].ttl, STALE_TTL);
+        let question = &stale.questions[0];
+        assert!(server.refresher.as_ref().unwrap().is_pending(question));
+
+        // nothing to serve for other names
+        let mut request = DnsRequest::query(8, question.clone(), false);
+        request.questions[0].q_type = QType::Aaaa;
+        let bytes: Vec<u8> = request.into();
+        let reply = handle_request(&mut server, &bytes)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::ServerFailure);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch() -> Result<()> {
+        let upstream = spawn_authoritative(test_key(1)?, usize::MAX)?;
+        let cache = CacheConfig {
+            max_ttl: 4,
+            prefetch: 0.5,
+            prefetch_hits: 1,
+            ..CacheConfig::default()
+        };
+        let mut server = forwarder(upstream, cache)?;
+        let question = query(&mut server, false, false)?.questions[0].clone();
+        let pending = |server: &Server| server.refresher.as_ref().unwrap().is_pending(&question);
+        query(&mut server, false, false)?;
+        assert!(!pending(&server));
+
+        // half of the TTL is left, the popular entry is refreshed in the
+        // background while served from the cache
+        thread::sleep(Duration::from_millis(2100));
+        let reply = query(&mut server, false, false)?;
+        assert!(reply.answers[0].ttl <= 2);
+        assert!(pending(&server));
+        Ok(())
+    }
+
+    /// Upstream answering each query from the example. zone on its own
+    /// thread after `delay`, and never answering for lost.example
+    fn spawn_slow_upstream(delay: Duration) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let address = socket.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let request = DnsRequest::try_from(&buf[..size]).unwrap();
+                let socket = socket.try_clone().unwrap();
+                thread::spawn(move || {
+                    if labels_to_string(&request.questions[0].q_name) == "lost.example." {
+                        return;
+                    }
+                    thread::sleep(delay);
+                    let records = master_file::parse(ZONE, &[]).unwrap();
+                    let mut authoritative = AuthoritativeZone {
+                        zone: Zone::from_records(labels_from_str("example"), records).unwrap(),
+                        signer: None,
+                    };
+                    let reply: Vec<u8> = authoritative.answer(&request).into();
+                    let _ = socket.send_to(&reply, source);
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    fn multi_question_request(names: &[(&str, QType)]) -> Vec<u8> {
+        let questions: Vec<DnsQuestion> = names
+            .iter()
+            .map(|(name, q_type)| DnsQuestion {
+                q_name: labels_from_str(name),
+                q_type: q_type.clone(),
+                q_class: QClass::In,
+            })
+            .collect();
+        let mut request = DnsRequest::query(9, questions[0].clone(), false);
+        request.header.question_count = questions.len() as u16;
+        request.questions = questions;
+        request.into()
+    }
+
+    #[test]
+    fn test_forward_questions_concurrently() -> Result<()> {
+        let delay = Duration::from_millis(300);
+        let upstream = spawn_slow_upstream(delay)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+
+        let started = Instant::now();
+        let request = multi_question_request(&[
+            ("www.example", QType::A),
+            ("ns1.example", QType::A),
+            ("www.example", QType::Aaaa),
+        ]);
+        let reply = handle_request(&mut server, &request)?;
+        assert!(started.elapsed() < delay * 2);
+        assert_eq!(reply.header.packet_id, 9);
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(reply.questions.len(), 3);
+        assert_eq!(reply.answers.len(), 2);
+
+        // the failed question does not hide the answer to the other one
+        let request =
+            multi_question_request(&[("www.example", QType::A), ("lost.example", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::ServerFailure);
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_forwarding() -> Result<()> {
+        let public = spawn_slow_upstream(Duration::ZERO)?;
+        // answers anything with 45.87.98.65
+        let corp = UdpSocket::bind("127.0.0.1:0")?;
+        let corp_address = corp.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            while let Ok((size, source)) = corp.recv_from(&mut buf) {
+                let request = DnsRequest::try_from(&buf[..size]).unwrap();
+                let reply: Vec<u8> = DnsReply::try_from(request).unwrap().into();
+                let _ = corp.send_to(&reply, source);
+            }
+        });
+        let silent = UdpSocket::bind("127.0.0.1:0")?;
+
+        let forward_zone = |domain: &str, upstream: SocketAddr| ForwardZoneConfig {
+            domain: labels_from_str(domain),
+            upstreams: vec![upstream],
+            only: true,
+        };
+        let config = Config {
+            resolvers: vec![public],
+            forward_zones: vec![
+                forward_zone("corp.example", corp_address),
+                forward_zone("nx.example", silent.local_addr()?),
+            ],
+            ..Config::default()
+        };
+        let mut server = Server::new(&config)?;
+        let mut ask = |name: &str| -> Result<DnsReply> {
+            let request = multi_question_request(&[(name, QType::A)]);
+            handle_request(&mut server, &request)
+        };
+
+        let reply = ask("www.example")?;
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+        let reply = ask("host.corp.example")?;
+        assert_eq!(reply.answers[0].r_data, vec![45, 87, 98, 65]);
+        // forward only: the failure is not hidden by the public upstream
+        let reply = ask("nx.example")?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::ServerFailure);
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocklist() -> Result<()> {
+        let upstream = spawn_slow_upstream(Duration::ZERO)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        let mut blocklist = Blocklist::new(BlockResponse::Sinkhole("0.0.0.0".parse()?));
+        blocklist.add_rules("0.0.0.0 example\n@@||www.example^", false);
+        server.blocklist = Some(blocklist);
+
+        let request =
+            multi_question_request(&[("www.example", QType::A), ("ns1.example", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].r_data, vec![192, 0, 2, 2]);
+        assert_eq!(reply.answers[1].r_data, vec![0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_policy() -> Result<()> {
+        let upstream = spawn_slow_upstream(Duration::ZERO)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        let origin = labels_from_str("rpz.example");
+        let records = master_file::parse(
+            "@ 300 IN SOA ns admin 1 3600 600 86400 300\n\
+             ns1.example 300 IN CNAME .\n\
+             32.2.2.0.192.rpz-ip 300 IN A 198.51.100.1\n\
+             32.9.0.0.127.rpz-client-ip 300 IN CNAME rpz-drop.",
+            &origin,
+        )?;
+        let zone = Zone::from_records(origin, records)?;
+        server.policy = Some(ResponsePolicy::new(vec![PolicyZone::new(&zone)]));
+
+        let request = multi_question_request(&[("ns1.example", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NameError);
+        // the address of www.example triggers the local data
+        let request = multi_question_request(&[("www.example", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_data, vec![198, 51, 100, 1]);
+        assert!(server
+            .handle(&request, IpAddr::from([127, 0, 0, 9]))?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_records() -> Result<()> {
+        let config = Config {
+            static_records: master_file::parse("foo.test. 60 IN A 10.0.0.1", &[])?,
+            ..Config::default()
+        };
+        let mut server = Server::new(&config)?;
+        let request = multi_question_request(&[("foo.test", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert_eq!(reply.answers[0].r_data, vec![10, 0, 0, 1]);
+        let request = multi_question_request(&[("foo.test", QType::Aaaa)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert!(reply.answers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_size() -> Result<()> {
+        let records: Vec<String> = (0..100)
+            .map(|index| format!("big.test. 60 IN A 10.0.0.{}", index))
+            .collect();
+        let config = Config {
+            static_records: master_file::parse(&records.join("\n"), &[])?,
+            ..Config::default()
+        };
+        let mut server = Server::new(&config)?;
+        let question = DnsQuestion {
+            q_name: labels_from_str("big.test"),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let mut request = DnsRequest::query(3, question, false);
+        let mut edns = Edns::new(false);
+        edns.udp_payload_size = 4096;
+        request.additionals.push(edns.into());
+        request.header.additional_record_count = 1;
+        let request: Vec<u8> = request.into();
+
+        // the client accepts more than we advertise, the reply is truncated
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let response = server.handle(&request, client)?.unwrap();
+        assert!(response.len() <= UDP_PAYLOAD_SIZE as usize);
+        let reply = DnsReply::try_from(&response[..])?;
+        assert!(reply.header.third_byte.truncation);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dns64() -> Result<()> {
+        let mut config = Config {
+            static_records: master_file::parse("v4.test. 60 IN A 192.0.2.33", &[])?,
+            ..Config::default()
+        };
+        config.dns64.prefix = Some("64:ff9b::".parse()?);
+        let mut server = Server::new(&config)?;
+
+        let request = multi_question_request(&[("v4.test", QType::Aaaa)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_type, QType::Aaaa);
+        let address: std::net::Ipv6Addr = "64:ff9b::c000:221".parse()?;
+        assert_eq!(reply.answers[0].r_data, address.octets().to_vec());
+
+        let reverse = labels_to_string(&reverse_name(&IpAddr::V6(address)));
+        let request = multi_question_request(&[(&reverse, QType::Ptr)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].r_type, QType::Cname);
+        assert_eq!(reply.answers[1].r_type, QType::Ptr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_synthetic_zone() -> Result<()> {
+        let config = Config {
+            synthetic_zones: vec![SyntheticZoneConfig {
+                domain: labels_from_str("preview.example.com"),
+                ttl: 60,
+            }],
+            ..Config::default()
+        };
+        let mut server = Server::new(&config)?;
+        let request = multi_question_request(&[
+            ("app.10.1.2.3.preview.example.com", QType::A),
+            ("10-1-2-4.preview.example.com", QType::A),
+        ]);
+        let reply = handle_request(&mut server, &request)?;
+        assert!(reply.header.third_byte.authoritative_answer);
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[1].r_data, vec![10, 1, 2, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity() -> Result<()> {
+        let mut config = Config::default();
+        config.identity.nsid = Some(b"ns1".to_vec());
+        let mut server = Server::new(&config)?;
+        let question = DnsQuestion {
+            q_name: labels_from_str("version.bind"),
+            q_type: QType::Txt,
+            q_class: QClass::Ch,
+        };
+        let mut request = DnsRequest::query(7, question, false);
+        let mut edns = Edns::new(false);
+        edns.options.push(EdnsOption {
+            code: 3,
+            data: vec![],
+        });
+        request.additionals.push(edns.into());
+        request.header.additional_record_count = 1;
+        let request: Vec<u8> = request.into();
+
+        let reply = handle_request(&mut server, &request)?;
+        assert!(reply.header.third_byte.authoritative_answer);
+        assert_eq!(reply.answers[0].r_type, QType::Txt);
+        let edns = Edns::find(&reply.additionals).unwrap();
+        assert_eq!(edns.options[0].data, b"ns1".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_control() -> Result<()> {
+        let upstream = spawn_slow_upstream(Duration::ZERO)?;
+        let mut server = forwarder(upstream, CacheConfig::default())?;
+        let rule = |operation, action, prefix: &str| -> Result<AclRule> {
+            Ok(AclRule {
+                operation,
+                action,
+                prefix: prefix.parse()?,
+            })
+        };
+        server.acl = Acl::new(vec![
+            rule(Operation::Recursion, AclAction::Allow, "127.0.0.1")?,
+            rule(Operation::Recursion, AclAction::Refuse, "0.0.0.0/0")?,
+            rule(Operation::Transfer, AclAction::Drop, "0.0.0.0/0")?,
+        ]);
+
+        let request = multi_question_request(&[("www.example", QType::A)]);
+        let reply = handle_request(&mut server, &request)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        let outside = IpAddr::from([192, 0, 2, 1]);
+        let response = server.handle(&request, outside)?.unwrap();
+        let reply = DnsReply::try_from(&response[..])?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::Refused);
+        assert!(reply.answers.is_empty());
+        let request = multi_question_request(&[("example", QType::Axfr)]);
+        assert!(server.handle(&request, outside)?.is_none());
+        // the IPv4 rules apply to the IPv4 clients of an IPv6 listener
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse()?;
+        assert!(server.handle(&request, mapped)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Synthetic zones answer the addresses embedded in the names, without any
+//! zone records, as nip.io or sslip.io do. Under preview.example.com:
+//! - 10.1.2.3 and app.10.1.2.3 are IPv4 addresses in the last four labels
+//! - 10-1-2-3 and app-10-1-2-3 are IPv4 addresses in dashed form
+//! - 2001-db8--1 and app.2001-db8--1 are IPv6 addresses, colons being dashes
+//!
+//! Only the labels right before the domain are read, the others are free.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config::SyntheticZoneConfig;
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_edns::Edns;
+use crate::dns_header::{OpCode, RCode};
+use crate::dns_label::{is_subdomain, labels_eq, labels_to_bytes, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+
+/// Timers of the SOA record: refresh, retry and expire
+const SOA_TIMERS: [u32; 3] = [3600, 600, 86400];
+
+/// Address embedded in the labels of a name under the zone
+fn embedded_address(labels: &[DnsLabel]) -> Option<IpAddr> {
+    let texts: Vec<&str> = labels.iter().map(|label| label.label.as_str()).collect();
+    if let Some(octets) = texts.len().checked_sub(4).map(|start| &texts[start..]) {
+        if let Ok(address) = octets.join(".").parse::<Ipv4Addr>() {
+            return Some(IpAddr::V4(address));
+        }
+    }
+
+    let last = texts.last()?;
+    let parts: Vec<&str> = last.split('-').collect();
+    if let Some(octets) = parts.len().checked_sub(4).map(|start| &parts[start..]) {
+        if let Ok(address) = octets.join(".").parse::<Ipv4Addr>() {
+            return Some(IpAddr::V4(address));
+        }
+    }
+    last.replace('-', ":")
+        .parse::<Ipv6Addr>()
+        .ok()
+        .map(IpAddr::V6)
+}
+
+#[derive(Debug)]
+pub struct SyntheticZone {
+    origin: Vec<DnsLabel>,
+    /// TTL of the records and of the negative answers
+    ttl: u32,
+}
+
+impl SyntheticZone {
+    pub fn new(config: &SyntheticZoneConfig) -> Self {
+        Self {
+            origin: config.domain.clone(),
+            ttl: config.ttl,
+        }
+    }
+
+    pub fn contains(&self, name: &[DnsLabel]) -> bool {
+        is_subdomain(name, &self.origin)
+    }
+
+    fn record(&self, r_name: &[DnsLabel], r_type: QType, r_data: Vec<u8>) -> DnsAnswer {
+        DnsAnswer {
+            r_name: r_name.to_vec(),
+            r_type,
+            r_class: QClass::In,
+            ttl: self.ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        }
+    }
+
+    /// SOA record of the zone, made up from its domain
+    fn soa(&self) -> DnsAnswer {
+        let mut hostmaster = vec![DnsLabel::new("hostmaster")];
+        hostmaster.extend(self.origin.iter().cloned());
+        let mut r_data = labels_to_bytes(&self.origin);
+        r_data.extend(labels_to_bytes(&hostmaster));
+        // the serial never changes as the zone has no data
+        r_data.extend(1u32.to_be_bytes());
+        for timer in SOA_TIMERS.iter().chain([&self.ttl]) {
+            r_data.extend(timer.to_be_bytes());
+        }
+        self.record(&self.origin, QType::Soa, r_data)
+    }
+
+    /// Records of the question, none for a name without an address
+    fn lookup(&self, question: &DnsQuestion) -> Option<Vec<DnsAnswer>> {
+        let any = question.q_type == QType::StarSign;
+        let q_name = &question.q_name;
+        if labels_eq(q_name, &self.origin) {
+            let soa = question.q_type == QType::Soa || any;
+            return Some(soa.then(|| self.soa()).into_iter().collect());
+        }
+        let relative = &q_name[..q_name.len() - self.origin.len()];
+        let record = match embedded_address(relative)? {
+            IpAddr::V4(address) if question.q_type == QType::A || any => {
+                Some(self.record(q_name, QType::A, address.octets().to_vec()))
+            }
+            IpAddr::V6(address) if question.q_type == QType::Aaaa || any => {
+                Some(self.record(q_name, QType::Aaaa, address.octets().to_vec()))
+            }
+            _ => None,
+        };
+        Some(record.into_iter().collect())
+    }
+
+    /// Answers the questions from the addresses in their names, NXDOMAIN for
+    /// the names without one and REFUSED for the names outside of the zone
+    pub fn answer(&self, dns_request: &DnsRequest) -> DnsReply {
+        let edns = dns_request.edns();
+
+        let mut header = dns_request.header.clone();
+        header.third_byte.query_response_ind = true;
+        header.third_byte.authoritative_answer = true;
+        header.third_byte.truncation = false;
+        header.fourth_byte.recursion_available = false;
+        header.fourth_byte.reserved = 0;
+        header.fourth_byte.response_code = RCode::NoError;
+
+        let mut dns_reply = DnsReply {
+            header,
+            questions: dns_request.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        if dns_request.header.third_byte.operation_code != OpCode::Query {
+            dns_reply.header.fourth_byte.response_code = RCode::NotImplemented;
+        }
+
+        for question in dns_request.questions.iter() {
+            if dns_reply.header.fourth_byte.response_code != RCode::NoError {
+                break;
+            }
+            // the zone is chosen from the first question only
+            if !self.contains(&question.q_name) {
+                dns_reply.header.fourth_byte.response_code = RCode::Refused;
+                break;
+            }
+            match self.lookup(question) {
+                Some(records) if records.is_empty() => dns_reply.authorities.push(self.soa()),
+                Some(records) => dns_reply.answers.extend(records),
+                None => {
+                    dns_reply.header.fourth_byte.response_code = RCode::NameError;
+                    dns_reply.authorities.push(self.soa());
+                }
+            }
+        }
+
+        if edns.is_some() {
+            dns_reply.additionals.push(Edns::new(false).into());
+        }
+        dns_reply.update_counts();
+        dns_reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_label::labels_from_str;
+    use crate::Result;
+
+    fn address(name: &str) -> Option<IpAddr> {
+        embedded_address(&labels_from_str(name))
+    }
+
+    #[test]
+    fn test_embedded_address() -> Result<()> {
+        let v4 = Some("10.1.2.3".parse::<IpAddr>()?);
+        assert_eq!(address("10.1.2.3"), v4);
+        assert_eq!(address("app.10.1.2.3"), v4);
+        assert_eq!(address("10-1-2-3"), v4);
+        assert_eq!(address("app-10-1-2-3"), v4);
+        assert_eq!(address("my.app-10-1-2-3"), v4);
+        assert_eq!(address("2001-db8--1"), Some("2001:db8::1".parse()?));
+        assert_eq!(address("app.--1"), Some("::1".parse()?));
+        assert_eq!(address("10-1-2-3.app"), None);
+        assert_eq!(address("10.1.2.256"), None);
+        assert_eq!(address("app"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_answer() -> Result<()> {
+        let zone = SyntheticZone::new(&SyntheticZoneConfig {
+            domain: labels_from_str("preview.example.com"),
+            ttl: 60,
+        });
+        let ask = |name: &str, q_type| {
+            let question = DnsQuestion {
+                q_name: labels_from_str(name),
+                q_type,
+                q_class: QClass::In,
+            };
+            zone.answer(&DnsRequest::query(1, question, false))
+        };
+
+        let reply = ask("app.10-1-2-3.PREVIEW.example.com", QType::A);
+        assert!(reply.header.third_byte.authoritative_answer);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].r_data, vec![10, 1, 2, 3]);
+        assert_eq!(reply.answers[0].ttl, 60);
+
+        let reply = ask("2001-db8--1.preview.example.com", QType::Aaaa);
+        let ipv6: Ipv6Addr = "2001:db8::1".parse()?;
+        assert_eq!(reply.answers[0].r_data, ipv6.octets().to_vec());
+
+        let reply = ask("10.1.2.3.preview.example.com", QType::Aaaa);
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.authorities[0].r_type, QType::Soa);
+
+        let reply = ask("www.preview.example.com", QType::A);
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NameError);
+
+        let reply = ask("preview.example.com", QType::Soa);
+        assert_eq!(reply.answers[0].r_type, QType::Soa);
+
+        let question = |name: &str| DnsQuestion {
+            q_name: labels_from_str(name),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let mut request = DnsRequest::query(1, question("10-1-2-3.preview.example.com"), false);
+        request.questions.push(question("example.com"));
+        request.header.question_count = 2;
+        let reply = zone.answer(&request);
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::Refused);
+        Ok(())
+    }
+}
@@ -0,0 +1,701 @@
+//! Exchanges with the upstream resolvers. Each query gets a random ID, is sent
+//! again with a doubled timeout when no reply comes, and only a reply with
+//! the same ID and question is accepted, stray datagrams are dropped.
+//! https://datatracker.ietf.org/doc/html/rfc5452#section-9.1
+//! Truncated replies are asked again over TCP, which also carries zone
+//! transfers.
+//! The fastest upstream by smoothed RTT is asked first, and the others in
+//! turn when it times out or fails. Upstreams that time out or can not be
+//! reached are left aside for a while, longer after each consecutive failure.
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dns::{DnsReply, DnsRequest};
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_header::RCode;
+use crate::dns_label::{is_subdomain, labels_eq, DnsLabel};
+use crate::dns_question::DnsQuestion;
+use crate::dns_type::QType;
+use crate::Result;
+
+/// Time the first attempt waits for a reply, doubled at each retry
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+/// Attempts made before the upstream is considered unavailable
+const ATTEMPTS: u32 = 3;
+/// Time after which a query gives up, whatever the number of upstreams, so
+/// that stale answers are served in time
+/// https://datatracker.ietf.org/doc/html/rfc8767#section-5
+const QUERY_TIME_LIMIT: Duration = Duration::from_millis(1800);
+/// Time an upstream is left aside after a timeout, doubled at each
+/// consecutive failure up to `MAX_DOWN_TIME`
+const DOWN_TIME: Duration = Duration::from_secs(1);
+const MAX_DOWN_TIME: Duration = Duration::from_secs(60);
+/// Share of each new measure in the smoothed RTT
+const SRTT_GAIN: u32 = 8;
+/// The SRTT of the upstreams not chosen decays by this share at each query,
+/// so that a slow upstream is measured again once in a while
+const SRTT_DECAY: u32 = 50;
+/// Time given to each step of a TCP exchange
+const TCP_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Bounds on a zone transfer, the primary could send messages forever
+const MAX_TRANSFER_RECORDS: usize = 1_000_000;
+const MAX_TRANSFER_SIZE: usize = 64 << 20;
+
+/// Index of the longest domain containing the name
+pub fn route<'a>(
+    domains: impl Iterator<Item = &'a [DnsLabel]>,
+    name: &[DnsLabel],
+) -> Option<usize> {
+    domains
+        .enumerate()
+        .filter(|(_, domain)| is_subdomain(name, domain))
+        .max_by_key(|(_, domain)| domain.len())
+        .map(|(index, _)| index)
+}
+
+/// Socket connected to the upstream, only its datagrams are received
+fn upstream_socket(upstream: SocketAddr) -> Result<UdpSocket> {
+    let socket = match upstream {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+    socket.connect(upstream)?;
+    Ok(socket)
+}
+
+/// Whether the reply answers the request: same ID and same questions
+fn is_reply_to(reply: &DnsReply, request: &DnsRequest) -> bool {
+    reply.header.packet_id == request.header.packet_id
+        && reply.header.third_byte.query_response_ind
+        && reply.questions.len() == request.questions.len()
+        && reply
+            .questions
+            .iter()
+            .zip(&request.questions)
+            .all(|(a, b)| {
+                labels_eq(&a.q_name, &b.q_name) && a.q_type == b.q_type && a.q_class == b.q_class
+            })
+}
+
+/// Waits until `deadline` for the reply to the request, none on timeout. Only
+/// replies fitting the UDP size advertised by the request are read.
+fn receive(
+    socket: &UdpSocket,
+    request: &DnsRequest,
+    deadline: Instant,
+) -> Result<Option<DnsReply>> {
+    let mut buf = vec![0; request.max_udp_size()];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(left))?;
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match DnsReply::try_from(&buf[..size]) {
+            Ok(reply) if is_reply_to(&reply, request) => return Ok(Some(reply)),
+            _ => eprintln!("Dropped a datagram from the upstream not matching the query"),
+        }
+    }
+}
+
+/// Connection to the server sending the request as a single message, each
+/// step of the exchange being given `timeout`
+fn tcp_send(server: SocketAddr, request: &DnsRequest, timeout: Duration) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let bytes: Vec<u8> = request.clone().into();
+    let mut message = (bytes.len() as u16).to_be_bytes().to_vec();
+    message.extend(bytes);
+    stream.write_all(&message)?;
+    Ok(stream)
+}
+
+/// Next length-prefixed message of the stream
+fn tcp_receive(stream: &mut TcpStream) -> Result<DnsReply> {
+    DnsReply::try_from(&tcp_read(stream)?[..])
+}
+
+/// Message following its length on the stream
+fn tcp_read(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Sends the request over TCP, for the replies too large for UDP
+/// https://datatracker.ietf.org/doc/html/rfc7766#section-8
+pub fn exchange_tcp(server: SocketAddr, request: &DnsRequest) -> Result<DnsReply> {
+    exchange_tcp_within(server, request, TCP_TIMEOUT)
+}
+
+fn exchange_tcp_within(
+    server: SocketAddr,
+    request: &DnsRequest,
+    timeout: Duration,
+) -> Result<DnsReply> {
+    let mut stream = tcp_send(server, request, timeout)?;
+    let reply = tcp_receive(&mut stream)?;
+    if !is_reply_to(&reply, request) {
+        anyhow::bail!("TCP reply from {} does not match the query", server);
+    }
+    Ok(reply)
+}
+
+/// Records of the zone transferred from its primary server with AXFR. The
+/// transfer ends with the SOA record it starts with, and fails past
+/// `MAX_TRANSFER_RECORDS` records or `MAX_TRANSFER_SIZE` bytes.
+/// https://datatracker.ietf.org/doc/html/rfc5936#section-2.2
+pub fn transfer(server: SocketAddr, zone: &[DnsLabel]) -> Result<Vec<DnsAnswer>> {
+    let question = DnsQuestion {
+        q_name: zone.to_vec(),
+        q_type: QType::Axfr,
+        q_class: QClass::In,
+    };
+    let mut request = DnsRequest::query(rand::random::<u16>(), question, false);
+    request.header.third_byte.recursion_desired = false;
+    let mut stream = tcp_send(server, &request, TCP_TIMEOUT)?;
+
+    let mut records: Vec<DnsAnswer> = Vec::new();
+    let mut size = 0;
+    loop {
+        let message = tcp_read(&mut stream)?;
+        size += message.len();
+        if size > MAX_TRANSFER_SIZE {
+            anyhow::bail!(
+                "Transfer from {} exceeds {} bytes",
+                server,
+                MAX_TRANSFER_SIZE
+            );
+        }
+        let reply = DnsReply::try_from(&message[..])?;
+        // only the first message has to repeat the question
+        if reply.header.packet_id != request.header.packet_id
+            || (records.is_empty() && !is_reply_to(&reply, &request))
+        {
+            anyhow::bail!("Transfer message from {} does not match the query", server);
+        }
+        let rcode = &reply.header.fourth_byte.response_code;
+        if rcode != &RCode::NoError {
+            anyhow::bail!("Transfer of the zone refused by {}: {:?}", server, rcode);
+        }
+        for record in reply.answers {
+            let is_soa = record.r_type == QType::Soa;
+            if records.is_empty() && !is_soa {
+                anyhow::bail!("Transfer from {} does not start with the SOA", server);
+            }
+            if is_soa && !records.is_empty() {
+                return Ok(records);
+            }
+            if records.len() >= MAX_TRANSFER_RECORDS {
+                anyhow::bail!(
+                    "Transfer from {} exceeds {} records",
+                    server,
+                    MAX_TRANSFER_RECORDS
+                );
+            }
+            records.push(record);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Upstream {
+    address: SocketAddr,
+    /// Smoothed round-trip time, zero until measured
+    srtt: Duration,
+    /// Consecutive timeouts
+    failures: u32,
+    /// Left aside until then after a timeout
+    down_until: Option<Instant>,
+}
+
+impl Upstream {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.is_none_or(|until| until <= now)
+    }
+
+    fn answered(&mut self, rtt: Duration) {
+        self.srtt = match self.srtt.is_zero() {
+            true => rtt,
+            false => self.srtt - self.srtt / SRTT_GAIN + rtt / SRTT_GAIN,
+        };
+        self.failures = 0;
+        self.down_until = None;
+    }
+
+    /// After a timeout or a socket error
+    fn timed_out(&mut self, now: Instant) {
+        self.failures += 1;
+        let down_time = DOWN_TIME.saturating_mul(1 << (self.failures - 1).min(16));
+        self.down_until = Some(now + down_time.min(MAX_DOWN_TIME));
+    }
+}
+
+/// What the upstreams did during an exchange, by index
+#[derive(Debug, Default)]
+pub struct Report {
+    answered: Vec<(usize, Duration)>,
+    timed_out: Vec<usize>,
+    /// Upstreams whose socket failed, not asked again during the exchange
+    unreachable: Vec<usize>,
+    /// Upstream whose reply was kept
+    chosen: Option<usize>,
+}
+
+/// Sends the request under a random ID from a fresh socket per upstream, and
+/// waits for the matching reply, asking each upstream in turn at every
+/// attempt, until `QUERY_TIME_LIMIT`. A SERVFAIL is only returned when no
+/// upstream gives a better reply. The reply gets the ID of the request.
+pub fn exchange(
+    upstreams: &[(usize, SocketAddr)],
+    request: &DnsRequest,
+) -> (Result<DnsReply>, Report) {
+    let mut query = request.clone();
+    query.header.packet_id = rand::random::<u16>();
+    let bytes: Vec<u8> = query.clone().into();
+
+    let mut report = Report::default();
+    let mut candidates = upstreams.to_vec();
+    let mut sockets: Vec<Option<UdpSocket>> = upstreams.iter().map(|_| None).collect();
+    let mut server_failure = None;
+    let mut socket_error = None;
+    let mut timeout = INITIAL_TIMEOUT;
+    let deadline = Instant::now() + QUERY_TIME_LIMIT;
+    'attempts: for _ in 0..ATTEMPTS {
+        for (position, &(index, address)) in upstreams.iter().enumerate() {
+            if !candidates.contains(&(index, address)) {
+                continue;
+            }
+            let started = Instant::now();
+            if started >= deadline {
+                break 'attempts;
+            }
+            let reply = match &sockets[position] {
+                Some(socket) => Ok(socket),
+                None => upstream_socket(address).map(|socket| &*sockets[position].insert(socket)),
+            }
+            .and_then(|socket| {
+                socket.send(&bytes)?;
+                receive(socket, &query, (started + timeout).min(deadline))
+            });
+            let mut reply = match reply {
+                Ok(Some(reply)) => reply,
+                Ok(None) => {
+                    if !report.timed_out.contains(&index) {
+                        report.timed_out.push(index);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Socket error with upstream {}: {}", address, e);
+                    candidates.retain(|&(other, _)| other != index);
+                    report.timed_out.retain(|&other| other != index);
+                    report.unreachable.push(index);
+                    socket_error = Some(format!("{}: {}", address, e));
+                    continue;
+                }
+            };
+            report.answered.push((index, started.elapsed()));
+            report.timed_out.retain(|&other| other != index);
+            let left = deadline.saturating_duration_since(Instant::now());
+            if reply.header.third_byte.truncation && !left.is_zero() {
+                match exchange_tcp_within(address, &query, left.min(TCP_TIMEOUT)) {
+                    Ok(full_reply) => reply = full_reply,
+                    Err(e) => eprintln!("TCP query to upstream {} failed: {}", address, e),
+                }
+            }
+            reply.header.packet_id = request.header.packet_id;
+            if reply.header.fourth_byte.response_code == RCode::ServerFailure {
+                eprintln!("Upstream {} failed to answer", address);
+                candidates.retain(|&(other, _)| other != index);
+                server_failure = Some(reply);
+                continue;
+            }
+            report.chosen = Some(index);
+            return (Ok(reply), report);
+        }
+        timeout *= 2;
+    }
+    let reply = server_failure.ok_or_else(|| match (report.timed_out.is_empty(), socket_error) {
+        (true, Some(e)) => anyhow::anyhow!("No upstream reachable, socket error with {}", e),
+        (false, Some(e)) => anyhow::anyhow!(
+            "No upstream answered within {:?}, socket error with {}",
+            QUERY_TIME_LIMIT,
+            e
+        ),
+        (_, None) => anyhow::anyhow!("No upstream answered within {:?}", QUERY_TIME_LIMIT),
+    });
+    (reply, report)
+}
+
+#[derive(Debug)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+}
+
+impl Upstreams {
+    pub fn new(addresses: &[SocketAddr]) -> Result<Self> {
+        if addresses.is_empty() {
+            anyhow::bail!("No upstream resolver");
+        }
+        let upstreams = addresses
+            .iter()
+            .map(|&address| Upstream {
+                address,
+                srtt: Duration::ZERO,
+                failures: 0,
+                down_until: None,
+            })
+            .collect();
+        Ok(Self { upstreams })
+    }
+
+    /// Indexes of the upstreams in the order they are asked: those up by
+    /// SRTT, then those left aside, the soonest back first
+    fn preference(&self, now: Instant) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+        order.sort_by_key(|&index| {
+            let upstream = &self.upstreams[index];
+            match upstream.is_up(now) {
+                true => (false, None, upstream.srtt),
+                false => (true, upstream.down_until, Duration::ZERO),
+            }
+        });
+        order
+    }
+
+    /// Addresses to ask in turn, with their indexes for the report
+    pub fn ordered_addresses(&self) -> Vec<(usize, SocketAddr)> {
+        self.preference(Instant::now())
+            .into_iter()
+            .map(|index| (index, self.upstreams[index].address))
+            .collect()
+    }
+
+    /// Updates the statistics of the upstreams after an exchange
+    pub fn apply(&mut self, report: Report) {
+        for (index, rtt) in report.answered {
+            self.upstreams[index].answered(rtt);
+        }
+        let now = Instant::now();
+        for index in report.timed_out.into_iter().chain(report.unreachable) {
+            self.upstreams[index].timed_out(now);
+        }
+        if let Some(chosen) = report.chosen {
+            for (index, upstream) in self.upstreams.iter_mut().enumerate() {
+                if index != chosen {
+                    upstream.srtt -= upstream.srtt / SRTT_DECAY;
+                }
+            }
+        }
+    }
+
+    /// Exchanges the requests concurrently, each on its own thread with its
+    /// own sockets and ID. The replies are in the order of the requests.
+    pub fn exchange_all(&mut self, requests: &[DnsRequest]) -> Vec<Result<DnsReply>> {
+        let order = self.ordered_addresses();
+        let outcomes: Vec<(Result<DnsReply>, Report)> = thread::scope(|scope| {
+            let handles: Vec<_> = requests
+                .iter()
+                .map(|request| scope.spawn(|| exchange(&order, request)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        (
+                            Err(anyhow::anyhow!("Upstream exchange panicked")),
+                            Report::default(),
+                        )
+                    })
+                })
+                .collect()
+        });
+        outcomes
+            .into_iter()
+            .map(|(reply, report)| {
+                self.apply(report);
+                reply
+            })
+            .collect()
+    }
+
+    pub fn exchange(&mut self, request: &DnsRequest) -> Result<DnsReply> {
+        let order = self.ordered_addresses();
+        let (reply, report) = exchange(&order, request);
+        self.apply(report);
+        reply
+    }
+
+    /// Single question with the DO bit, and the CD bit when `checking_disabled`
+    pub fn query(&mut self, question: &DnsQuestion, checking_disabled: bool) -> Result<DnsReply> {
+        self.exchange(&query_request(question, checking_disabled))
+    }
+}
+
+/// Request for a single question with the DO bit, and the CD bit when
+/// `checking_disabled`
+pub fn query_request(question: &DnsQuestion, checking_disabled: bool) -> DnsRequest {
+    let mut request = DnsRequest::query(0, question.clone(), true);
+    request
+        .header
+        .fourth_byte
+        .set_checking_disabled(checking_disabled);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::dns_edns::UDP_PAYLOAD_SIZE;
+    use crate::dns_label::labels_from_str;
+    use crate::master_file;
+
+    fn question() -> DnsQuestion {
+        DnsQuestion {
+            q_name: labels_from_str("www.example"),
+            q_type: QType::A,
+            q_class: QClass::In,
+        }
+    }
+
+    /// Upstream answering every query with the response code, or a silent
+    /// one without it
+    fn spawn_upstream(rcode: Option<RCode>) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let address = socket.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let Some(rcode) = rcode.clone() else {
+                    continue;
+                };
+                let request = DnsRequest::try_from(&buf[..size]).unwrap();
+                let mut reply = DnsReply::try_from(request).unwrap();
+                reply.header.fourth_byte.response_code = rcode;
+                let bytes: Vec<u8> = reply.into();
+                let _ = socket.send_to(&bytes, source);
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn test_exchange() -> Result<()> {
+        let upstream = UdpSocket::bind("127.0.0.1:0")?;
+        let mut upstreams = Upstreams::new(&[upstream.local_addr()?])?;
+        // the first query is lost, the retry is answered after two strays
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            let _ = upstream.recv_from(&mut buf);
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let request = DnsRequest::try_from(&buf[..size]).unwrap();
+            let reply = DnsReply::try_from(request).unwrap();
+
+            let mut wrong_id = reply.clone();
+            wrong_id.header.packet_id = reply.header.packet_id.wrapping_add(1);
+            let mut wrong_type = reply.clone();
+            wrong_type.questions[0].q_type = QType::Aaaa;
+            for reply in [wrong_id, wrong_type, reply] {
+                let bytes: Vec<u8> = reply.into();
+                upstream.send_to(&bytes, source).unwrap();
+            }
+            while upstream.recv_from(&mut buf).is_ok() {}
+        });
+
+        let request = DnsRequest::query(1234, question(), false);
+        let reply = upstreams.exchange(&request)?;
+        assert_eq!(reply.header.packet_id, 1234);
+        assert_eq!(reply.questions[0].q_type, QType::A);
+
+        // nothing left to receive, all attempts time out
+        let started = Instant::now();
+        assert!(upstreams.exchange(&request).is_err());
+        assert!(started.elapsed() >= INITIAL_TIMEOUT * 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_failover() -> Result<()> {
+        let silent = spawn_upstream(None)?;
+        let failing = spawn_upstream(Some(RCode::ServerFailure))?;
+        let working = spawn_upstream(Some(RCode::NoError))?;
+        let mut upstreams = Upstreams::new(&[silent, failing, working])?;
+
+        let reply = upstreams.query(&question(), false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::NoError);
+        let now = Instant::now();
+        assert!(!upstreams.upstreams[0].is_up(now));
+        assert_eq!(upstreams.preference(now)[2], 0);
+
+        // the silent upstream is left aside, no time is lost on it
+        let started = Instant::now();
+        upstreams.query(&question(), false)?;
+        assert!(started.elapsed() < INITIAL_TIMEOUT);
+
+        // only failures left
+        let mut upstreams = Upstreams::new(&[silent, failing])?;
+        let reply = upstreams.query(&question(), false)?;
+        assert_eq!(reply.header.fourth_byte.response_code, RCode::ServerFailure);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_time_limit() -> Result<()> {
+        let silent = [spawn_upstream(None)?, spawn_upstream(None)?];
+        let mut upstreams = Upstreams::new(&silent)?;
+        let started = Instant::now();
+        let error = upstreams.query(&question(), false).unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= QUERY_TIME_LIMIT);
+        assert!(elapsed < QUERY_TIME_LIMIT + INITIAL_TIMEOUT);
+        assert!(error.to_string().starts_with("No upstream answered within"));
+
+        // nothing listens on the port of a closed socket
+        let closed = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+        let mut upstreams = Upstreams::new(&[closed])?;
+        let started = Instant::now();
+        let error = upstreams.query(&question(), false).unwrap_err();
+        assert!(started.elapsed() < INITIAL_TIMEOUT);
+        assert!(error.to_string().starts_with("No upstream reachable"));
+        assert!(!upstreams.upstreams[0].is_up(Instant::now()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_srtt() -> Result<()> {
+        let (first, second) = (spawn_upstream(None)?, spawn_upstream(None)?);
+        let mut upstreams = Upstreams::new(&[first, second])?;
+        upstreams.upstreams[0].answered(Duration::from_millis(80));
+        upstreams.upstreams[1].answered(Duration::from_millis(40));
+        assert_eq!(upstreams.preference(Instant::now()), vec![1, 0]);
+
+        upstreams.upstreams[1].answered(Duration::from_millis(360));
+        assert_eq!(upstreams.upstreams[1].srtt, Duration::from_millis(80));
+        upstreams.upstreams[1].answered(Duration::from_millis(160));
+        assert_eq!(upstreams.preference(Instant::now()), vec![0, 1]);
+
+        upstreams.upstreams[0].timed_out(Instant::now());
+        upstreams.upstreams[0].timed_out(Instant::now());
+        let down_until = upstreams.upstreams[0].down_until.unwrap();
+        assert!(down_until >= Instant::now() + DOWN_TIME);
+        assert_eq!(upstreams.preference(Instant::now()), vec![1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exchange_over_tcp_when_truncated() -> Result<()> {
+        let udp = UdpSocket::bind("127.0.0.1:0")?;
+        let address = udp.local_addr()?;
+        let tcp = TcpListener::bind(address)?;
+        thread::spawn(move || {
+            let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+            let (size, source) = udp.recv_from(&mut buf).unwrap();
+            let request = DnsRequest::try_from(&buf[..size]).unwrap();
+            let reply: Vec<u8> = DnsReply::try_from(request).unwrap().truncated().into();
+            udp.send_to(&reply, source).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut message = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).unwrap();
+            let request = DnsRequest::try_from(&message[..]).unwrap();
+            let reply: Vec<u8> = DnsReply::try_from(request).unwrap().into();
+            stream
+                .write_all(&(reply.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&reply).unwrap();
+        });
+
+        let mut upstreams = Upstreams::new(&[address])?;
+        let reply = upstreams.query(&question(), false)?;
+        assert!(!reply.header.third_byte.truncation);
+        assert_eq!(reply.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer() -> Result<()> {
+        let origin = labels_from_str("rpz.example");
+        let records = master_file::parse(
+            "@ 300 IN SOA ns.rpz.example. admin.rpz.example. 1 3600 600 86400 300\n\
+             bad.example 300 IN CNAME .\n\
+             *.bad.example 300 IN CNAME .",
+            &origin,
+        )?;
+        let messages = vec![
+            records[..2].to_vec(),
+            vec![records[2].clone(), records[0].clone()],
+        ];
+        let address = spawn_primary(messages.into_iter(), false)?;
+
+        let transferred = transfer(address, &origin)?;
+        assert_eq!(transferred.len(), 3);
+        assert_eq!(transferred[0].r_type, QType::Soa);
+        assert_eq!(
+            transferred[2].r_name,
+            labels_from_str("*.bad.example.rpz.example")
+        );
+
+        // a transfer that never ends is given up
+        let endless =
+            std::iter::once(records[..1].to_vec()).chain(std::iter::repeat(records[1..2].to_vec()));
+        let address = spawn_primary(endless, true)?;
+        let error = transfer(address, &origin).unwrap_err();
+        assert!(error.to_string().contains("exceeds"));
+        Ok(())
+    }
+
+    /// Primary answering a transfer with a message for each set of answers,
+    /// padded to the largest size when `padded`
+    fn spawn_primary(
+        messages: impl Iterator<Item = Vec<DnsAnswer>> + Send + 'static,
+        padded: bool,
+    ) -> Result<SocketAddr> {
+        let tcp = TcpListener::bind("127.0.0.1:0")?;
+        let address = tcp.local_addr()?;
+        thread::spawn(move || {
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut message = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).unwrap();
+            let request = DnsRequest::try_from(&message[..]).unwrap();
+            for (index, answers) in messages.enumerate() {
+                let mut reply = DnsReply::try_from(request.clone()).unwrap();
+                if index > 0 {
+                    reply.questions.clear();
+                }
+                reply.answers = answers;
+                reply.update_counts();
+                let mut reply: Vec<u8> = reply.into();
+                if padded {
+                    reply.resize(u16::MAX as usize, 0);
+                }
+                let sent = stream
+                    .write_all(&(reply.len() as u16).to_be_bytes())
+                    .and_then(|_| stream.write_all(&reply));
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(address)
+    }
+}
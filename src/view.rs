@@ -0,0 +1,148 @@
+//! Split-horizon views: each view serves its own zones, forwarding, policies
+//! and cache to the clients of its prefixes, possibly only on some
+//! listeners. The first matching view answers, and the clients of no view
+//! are answered with the server settings. Each view has its own lock, so
+//! that a slow resolution only holds up the clients of its view.
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::acl::Cidr;
+use crate::config::Config;
+use crate::server::Server;
+use crate::Result;
+
+struct View {
+    name: String,
+    clients: Vec<Cidr>,
+    /// All the listeners when empty
+    listeners: Vec<SocketAddr>,
+    server: Mutex<Server>,
+}
+
+impl View {
+    fn matches(&self, client: &IpAddr, listener: &SocketAddr) -> bool {
+        self.clients.iter().any(|prefix| prefix.contains(client))
+            && (self.listeners.is_empty() || self.listeners.contains(listener))
+    }
+}
+
+pub struct Views {
+    views: Vec<View>,
+    /// Answers the clients of no view
+    default: Mutex<Server>,
+}
+
+/// Server of a view
+fn lock(server: &Mutex<Server>) -> MutexGuard<'_, Server> {
+    server.lock().expect("A request handler panicked")
+}
+
+impl Views {
+    pub fn new(config: &Config) -> Result<Self> {
+        let views = config
+            .views
+            .iter()
+            .map(|view| {
+                let server = Server::new(&view.config)
+                    .map_err(|e| anyhow::anyhow!("view {}: {}", view.name, e))?;
+                Ok(View {
+                    name: view.name.clone(),
+                    clients: view.clients.clone(),
+                    listeners: view.listeners.clone(),
+                    server: Mutex::new(server),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            views,
+            default: Mutex::new(Server::new(config)?),
+        })
+    }
+
+    /// View of the client, none for the server settings
+    fn select(&self, client: &IpAddr, listener: &SocketAddr) -> Option<usize> {
+        self.views
+            .iter()
+            .position(|view| view.matches(client, listener))
+    }
+
+    /// Builds the response to a raw request received on the listener, with
+    /// the view of the client
+    pub fn handle(
+        &self,
+        buf: &[u8],
+        client: IpAddr,
+        listener: SocketAddr,
+    ) -> Result<Option<Vec<u8>>> {
+        // IPv4 clients of a dual-stack listener are seen as ::ffff:a.b.c.d
+        let client = client.to_canonical();
+        match self.select(&client, &listener) {
+            Some(index) => {
+                let view = &self.views[index];
+                lock(&view.server)
+                    .handle(buf, client)
+                    .map_err(|e| anyhow::anyhow!("view {}: {}", view.name, e))
+            }
+            None => lock(&self.default).handle(buf, client),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsReply, DnsRequest};
+    use crate::dns_class::QClass;
+    use crate::dns_label::labels_from_str;
+    use crate::dns_question::DnsQuestion;
+    use crate::dns_type::QType;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_views() -> Result<()> {
+        let mut arguments = args("--static-record");
+        arguments.push("app.example.com 60 A 203.0.113.10".to_string());
+        arguments.extend(args("--view internal 10.0.0.0/8 --static-record"));
+        arguments.push("app.example.com 60 A 10.0.0.10".to_string());
+        arguments.extend(args(
+            "--view lab 192.168.0.0/16 --view-listener 192.168.0.1:53",
+        ));
+        let views = Views::new(&Config::from_args(&arguments)?)?;
+
+        let listener: SocketAddr = "127.0.0.1:2053".parse()?;
+        let internal: IpAddr = "10.1.2.3".parse()?;
+        let external: IpAddr = "198.51.100.1".parse()?;
+        let lab: IpAddr = "192.168.1.1".parse()?;
+        let name = |client: &IpAddr, listener: &SocketAddr| {
+            let index = views.select(client, listener)?;
+            Some(views.views[index].name.clone())
+        };
+        assert_eq!(name(&internal, &listener), Some("internal".to_string()));
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse()?;
+        assert_eq!(name(&external, &listener), None);
+        assert_eq!(name(&lab, &listener), None);
+        assert_eq!(
+            name(&lab, &"192.168.0.1:53".parse()?),
+            Some("lab".to_string())
+        );
+
+        let question = DnsQuestion {
+            q_name: labels_from_str("app.example.com"),
+            q_type: QType::A,
+            q_class: QClass::In,
+        };
+        let request: Vec<u8> = DnsRequest::query(3, question, false).into();
+        let address = |client: IpAddr| -> Result<Vec<u8>> {
+            let response = views.handle(&request, client, listener)?.unwrap();
+            Ok(DnsReply::try_from(&response[..])?.answers[0].r_data.clone())
+        };
+        assert_eq!(address(internal)?, vec![10, 0, 0, 10]);
+        assert_eq!(address(mapped)?, vec![10, 0, 0, 10]);
+        assert_eq!(address(external)?, vec![203, 0, 113, 10]);
+        assert!(views.handle(&[0, 1, 2], external, listener).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+//! Offline zone signing: reads a master file and key files, writes the signed
+//! zone in canonical order and the DS records to hand to the parent zone.
+//! Usage: `dns-starter-rust signzone --zone <file> --key <prefix> [--key <prefix>...] [options]`
+use crate::dns_answer::DnsAnswer;
+use crate::dns_class::QClass;
+use crate::dns_label::{labels_to_string, DnsLabel};
+use crate::dns_type::QType;
+use crate::dns_zone::Zone;
+use crate::dnssec::{canonical_sort, Ds, DIGEST_SHA256};
+use crate::dnssec_chain::{Nsec3Chain, NsecChain};
+use crate::dnssec_key::SigningKey;
+use crate::dnssec_signer::{
+    group_rrsets, keys_for, now, publish_keys, publish_nsec3_param, INCEPTION_OFFSET,
+};
+use crate::encoding::{hex_decode, parse_timestamp};
+use crate::master_file::{self, parse_name, parse_ttl};
+use crate::Result;
+
+/// Default validity of offline signatures: 30 days
+const DEFAULT_VALIDITY: u32 = 30 * 86400;
+
+/// Records generated by the signer, removed from the input before signing again
+const GENERATED_TYPES: [QType; 4] = [QType::Rrsig, QType::Nsec, QType::Nsec3, QType::Nsec3param];
+
+#[derive(Debug, PartialEq)]
+pub struct SignZoneConfig {
+    pub zone_file: String,
+    /// Taken from the SOA record when not given
+    pub origin: Option<Vec<DnsLabel>>,
+    pub keys: Vec<String>,
+    /// Directory holding the keys of the zone, in addition to `keys`
+    pub key_dir: Option<String>,
+    /// NSEC3 salt and iterations, NSEC is used when not set
+    pub nsec3: Option<(Vec<u8>, u16)>,
+    pub opt_out: bool,
+    pub inception: u32,
+    pub expiration: u32,
+    /// Defaults to `<zone file>.signed`
+    pub output: Option<String>,
+    /// Defaults to `dsset-<origin>`
+    pub ds_output: Option<String>,
+    /// Time at which the key timings are evaluated
+    pub now: u32,
+}
+
+/// Absolute timestamp, or a duration relative to now when prefixed by + or -
+fn parse_time(text: &str, now: u32) -> Result<u32> {
+    if let Some(duration) = text.strip_prefix('+') {
+        return Ok(now.wrapping_add(parse_ttl(duration)?));
+    }
+    if let Some(duration) = text.strip_prefix('-') {
+        return Ok(now.wrapping_sub(parse_ttl(duration)?));
+    }
+    parse_timestamp(text)
+}
+
+impl SignZoneConfig {
+    /// --zone <file>
+    /// --origin <name>
+    /// --key <key file prefix>             (repeatable)
+    /// --key-dir <dir>
+    /// --nsec3 <salt hex or -> <iterations>
+    /// --opt-out                           (with --nsec3)
+    /// --inception <time>                  (YYYYMMDDHHmmSS, seconds, or +/-duration from now)
+    /// --expiration <time>
+    /// --output <file>
+    /// --ds-output <file>
+    pub fn from_args(args: &[String], now: u32) -> Result<Self> {
+        let mut zone_file = None;
+        let mut config = Self {
+            zone_file: String::new(),
+            origin: None,
+            keys: vec![],
+            key_dir: None,
+            nsec3: None,
+            opt_out: false,
+            inception: now.saturating_sub(INCEPTION_OFFSET),
+            expiration: now.saturating_add(DEFAULT_VALIDITY),
+            output: None,
+            ds_output: None,
+            now,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--zone" => zone_file = Some(value()?.to_string()),
+                "--origin" => config.origin = Some(parse_name(value()?, &[])?),
+                "--key" => config.keys.push(value()?.to_string()),
+                "--key-dir" => config.key_dir = Some(value()?.to_string()),
+                "--nsec3" => {
+                    let salt = match value()?.as_str() {
+                        "-" => vec![],
+                        salt => hex_decode(salt)?,
+                    };
+                    let iterations = value()?.parse::<u16>()?;
+                    config.nsec3 = Some((salt, iterations));
+                }
+                "--opt-out" => config.opt_out = true,
+                "--inception" => config.inception = parse_time(value()?, now)?,
+                "--expiration" => config.expiration = parse_time(value()?, now)?,
+                "--output" => config.output = Some(value()?.to_string()),
+                "--ds-output" => config.ds_output = Some(value()?.to_string()),
+                _ => anyhow::bail!("Unknown argument {}", arg),
+            }
+        }
+
+        let Some(zone_file) = zone_file else {
+            anyhow::bail!("--zone is required");
+        };
+        config.zone_file = zone_file;
+        if config.keys.is_empty() && config.key_dir.is_none() {
+            anyhow::bail!("At least one --key or a --key-dir is required");
+        }
+        if config.opt_out && config.nsec3.is_none() {
+            anyhow::bail!("--opt-out needs --nsec3");
+        }
+        // serial number arithmetic, the expiration must come after the inception
+        if (config.expiration.wrapping_sub(config.inception) as i32) <= 0 {
+            anyhow::bail!("Signature expiration must be after the inception");
+        }
+        Ok(config)
+    }
+}
+
+/// Signs every authoritative RRset of the zone and adds the denial chain.
+/// Glue is kept unsigned, delegation points only get their DS and NSEC signed.
+/// The records are returned in canonical order.
+pub fn sign_zone(
+    mut zone: Zone,
+    keys: &[SigningKey],
+    config: &SignZoneConfig,
+) -> Result<Vec<DnsAnswer>> {
+    zone.retain(|record| !GENERATED_TYPES.contains(&record.r_type));
+    publish_keys(&mut zone, keys, config.now)?;
+
+    let mut chain_records: Vec<DnsAnswer> = match config.nsec3.clone() {
+        Some((salt, iterations)) => {
+            let params = publish_nsec3_param(&mut zone, salt, iterations)?;
+            Nsec3Chain::build(&zone, params, config.opt_out)
+                .records()
+                .cloned()
+                .collect()
+        }
+        None => NsecChain::build(&zone).records().to_vec(),
+    };
+
+    let mut records: Vec<DnsAnswer> = zone.records().cloned().collect();
+    records.append(&mut chain_records);
+    canonical_sort(&mut records);
+
+    let mut rrsigs = Vec::new();
+    for rrset in group_rrsets(&records) {
+        let first = &rrset[0];
+        let owner = &first.r_name;
+        let is_chain_record = matches!(first.r_type, QType::Nsec | QType::Nsec3);
+        if !is_chain_record && !zone.is_authoritative(owner) {
+            continue;
+        }
+        let at_cut = zone.delegation_point(owner).is_some();
+        if at_cut && !matches!(first.r_type, QType::Ds | QType::Nsec) {
+            continue;
+        }
+        for key in keys_for(keys, &first.r_type, config.now) {
+            rrsigs.push(key.sign_rrset(&rrset, config.inception, config.expiration));
+        }
+    }
+
+    records.append(&mut rrsigs);
+    canonical_sort(&mut records);
+    Ok(records)
+}
+
+/// DS records of the key signing keys active at `now` (of all keys when there is none)
+pub fn ds_records(keys: &[SigningKey], ttl: u32, now: u32) -> Result<Vec<DnsAnswer>> {
+    let mut records = Vec::new();
+    for key in keys_for(keys, &QType::Dnskey, now) {
+        let r_data: Vec<u8> = Ds::from_dnskey(&key.owner, &key.dnskey, DIGEST_SHA256)?.into();
+        records.push(DnsAnswer {
+            r_name: key.owner.clone(),
+            r_type: QType::Ds,
+            r_class: QClass::In,
+            ttl,
+            rd_length: r_data.len() as u16,
+            r_data,
+        });
+    }
+    Ok(records)
+}
+
+pub fn write_records(path: &str, records: &[DnsAnswer]) -> Result<()> {
+    let mut content = String::new();
+    for record in records {
+        content.push_str(&master_file::format_record(record));
+        content.push('\n');
+    }
+    std::fs::write(path, content).map_err(|e| anyhow::anyhow!("Could not write {}: {}", path, e))
+}
+
+/// Entry point of the `signzone` command
+pub fn run(args: &[String]) -> Result<()> {
+    let config = SignZoneConfig::from_args(args, now())?;
+    let zone = Zone::load(&config.zone_file, config.origin.clone())?;
+    let mut keys = config
+        .keys
+        .iter()
+        .map(|prefix| SigningKey::load(prefix))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(key_dir) = &config.key_dir {
+        keys.extend(SigningKey::load_dir(key_dir, &zone.origin)?);
+    }
+
+    let origin = zone.origin.clone();
+    let ttl = zone.soa().map(|soa| soa.ttl).unwrap_or_default();
+    let records = sign_zone(zone, &keys, &config)?;
+
+    let output = config
+        .output
+        .clone()
+        .unwrap_or_else(|| format!("{}.signed", config.zone_file));
+    write_records(&output, &records)?;
+
+    let ds_output = config
+        .ds_output
+        .clone()
+        .unwrap_or_else(|| format!("dsset-{}", labels_to_string(&origin)));
+    write_records(&ds_output, &ds_records(&keys, ttl, config.now)?)?;
+
+    println!(
+        "Signed {} with {} keys: {} records written to {}, DS records to {}",
+        labels_to_string(&origin),
+        keys.len(),
+        records.len(),
+        output,
+        ds_output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519;
+    use crate::dns_label::labels_from_str;
+    use crate::dnssec::Dnskey;
+    use crate::dnssec::{Nsec3, Rrsig, ALGORITHM_ED25519, DNSKEY_SEP, DNSKEY_ZONE};
+    use crate::dnssec_key::{KeyTiming, PrivateKey};
+
+    const ZONE: &str = r#"
+$ORIGIN example.
+@       3600 SOA ns1 hostmaster 1 7200 3600 1209600 300
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.2
+        A   192.0.2.3
+sub     NS  ns.sub
+ns.sub  A   192.0.2.4
+"#;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn test_key(seed: u8, flags: u16) -> Result<SigningKey> {
+        let private_key = PrivateKey::Ed25519(ed25519::PrivateKey::from_bytes(&[seed; 32])?);
+        Ok(SigningKey {
+            owner: labels_from_str("example"),
+            dnskey: Dnskey {
+                flags,
+                protocol: 3,
+                algorithm: ALGORITHM_ED25519,
+                public_key: private_key.public_key(),
+            },
+            private_key,
+            timing: KeyTiming::default(),
+        })
+    }
+
+    #[test]
+    fn test_sign_zone_config() -> Result<()> {
+        let config = SignZoneConfig::from_args(
+            &args("--zone z --key Ka --nsec3 - 0 --opt-out --inception -1h --expiration 20260101000000"),
+            1_000_000,
+        )?;
+        assert_eq!(config.inception, 1_000_000 - 3600);
+        assert_eq!(config.expiration, parse_timestamp("20260101000000")?);
+        assert_eq!(config.nsec3, Some((vec![], 0)));
+        assert!(config.opt_out);
+
+        assert!(SignZoneConfig::from_args(&args("--zone z"), 0).is_err());
+        assert!(SignZoneConfig::from_args(&args("--zone z --key Ka --opt-out"), 0).is_err());
+        assert!(SignZoneConfig::from_args(
+            &args("--zone z --key Ka --inception +2d --expiration +1d"),
+            1_000_000
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_zone() -> Result<()> {
+        let zone = Zone::from_records(labels_from_str("example"), master_file::parse(ZONE, &[])?)?;
+        let keys = vec![
+            test_key(1, DNSKEY_ZONE | DNSKEY_SEP)?,
+            test_key(2, DNSKEY_ZONE)?,
+        ];
+        let mut config = SignZoneConfig::from_args(&args("--zone z --key K --key K"), 1_000_000)?;
+        config.nsec3 = Some((vec![0xab], 1));
+        config.opt_out = true;
+
+        let records = sign_zone(zone, &keys, &config)?;
+        let mut sorted = records.clone();
+        canonical_sort(&mut sorted);
+        assert_eq!(records, sorted);
+
+        let covered = |owner: &str| -> Vec<QType> {
+            records
+                .iter()
+                .filter(|r| r.r_type == QType::Rrsig && r.r_name == labels_from_str(owner))
+                .map(|r| Rrsig::try_from(&r.r_data[..]).unwrap().type_covered)
+                .collect()
+        };
+        // SOA, NS, DNSKEY (by the KSK) and NSEC3PARAM at the apex
+        assert_eq!(covered("example").len(), 4);
+        // both A records are covered by a single signature
+        assert_eq!(covered("www.example"), vec![QType::A]);
+        // the unsigned delegation and its glue are not signed
+        assert!(covered("sub.example").is_empty());
+        assert!(covered("ns.sub.example").is_empty());
+
+        let nsec3s: Vec<&DnsAnswer> = records
+            .iter()
+            .filter(|r| r.r_type == QType::Nsec3)
+            .collect();
+        // apex, ns1 and www, sub is opted out
+        assert_eq!(nsec3s.len(), 3);
+        for nsec3 in &nsec3s {
+            assert_eq!(Nsec3::try_from(&nsec3.r_data[..])?.flags, 1);
+            assert_eq!(
+                covered(&labels_to_string(&nsec3.r_name)),
+                vec![QType::Nsec3]
+            );
+        }
+
+        let rrsig = records.iter().find(|r| r.r_type == QType::Rrsig).unwrap();
+        let rrsig = Rrsig::try_from(&rrsig.r_data[..])?;
+        assert_eq!(rrsig.inception, config.inception);
+        assert_eq!(rrsig.expiration, config.expiration);
+
+        let ds = ds_records(&keys, 3600, 0)?;
+        assert_eq!(ds.len(), 1);
+        assert_eq!(Ds::try_from(&ds[0].r_data[..])?.key_tag, keys[0].key_tag());
+        Ok(())
+    }
+}